@@ -0,0 +1,9 @@
+//! The body of the requests, before the server looks at the command.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = shared::command::parse(data);
+});
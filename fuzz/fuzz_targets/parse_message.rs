@@ -0,0 +1,14 @@
+//! The framing of everything read from a socket.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::protocol::parse_message;
+
+fuzz_target!(|data: &[u8]| {
+    // Like the event loop, parse as many messages as the buffer holds
+    let mut buf = data;
+    while let Ok((read, _)) = parse_message(buf) {
+        buf = &buf[read..];
+    }
+});
@@ -0,0 +1,19 @@
+//! The replies read by the clients and the replication handshake read by the server and its replicas.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::client::Value;
+use shared::protocol::{Reader, MAX_MSG_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    // Bodies are never longer than a message, which also bounds how deep arrays nest
+    if data.len() > MAX_MSG_LEN {
+        return;
+    }
+
+    let _ = Value::decode(data);
+
+    let _ = Reader::new(data).read_psync();
+    let _ = Reader::new(data).read_psync_reply();
+});
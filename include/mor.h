@@ -0,0 +1,83 @@
+/*
+ * The C ABI of the my-own-redis client, implemented in src/shared/ffi.rs.
+ *
+ * Build the library with:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * or --crate-type staticlib, and link with target/release/libshared.so or libshared.a.
+ *
+ * Keep in sync with src/shared/ffi.rs, the layout of mor_reply must match the Rust Reply.
+ */
+
+#ifndef MOR_H
+#define MOR_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A connection to the server, only used through pointers. */
+typedef struct mor_client mor_client;
+
+/* The type of a reply, the data type of the protocol. */
+typedef enum mor_reply_type {
+    MOR_REPLY_NIL = 0,
+    MOR_REPLY_ERR = 1,
+    MOR_REPLY_STR = 2,
+    MOR_REPLY_INT = 3,
+    MOR_REPLY_ARR = 4,
+} mor_reply_type;
+
+/*
+ * A reply of the server:
+ * - code, str and len are set for an error, str and len for a string. str isn't NUL-terminated.
+ * - integer is set for an integer
+ * - elements and n_elements are set for an array
+ */
+typedef struct mor_reply {
+    mor_reply_type type;
+    uint32_t code;
+    uint64_t integer;
+    uint8_t *str;
+    size_t len;
+    struct mor_reply *elements;
+    size_t n_elements;
+} mor_reply;
+
+/*
+ * Connect to addr, either host:port or the path of a unix socket.
+ * Returns NULL if the connection fails.
+ */
+mor_client *mor_connect(const char *addr);
+
+/* Close the connection and free the client. Does nothing if client is NULL. */
+void mor_close(mor_client *client);
+
+/*
+ * Execute the command made of the argc arguments in argv, the length of each one in argv_len.
+ * Returns the reply, error replies included, or NULL if there are no arguments or the command couldn't be sent or
+ * its reply read. After NULL the client should be closed.
+ */
+mor_reply *mor_command(mor_client *client, size_t argc, const uint8_t *const *argv, const size_t *argv_len);
+
+/*
+ * Send the n commands before reading their replies, command i made of the argc[i] arguments in argv[i], the length of
+ * each one in argv_len[i] like for mor_command.
+ * Returns an array reply holding the n replies in order, or NULL if a command has no arguments or the commands
+ * couldn't be sent or their replies read. After NULL the client should be closed.
+ */
+mor_reply *mor_pipeline(mor_client *client, size_t n, const size_t *argc, const uint8_t *const *const *argv,
+                        const size_t *const *argv_len);
+
+/* Free a reply and its elements. Does nothing if reply is NULL. */
+void mor_free_reply(mor_reply *reply);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,246 @@
+//! The `myownredis` Python module, wrapping [`shared::client::Client`].
+//!
+//! Build it from this directory and copy the library where Python finds it, under the name of the module:
+//!
+//! ```text
+//! cargo build --release
+//! cp target/release/libmyownredis.so myownredis.so
+//! ```
+//!
+//! then, with the directory of `myownredis.so` in `PYTHONPATH`:
+//!
+//! ```python
+//! import myownredis
+//!
+//! client = myownredis.connect("127.0.0.1:1234")
+//! client.set("name", "vincent")
+//! assert client.get("name") == b"vincent"
+//! ```
+//!
+//! Arguments are bytes, or str encoded as UTF-8. Replies are None, bytes, int, a list of replies, or a `ReplyError`.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList, PyString};
+use shared::client::{self, Value};
+
+create_exception!(
+    myownredis,
+    ConnectionError,
+    PyOSError,
+    "The connection failed, or a command couldn't be sent or its reply read."
+);
+
+/// An error reply of the server.
+#[pyclass(extends = PyException, module = "myownredis")]
+struct ReplyError {
+    #[pyo3(get)]
+    code: u32,
+    #[pyo3(get)]
+    message: String,
+}
+
+#[pymethods]
+impl ReplyError {
+    #[new]
+    fn new(code: u32, message: String) -> Self {
+        Self { code, message }
+    }
+
+    fn __str__(&self) -> String {
+        format!("error reply {}: {}", self.code, self.message)
+    }
+}
+
+fn client_error(py: Python, err: client::Error) -> PyErr {
+    match err {
+        client::Error::Reply { code, message } => reply_error(py, code, message),
+        err => ConnectionError::new_err(error_chain(&err)),
+    }
+}
+
+/// The error with its sources, the Python exception only has the message.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+
+    message
+}
+
+fn reply_error(py: Python, code: u32, message: String) -> PyErr {
+    match Bound::new(py, ReplyError { code, message }) {
+        Ok(err) => PyErr::from_value(err.into_any()),
+        Err(err) => err,
+    }
+}
+
+fn encode(arg: &Bound<PyAny>) -> PyResult<Vec<u8>> {
+    if let Ok(arg) = arg.cast::<PyBytes>() {
+        return Ok(arg.as_bytes().to_vec());
+    }
+    if let Ok(arg) = arg.cast::<PyString>() {
+        return Ok(arg.to_str()?.as_bytes().to_vec());
+    }
+
+    let name = arg.get_type().name()?;
+    Err(PyTypeError::new_err(format!(
+        "argument must be bytes or str, not {}",
+        name
+    )))
+}
+
+fn encode_command(args: &Bound<PyAny>) -> PyResult<Vec<Vec<u8>>> {
+    let command = args
+        .try_iter()?
+        .map(|arg| encode(&arg?))
+        .collect::<PyResult<Vec<_>>>()?;
+    if command.is_empty() {
+        return Err(PyValueError::new_err(
+            "a command needs at least one argument",
+        ));
+    }
+
+    Ok(command)
+}
+
+/// Converts the reply, error replies become a `ReplyError` returned and not raised.
+fn convert(py: Python, value: Value) -> PyResult<Py<PyAny>> {
+    let value = match value {
+        Value::Nil => py.None(),
+        Value::Error { code, message } => Py::new(py, ReplyError { code, message })?.into_any(),
+        Value::Bytes(value) => PyBytes::new(py, &value).into_any().unbind(),
+        Value::Int(value) => value.into_pyobject(py)?.into_any().unbind(),
+        Value::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| convert(py, item))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, items)?.into_any().unbind()
+        }
+    };
+
+    Ok(value)
+}
+
+/// A connection to the server, see `connect`. Also a context manager closing the connection.
+#[pyclass(module = "myownredis")]
+struct Client {
+    client: Option<client::Client>,
+}
+
+impl Client {
+    /// Send the commands and read their replies without holding the GIL.
+    fn pipeline_bytes(&mut self, py: Python, commands: Vec<Vec<Vec<u8>>>) -> PyResult<Vec<Value>> {
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return Err(ConnectionError::new_err("the connection is closed")),
+        };
+
+        let result = py.detach(|| {
+            let commands: Vec<Vec<&[u8]>> = commands
+                .iter()
+                .map(|command| command.iter().map(Vec::as_slice).collect())
+                .collect();
+            client.pipeline(&commands)
+        });
+
+        match result {
+            Ok(replies) => Ok(replies),
+            Err(err) => {
+                // NOTE(vincent): nothing is retried, the client can't be used after an error.
+                self.client = None;
+                Err(client_error(py, err))
+            }
+        }
+    }
+
+    /// Execute the command, raising `ReplyError` for an error reply.
+    fn execute_bytes(&mut self, py: Python, command: Vec<Vec<u8>>) -> PyResult<Value> {
+        match self.pipeline_bytes(py, vec![command])?.remove(0) {
+            Value::Error { code, message } => Err(reply_error(py, code, message)),
+            value => Ok(value),
+        }
+    }
+}
+
+#[pymethods]
+impl Client {
+    /// Execute the command made of args, raising `ReplyError` for an error reply.
+    #[pyo3(signature = (*args))]
+    fn execute(&mut self, py: Python, args: &Bound<PyAny>) -> PyResult<Py<PyAny>> {
+        let reply = self.execute_bytes(py, encode_command(args)?)?;
+        convert(py, reply)
+    }
+
+    /// Send all the commands before reading their replies, error replies are returned and not raised.
+    fn pipeline(&mut self, py: Python, commands: &Bound<PyAny>) -> PyResult<Py<PyAny>> {
+        let commands = commands
+            .try_iter()?
+            .map(|command| encode_command(&command?))
+            .collect::<PyResult<Vec<_>>>()?;
+        if commands.is_empty() {
+            return Ok(PyList::empty(py).into_any().unbind());
+        }
+
+        let replies = self.pipeline_bytes(py, commands)?;
+        convert(py, Value::Array(replies))
+    }
+
+    /// Returns the value of key, None if it doesn't exist.
+    fn get(&mut self, py: Python, key: &Bound<PyAny>) -> PyResult<Py<PyAny>> {
+        let reply = self.execute_bytes(py, vec![b"get".to_vec(), encode(key)?])?;
+        convert(py, reply)
+    }
+
+    fn set(&mut self, py: Python, key: &Bound<PyAny>, value: &Bound<PyAny>) -> PyResult<()> {
+        self.execute_bytes(py, vec![b"set".to_vec(), encode(key)?, encode(value)?])?;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.client = None;
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_exc))]
+    fn __exit__(&mut self, _exc: &Bound<PyAny>) {
+        self.close();
+    }
+}
+
+/// Connect to addr, either host:port or the path of a unix socket.
+#[pyfunction]
+fn connect(py: Python, addr: &str) -> PyResult<Client> {
+    match py.detach(|| client::Client::connect(addr)) {
+        Ok(client) => Ok(Client {
+            client: Some(client),
+        }),
+        Err(err) => Err(ConnectionError::new_err(format!(
+            "unable to connect to {}: {}",
+            addr,
+            error_chain(&err)
+        ))),
+    }
+}
+
+/// Python bindings for the my-own-redis client.
+///
+/// Arguments are bytes, or str encoded as UTF-8. Replies are None, bytes, int, a list of replies, or a ReplyError.
+#[pymodule]
+fn myownredis(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add("ConnectionError", m.py().get_type::<ConnectionError>())?;
+    m.add_class::<ReplyError>()?;
+    m.add_class::<Client>()?;
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    Ok(())
+}
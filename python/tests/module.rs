@@ -0,0 +1,49 @@
+//! Builds the module like its documentation says and runs tests/test_myownredis.py against an embedded server, with
+//! `python3` from `PATH`.
+
+use shared::server::Server;
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs, process};
+
+#[test]
+fn python() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib"])
+        .current_dir(manifest_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "unable to build the module");
+
+    // NOTE(vincent): the test binary is in target/<profile>/deps, the library in target/<profile>.
+    let exe = env::current_exe().unwrap();
+    let library = exe
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("libmyownredis.so");
+
+    // Python only imports the library under the name of the module
+    let dir = env::temp_dir().join(format!("myownredis-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy(&library, dir.join("myownredis.so")).unwrap();
+
+    let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+
+    let output = Command::new("python3")
+        .arg(manifest_dir.join("tests").join("test_myownredis.py"))
+        .env("PYTHONPATH", &dir)
+        .env("MYOWNREDIS_ADDR", server.addr().to_string())
+        .output();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let output = output.expect("unable to run python3");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,58 @@
+"""Run by tests/module.rs against an embedded server, at the address in MYOWNREDIS_ADDR."""
+
+import os
+import unittest
+
+import myownredis
+
+ADDR = os.environ["MYOWNREDIS_ADDR"]
+
+
+class ClientTest(unittest.TestCase):
+    def setUp(self):
+        self.client = myownredis.connect(ADDR)
+
+    def tearDown(self):
+        self.client.close()
+
+    def test_get_set(self):
+        self.assertIsNone(self.client.get("a"))
+        self.client.set("a", b"1")
+        self.assertEqual(b"1", self.client.get(b"a"))
+        self.assertEqual(1, self.client.execute("del", "a"))
+        self.assertIsNone(self.client.get("a"))
+
+    def test_execute(self):
+        self.assertEqual(b"PONG", self.client.execute("ping"))
+
+        with self.assertRaises(myownredis.ReplyError) as cm:
+            self.client.execute("nope")
+        self.assertEqual(100, cm.exception.code)
+        self.assertIn(cm.exception.message, str(cm.exception))
+
+        with self.assertRaises(TypeError):
+            self.client.execute("get", 1)
+        with self.assertRaises(ValueError):
+            self.client.execute()
+
+    def test_pipeline(self):
+        replies = self.client.pipeline([["set", "b", "2"], ["get", "b"], ["nope"], ["keys"]])
+        self.assertIsNone(replies[0])
+        self.assertEqual(b"2", replies[1])
+        self.assertIsInstance(replies[2], myownredis.ReplyError)
+        self.assertIn(b"b", replies[3])
+
+        self.assertEqual([], self.client.pipeline([]))
+
+    def test_close(self):
+        with myownredis.connect(ADDR) as client:
+            self.assertEqual(b"PONG", client.execute("ping"))
+        with self.assertRaises(myownredis.ConnectionError):
+            client.execute("ping")
+
+        with self.assertRaises(myownredis.ConnectionError):
+            myownredis.connect("127.0.0.1:1")
+
+
+if __name__ == "__main__":
+    unittest.main()
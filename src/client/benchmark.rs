@@ -0,0 +1,180 @@
+//! The benchmark mode, like redis-benchmark: several connections send SET and GET commands for random keys, and the
+//! throughput and latencies are reported once they're all done.
+//!
+//! With a pipeline the latency of a command is the time between the write of its batch and the read of its reply.
+
+use crate::config::ClientConfig;
+use crate::connection::Connection;
+use crate::QueryError;
+use shared::protocol;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Random numbers from the standard library hasher, seeded differently for each connection.
+struct Random {
+    state: RandomState,
+    counter: u64,
+}
+
+impl Random {
+    fn new() -> Self {
+        Self {
+            state: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    /// Returns a number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        let mut hasher = self.state.build_hasher();
+        hasher.write_u64(self.counter);
+        self.counter += 1;
+
+        (hasher.finish() % bound as u64) as usize
+    }
+}
+
+struct Results {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+fn run_client(
+    config: &ClientConfig,
+    requests: usize,
+    pipeline: usize,
+) -> Result<Results, QueryError> {
+    let mut conn = Connection::open(config)?;
+    let config = &config.benchmark;
+
+    let value = vec![b'x'; config.value_size];
+    let mut random = Random::new();
+    let mut results = Results {
+        latencies: Vec::with_capacity(requests),
+        errors: 0,
+    };
+
+    let mut remaining = requests;
+    while remaining > 0 {
+        let nb_commands = remaining.min(pipeline);
+
+        let keys: Vec<String> = (0..nb_commands)
+            .map(|_| format!("key:{:012}", random.below(config.keyspace)))
+            .collect();
+        let commands: Vec<Vec<&[u8]>> = keys
+            .iter()
+            .map(|key| {
+                if random.below(100) < config.set_ratio as usize {
+                    vec![b"set".as_slice(), key.as_bytes(), &value]
+                } else {
+                    vec![b"get".as_slice(), key.as_bytes()]
+                }
+            })
+            .collect();
+
+        let start = Instant::now();
+        conn.write_commands(&commands)?;
+
+        for _ in 0..nb_commands {
+            let message = conn.read_message()?;
+            results.latencies.push(start.elapsed());
+
+            if message.first() == Some(&(protocol::DataType::Err as u8)) {
+                results.errors += 1;
+            }
+        }
+
+        remaining -= nb_commands;
+    }
+
+    Ok(results)
+}
+
+/// Returns the latency below which `p` percent of the sorted latencies are.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub fn run(client_config: &ClientConfig, pipeline: usize) -> Result<(), QueryError> {
+    let config = &client_config.benchmark;
+    let start = Instant::now();
+
+    // The requests are split evenly, the first connections send one more if needed
+    let handles: Vec<_> = (0..config.clients)
+        .map(|i| {
+            let requests = config.requests / config.clients
+                + usize::from(i < config.requests % config.clients);
+            let client_config = client_config.clone();
+
+            thread::spawn(move || run_client(&client_config, requests, pipeline))
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut errors = 0;
+    for handle in handles {
+        let results = handle.join().expect("benchmark client panicked")?;
+
+        latencies.extend(results.latencies);
+        errors += results.errors;
+    }
+
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    println!(
+        "{} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "{} parallel clients, {}% SET, {} bytes payload, {} keys, pipeline {}",
+        config.clients, config.set_ratio, config.value_size, config.keyspace, pipeline
+    );
+    println!("{} errors", errors);
+    println!();
+    println!(
+        "throughput: {:.2} requests per second",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 95.0),
+        percentile(&latencies, 99.0),
+        percentile(&latencies, 100.0)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percentile, Random};
+    use std::time::Duration;
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(Duration::ZERO, percentile(&[], 50.0));
+
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(Duration::from_millis(1), percentile(&latencies, 0.0));
+        assert_eq!(Duration::from_millis(50), percentile(&latencies, 50.0));
+        assert_eq!(Duration::from_millis(99), percentile(&latencies, 99.0));
+        assert_eq!(Duration::from_millis(100), percentile(&latencies, 100.0));
+    }
+
+    #[test]
+    fn random() {
+        let mut random = Random::new();
+        assert!((0..1000).all(|_| random.below(10) < 10));
+        assert_eq!(0, random.below(1));
+    }
+}
@@ -0,0 +1,175 @@
+//! The cluster mode, enabled with `-c`: the `MOVED` and `ASK` redirections sent by the nodes are followed, opening a
+//! connection to each node as needed.
+//!
+//! The slots learned from the `MOVED` redirections are cached, the next commands for a key in one of them being sent
+//! directly to its owner. The client doesn't know which arguments are keys, it assumes it's the first one: a wrong
+//! guess only costs a redirection.
+
+use crate::config::{Address, ClientConfig};
+use crate::connection::Connection;
+use crate::QueryError;
+use shared::client::Value;
+use shared::{debug, slot, ResponseCode};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+/// Number of redirections followed for a command before giving up and returning the last one.
+const MAX_REDIRECTS: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Redirect {
+    Moved(u16, SocketAddrV4),
+    Ask(SocketAddrV4),
+}
+
+/// Returns the redirection if the reply is a `MOVED <slot> <ip>:<port>` or `ASK <slot> <ip>:<port>` error.
+fn parse_redirect(reply: &Value) -> Option<Redirect> {
+    let (code, message) = match reply {
+        Value::Error { code, message } => (*code, message),
+        _ => return None,
+    };
+
+    let (slot, addr) = message.split_once(' ')?;
+    let slot = slot.parse().ok()?;
+    let addr = addr.parse().ok()?;
+
+    match ResponseCode::try_from(code).ok()? {
+        ResponseCode::Moved => Some(Redirect::Moved(slot, addr)),
+        ResponseCode::Ask => Some(Redirect::Ask(addr)),
+        _ => None,
+    }
+}
+
+pub struct Cluster {
+    config: ClientConfig,
+    /// The connections to the nodes, opened when a command is first sent to them.
+    nodes: HashMap<Address, Connection>,
+    /// The owners of the slots learned from the redirections.
+    slots: HashMap<u16, SocketAddrV4>,
+    /// The node the last command was executed on.
+    last: Address,
+}
+
+impl Cluster {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            config: config.clone(),
+            nodes: HashMap::new(),
+            slots: HashMap::new(),
+            last: config.address.clone(),
+        }
+    }
+
+    /// Returns the connection to the node at `address`, connecting to it if needed.
+    fn connection(&mut self, address: &Address) -> Result<&mut Connection, QueryError> {
+        if !self.nodes.contains_key(address) {
+            let config = ClientConfig {
+                address: address.clone(),
+                ..self.config.clone()
+            };
+            self.nodes
+                .insert(address.clone(), Connection::open(&config)?);
+        }
+
+        Ok(self.nodes.get_mut(address).unwrap())
+    }
+
+    /// Returns the connection to the node the last command was executed on.
+    pub fn last_connection(&mut self) -> Result<&mut Connection, QueryError> {
+        let address = self.last.clone();
+        self.connection(&address)
+    }
+
+    /// Execute `command` on the node owning its key, following the redirections, and returns its reply.
+    pub fn execute(&mut self, command: &[&[u8]]) -> Result<Value, QueryError> {
+        let cached = command
+            .get(1)
+            .and_then(|key| self.slots.get(&slot::key_slot(key)));
+        let mut address = match cached {
+            Some(addr) => Address::Tcp(*addr),
+            None => self.config.address.clone(),
+        };
+        let mut asking = false;
+
+        let mut redirects = 0;
+        loop {
+            let conn = self.connection(&address)?;
+
+            // The reply to ASKING is always OK
+            let reply = if asking {
+                conn.write_commands(&[vec![b"asking".as_slice()], command.to_vec()])?;
+                conn.read_message()?;
+                Value::decode(&conn.read_message()?)?
+            } else {
+                conn.query(&[command.to_vec()])?.remove(0)
+            };
+            self.last = address;
+
+            let redirect = match parse_redirect(&reply) {
+                Some(redirect) if redirects < MAX_REDIRECTS => redirect,
+                _ => return Ok(reply),
+            };
+            redirects += 1;
+
+            debug!("redirected: {:?}", redirect);
+
+            (address, asking) = match redirect {
+                Redirect::Moved(slot, addr) => {
+                    self.slots.insert(slot, addr);
+                    (Address::Tcp(addr), false)
+                }
+                Redirect::Ask(addr) => (Address::Tcp(addr), true),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_redirect, Redirect};
+    use shared::client::Value;
+    use shared::protocol::{self, Writer, BUF_LEN};
+    use shared::ResponseCode;
+
+    fn message<F: FnOnce(&mut Writer)>(f: F) -> Value {
+        let mut buf = [0; BUF_LEN];
+        {
+            let mut writer = Writer::new(&mut buf);
+            f(&mut writer);
+            writer.finish();
+        }
+
+        let (_, body) = protocol::parse_message(&buf).unwrap();
+        Value::decode(body).unwrap()
+    }
+
+    #[test]
+    fn redirects() {
+        let addr = "127.0.0.1:1235".parse().unwrap();
+
+        assert_eq!(
+            Some(Redirect::Moved(3999, addr)),
+            parse_redirect(&message(
+                |w| w.push_err(ResponseCode::Moved, "3999 127.0.0.1:1235")
+            ))
+        );
+        assert_eq!(
+            Some(Redirect::Ask(addr)),
+            parse_redirect(&message(
+                |w| w.push_err(ResponseCode::Ask, "3999 127.0.0.1:1235")
+            ))
+        );
+
+        assert_eq!(
+            None,
+            parse_redirect(&message(
+                |w| w.push_err(ResponseCode::ClusterDown, "3999 127.0.0.1:1235")
+            ))
+        );
+        assert_eq!(
+            None,
+            parse_redirect(&message(|w| w.push_err(ResponseCode::Moved, "3999")))
+        );
+        assert_eq!(None, parse_redirect(&message(|w| w.push_string("OK"))));
+    }
+}
@@ -0,0 +1,535 @@
+//! The connection to the server, reconnected with an exponential backoff when the server drops it.
+//!
+//! Commands are only sent again when it's safe, that is when they can't have reached the server: the connection was
+//! found closed before writing them, or the write failed before anything was sent. Commands sent without getting their
+//! replies are reported as failed, they may have been executed.
+//!
+//! [`Connection::query`] goes further: if the connection is lost before all the replies are read, the commands without
+//! a reply are sent again on a new connection if they're all read-only, or if `--retry-writes` allows it.
+//!
+//! The connection is authenticated right after connecting, with the password of the config or the last AUTH command
+//! sent, so that reconnecting doesn't lose it.
+
+use crate::config::{Address, ClientConfig};
+use crate::QueryError;
+use shared::client::Value;
+use shared::protocol::{self, BUF_LEN};
+use shared::{client, command, debug, log, trace, warn, ErrorKind, OwnedSocket, SockAddr};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
+
+const RECONNECT_ATTEMPTS: u32 = 6;
+/// Delay before the second attempt, doubled after each one.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+/// Number of times the commands of a query are sent again after losing the connection.
+const MAX_RETRIES: usize = 3;
+
+/// Returns true if the error means the server closed the connection or reset it.
+pub fn is_disconnect(err: &io::Error) -> bool {
+    ErrorKind::of_io(err) == ErrorKind::Closed
+}
+
+/// Returns true if the error means a socket timeout expired, see [`shared::set_socket_timeout`].
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Create a socket connected to `address`, whose connect, reads and writes fail after `timeout`.
+///
+/// A host name is resolved first and its addresses are tried in order until one accepts the connection.
+fn connect(address: &Address, timeout: Option<Duration>) -> io::Result<OwnedSocket> {
+    match address {
+        Address::Tcp(addr) => connect_tcp((*addr).into(), timeout),
+        Address::Host(host, port) => {
+            let addrs = shared::resolve(host, *port)?;
+            debug!("resolved {} to {:?}", address, addrs);
+
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address found for {}", host),
+            );
+            for addr in addrs {
+                match connect_tcp(addr, timeout) {
+                    Ok(socket) => return Ok(socket),
+                    Err(err) => {
+                        debug!("unable to connect to {}: {}", addr, err);
+                        last_err = err;
+                    }
+                }
+            }
+
+            Err(last_err)
+        }
+        Address::Unix(path) => {
+            let socket = OwnedSocket::unix()?;
+            debug!("created socket fd={}", socket.as_raw_fd());
+
+            // Connecting to a unix socket never waits for the network
+            shared::connect_unix(socket.as_raw_fd(), path)?;
+            finish_connect(socket, timeout)
+        }
+    }
+}
+
+fn connect_tcp(addr: SockAddr, timeout: Option<Duration>) -> io::Result<OwnedSocket> {
+    let socket = OwnedSocket::tcp(&addr)?;
+    debug!("created socket fd={}", socket.as_raw_fd());
+
+    match timeout {
+        Some(timeout) => shared::connect_timeout(socket.as_raw_fd(), &addr, timeout)?,
+        None => shared::connect(socket.as_raw_fd(), &addr)?,
+    }
+    finish_connect(socket, timeout)
+}
+
+/// Set the timeout of the connected socket.
+fn finish_connect(socket: OwnedSocket, timeout: Option<Duration>) -> io::Result<OwnedSocket> {
+    shared::set_socket_timeout(socket.as_raw_fd(), timeout.unwrap_or(Duration::ZERO))?;
+    Ok(socket)
+}
+
+/// Returns the AUTH command authenticating with the user and password of the config, if there's a password.
+fn auth_command(config: &ClientConfig) -> Option<Vec<Vec<u8>>> {
+    let password = config.password.as_ref()?;
+
+    let mut command = vec![b"auth".to_vec()];
+    if let Some(user) = &config.user {
+        command.push(user.as_bytes().to_vec());
+    }
+    command.push(password.as_bytes().to_vec());
+
+    Some(command)
+}
+
+/// Encode the commands one message each.
+fn encode_commands(commands: &[Vec<&[u8]>]) -> Result<Vec<u8>, QueryError> {
+    client::encode_commands(commands).map_err(|err| match err {
+        protocol::Error::MessageTooLong(len) => QueryError::MessageTooLong(len),
+        err => err.into(),
+    })
+}
+
+pub struct Connection {
+    address: Address,
+    timeout: Option<Duration>,
+    /// `None` once closed, if reconnecting failed.
+    socket: Option<OwnedSocket>,
+    /// What was read past the last message.
+    pending: Vec<u8>,
+    /// The AUTH command sent after each connect.
+    auth: Option<Vec<Vec<u8>>>,
+    /// Send the write commands again too when the connection is lost, they may be executed twice.
+    retry_writes: bool,
+}
+
+impl Connection {
+    pub fn open(config: &ClientConfig) -> Result<Self, QueryError> {
+        let address = &config.address;
+
+        debug!("connecting to {}", address);
+
+        let socket = connect(address, config.timeout)
+            .map_err(|err| QueryError::Connect(format!("{}: {}", address, err)))?;
+
+        debug!("connected to {}", address);
+
+        let mut conn = Self {
+            address: address.clone(),
+            timeout: config.timeout,
+            socket: Some(socket),
+            pending: Vec::new(),
+            auth: auth_command(config),
+            retry_writes: config.retry_writes,
+        };
+        conn.authenticate()?;
+
+        Ok(conn)
+    }
+
+    /// Send the AUTH command if there's one, failing if the server doesn't accept it.
+    fn authenticate(&mut self) -> Result<(), QueryError> {
+        let command: Vec<&[u8]> = match &self.auth {
+            Some(command) => command.iter().map(Vec::as_slice).collect(),
+            None => return Ok(()),
+        };
+
+        let buf = encode_commands(&[command])?;
+        self.write_all(&buf).map_err(|(_, err)| self.error(err))?;
+
+        let message = self.read_message()?;
+        let mut reader = protocol::Reader::new(&message);
+        if let protocol::DataType::Err = reader.read_data_type()? {
+            let (_, message) = reader.read_err()?;
+            return Err(QueryError::Auth(
+                String::from_utf8_lossy(message).into_owned(),
+            ));
+        }
+
+        debug!("authenticated to {}", self.address);
+
+        Ok(())
+    }
+
+    /// Close the connection and open a new one, retrying with an exponential backoff.
+    pub fn reconnect(&mut self) -> Result<(), QueryError> {
+        self.socket = None;
+        self.pending.clear();
+
+        let mut backoff = RECONNECT_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match connect(&self.address, self.timeout) {
+                Ok(socket) => {
+                    warn!("reconnected to {}", self.address);
+                    self.socket = Some(socket);
+                    return self.authenticate();
+                }
+                Err(err) if attempt == RECONNECT_ATTEMPTS => {
+                    return Err(QueryError::Connect(format!("{}: {}", self.address, err)))
+                }
+                Err(err) => {
+                    debug!(
+                        "unable to reconnect to {}, retrying in {:?}, err: {}",
+                        self.address, backoff, err
+                    );
+
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the server closed the connection while we weren't expecting anything, checked without blocking.
+    fn is_closed(&self) -> bool {
+        let fd = match &self.socket {
+            Some(socket) => socket.as_raw_fd(),
+            None => return true,
+        };
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let n = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if n <= 0 {
+            return false;
+        }
+        if pollfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+            return true;
+        }
+
+        // Readable: either the end of the stream or a message we didn't ask for
+        let mut byte = 0u8;
+        let n = unsafe {
+            libc::recv(
+                fd,
+                &mut byte as *mut _ as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        n == 0 || (n < 0 && is_disconnect(&io::Error::last_os_error()))
+    }
+
+    /// Returns the socket, failing if reconnecting failed.
+    fn socket(&self) -> io::Result<&OwnedSocket> {
+        self.socket
+            .as_ref()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn error(&self, err: io::Error) -> QueryError {
+        match self.timeout {
+            Some(timeout) if is_timeout(&err) => QueryError::Timeout(timeout),
+            _ => err.into(),
+        }
+    }
+
+    /// Write the whole buffer, returning the number of bytes written along with the error if it fails.
+    fn write_all(&self, mut buf: &[u8]) -> Result<(), (usize, io::Error)> {
+        let mut written = 0;
+
+        while !buf.is_empty() {
+            let n = self
+                .socket()
+                .and_then(|socket| socket.write(buf))
+                .map_err(|err| (written, err))?;
+            written += n;
+            buf = &buf[n..];
+        }
+
+        Ok(())
+    }
+
+    /// Send messages already encoded, reconnecting first if the server closed the connection.
+    pub fn send(&mut self, buf: &[u8]) -> Result<(), QueryError> {
+        if self.is_closed() {
+            warn!("connection to {} lost, reconnecting", self.address);
+            self.reconnect()?;
+        }
+
+        match self.write_all(buf) {
+            Ok(()) => Ok(()),
+            // Nothing was sent, the commands can't have been executed
+            Err((0, err)) if is_disconnect(&err) => {
+                warn!("connection to {} lost, reconnecting", self.address);
+                self.reconnect()?;
+
+                self.write_all(buf).map_err(|(_, err)| self.error(err))
+            }
+            Err((_, err)) => Err(self.error(err)),
+        }
+    }
+
+    /// Write the commands one message each, without waiting for their replies.
+    ///
+    /// An AUTH command is remembered to authenticate again after reconnecting.
+    pub fn write_commands(&mut self, commands: &[Vec<&[u8]>]) -> Result<(), QueryError> {
+        let buf = encode_commands(commands)?;
+
+        if let Some(command) = commands.iter().rev().find(|command| command[0] == b"auth") {
+            self.auth = Some(command.iter().map(|arg| arg.to_vec()).collect());
+        }
+
+        let write_start = std::time::Instant::now();
+
+        debug!("writing all commands: {:?}", commands);
+        trace!(
+            "writing {} bytes to {}:\n{}",
+            buf.len(),
+            self.address,
+            log::hexdump(&buf)
+        );
+
+        self.send(&buf)?;
+
+        debug!("wrote all queries in {:?}", write_start.elapsed());
+
+        Ok(())
+    }
+
+    /// Returns true if the commands can be sent again without risking to execute them twice.
+    fn can_retry(&self, commands: &[Vec<&[u8]>]) -> bool {
+        self.retry_writes
+            || commands
+                .iter()
+                .all(|command| command::find(command[0]).is_some_and(|command| command.read_only))
+    }
+
+    /// Send the commands and read their replies, sending them again if the connection is lost and it's safe.
+    pub fn query(&mut self, commands: &[Vec<&[u8]>]) -> Result<Vec<Value>, QueryError> {
+        let mut replies = Vec::with_capacity(commands.len());
+        let mut retries = 0;
+
+        loop {
+            let remaining = &commands[replies.len()..];
+
+            let result = self.write_commands(remaining).and_then(|_| {
+                for _ in 0..remaining.len() {
+                    replies.push(Value::decode(&self.read_message()?)?);
+                }
+                Ok(())
+            });
+
+            match result {
+                Ok(()) => return Ok(replies),
+                Err(err)
+                    if err.is_disconnect()
+                        && retries < MAX_RETRIES
+                        && self.can_retry(&commands[replies.len()..]) =>
+                {
+                    retries += 1;
+                    warn!(
+                        "connection to {} lost, sending {} commands again",
+                        self.address,
+                        commands.len() - replies.len()
+                    );
+
+                    self.reconnect()?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Read the next message, keeping what's read past it for the next call.
+    pub fn read_message(&mut self) -> Result<Vec<u8>, QueryError> {
+        loop {
+            match protocol::parse_message(&self.pending) {
+                Ok((read, message)) => {
+                    trace!(
+                        "read {} bytes from {}:\n{}",
+                        read,
+                        self.address,
+                        log::hexdump(&self.pending[..read])
+                    );
+
+                    let message = message.to_vec();
+                    self.pending.drain(..read);
+                    return Ok(message);
+                }
+                Err(protocol::Error::InputTooShort(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+            let read_buf = match self.socket().and_then(|socket| socket.read(&mut buf)) {
+                Ok(read_buf) => read_buf,
+                Err(err) => return Err(self.error(err)),
+            };
+            if read_buf.is_empty() {
+                return Err(QueryError::EndOfStream);
+            }
+            self.pending.extend_from_slice(read_buf);
+        }
+    }
+
+    /// Read up to `max` raw bytes, what's left past the last message first. Fails at the end of the stream.
+    pub fn read_raw(&mut self, max: usize) -> Result<Vec<u8>, QueryError> {
+        if !self.pending.is_empty() {
+            let n = max.min(self.pending.len());
+            return Ok(self.pending.drain(..n).collect());
+        }
+
+        // NOTE(vincent): shared::read leaves the last byte of the buffer unused
+        let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+        let n = max.min(BUF_LEN - 1);
+        let read_buf = match self
+            .socket()
+            .and_then(|socket| socket.read(&mut buf[..n + 1]))
+        {
+            Ok(read_buf) => read_buf,
+            Err(err) => return Err(self.error(err)),
+        };
+        if read_buf.is_empty() {
+            return Err(QueryError::EndOfStream);
+        }
+
+        Ok(read_buf.to_vec())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            debug!("closing file descriptor fd={}", socket.as_raw_fd());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connection, Value};
+    use crate::config::Address;
+    use crate::QueryError;
+    use shared::{protocol, OwnedSocket};
+    use std::io::Write;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    fn connection() -> (Connection, UnixStream) {
+        let (client, server) = UnixStream::pair().unwrap();
+
+        let conn = Connection {
+            address: Address::Unix(PathBuf::from("/nonexistent")),
+            timeout: None,
+            socket: Some(unsafe { OwnedSocket::from_raw_fd(client.into_raw_fd()) }),
+            pending: Vec::new(),
+            auth: None,
+            retry_writes: false,
+        };
+        (conn, server)
+    }
+
+    fn message(body: &[u8]) -> Vec<u8> {
+        let mut message = (body.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn read_message() {
+        let (mut conn, mut server) = connection();
+
+        // Split in the header and in the body
+        let first = message(b"\x02\x00\x00\x00\x03foo");
+        let writer = thread::spawn(move || {
+            for chunk in [&first[..2], &first[2..7], &first[7..]] {
+                server.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+
+            // Two messages at once
+            let mut both = message(b"\x00");
+            both.extend(message(b"\x03\x00\x00\x00\x00\x00\x00\x00\x02"));
+            server.write_all(&both).unwrap();
+            server
+        });
+
+        assert_eq!(
+            b"\x02\x00\x00\x00\x03foo",
+            conn.read_message().unwrap().as_slice()
+        );
+        assert_eq!(b"\x00", conn.read_message().unwrap().as_slice());
+        assert_eq!(
+            b"\x03\x00\x00\x00\x00\x00\x00\x00\x02",
+            conn.read_message().unwrap().as_slice()
+        );
+
+        // The end of the stream in the middle of a message
+        let mut server = writer.join().unwrap();
+        server.write_all(&message(b"\x02\x00")[..3]).unwrap();
+        drop(server);
+        assert!(matches!(conn.read_message(), Err(QueryError::EndOfStream)));
+    }
+
+    #[test]
+    fn query() {
+        let (mut conn, mut server) = connection();
+
+        let mut replies = message(b"\x02\x00\x00\x00\x03foo");
+        replies.extend(message(b"\x03\x00\x00\x00\x00\x00\x00\x00\x02"));
+        server.write_all(&replies).unwrap();
+
+        assert_eq!(
+            vec![Value::Bytes(b"foo".to_vec()), Value::Int(2)],
+            conn.query(&[vec![b"get", b"a"], vec![b"ttl", b"a"]])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn retries() {
+        let (mut conn, _server) = connection();
+
+        let reads: Vec<Vec<&[u8]>> = vec![vec![b"get", b"a"], vec![b"ttl", b"a"]];
+        assert!(conn.can_retry(&reads));
+        assert!(!conn.can_retry(&[vec![b"get", b"a"], vec![b"set", b"a", b"1"]]));
+        assert!(!conn.can_retry(&[vec![b"nope"]]));
+
+        conn.retry_writes = true;
+        assert!(conn.can_retry(&[vec![b"set", b"a", b"1"]]));
+    }
+
+    #[test]
+    fn read_message_too_long() {
+        let (mut conn, mut server) = connection();
+
+        server
+            .write_all(&((protocol::MAX_MSG_LEN + 1) as u32).to_be_bytes())
+            .unwrap();
+        assert!(matches!(
+            conn.read_message(),
+            Err(QueryError::Protocol(protocol::Error::MessageTooLong(_)))
+        ));
+    }
+}
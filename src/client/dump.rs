@@ -0,0 +1,91 @@
+//! The dump mode, enabled with `--dump <path>`: the client asks for a snapshot like a replica synchronizing from
+//! scratch, writes it to `path` and disconnects.
+//!
+//! The snapshot is written to a temporary file first, `path` is only replaced once it's complete.
+
+use crate::connection::Connection;
+use crate::output::Format;
+use crate::{process_response, QueryError};
+use shared::log::{self, Level};
+use shared::protocol::{self, Psync, PsyncReply, Writer, BUF_LEN};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Download a snapshot to `path`. Returns true if the server refused with an error reply, printed with `format`.
+pub fn run(conn: &mut Connection, path: &Path, format: Format) -> Result<bool, QueryError> {
+    // No replication ID nor capabilities, the server can only send a snapshot
+    let psync = Psync {
+        capabilities: 0,
+        replication_id: String::new(),
+        offset: 0,
+    };
+
+    let mut buf = vec![0; BUF_LEN];
+    let written = {
+        let mut writer = Writer::new(&mut buf);
+        writer.push_psync(&psync);
+        writer.finish();
+        writer.written()
+    };
+    conn.send(&buf[..written])?;
+
+    let message = conn.read_message()?;
+    let (offset, snapshot_len) = match protocol::Reader::new(&message).read_psync_reply() {
+        Ok(PsyncReply::FullResync {
+            offset,
+            snapshot_len,
+            ..
+        }) => (offset, snapshot_len),
+        // Can't happen without a replication ID
+        Ok(PsyncReply::Continue { .. }) => {
+            return Err(protocol::Error::UnexpectedFrame(message[0]).into())
+        }
+        Err(protocol::Error::UnexpectedFrame(_)) => return process_response(&message, format),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp-{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = save(conn, snapshot_len, &tmp_path, path);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // Only the exit code tells if it worked with -q
+    if log::enabled(Level::Warn) {
+        println!(
+            "wrote a snapshot of {} bytes taken at offset {} to {}",
+            snapshot_len,
+            offset,
+            path.display()
+        );
+    }
+
+    Ok(false)
+}
+
+/// Write the `len` bytes of the snapshot to `tmp_path`, then rename it to `path`.
+fn save(conn: &mut Connection, len: u64, tmp_path: &Path, path: &Path) -> Result<(), QueryError> {
+    let write_error =
+        |err: std::io::Error| QueryError::WriteFile(format!("{}: {}", path.display(), err));
+
+    let mut writer = BufWriter::new(File::create(tmp_path).map_err(write_error)?);
+
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = conn.read_raw(remaining.min(usize::MAX as u64) as usize)?;
+        writer.write_all(&chunk).map_err(write_error)?;
+        remaining -= chunk.len() as u64;
+    }
+
+    let file = writer
+        .into_inner()
+        .map_err(|err| write_error(err.into_error()))?;
+    file.sync_all().map_err(write_error)?;
+
+    fs::rename(tmp_path, path).map_err(write_error)
+}
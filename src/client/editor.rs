@@ -0,0 +1,469 @@
+//! A minimal line editor for the REPL, like linenoise: the terminal is put in raw mode while a line is read, and the
+//! usual keys of readline are handled.
+//!
+//! * Left, Right, Home, End, Ctrl-A, Ctrl-E, Ctrl-B and Ctrl-F move the cursor.
+//! * Backspace, Delete, Ctrl-U, Ctrl-K and Ctrl-W delete characters, the whole line before or after the cursor, or
+//!   the word before it.
+//! * Up, Down, Ctrl-P and Ctrl-N browse the history, which is saved to a file after each line.
+//! * Tab completes the line: directly if there's one candidate, up to their common prefix otherwise, and lists them if
+//!   there's nothing left in common.
+//! * Ctrl-C abandons the line, Ctrl-D on an empty line ends the input.
+//!
+//! NOTE(vincent): every character is assumed to be one column wide, and lines longer than the terminal are not
+//! wrapped nicely.
+
+use shared::debug;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Number of lines kept in the history.
+const MAX_HISTORY: usize = 1000;
+
+/// The terminal settings of stdin, restored when dropped.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+        raw.c_oflag &= !libc::OPOST;
+        raw.c_cflag |= libc::CS8;
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original) };
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillBefore,
+    KillAfter,
+    KillWord,
+    ClearScreen,
+    Interrupt,
+    Eof,
+    Unknown,
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+
+    loop {
+        let n = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                &mut byte as *mut _ as *mut libc::c_void,
+                1,
+            )
+        };
+        match n {
+            0 => return Ok(None),
+            1 => return Ok(Some(byte)),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Read the next key, `None` at the end of the input.
+fn read_key() -> io::Result<Option<Key>> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        6 => Key::Right,
+        11 => Key::KillAfter,
+        12 => Key::ClearScreen,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillBefore,
+        23 => Key::KillWord,
+        27 => read_escape()?,
+        0x20..=0x7e => Key::Char(byte as char),
+        0xc0..=0xf7 => {
+            // The number of continuation bytes is the number of leading ones minus one
+            let len = byte.leading_ones() as usize;
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte()? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        }
+        _ => Key::Unknown,
+    };
+
+    Ok(Some(key))
+}
+
+/// Read the rest of an escape sequence, like `ESC [ A` for Up.
+fn read_escape() -> io::Result<Key> {
+    let key = match (read_byte()?, read_byte()?) {
+        (Some(b'['), Some(b'A')) | (Some(b'O'), Some(b'A')) => Key::Up,
+        (Some(b'['), Some(b'B')) | (Some(b'O'), Some(b'B')) => Key::Down,
+        (Some(b'['), Some(b'C')) | (Some(b'O'), Some(b'C')) => Key::Right,
+        (Some(b'['), Some(b'D')) | (Some(b'O'), Some(b'D')) => Key::Left,
+        (Some(b'['), Some(b'H')) | (Some(b'O'), Some(b'H')) => Key::Home,
+        (Some(b'['), Some(b'F')) | (Some(b'O'), Some(b'F')) => Key::End,
+        (Some(b'['), Some(digit)) if digit.is_ascii_digit() => match read_byte()? {
+            Some(b'~') => match digit {
+                b'1' | b'7' => Key::Home,
+                b'3' => Key::Delete,
+                b'4' | b'8' => Key::End,
+                _ => Key::Unknown,
+            },
+            _ => Key::Unknown,
+        },
+        _ => Key::Unknown,
+    };
+
+    Ok(key)
+}
+
+/// The line being edited.
+#[derive(Default)]
+struct Line {
+    chars: Vec<char>,
+    /// Position of the cursor, in characters.
+    pos: usize,
+}
+
+impl Line {
+    fn set(&mut self, value: &str) {
+        self.chars = value.chars().collect();
+        self.pos = self.chars.len();
+    }
+
+    fn as_string(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn edit(&mut self, key: &Key) {
+        match key {
+            Key::Char(c) => {
+                self.chars.insert(self.pos, *c);
+                self.pos += 1;
+            }
+            Key::Backspace if self.pos > 0 => {
+                self.pos -= 1;
+                self.chars.remove(self.pos);
+            }
+            Key::Delete if self.pos < self.chars.len() => {
+                self.chars.remove(self.pos);
+            }
+            Key::Left => self.pos = self.pos.saturating_sub(1),
+            Key::Right => self.pos = (self.pos + 1).min(self.chars.len()),
+            Key::Home => self.pos = 0,
+            Key::End => self.pos = self.chars.len(),
+            Key::KillBefore => {
+                self.chars.drain(..self.pos);
+                self.pos = 0;
+            }
+            Key::KillAfter => self.chars.truncate(self.pos),
+            Key::KillWord => {
+                let mut start = self.pos;
+                while start > 0 && self.chars[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.chars[start - 1] != ' ' {
+                    start -= 1;
+                }
+
+                self.chars.drain(start..self.pos);
+                self.pos = start;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the longest prefix shared by all the candidates.
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix: &str = match candidates.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+
+    for candidate in &candidates[1..] {
+        while !candidate.starts_with(prefix) {
+            let mut chars = prefix.chars();
+            chars.next_back();
+            prefix = chars.as_str();
+        }
+    }
+
+    prefix.to_string()
+}
+
+pub struct Editor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Create an editor whose history is loaded from and saved to `history_path`, if any.
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let history = match &history_path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(data) => data.lines().map(str::to_string).collect(),
+                Err(err) => {
+                    debug!("unable to read the history from {:?}, err: {}", path, err);
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let mut editor = Self {
+            history,
+            history_path,
+        };
+        editor.trim_history();
+        editor
+    }
+
+    fn trim_history(&mut self) {
+        if self.history.len() > MAX_HISTORY {
+            self.history.drain(..self.history.len() - MAX_HISTORY);
+        }
+    }
+
+    /// Add `line` to the history and save it, unless it's empty or the same as the last one.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        self.history.push(line.to_string());
+        self.trim_history();
+
+        if let Some(path) = &self.history_path {
+            if let Err(err) = self.save_history(path) {
+                debug!("unable to save the history to {:?}, err: {}", path, err);
+            }
+        }
+    }
+
+    /// Write the whole history, readable by the user only since it may contain sensitive data.
+    fn save_history(&self, path: &PathBuf) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+
+        for line in &self.history {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a line from the terminal, completed with `complete` which returns the candidates replacing the whole
+    /// line. Returns `None` at the end of the input.
+    pub fn read_line<F: Fn(&str) -> Vec<String>>(
+        &mut self,
+        prompt: &str,
+        complete: F,
+    ) -> io::Result<Option<String>> {
+        let _raw_mode = RawMode::enable()?;
+        let mut out = io::stdout();
+
+        // The history being browsed, the line being edited being the last entry
+        let mut entries = self.history.clone();
+        entries.push(String::new());
+        let mut index = entries.len() - 1;
+
+        let mut line = Line::default();
+        refresh(&mut out, prompt, &line)?;
+
+        loop {
+            let key = match read_key()? {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+
+            match key {
+                Key::Enter => {
+                    write!(out, "\r\n")?;
+                    return Ok(Some(line.as_string()));
+                }
+                Key::Eof if line.chars.is_empty() => {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Eof => line.edit(&Key::Delete),
+                Key::Interrupt => {
+                    write!(out, "^C\r\n")?;
+                    line = Line::default();
+                    index = entries.len() - 1;
+                }
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up => index.checked_sub(1),
+                        _ => Some(index + 1).filter(|&next| next < entries.len()),
+                    };
+                    if let Some(next) = next {
+                        entries[index] = line.as_string();
+                        index = next;
+                        line.set(&entries[index]);
+                    }
+                }
+                Key::Tab => {
+                    let current = line.as_string();
+                    let candidates = complete(&current);
+
+                    match candidates.as_slice() {
+                        [] => write!(out, "\x07")?,
+                        [candidate] => line.set(candidate),
+                        _ => {
+                            let prefix = common_prefix(&candidates);
+                            if prefix.len() > current.len() {
+                                line.set(&prefix);
+                            } else {
+                                write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+                            }
+                        }
+                    }
+                }
+                Key::ClearScreen => write!(out, "\x1b[H\x1b[2J")?,
+                key => line.edit(&key),
+            }
+
+            refresh(&mut out, prompt, &line)?;
+        }
+    }
+}
+
+/// Redraw the prompt and the line, then put the cursor back where it is in the line.
+fn refresh<W: Write>(out: &mut W, prompt: &str, line: &Line) -> io::Result<()> {
+    write!(out, "\r{}{}\x1b[K\r", prompt, line.as_string())?;
+
+    let column = prompt.chars().count() + line.pos;
+    if column > 0 {
+        write!(out, "\x1b[{}C", column)?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_prefix, Editor, Key, Line};
+
+    #[test]
+    fn edit() {
+        let mut line = Line::default();
+        for key in [Key::Char('g'), Key::Char('t'), Key::Left, Key::Char('e')] {
+            line.edit(&key);
+        }
+        assert_eq!("get", line.as_string());
+        assert_eq!(2, line.pos);
+
+        line.edit(&Key::End);
+        for c in " foo bar".chars() {
+            line.edit(&Key::Char(c));
+        }
+        line.edit(&Key::KillWord);
+        assert_eq!("get foo ", line.as_string());
+
+        line.edit(&Key::Home);
+        line.edit(&Key::Delete);
+        line.edit(&Key::Backspace);
+        assert_eq!("et foo ", line.as_string());
+
+        line.edit(&Key::Right);
+        line.edit(&Key::KillAfter);
+        assert_eq!("e", line.as_string());
+        line.edit(&Key::KillBefore);
+        assert_eq!("", line.as_string());
+        assert_eq!(0, line.pos);
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!("", common_prefix(&[]));
+        assert_eq!(
+            "set",
+            common_prefix(&["set".to_string(), "setex".to_string()])
+        );
+        assert_eq!(
+            "client ",
+            common_prefix(&["client getname".to_string(), "client id".to_string()])
+        );
+    }
+
+    #[test]
+    fn history() {
+        let path =
+            std::env::temp_dir().join(format!("my-own-redis-history-{}", std::process::id()));
+
+        let mut editor = Editor::new(Some(path.clone()));
+        assert!(editor.history.is_empty());
+        editor.add_history("get a");
+        editor.add_history("get a");
+        editor.add_history(" ");
+        editor.add_history("set a 1");
+
+        let editor = Editor::new(Some(path.clone()));
+        assert_eq!(vec!["get a", "set a 1"], editor.history);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
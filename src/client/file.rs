@@ -0,0 +1,95 @@
+//! The file mode, enabled with `--file <path>`: the commands are read from a file, one per line with their arguments
+//! quoted like in the interactive mode. Empty lines and lines starting with `#` are ignored.
+//!
+//! The commands are sent one at a time unless `--pipeline` is given, and the line of each command getting an error
+//! reply is reported.
+
+use crate::connection::Connection;
+use crate::output::Format;
+use crate::repl::split_args;
+use crate::{print_reply, QueryError};
+use std::path::Path;
+
+/// A command with the number of its line, starting at 1.
+#[derive(Debug, PartialEq, Eq)]
+struct Command {
+    line: usize,
+    args: Vec<Vec<u8>>,
+}
+
+/// Returns the commands of the file, or the number of the first invalid line.
+fn parse(data: &str) -> Result<Vec<Command>, usize> {
+    let mut commands = Vec::new();
+
+    for (i, line) in data.lines().enumerate() {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let args = split_args(line).ok_or(i + 1)?;
+        if !args.is_empty() {
+            commands.push(Command { line: i + 1, args });
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Execute the commands of the file at `path`, `pipeline` at a time. Returns true if a reply is an error.
+pub fn run(
+    conn: &mut Connection,
+    path: &Path,
+    pipeline: usize,
+    format: Format,
+) -> Result<bool, QueryError> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|err| QueryError::ReadFile(format!("{}: {}", path.display(), err)))?;
+    let commands = parse(&data).map_err(|line| QueryError::InvalidLine {
+        path: path.display().to_string(),
+        line,
+    })?;
+
+    let mut error = false;
+
+    for batch in commands.chunks(pipeline) {
+        let args: Vec<Vec<&[u8]>> = batch
+            .iter()
+            .map(|command| command.args.iter().map(Vec::as_slice).collect())
+            .collect();
+        let replies = conn.query(&args)?;
+
+        for (command, reply) in batch.iter().zip(&replies) {
+            if print_reply(reply, format) {
+                eprintln!("{}:{}: the command failed", path.display(), command.line);
+                error = true;
+            }
+        }
+    }
+
+    Ok(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Command};
+
+    #[test]
+    fn parse_file() {
+        let commands = parse("# setup\nset a \"1 2\"\n\n   # get it back\n  get a\n").unwrap();
+        assert_eq!(
+            vec![
+                Command {
+                    line: 2,
+                    args: vec![b"set".to_vec(), b"a".to_vec(), b"1 2".to_vec()],
+                },
+                Command {
+                    line: 5,
+                    args: vec![b"get".to_vec(), b"a".to_vec()],
+                },
+            ],
+            commands
+        );
+
+        assert_eq!(Err(3), parse("get a\n\nset a \"1\n"));
+    }
+}
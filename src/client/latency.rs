@@ -0,0 +1,102 @@
+//! The latency mode, like `redis-cli --latency`: PING is sent in a loop and the round-trip times are reported every
+//! second until the client is interrupted.
+//!
+//! The report is rewritten in place when stdout is a terminal, otherwise a new line is printed each time.
+
+use crate::benchmark::percentile;
+use crate::connection::Connection;
+use crate::QueryError;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay between two PING, so that the server isn't busy only answering us.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Stats {
+    /// Every round-trip time measured, sorted when reporting.
+    latencies: Vec<Duration>,
+    total: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, latency: Duration) {
+        self.latencies.push(latency);
+        self.total += latency;
+    }
+
+    fn report(&mut self) -> String {
+        self.latencies.sort_unstable();
+
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        let avg = match self.latencies.len() {
+            0 => Duration::ZERO,
+            n => self.total / n as u32,
+        };
+
+        format!(
+            "min: {:.2}ms, max: {:.2}ms, avg: {:.2}ms, p50: {:.2}ms, p99: {:.2}ms ({} samples)",
+            ms(percentile(&self.latencies, 0.0)),
+            ms(percentile(&self.latencies, 100.0)),
+            ms(avg),
+            ms(percentile(&self.latencies, 50.0)),
+            ms(percentile(&self.latencies, 99.0)),
+            self.latencies.len()
+        )
+    }
+}
+
+pub fn run(conn: &mut Connection) -> Result<(), QueryError> {
+    let in_place = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+
+    let mut stats = Stats::default();
+    let mut last_report = Instant::now();
+
+    loop {
+        let start = Instant::now();
+        conn.write_commands(&[vec![b"ping".as_slice()]])?;
+        conn.read_message()?;
+        stats.add(start.elapsed());
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            let report = stats.report();
+            if in_place {
+                // Clear what's left of a longer previous report
+                print!("\r\x1b[K{}", report);
+                io::stdout().flush()?;
+            } else {
+                println!("{}", report);
+            }
+
+            last_report = Instant::now();
+        }
+
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::time::Duration;
+
+    #[test]
+    fn report() {
+        let mut stats = Stats::default();
+        assert_eq!(
+            "min: 0.00ms, max: 0.00ms, avg: 0.00ms, p50: 0.00ms, p99: 0.00ms (0 samples)",
+            stats.report()
+        );
+
+        for micros in [300, 100, 200, 1400] {
+            stats.add(Duration::from_micros(micros));
+        }
+        assert_eq!(
+            "min: 0.10ms, max: 1.40ms, avg: 0.50ms, p50: 0.20ms, p99: 1.40ms (4 samples)",
+            stats.report()
+        );
+    }
+}
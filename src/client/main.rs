@@ -1,177 +1,493 @@
+use cluster::Cluster;
+use config::{ClientConfig, Mode};
+use connection::Connection;
 use onlyerror::Error;
-use shared::protocol::{self, BUF_LEN, MAX_MSG_LEN};
-use std::io;
+use output::Format;
+use shared::client::Value;
+use shared::log::{self, Level};
+use shared::protocol::{self, MAX_MSG_LEN};
+use shared::{command, debug};
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+
+mod benchmark;
+mod cluster;
+mod config;
+mod connection;
+mod dump;
+mod editor;
+mod file;
+mod latency;
+mod output;
+mod repl;
+
+/// At least one command got an error reply.
+const EXIT_ERROR_REPLY: u8 = 1;
+/// The flags are invalid.
+const EXIT_USAGE: u8 = 2;
+/// Connecting or authenticating failed.
+const EXIT_CONNECT: u8 = 3;
+/// The server sent an invalid reply.
+const EXIT_PROTOCOL: u8 = 4;
+/// Talking to the server or writing the snapshot failed, or timed out.
+const EXIT_IO: u8 = 5;
 
 #[derive(Error, Debug)]
 enum QueryError {
-    #[error("read_full error")]
-    ReadFullError(#[from] shared::ReadFullError),
+    #[error("end of stream")]
+    EndOfStream,
     #[error("i/o error")]
     IO(#[from] io::Error),
     #[error("protocol error")]
     Protocol(#[from] protocol::Error),
     #[error("message too long ({0} bytes)")]
     MessageTooLong(usize),
+    #[error("timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("unable to connect to {0}")]
+    Connect(String),
+    #[error("unable to read {0}")]
+    ReadFile(String),
+    #[error("unable to write {0}")]
+    WriteFile(String),
+    #[error("invalid line {line} in {path}")]
+    InvalidLine { path: String, line: usize },
+    #[error("invalid command, {0}")]
+    InvalidCommand(String),
 }
 
-fn process_response(reader: &mut protocol::Reader) -> Result<(), QueryError> {
-    match reader.read_data_type()? {
-        protocol::DataType::Nil => {
-            println!("nil");
+impl QueryError {
+    /// Returns true if the server closed the connection or reset it.
+    fn is_disconnect(&self) -> bool {
+        match self {
+            Self::EndOfStream => true,
+            Self::IO(err) => connection::is_disconnect(err),
+            _ => false,
         }
-        protocol::DataType::Err => {
-            let (response_code, message) = reader.read_err()?;
-
-            println!("response code: {}", response_code);
-            println!("message: {}", String::from_utf8_lossy(message));
-        }
-        protocol::DataType::Str => {
-            let body = reader.read_string()?;
+    }
 
-            println!(
-                "server says: {} (len={})",
-                String::from_utf8_lossy(body),
-                body.len(),
-            );
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Connect(_) | Self::Auth(_) => EXIT_CONNECT,
+            Self::Protocol(_) => EXIT_PROTOCOL,
+            Self::MessageTooLong(_)
+            | Self::ReadFile(_)
+            | Self::InvalidLine { .. }
+            | Self::InvalidCommand(_) => EXIT_USAGE,
+            Self::EndOfStream | Self::IO(_) | Self::Timeout(_) | Self::WriteFile(_) => EXIT_IO,
         }
-        protocol::DataType::Int => {
-            let n = reader.read_int()?;
+    }
+}
 
-            println!("server says: {}", n);
-        }
-        protocol::DataType::Arr => {
-            let n = reader.read_arr_length()?;
+/// Print the reply, returning true if it's an error.
+fn print_reply(reply: &Value, format: Format) -> bool {
+    println!("{}", output::format_reply(reply, format));
 
-            for _ in 0..n {
-                process_response(reader)?;
-            }
-        }
-    }
+    reply.is_error()
+}
 
-    Ok(())
+/// Print the reply in `message`, returning true if it's an error.
+fn process_response(message: &[u8], format: Format) -> Result<bool, QueryError> {
+    Ok(print_reply(&Value::decode(message)?, format))
 }
 
-fn execute_commands(fd: i32, commands: &[Vec<&[u8]>]) -> Result<(), QueryError> {
-    // Sanity checks
+/// Check the number of arguments of a command of the shared table before sending it, the server would only reply
+/// with an error. The other commands, like those of the modules, are left to the server.
+fn validate_command(command: &[&[u8]]) -> Result<(), QueryError> {
+    let (name, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let info = match command::find(name.to_ascii_lowercase()) {
+        Some(info) => info,
+        None => return Ok(()),
+    };
 
-    let buffer_size_needed = protocol::buffer_size_needed(commands);
-    if buffer_size_needed >= MAX_MSG_LEN {
-        return Err(QueryError::MessageTooLong(buffer_size_needed));
+    if info.accepts(args.len()) {
+        return Ok(());
     }
 
-    // Write all commands
+    Err(QueryError::InvalidCommand(format!(
+        "{} takes {}, got {}",
+        info.name,
+        info.describe_args(),
+        args.len()
+    )))
+}
 
-    let write_start = std::time::Instant::now();
+/// Send the commands and print their replies, `pipeline` commands at a time. Returns true if a reply is an error.
+fn execute_commands(
+    conn: &mut Connection,
+    commands: &[Vec<&[u8]>],
+    pipeline: usize,
+    format: Format,
+) -> Result<bool, QueryError> {
+    let mut error = false;
 
-    println!("writing all commands: {:?}", commands);
+    for batch in commands.chunks(pipeline) {
+        let start = std::time::Instant::now();
 
-    let n_args: usize = commands.iter().map(|command| command.len()).sum();
+        // NOTE(vincent): the replies are printed once the whole batch is read so that a retried batch isn't printed twice
+        let replies = conn.query(batch)?;
 
-    let write_buf = {
-        let mut buf = Vec::with_capacity(BUF_LEN);
-        buf.resize(BUF_LEN, 0xAA);
+        debug!("read all responses in {:?}", start.elapsed());
 
-        let written = {
-            let mut writer = shared::protocol::Writer::new(&mut buf);
+        for reply in &replies {
+            error |= print_reply(reply, format);
+        }
+    }
 
-            writer.push_int(n_args);
+    Ok(error)
+}
 
-            for command in commands {
-                let (cmd, args) = (command[0], &command[1..]);
+/// Execute the commands one by one on the nodes of the cluster owning their keys and print their replies. Returns
+/// true if a reply is an error.
+fn execute_cluster_commands(
+    cluster: &mut Cluster,
+    commands: &[Vec<&[u8]>],
+    format: Format,
+) -> Result<bool, QueryError> {
+    let mut error = false;
 
-                writer.push_string(cmd);
-                for arg in args {
-                    writer.push_string(arg);
-                }
-            }
+    for command in commands {
+        let reply = cluster.execute(command)?;
 
-            writer.finish();
-            writer.written()
-        };
+        error |= print_reply(&reply, format);
+    }
 
-        buf.truncate(written);
+    Ok(error)
+}
 
-        buf
-    };
+/// Call `execute` as many times as asked with `--repeat`, waiting for `--interval` between two calls. Returns true if
+/// one of the calls did.
+fn repeat<F: FnMut() -> Result<bool, QueryError>>(
+    config: &ClientConfig,
+    mut execute: F,
+) -> Result<bool, QueryError> {
+    let mut remaining = config.repeat;
+    let mut error = false;
+
+    loop {
+        error |= execute()?;
+
+        remaining = remaining.map(|n| n - 1);
+        if remaining == Some(0) {
+            return Ok(error);
+        }
 
-    println!("client write buf: {:?}", &write_buf);
+        if let Some(interval) = config.interval {
+            std::thread::sleep(interval);
+        }
+    }
+}
 
-    shared::write_full(fd, &write_buf)?;
+/// Print every message received until the server closes the connection, after subscribing to channels.
+fn print_messages(conn: &mut Connection, format: Format) -> Result<(), QueryError> {
+    loop {
+        let message = match conn.read_message() {
+            Ok(message) => message,
+            Err(QueryError::EndOfStream) => return Ok(()),
+            // Messages can take any time to come, the timeout only applies to the commands
+            Err(QueryError::Timeout(_)) => continue,
+            Err(err) => return Err(err),
+        };
 
-    let write_elapsed = std::time::Instant::now() - write_start;
+        process_response(&message, format)?;
+    }
+}
 
-    println!("wrote all queries in {:?}", write_elapsed);
+/// Number of commands sent before reading their replies in pipe mode by default, so that neither side blocks writing.
+const PIPE_BATCH: usize = 1000;
 
-    // Read all
+#[derive(Default)]
+struct PipeStats {
+    ok: usize,
+    errors: usize,
+}
 
-    let read_start = std::time::Instant::now();
+/// Send the commands of `batch` and count their replies, printing the errors.
+///
+/// If the connection is lost the commands without a reply are counted as errors, and the next batch is sent on a new
+/// connection.
+fn send_batch(
+    conn: &mut Connection,
+    batch: &mut Vec<u8>,
+    nb_commands: usize,
+    stats: &mut PipeStats,
+) -> Result<(), QueryError> {
+    conn.send(batch)?;
+    batch.clear();
+
+    for i in 0..nb_commands {
+        let message = match conn.read_message() {
+            Ok(message) => message,
+            Err(err) if err.is_disconnect() => {
+                eprintln!(
+                    "error: connection lost, {} commands without a reply",
+                    nb_commands - i
+                );
+                stats.errors += nb_commands - i;
+
+                conn.reconnect()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let mut reader = protocol::Reader::new(&message);
+
+        match reader.read_data_type()? {
+            protocol::DataType::Err => {
+                let (response_code, message) = reader.read_err()?;
+                eprintln!(
+                    "error {}: {}",
+                    response_code,
+                    String::from_utf8_lossy(message)
+                );
+                stats.errors += 1;
+            }
+            _ => stats.ok += 1,
+        }
+    }
 
-    println!("reading all responses");
+    Ok(())
+}
 
-    for _ in 0..commands.len() {
-        let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+/// Send the commands read from stdin, one per line with their arguments separated by whitespace, pipelined in
+/// batches. Prints the errors and how many replies were OK or errors, returning true if there were errors.
+fn run_pipe(conn: &mut Connection, pipeline: usize) -> Result<bool, QueryError> {
+    let mut stats = PipeStats::default();
+    let mut batch = Vec::new();
+    let mut nb_commands = 0;
 
-        let read_buf = shared::read(fd, &mut buf)?;
+    for line in io::stdin().lock().lines() {
+        let line = line?;
 
-        //
+        let args: Vec<&[u8]> = line.split_whitespace().map(str::as_bytes).collect();
+        if args.is_empty() {
+            continue;
+        }
 
-        // TODO(vincent): maybe better error handling ?
-        let (_, message) = protocol::parse_message(read_buf).unwrap();
+        let body = command::encode(&args);
+        if body.len() > MAX_MSG_LEN {
+            eprintln!("error: command too long ({} bytes)", body.len());
+            stats.errors += 1;
+            continue;
+        }
 
-        let mut reader = protocol::Reader::new(message);
+        batch.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        batch.extend_from_slice(&body);
+        nb_commands += 1;
 
-        process_response(&mut reader)?;
+        if nb_commands == pipeline {
+            send_batch(conn, &mut batch, nb_commands, &mut stats)?;
+            nb_commands = 0;
+        }
     }
+    send_batch(conn, &mut batch, nb_commands, &mut stats)?;
 
-    let read_elapsed = std::time::Instant::now() - read_start;
+    println!(
+        "replies: {}, ok: {}, errors: {}",
+        stats.ok + stats.errors,
+        stats.ok,
+        stats.errors
+    );
 
-    println!("read all responses in {:?}", read_elapsed);
+    Ok(stats.errors > 0)
+}
 
-    Ok(())
+fn print_usage() {
+    println!(
+        "Usage: my-own-redis [<flag> ...] [<command> [<arg> ...] [';' <command> [<arg> ...] ...]]"
+    );
+    println!("       my-own-redis [<flag> ...] --pipe");
+    println!("       my-own-redis [<flag> ...] --latency");
+    println!("       my-own-redis [<flag> ...] --file <path>");
+    println!("       my-own-redis [<flag> ...] --dump <path>");
+    println!("       my-own-redis [<flag> ...] --benchmark [--clients <n>] [--requests <n>] [--keyspace <n>]");
+    println!("                    [--set-ratio <percent>] [--value-size <bytes>]");
+    println!();
+    println!(
+        "Flags: -h <host>, -p <port>, -s <socket>, -u|--url <url>, -a|--pass <password>, --user <user>,"
+    );
+    println!("       -c|--cluster, -r|--repeat <n>, -i|--interval <seconds>, --pipeline <n>,");
+    println!("       --output raw|json|pretty|csv, --csv, --timeout <seconds>, --retry-writes, -q|--quiet,");
+    println!("       -v|--verbose (repeat for more)");
+    println!();
+    println!("URLs: redis://[[user]:password@]host[:port] or unix://[[user]:password@]/path");
+    println!();
+    println!(
+        "Exit codes: {} if a command got an error reply, or with --pipe if a command failed",
+        EXIT_ERROR_REPLY
+    );
+    println!(
+        "            {} if the flags or the file are invalid",
+        EXIT_USAGE
+    );
+    println!(
+        "            {} if connecting or authenticating failed",
+        EXIT_CONNECT
+    );
+    println!(
+        "            {} if the server sent an invalid reply",
+        EXIT_PROTOCOL
+    );
+    println!(
+        "            {} if talking to the server or writing the snapshot failed, or timed out",
+        EXIT_IO
+    );
 }
 
-fn main() -> anyhow::Result<()> {
-    // Parse the command
+/// Run the client, returning true if a command got an error reply.
+fn run(config: &ClientConfig, interactive: bool) -> Result<bool, QueryError> {
+    let format = config.output.unwrap_or_else(|| {
+        if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+            Format::Pretty
+        } else {
+            Format::Raw
+        }
+    });
 
-    let mut args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: my-own-redis <command> [<arg> ...]");
-        std::process::exit(1);
+    if config.mode == Mode::Benchmark {
+        benchmark::run(config, config.pipeline.unwrap_or(1))?;
+        return Ok(false);
     }
 
-    // Remove the binary name
-    args.remove(0);
-    // Construct the command and args
-    let command: Vec<&[u8]> = args.iter().map(|v| v.as_ref()).collect();
+    // Like in the command line, a subscription is only left with Ctrl-C
+    if interactive {
+        let address = config.address.to_string();
+
+        if config.cluster {
+            let mut cluster = Cluster::new(config);
+            repl::run(&address, |command| {
+                let error = execute_cluster_commands(&mut cluster, &[command.to_vec()], format)?;
+                if command[0] == b"ssubscribe" {
+                    print_messages(cluster.last_connection()?, format)?;
+                }
+                Ok(error)
+            })?;
+        } else {
+            let mut conn = Connection::open(config)?;
+            repl::run(&address, |command| {
+                let error = execute_commands(&mut conn, &[command.to_vec()], 1, format)?;
+                if command[0] == b"ssubscribe" {
+                    print_messages(&mut conn, format)?;
+                }
+                Ok(error)
+            })?;
+        }
 
-    // if !command::is_valid(command[0]) {
-    //     println!("Usage: my-own-redis <command> [<arg> ...]");
-    //     std::process::exit(1);
-    // }
+        return Ok(false);
+    }
 
-    // Create socket
+    // Construct the commands and args
+    let mut commands: Vec<Vec<&[u8]>> = config
+        .commands
+        .iter()
+        .map(|command| command.iter().map(|v| v.as_ref()).collect())
+        .collect();
 
-    let fd = shared::create_socket()?;
+    for command in &commands {
+        validate_command(command)?;
+    }
 
-    println!("created socket fd={}", fd);
+    // Once subscribed there are only messages, it has to be the last command
+    let subscribe = match commands.last() {
+        Some(command) if config.mode == Mode::Commands && command[0] == b"ssubscribe" => {
+            commands.pop()
+        }
+        _ => None,
+    };
 
-    // Connect
+    if config.cluster {
+        let mut cluster = Cluster::new(config);
+        let mut error = repeat(config, || {
+            execute_cluster_commands(&mut cluster, &commands, format)
+        })?;
 
-    let addr = shared::make_addr([127, 0, 0, 1], 1234);
+        if let Some(command) = subscribe {
+            let reply = cluster.execute(&command)?;
+            error |= print_reply(&reply, format);
+            print_messages(cluster.last_connection()?, format)?;
+        }
 
-    println!("connecting to 127.0.0.1:1234");
+        return Ok(error);
+    }
 
-    shared::connect(fd, &addr)?;
+    // Connect
 
-    println!("connected to 127.0.0.1:1234");
+    let mut conn = Connection::open(config)?;
 
     // Run multiple queries
 
-    execute_commands(fd, &[command])?;
+    match config.mode {
+        Mode::Latency => {
+            latency::run(&mut conn)?;
+            Ok(false)
+        }
+        Mode::Pipe => run_pipe(&mut conn, config.pipeline.unwrap_or(PIPE_BATCH)),
+        Mode::File => {
+            let path = config.file.as_deref().expect("no file in file mode");
+            file::run(&mut conn, path, config.pipeline.unwrap_or(1), format)
+        }
+        Mode::Dump => {
+            let path = config.dump.as_deref().expect("no path in dump mode");
+            dump::run(&mut conn, path, format)
+        }
+        _ => {
+            let pipeline = config.pipeline.unwrap_or(commands.len()).max(1);
+            let error = repeat(config, || {
+                execute_commands(&mut conn, &commands, pipeline, format)
+            })?;
+
+            if let Some(command) = subscribe {
+                conn.write_commands(&[command])?;
+                print_messages(&mut conn, format)?;
+            }
+
+            Ok(error)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    // Parse the command
 
-    println!("closing file descriptor fd={}", fd);
+    let config = match ClientConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-    shared::close(fd)?;
+    // Only the responses and the warnings are printed unless asked otherwise with -q and -v, or with
+    // MY_OWN_REDIS_LOG_LEVEL=debug for example
+    let level = config
+        .log_level
+        .or_else(|| {
+            std::env::var("MY_OWN_REDIS_LOG_LEVEL")
+                .ok()
+                .and_then(|value| Level::parse(&value))
+        })
+        .unwrap_or(Level::Warn);
+    log::set_level(level);
+
+    let no_commands = config.commands.is_empty() && config.mode == Mode::Commands;
+    let interactive = no_commands && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if no_commands && !interactive {
+        print_usage();
+        return ExitCode::from(EXIT_USAGE);
+    }
 
-    Ok(())
+    match run(&config, interactive) {
+        Ok(false) => ExitCode::SUCCESS,
+        Ok(true) => ExitCode::from(EXIT_ERROR_REPLY),
+        Err(err) => {
+            let exit_code = err.exit_code();
+            eprintln!("Error: {:?}", anyhow::Error::from(err));
+            ExitCode::from(exit_code)
+        }
+    }
 }
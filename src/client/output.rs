@@ -0,0 +1,293 @@
+//! Formatting of the replies printed by the client.
+//!
+//! * `raw` prints the values as is, one per line, for shell scripts.
+//! * `json` prints one JSON value per reply, errors being `{"error":"<code>","message":"<message>"}`.
+//! * `pretty` prints typed values like redis-cli, with the elements of the arrays numbered and aligned.
+//! * `csv` prints one row per reply, the elements of the arrays, nested ones included, being its columns. Strings are
+//!   always quoted, nil is an empty column and an error is the `ERROR` column followed by the quoted error.
+
+use shared::client::Value;
+use shared::ResponseCode;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    Json,
+    Pretty,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "raw" => Ok(Self::Raw),
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            "csv" => Ok(Self::Csv),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the name of the response code, or the code itself if it's unknown.
+fn code_name(response_code: u32) -> String {
+    match ResponseCode::try_from(response_code) {
+        Ok(code) => code.to_string(),
+        Err(_) => response_code.to_string(),
+    }
+}
+
+/// Format the reply, without a trailing newline.
+pub fn format_reply(reply: &Value, format: Format) -> String {
+    let mut output = String::new();
+    match format {
+        Format::Raw => write_raw(&mut output, reply),
+        Format::Json => write_json(&mut output, reply),
+        Format::Pretty => write_pretty(&mut output, reply, 0),
+        Format::Csv => write_csv(&mut output, reply),
+    }
+
+    output
+}
+
+fn write_raw(output: &mut String, reply: &Value) {
+    match reply {
+        Value::Nil => {}
+        Value::Error { code, message } => {
+            let _ = write!(output, "{} {}", code_name(*code), message);
+        }
+        Value::Bytes(value) => output.push_str(&String::from_utf8_lossy(value)),
+        Value::Int(value) => {
+            let _ = write!(output, "{}", value);
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push('\n');
+                }
+                write_raw(output, item);
+            }
+        }
+    }
+}
+
+fn write_json_string(output: &mut String, value: &[u8]) {
+    output.push('"');
+
+    for c in String::from_utf8_lossy(value).chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+
+    output.push('"');
+}
+
+fn write_json(output: &mut String, reply: &Value) {
+    match reply {
+        Value::Nil => output.push_str("null"),
+        Value::Error { code, message } => {
+            output.push_str("{\"error\":");
+            write_json_string(output, code_name(*code).as_bytes());
+            output.push_str(",\"message\":");
+            write_json_string(output, message.as_bytes());
+            output.push('}');
+        }
+        Value::Bytes(value) => write_json_string(output, value),
+        Value::Int(value) => {
+            let _ = write!(output, "{}", value);
+        }
+        Value::Array(items) => {
+            output.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_json(output, item);
+            }
+            output.push(']');
+        }
+    }
+}
+
+/// Write a quoted string, escaping the bytes which aren't printable ASCII.
+fn write_quoted(output: &mut String, value: &[u8]) {
+    output.push('"');
+
+    for b in value {
+        match b {
+            b'"' => output.push_str("\\\""),
+            b'\\' => output.push_str("\\\\"),
+            b'\n' => output.push_str("\\n"),
+            b'\r' => output.push_str("\\r"),
+            b'\t' => output.push_str("\\t"),
+            0x20..=0x7e => output.push(*b as char),
+            b => {
+                let _ = write!(output, "\\x{:02x}", b);
+            }
+        }
+    }
+
+    output.push('"');
+}
+
+/// Write `reply`, its lines after the first one being indented by `indent` spaces.
+fn write_pretty(output: &mut String, reply: &Value, indent: usize) {
+    match reply {
+        Value::Nil => output.push_str("(nil)"),
+        Value::Error { code, message } => {
+            let _ = write!(output, "(error) {} {}", code_name(*code), message);
+        }
+        Value::Bytes(value) => write_quoted(output, value),
+        Value::Int(value) => {
+            let _ = write!(output, "(integer) {}", value);
+        }
+        Value::Array(items) if items.is_empty() => output.push_str("(empty array)"),
+        Value::Array(items) => {
+            // The indexes are right aligned, the elements left aligned after them
+            let width = items.len().to_string().len();
+
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push('\n');
+                    output.push_str(&" ".repeat(indent));
+                }
+
+                let _ = write!(output, "{:>width$}) ", i + 1, width = width);
+                write_pretty(output, item, indent + width + 2);
+            }
+        }
+    }
+}
+
+/// Write a quoted CSV field, doubling the quotes like in RFC 4180.
+fn write_csv_string(output: &mut String, value: &[u8]) {
+    output.push('"');
+    output.push_str(&String::from_utf8_lossy(value).replace('"', "\"\""));
+    output.push('"');
+}
+
+fn write_csv(output: &mut String, reply: &Value) {
+    match reply {
+        Value::Nil => {}
+        Value::Error { code, message } => {
+            output.push_str("ERROR,");
+            write_csv_string(
+                output,
+                format!("{} {}", code_name(*code), message).as_bytes(),
+            );
+        }
+        Value::Bytes(value) => write_csv_string(output, value),
+        Value::Int(value) => {
+            let _ = write!(output, "{}", value);
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_csv(output, item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_reply, Format};
+    use shared::client::Value;
+    use shared::protocol::{self, Writer, BUF_LEN};
+    use shared::ResponseCode;
+
+    fn format<F: FnOnce(&mut Writer)>(format: Format, f: F) -> String {
+        let mut buf = [0; BUF_LEN];
+        {
+            let mut writer = Writer::new(&mut buf);
+            f(&mut writer);
+            writer.finish();
+        }
+
+        let (_, body) = protocol::parse_message(&buf).unwrap();
+        format_reply(&Value::decode(body).unwrap(), format)
+    }
+
+    fn nested(writer: &mut Writer) {
+        writer.push_arr(3);
+        writer.push_string("a\"b");
+        writer.push_arr(2);
+        writer.push_int(1);
+        writer.push_nil();
+        writer.push_err(ResponseCode::Unknown, "oops");
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!("", format(Format::Raw, |w| w.push_nil()));
+        assert_eq!("bar", format(Format::Raw, |w| w.push_string("bar")));
+        assert_eq!("a\"b\n1\n\nUNKNOWN oops", format(Format::Raw, nested));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!("null", format(Format::Json, |w| w.push_nil()));
+        assert_eq!("\"a\\nb\"", format(Format::Json, |w| w.push_string("a\nb")));
+        assert_eq!(
+            "[\"a\\\"b\",[1,null],{\"error\":\"UNKNOWN\",\"message\":\"oops\"}]",
+            format(Format::Json, nested)
+        );
+        assert_eq!(
+            "{\"error\":\"999\",\"message\":\"\"}",
+            format(Format::Json, |w| w.push_err(999u32, ""))
+        );
+    }
+
+    #[test]
+    fn pretty() {
+        assert_eq!("(nil)", format(Format::Pretty, |w| w.push_nil()));
+        assert_eq!("(integer) 3", format(Format::Pretty, |w| w.push_int(3)));
+        assert_eq!(
+            "\"\\x00\\n\"",
+            format(Format::Pretty, |w| w.push_string(b"\x00\n"))
+        );
+        assert_eq!("(empty array)", format(Format::Pretty, |w| w.push_arr(0)));
+        assert_eq!(
+            "1) \"a\\\"b\"\n2) 1) (integer) 1\n   2) (nil)\n3) (error) UNKNOWN oops",
+            format(Format::Pretty, nested)
+        );
+
+        let ten = format(Format::Pretty, |w| {
+            w.push_arr(10);
+            for i in 0..10 {
+                w.push_int(i);
+            }
+        });
+        assert!(ten.starts_with(" 1) (integer) 0\n 2)"));
+        assert!(ten.ends_with("\n10) (integer) 9"));
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!("", format(Format::Csv, |w| w.push_nil()));
+        assert_eq!("3", format(Format::Csv, |w| w.push_int(3)));
+        assert_eq!(
+            "\"a,\"\"b\"\"\nc\"",
+            format(Format::Csv, |w| w.push_string("a,\"b\"\nc"))
+        );
+        assert_eq!(
+            "\"a\"\"b\",1,,ERROR,\"UNKNOWN oops\"",
+            format(Format::Csv, nested)
+        );
+    }
+}
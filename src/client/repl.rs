@@ -0,0 +1,310 @@
+//! The interactive mode, started when no command is given and stdin is a terminal.
+//!
+//! The arguments are separated by whitespace and can be quoted like in redis-cli: `"..."` understands the `\"`, `\\`,
+//! `\n`, `\r`, `\t` and `\xHH` escapes, `'...'` only `\'`. The command names and their subcommands are completed with
+//! Tab from the shared command table, and the lines are saved in `~/.my-own-redis_history`.
+//!
+//! The prompt shows `(TX)` after the address between MULTI and EXEC or DISCARD, tracked from the commands sent and
+//! their replies. There's no database index since the server has a single keyspace, and no subscription marker since
+//! the prompt only comes back once the server ends the subscription.
+//!
+//! `help` lists the commands of the table with their arguments and what they do, `help <command>` shows a single one.
+
+use crate::editor::Editor;
+use crate::QueryError;
+use shared::command::{self, CommandInfo, COMMANDS};
+use std::path::PathBuf;
+
+const HISTORY_FILE: &str = ".my-own-redis_history";
+
+/// Split a line into arguments, returning `None` if a quote isn't closed or isn't followed by a space.
+pub fn split_args(line: &str) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first = match chars.peek() {
+            Some(c) => *c,
+            None => return Some(args),
+        };
+
+        let mut arg = Vec::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    let c = match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => '\n',
+                            'r' => '\r',
+                            't' => '\t',
+                            'x' => {
+                                let hex: String = [chars.next()?, chars.next()?].iter().collect();
+                                arg.push(u8::from_str_radix(&hex, 16).ok()?);
+                                continue;
+                            }
+                            c => c,
+                        },
+                        c => c,
+                    };
+                    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    let c = match chars.next()? {
+                        '\'' => break,
+                        '\\' if chars.peek() == Some(&'\'') => chars.next()?,
+                        c => c,
+                    };
+                    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+        }
+
+        // Like redis-cli, "a"b is an error rather than two arguments
+        if matches!(first, '"' | '\'') && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+
+        args.push(arg);
+    }
+}
+
+/// Returns the lines completing the command name or the subcommand being typed.
+fn complete(line: &str) -> Vec<String> {
+    let words: Vec<&str> = line.split(' ').collect();
+
+    match words.as_slice() {
+        [name] => COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(&name.to_ascii_lowercase()))
+            .map(|command| command.name.to_string())
+            .collect(),
+        [name, subcommand] => {
+            let command = match COMMANDS
+                .iter()
+                .find(|command| command.name.eq_ignore_ascii_case(name))
+            {
+                Some(command) => command,
+                None => return Vec::new(),
+            };
+
+            command
+                .subcommands
+                .iter()
+                .filter(|candidate| candidate.starts_with(&subcommand.to_ascii_lowercase()))
+                .map(|candidate| format!("{} {}", name, candidate))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The state of the session shown in the prompt.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Session {
+    transaction: bool,
+}
+
+impl Session {
+    /// Update the state after `command` got a reply, an error one if `error`.
+    fn update(&mut self, command: &[&[u8]], error: bool) {
+        let name = match command.first() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return,
+        };
+
+        match name.as_slice() {
+            // A nested MULTI fails but the transaction goes on
+            b"multi" if !error => self.transaction = true,
+            // Even when they fail there's no transaction afterwards
+            b"exec" | b"discard" => self.transaction = false,
+            _ => {}
+        }
+    }
+
+    fn prompt(&self, address: &str) -> String {
+        let mut prompt = address.to_string();
+        if self.transaction {
+            prompt.push_str("(TX)");
+        }
+        prompt.push_str("> ");
+        prompt
+    }
+}
+
+/// Returns the line describing the command in the help.
+fn help_line(command: &CommandInfo) -> String {
+    format!(
+        "{:<14} {:<9} {}",
+        command.name,
+        command.describe_args(),
+        command.summary
+    )
+}
+
+/// Returns the help of all the commands, or of the one named `topic`.
+fn help(topic: Option<&[u8]>) -> String {
+    let topic = match topic {
+        Some(topic) => topic.to_ascii_lowercase(),
+        None => {
+            let lines: Vec<String> = COMMANDS.iter().map(help_line).collect();
+            return lines.join("\n");
+        }
+    };
+
+    let command = match command::find(&topic) {
+        Some(command) => command,
+        None => return format!("unknown command {:?}", String::from_utf8_lossy(&topic)),
+    };
+
+    let mut help = help_line(command);
+    if !command.subcommands.is_empty() {
+        help.push_str(&format!(
+            "\n{:<14} subcommands: {}",
+            "",
+            command.subcommands.join(", ")
+        ));
+    }
+    help
+}
+
+/// Read commands from the terminal and execute them with `execute` until the end of the input or `quit`.
+///
+/// `execute` returns true if the command got an error reply.
+pub fn run<F: FnMut(&[&[u8]]) -> Result<bool, QueryError>>(
+    address: &str,
+    mut execute: F,
+) -> Result<(), QueryError> {
+    let history_path = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    let mut editor = Editor::new(history_path);
+    let mut session = Session::default();
+
+    while let Some(line) = editor.read_line(&session.prompt(address), complete)? {
+        let args = match split_args(&line) {
+            Some(args) => args,
+            None => {
+                eprintln!("Invalid argument(s)");
+                continue;
+            }
+        };
+        let command: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+
+        match command.first() {
+            None => continue,
+            Some(&(b"quit" | b"exit")) => break,
+            Some(&b"help") => {
+                editor.add_history(&line);
+                println!("{}", help(command.get(1).copied()));
+                continue;
+            }
+            // Don't save the passwords
+            Some(&b"auth") => {}
+            Some(_) => editor.add_history(&line),
+        }
+
+        if let Err(err) = crate::validate_command(&command) {
+            eprintln!("error: {}", err);
+            continue;
+        }
+
+        match execute(&command) {
+            Ok(error) => session.update(&command, error),
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete, help, split_args, Session};
+
+    fn split(line: &str) -> Option<Vec<String>> {
+        split_args(line).map(|args| {
+            args.into_iter()
+                .map(|arg| String::from_utf8(arg).unwrap())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(Vec::<String>::new(), split("  ").unwrap());
+        assert_eq!(vec!["set", "a", "1"], split(" set  a\t1 ").unwrap());
+        assert_eq!(
+            vec!["set", "a b", "it's \"x\""],
+            split(r#"set "a b" 'it\'s "x"'"#).unwrap()
+        );
+        assert_eq!(
+            vec!["set", "a", "\"\t\x01"],
+            split(r#"set a "\"\t\x01""#).unwrap()
+        );
+        assert_eq!(vec!["get", ""], split("get \"\"").unwrap());
+
+        assert_eq!(None, split("get \"a"));
+        assert_eq!(None, split("get 'a"));
+        assert_eq!(None, split("get \"a\"b"));
+        assert_eq!(None, split("get \"\\xzz\""));
+    }
+
+    #[test]
+    fn session() {
+        let mut session = Session::default();
+        assert_eq!("127.0.0.1:1234> ", session.prompt("127.0.0.1:1234"));
+        session.update(&[b"multi"], true);
+        assert!(!session.transaction);
+
+        session.update(&[b"MULTI"], false);
+        assert_eq!("127.0.0.1:1234(TX)> ", session.prompt("127.0.0.1:1234"));
+        session.update(&[b"set", b"a", b"1"], false);
+        session.update(&[b"multi"], true);
+        assert!(session.transaction);
+
+        session.update(&[b"exec"], true);
+        assert!(!session.transaction);
+        session.update(&[b"multi"], false);
+        session.update(&[b"discard"], false);
+        assert_eq!("/tmp/redis.sock> ", session.prompt("/tmp/redis.sock"));
+    }
+
+    #[test]
+    fn help_topics() {
+        let all = help(None);
+        assert_eq!(shared::command::COMMANDS.len(), all.lines().count());
+        assert!(all.starts_with("asking         no args   "));
+
+        assert_eq!(
+            "get            1 arg     Get the value of a key",
+            help(Some(b"GET"))
+        );
+        assert_eq!(
+            "setex          3+ args   Set the value and the time to live of a key",
+            help(Some(b"setex"))
+        );
+        assert!(help(Some(b"ping")).starts_with("ping           any args  "));
+        assert!(help(Some(b"config")).ends_with("subcommands: get, set"));
+        assert_eq!("unknown command \"nope\"", help(Some(b"nope")));
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(vec!["set", "setex"], complete("se"));
+        assert_eq!(vec!["ssubscribe"], complete("SSU"));
+        assert_eq!(vec!["client setname"], complete("client sETn"));
+        assert_eq!(vec!["CONFIG get", "CONFIG set"], complete("CONFIG "));
+        assert!(complete("nope").is_empty());
+        assert!(complete("get ").is_empty());
+        assert!(complete("config get maxmemory").is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+//! The append only file, a log of every request which changed the keyspace.
+//!
+//! The file starts with a header: `MAGIC` and the format version as a big-endian u16.
+//!
+//! Then each request is stored as sent by the clients, the body prefixed by its length (see
+//! [`protocol::parse_message`]), followed by the CRC-64 of the body as a big-endian u64.
+//!
+//! Files written before the header was added (version 0) have no header and no checksums, they are converted
+//! when replayed.
+
+use crate::server::crc64;
+use crate::{info, protocol, warn};
+use onlyerror::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 8] = *b"MORAOF\0\0";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const CHECKSUM_LEN: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("append only file truncated at offset {0}")]
+    Truncated(usize),
+    #[error("corrupt request at offset {offset}: {error}")]
+    Corrupt {
+        offset: usize,
+        error: protocol::Error,
+    },
+    #[error("checksum mismatch for the request at offset {0}")]
+    ChecksumMismatch(usize),
+    #[error("not an append only file, invalid magic")]
+    InvalidMagic,
+    #[error("unsupported append only file version {0}")]
+    UnsupportedVersion(u16),
+}
+
+fn header() -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&VERSION.to_be_bytes());
+    header
+}
+
+fn encode_record(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(4 + body.len() + CHECKSUM_LEN);
+    record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    record.extend_from_slice(body);
+    record.extend_from_slice(&crc64::checksum(body).to_be_bytes());
+    record
+}
+
+pub struct AppendOnlyFile {
+    file: File,
+}
+
+impl AppendOnlyFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header())?;
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Append a request body.
+    pub fn append(&mut self, body: &[u8]) -> io::Result<()> {
+        self.file.write_all(&encode_record(body))
+    }
+}
+
+/// Returns the version of the file and the length of its header.
+fn parse_header(data: &[u8]) -> Result<(u16, usize), ReplayError> {
+    // Files written before the header was added start directly with a request, whose length is small
+    if data.first().is_some_and(|b| *b != MAGIC[0]) {
+        return Ok((0, 0));
+    }
+
+    if data.len() < HEADER_LEN {
+        return Err(ReplayError::Truncated(0));
+    }
+    if data[..MAGIC.len()] != MAGIC {
+        return Err(ReplayError::InvalidMagic);
+    }
+
+    let version = u16::from_be_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+    match version {
+        1..=VERSION => Ok((version, HEADER_LEN)),
+        version => Err(ReplayError::UnsupportedVersion(version)),
+    }
+}
+
+/// Parse the request at the start of `data`, returning the length of the record, the body and its checksum.
+fn parse_record(data: &[u8], version: u16) -> Result<(usize, &[u8], Option<u64>), protocol::Error> {
+    let (read, body) = protocol::parse_message(data)?;
+
+    if version == 0 {
+        return Ok((read, body, None));
+    }
+
+    match data.get(read..read + CHECKSUM_LEN) {
+        Some(checksum) => {
+            let checksum = u64::from_be_bytes(checksum.try_into().unwrap());
+            Ok((read + CHECKSUM_LEN, body, Some(checksum)))
+        }
+        None => Err(protocol::Error::InputTooShort(data.len())),
+    }
+}
+
+fn truncate(path: &Path, len: usize) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len as u64)?;
+    file.sync_all()
+}
+
+/// Replace the file at `path` with one in the current version containing `bodies`.
+fn convert(path: &Path, bodies: &[&[u8]]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp-{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&header())?;
+        for body in bodies {
+            writer.write_all(&encode_record(body))?;
+        }
+
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Call `execute` with every request of the append only file at `path`, if it exists.
+/// Returns the number of requests replayed.
+///
+/// An incomplete request at the end of the file is the sign of a crash in the middle of a write: with
+/// `load_truncated` the file is truncated to the last complete request instead of failing.
+pub fn replay<F>(
+    path: &Path,
+    load_truncated: bool,
+    mut execute: F,
+) -> Result<Option<usize>, ReplayError>
+where
+    F: FnMut(&[u8]),
+{
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let (version, header_len) = match parse_header(&data) {
+        Ok(header) => header,
+        Err(ReplayError::Truncated(_)) if load_truncated => {
+            warn!("truncating {}, the header is incomplete", path.display());
+            truncate(path, 0)?;
+
+            return Ok(Some(0));
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut offset = header_len;
+    let mut nb_requests = 0;
+    // Kept to convert a file written before the header was added
+    let mut legacy_bodies = Vec::new();
+
+    while offset < data.len() {
+        let (read, body, checksum) = match parse_record(&data[offset..], version) {
+            Ok(record) => record,
+            Err(protocol::Error::InputTooShort(_)) => {
+                if !load_truncated {
+                    return Err(ReplayError::Truncated(offset));
+                }
+
+                warn!(
+                    "truncating {} to {} bytes, the last request is incomplete",
+                    path.display(),
+                    offset
+                );
+
+                truncate(path, offset)?;
+
+                break;
+            }
+            Err(error) => return Err(ReplayError::Corrupt { offset, error }),
+        };
+
+        if checksum.is_some_and(|checksum| checksum != crc64::checksum(body)) {
+            return Err(ReplayError::ChecksumMismatch(offset));
+        }
+
+        execute(body);
+
+        if version == 0 {
+            legacy_bodies.push(body);
+        }
+
+        offset += read;
+        nb_requests += 1;
+    }
+
+    if version == 0 && !data.is_empty() {
+        convert(path, &legacy_bodies)?;
+        info!(
+            "converted {} to version {} of the format",
+            path.display(),
+            VERSION
+        );
+    }
+
+    Ok(Some(nb_requests))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{header, replay, AppendOnlyFile, ReplayError, HEADER_LEN, MAGIC};
+    use std::path::{Path, PathBuf};
+    use std::{env, fs};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("aof-test-{}-{}", name, std::process::id()))
+    }
+
+    fn replay_all(path: &Path, load_truncated: bool) -> Result<Vec<Vec<u8>>, ReplayError> {
+        let mut bodies = Vec::new();
+        replay(path, load_truncated, |body| bodies.push(body.to_vec()))?;
+        Ok(bodies)
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = temp_path("replay");
+
+        let mut aof = AppendOnlyFile::open(&path).unwrap();
+        aof.append(b"foo").unwrap();
+        aof.append(b"barbaz").unwrap();
+        drop(aof);
+
+        assert_eq!(
+            vec![b"foo".to_vec(), b"barbaz".to_vec()],
+            replay_all(&path, false).unwrap()
+        );
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(replay(&path, false, |_| {}).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let path = temp_path("truncated");
+
+        let mut aof = AppendOnlyFile::open(&path).unwrap();
+        aof.append(b"foo").unwrap();
+        aof.append(b"barbaz").unwrap();
+        drop(aof);
+
+        // Crash in the middle of the second request
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        // Header, then the length, body and checksum of the first request
+        let end_of_first = HEADER_LEN + 4 + 3 + 8;
+
+        assert!(matches!(
+            replay_all(&path, false),
+            Err(ReplayError::Truncated(offset)) if offset == end_of_first
+        ));
+
+        assert_eq!(vec![b"foo".to_vec()], replay_all(&path, true).unwrap());
+        assert_eq!(end_of_first as u64, fs::metadata(&path).unwrap().len());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt() {
+        let path = temp_path("corrupt");
+
+        let mut data = header().to_vec();
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x00]);
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            replay_all(&path, true),
+            Err(ReplayError::Corrupt {
+                offset: HEADER_LEN,
+                ..
+            })
+        ));
+
+        fs::remove_file(&path).unwrap();
+        let mut aof = AppendOnlyFile::open(&path).unwrap();
+        aof.append(b"foo").unwrap();
+        drop(aof);
+
+        // A single bit flipped in the body
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN + 4] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            replay_all(&path, true),
+            Err(ReplayError::ChecksumMismatch(HEADER_LEN))
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn versions() {
+        let path = temp_path("versions");
+
+        let mut newer = header().to_vec();
+        newer[MAGIC.len() + 1] = 2;
+        fs::write(&path, &newer).unwrap();
+        assert!(matches!(
+            replay_all(&path, true),
+            Err(ReplayError::UnsupportedVersion(2))
+        ));
+
+        // Written before the header and the checksums were added
+        fs::write(&path, b"\x00\x00\x00\x03foo\x00\x00\x00\x06barbaz").unwrap();
+        assert_eq!(
+            vec![b"foo".to_vec(), b"barbaz".to_vec()],
+            replay_all(&path, false).unwrap()
+        );
+
+        // Converted while replayed
+        assert!(fs::read(&path).unwrap().starts_with(&header()));
+        assert_eq!(
+            vec![b"foo".to_vec(), b"barbaz".to_vec()],
+            replay_all(&path, false).unwrap()
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Audit log of the administrative commands and of the authentication attempts.
+//!
+//! Every event is appended to its own file as one line:
+//!
+//! `2023-11-14T22:13:20.123Z 127.0.0.1:50000 id=3 config set maxmemory 100mb`
+//!
+//! The commands are recorded right before they're executed, those of a transaction when it's executed and those of a
+//! script when it calls them. Passwords are never written.
+
+use crate::{log, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The parameters whose value is replaced in CONFIG SET.
+const SECRET_PARAMETERS: &[&[u8]] = &[b"requirepass", b"masterauth"];
+const REDACTED: &[u8] = b"<redacted>";
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record an AUTH attempt by the client `id` at `addr`.
+    pub fn record_auth(&self, addr: SocketAddrV4, id: u64, success: bool) {
+        let outcome = if success { "ok" } else { "failed" };
+        self.write(addr, id, format!("auth {}", outcome).as_bytes());
+    }
+
+    /// Record `request` by the client `id` at `addr` if it's an administrative command.
+    pub fn record_request(&self, addr: SocketAddrV4, id: u64, request: &[&[u8]]) {
+        if is_audited(request) {
+            self.write(addr, id, &describe(request));
+        }
+    }
+
+    fn write(&self, addr: SocketAddrV4, id: u64, event: &[u8]) {
+        let mut line = format!(
+            "{} {} id={} ",
+            log::format_timestamp(SystemTime::now()),
+            addr,
+            id
+        )
+        .into_bytes();
+        line.extend_from_slice(event);
+        line.push(b'\n');
+
+        // One write per line so that concurrent events are never interleaved
+        if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+            warn!("unable to write to the audit log, err: {}", err);
+        }
+    }
+}
+
+fn is_audited(request: &[&[u8]]) -> bool {
+    matches!(
+        request,
+        [b"config", b"set", ..] | [b"shutdown", ..] | [b"replicaof", ..] | [b"client", b"kill", ..]
+    )
+}
+
+/// Returns the arguments of `request` separated by spaces, with the secrets redacted and the other arguments escaped
+/// so that an event is always a single line.
+fn describe(request: &[&[u8]]) -> Vec<u8> {
+    let mut description = Vec::new();
+    let mut redact_next = false;
+
+    for (i, arg) in request.iter().enumerate() {
+        if i > 0 {
+            description.push(b' ');
+        }
+
+        // CONFIG SET takes pairs of parameter and value after the subcommand
+        let is_value = i >= 3 && i % 2 == 1;
+        if redact_next && is_value {
+            description.extend_from_slice(REDACTED);
+        } else {
+            description.extend(arg.escape_ascii());
+        }
+
+        redact_next = i >= 2 && !is_value && SECRET_PARAMETERS.contains(arg);
+    }
+
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, is_audited};
+
+    #[test]
+    fn audited() {
+        assert!(is_audited(&[b"config", b"set", b"maxmemory", b"1mb"]));
+        assert!(is_audited(&[b"shutdown"]));
+        assert!(is_audited(&[b"client", b"kill", b"id", b"3"]));
+        assert!(!is_audited(&[b"config", b"get", b"maxmemory"]));
+        assert!(!is_audited(&[b"client", b"list"]));
+        assert!(!is_audited(&[b"get", b"config"]));
+    }
+
+    #[test]
+    fn redacted() {
+        assert_eq!(
+            b"config set maxmemory 1mb requirepass <redacted> masterauth <redacted>".to_vec(),
+            describe(&[
+                b"config",
+                b"set",
+                b"maxmemory",
+                b"1mb",
+                b"requirepass",
+                b"hunter2",
+                b"masterauth",
+                b"hunter3"
+            ])
+        );
+
+        // A value named like a secret parameter isn't one
+        assert_eq!(
+            b"config set dir requirepass".to_vec(),
+            describe(&[b"config", b"set", b"dir", b"requirepass"])
+        );
+
+        assert_eq!(
+            b"shutdown \\n\\x00".to_vec(),
+            describe(&[b"shutdown", b"\n\0"])
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Registry of the connected clients, for the CLIENT command.
+//!
+//! The connections belong to their event loop, which registers each one here and keeps its metadata up to date.
+//! Killing a client only flags it: its event loop closes the connection the next time it checks for killed clients.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// What a client is doing, updated by its event loop.
+struct Activity {
+    name: String,
+    last_command: String,
+    last_activity: Instant,
+    /// Bytes of requests received but not processed yet.
+    query_buf_len: usize,
+    /// Bytes of responses not sent yet.
+    output_buf_len: usize,
+}
+
+pub struct Client {
+    pub id: u64,
+    pub addr: SocketAddrV4,
+    fd: i32,
+    created: Instant,
+    activity: Mutex<Activity>,
+    killed: AtomicBool,
+}
+
+impl Client {
+    pub fn name(&self) -> String {
+        self.activity.lock().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.activity.lock().unwrap().name = name.to_string();
+    }
+
+    /// Record that the client sent `command`, with the state of its buffers after that.
+    pub fn record_command(&self, command: &[u8], query_buf_len: usize, output_buf_len: usize) {
+        let mut activity = self.activity.lock().unwrap();
+
+        activity.last_command.clear();
+        activity
+            .last_command
+            .push_str(&String::from_utf8_lossy(command));
+        activity.last_activity = Instant::now();
+        activity.query_buf_len = query_buf_len;
+        activity.output_buf_len = output_buf_len;
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+}
+
+/// Which clients CLIENT KILL closes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KillFilter {
+    Id(u64),
+    Addr(SocketAddrV4),
+}
+
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+    /// Number of clients killed but not closed yet, the event loops only look for them if there are some.
+    nb_killed: AtomicUsize,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            clients: Mutex::new(BTreeMap::new()),
+            nb_killed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Register a new connection, assigning it a unique id.
+    pub fn register(&self, fd: i32, addr: SocketAddrV4) -> Arc<Client> {
+        let now = Instant::now();
+
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            fd,
+            created: now,
+            activity: Mutex::new(Activity {
+                name: String::new(),
+                last_command: String::new(),
+                last_activity: now,
+                query_buf_len: 0,
+                output_buf_len: 0,
+            }),
+            killed: AtomicBool::new(false),
+        });
+
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client.id, Arc::clone(&client));
+
+        client
+    }
+
+    /// Forget a connection once it's closed or handed to the replication.
+    pub fn unregister(&self, client: &Client) {
+        self.clients.lock().unwrap().remove(&client.id);
+
+        if client.is_killed() {
+            self.nb_killed.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.clients.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn has_killed(&self) -> bool {
+        self.nb_killed.load(Ordering::Relaxed) > 0
+    }
+
+    /// Flag the clients matching `filter` to be closed, returning how many there are.
+    pub fn kill(&self, filter: &KillFilter) -> usize {
+        let clients = self.clients.lock().unwrap();
+
+        let mut nb_killed = 0;
+        for client in clients.values() {
+            let matches = match filter {
+                KillFilter::Id(id) => client.id == *id,
+                KillFilter::Addr(addr) => client.addr == *addr,
+            };
+
+            if matches && !client.killed.swap(true, Ordering::Relaxed) {
+                self.nb_killed.fetch_add(1, Ordering::Relaxed);
+                nb_killed += 1;
+            }
+        }
+
+        nb_killed
+    }
+
+    /// Returns a line describing every client, in the order they connected.
+    pub fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+
+        let mut list = String::new();
+        for client in clients.values() {
+            list.push_str(&self.describe(client));
+        }
+
+        list
+    }
+
+    /// Returns the line describing `client` in [`Clients::list`].
+    pub fn describe(&self, client: &Client) -> String {
+        let now = Instant::now();
+        let activity = client.activity.lock().unwrap();
+
+        format!(
+            "id={} addr={} fd={} name={} age={} idle={} qbuf={} obl={} cmd={}\n",
+            client.id,
+            client.addr,
+            client.fd,
+            activity.name,
+            now.duration_since(client.created).as_secs(),
+            now.duration_since(activity.last_activity).as_secs(),
+            activity.query_buf_len,
+            activity.output_buf_len,
+            if activity.last_command.is_empty() {
+                "NULL"
+            } else {
+                &activity.last_command
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clients, KillFilter};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn kill() {
+        let clients = Clients::new();
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40000);
+        let first = clients.register(5, addr);
+        let second = clients.register(6, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40001));
+        assert_ne!(first.id, second.id);
+
+        assert!(clients.contains(first.id));
+        assert!(!clients.contains(100));
+
+        assert!(!clients.has_killed());
+        assert_eq!(0, clients.kill(&KillFilter::Id(100)));
+        assert_eq!(1, clients.kill(&KillFilter::Addr(addr)));
+        // Already killed
+        assert_eq!(0, clients.kill(&KillFilter::Id(first.id)));
+
+        assert!(first.is_killed());
+        assert!(!second.is_killed());
+        assert!(clients.has_killed());
+
+        clients.unregister(&first);
+        assert!(!clients.has_killed());
+    }
+
+    #[test]
+    fn list() {
+        let clients = Clients::new();
+
+        let client = clients.register(5, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40000));
+        assert_eq!(
+            "id=0 addr=127.0.0.1:40000 fd=5 name= age=0 idle=0 qbuf=0 obl=0 cmd=NULL\n",
+            clients.list()
+        );
+
+        client.set_name("worker");
+        client.record_command(b"get", 10, 20);
+        assert_eq!(
+            "id=0 addr=127.0.0.1:40000 fd=5 name=worker age=0 idle=0 qbuf=10 obl=20 cmd=get\n",
+            clients.list()
+        );
+
+        assert_eq!(clients.list(), clients.describe(&client));
+
+        clients.unregister(&client);
+        assert_eq!("", clients.list());
+    }
+}
@@ -0,0 +1,454 @@
+//! Cluster mode: the keyspace is partitioned in hash slots, each owned by one node.
+//!
+//! A node only serves the keys of the slots it owns and redirects the clients to the owner of the others:
+//! * `MOVED <slot> <ip>:<port>` if the slot is owned by another node, the client should update its routing table.
+//! * `ASK <slot> <ip>:<port>` if the slot is being migrated to another node and the key was already moved. The client
+//!   should send ASKING then the request to that node, once, without updating its routing table.
+//!
+//! The nodes don't talk to each other: every node is told who owns which slot, either at startup or with CLUSTER
+//! SETSLOT.
+
+use crate::server::crc64;
+pub use crate::slot::{key_slot, NB_SLOTS};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddrV4;
+
+/// Returns the ID of the node at `addr`.
+///
+/// The nodes don't exchange any information, their ID is derived from their address so that they all agree on it.
+pub fn node_id(addr: SocketAddrV4) -> String {
+    format!("{:016x}", crc64::checksum(addr.to_string().as_bytes()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    Myself,
+    Node(SocketAddrV4),
+}
+
+impl Owner {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("myself") {
+            Some(Self::Myself)
+        } else {
+            value.parse().ok().map(Self::Node)
+        }
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Myself => write!(f, "myself"),
+            Self::Node(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// A range of slots assigned to a node, both ends included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub owner: Owner,
+}
+
+/// Parses slot ranges and their owners: `<start>-<end> <owner>` pairs, where the owner is either `<ip>:<port>` or
+/// `myself`. A single slot can be written without the end.
+pub fn parse_slot_ranges(value: &str) -> Option<Vec<SlotRange>> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+
+    parts
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [range, owner] => {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                    None => {
+                        let slot = range.parse().ok()?;
+                        (slot, slot)
+                    }
+                };
+
+                if start > end || end as usize >= NB_SLOTS {
+                    return None;
+                }
+
+                Some(SlotRange {
+                    start,
+                    end,
+                    owner: Owner::parse(owner)?,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses a slot number.
+pub fn parse_slot(value: &[u8]) -> Option<u16> {
+    std::str::from_utf8(value)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|&slot: &u16| (slot as usize) < NB_SLOTS)
+}
+
+/// Where a request for a key must be executed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route {
+    Local,
+    Moved(u16, SocketAddrV4),
+    Ask(u16, SocketAddrV4),
+    /// Nobody owns the slot.
+    Down(u16),
+}
+
+pub struct Cluster {
+    /// The address the other nodes and the clients reach us at.
+    myself: SocketAddrV4,
+    slots: Vec<Option<Owner>>,
+    /// Slots we own being moved to another node.
+    migrating: HashMap<u16, SocketAddrV4>,
+    /// Slots being moved to us from another node.
+    importing: HashMap<u16, SocketAddrV4>,
+}
+
+impl Cluster {
+    pub fn new(myself: SocketAddrV4, ranges: &[SlotRange]) -> Self {
+        let mut cluster = Self {
+            myself,
+            slots: vec![None; NB_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        };
+
+        for range in ranges {
+            let owner = cluster.normalize(range.owner);
+            cluster.slots[range.start as usize..=range.end as usize].fill(Some(owner));
+        }
+
+        cluster
+    }
+
+    /// Our own address is the same as `myself`.
+    fn normalize(&self, owner: Owner) -> Owner {
+        match owner {
+            Owner::Node(addr) if addr == self.myself => Owner::Myself,
+            owner => owner,
+        }
+    }
+
+    pub fn address(&self, owner: Owner) -> SocketAddrV4 {
+        match owner {
+            Owner::Myself => self.myself,
+            Owner::Node(addr) => addr,
+        }
+    }
+
+    /// Returns the assigned slots, as ranges of contiguous slots with the same owner.
+    pub fn slot_ranges(&self) -> Vec<SlotRange> {
+        let mut ranges: Vec<SlotRange> = Vec::new();
+
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let owner = match owner {
+                Some(owner) => *owner,
+                None => continue,
+            };
+            let slot = slot as u16;
+
+            match ranges.last_mut() {
+                Some(range) if range.owner == owner && range.end + 1 == slot => range.end = slot,
+                _ => ranges.push(SlotRange {
+                    start: slot,
+                    end: slot,
+                    owner,
+                }),
+            }
+        }
+
+        ranges
+    }
+
+    /// Returns every node we know of, starting with ourselves.
+    pub fn nodes(&self) -> Vec<SocketAddrV4> {
+        let mut nodes = vec![self.myself];
+
+        let others = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|owner| self.address(*owner))
+            .chain(self.migrating.values().copied())
+            .chain(self.importing.values().copied());
+        for node in others {
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+
+        nodes
+    }
+
+    /// Returns the description of every node in the format of CLUSTER NODES, one per line.
+    pub fn describe_nodes(&self) -> String {
+        let ranges = self.slot_ranges();
+        let mut result = String::new();
+
+        for node in self.nodes() {
+            let flags = if node == self.myself {
+                "myself,master"
+            } else {
+                "master"
+            };
+            result.push_str(&format!(
+                "{} {} {} - 0 0 0 connected",
+                node_id(node),
+                node,
+                flags
+            ));
+
+            for range in ranges
+                .iter()
+                .filter(|range| self.address(range.owner) == node)
+            {
+                if range.start == range.end {
+                    result.push_str(&format!(" {}", range.start));
+                } else {
+                    result.push_str(&format!(" {}-{}", range.start, range.end));
+                }
+            }
+
+            // The slots being migrated are only known by ourselves
+            if node == self.myself {
+                let mut migrating: Vec<_> = self.migrating.iter().collect();
+                migrating.sort();
+                for (slot, target) in migrating {
+                    result.push_str(&format!(" [{}->-{}]", slot, node_id(*target)));
+                }
+
+                let mut importing: Vec<_> = self.importing.iter().collect();
+                importing.sort();
+                for (slot, source) in importing {
+                    result.push_str(&format!(" [{}-<-{}]", slot, node_id(*source)));
+                }
+            }
+
+            result.push('\n');
+        }
+
+        result
+    }
+
+    /// Returns the state of the cluster in the format of CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let assigned = self.slots.iter().flatten().count();
+
+        // Number of nodes serving at least one slot
+        let mut owners: Vec<SocketAddrV4> = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|owner| self.address(*owner))
+            .collect();
+        owners.sort();
+        owners.dedup();
+
+        let state = if assigned == NB_SLOTS { "ok" } else { "fail" };
+
+        format!(
+            "cluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\n",
+            state,
+            assigned,
+            self.nodes().len(),
+            owners.len(),
+        )
+    }
+
+    /// Returns where the request for a key in `slot` must be executed.
+    ///
+    /// `asking` is true if the client sent ASKING right before, `exists` returns true if the key is in our keyspace.
+    pub fn route<F: FnOnce() -> bool>(&self, slot: u16, asking: bool, exists: F) -> Route {
+        let importing = asking && self.importing.contains_key(&slot);
+
+        match self.slots[slot as usize] {
+            Some(Owner::Myself) => match self.migrating.get(&slot) {
+                // The keys already moved are only on the target
+                Some(target) if !exists() => Route::Ask(slot, *target),
+                _ => Route::Local,
+            },
+            _ if importing => Route::Local,
+            Some(Owner::Node(addr)) => Route::Moved(slot, addr),
+            None => Route::Down(slot),
+        }
+    }
+
+    /// Assign `slot` to `owner`, ending its migration if any.
+    pub fn set_owner(&mut self, slot: u16, owner: Owner) {
+        self.slots[slot as usize] = Some(self.normalize(owner));
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    /// Start moving `slot`, which we own, to `target`.
+    pub fn set_migrating(&mut self, slot: u16, target: SocketAddrV4) -> bool {
+        if self.slots[slot as usize] != Some(Owner::Myself) {
+            return false;
+        }
+
+        self.migrating.insert(slot, target);
+        true
+    }
+
+    /// Start accepting the keys of `slot`, owned by `source`, for the clients sending ASKING.
+    pub fn set_importing(&mut self, slot: u16, source: SocketAddrV4) -> bool {
+        if self.slots[slot as usize] == Some(Owner::Myself) {
+            return false;
+        }
+
+        self.importing.insert(slot, source);
+        true
+    }
+
+    /// Cancel the migration of `slot`, if any.
+    pub fn set_stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_id, parse_slot, parse_slot_ranges, Cluster, Owner, Route, SlotRange};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn slots() {
+        assert_eq!(Some(16383), parse_slot(b"16383"));
+        assert_eq!(None, parse_slot(b"16384"));
+        assert_eq!(None, parse_slot(b"-1"));
+    }
+
+    #[test]
+    fn slot_ranges() {
+        let node = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235);
+
+        assert_eq!(Some(vec![]), parse_slot_ranges(""));
+        assert_eq!(
+            Some(vec![
+                SlotRange {
+                    start: 0,
+                    end: 8191,
+                    owner: Owner::Myself,
+                },
+                SlotRange {
+                    start: 8192,
+                    end: 8192,
+                    owner: Owner::Node(node),
+                },
+            ]),
+            parse_slot_ranges("0-8191 myself 8192 127.0.0.1:1235")
+        );
+
+        assert_eq!(None, parse_slot_ranges("0-8191"));
+        assert_eq!(None, parse_slot_ranges("0-16384 myself"));
+        assert_eq!(None, parse_slot_ranges("10-1 myself"));
+        assert_eq!(None, parse_slot_ranges("0-10 localhost:1235"));
+    }
+
+    #[test]
+    fn route() {
+        let node = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235);
+
+        let myself = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234);
+        let mut cluster = Cluster::new(
+            myself,
+            &parse_slot_ranges("0-99 myself 100-199 127.0.0.1:1235").unwrap(),
+        );
+
+        assert_eq!(Route::Local, cluster.route(0, false, || true));
+        assert_eq!(Route::Moved(100, node), cluster.route(100, false, || true));
+        assert_eq!(Route::Down(200), cluster.route(200, false, || true));
+
+        // Migrating slot 1 to the other node
+        assert!(cluster.set_migrating(1, node));
+        assert!(!cluster.set_migrating(100, node));
+        assert_eq!(Route::Local, cluster.route(1, false, || true));
+        assert_eq!(Route::Ask(1, node), cluster.route(1, false, || false));
+
+        // Importing slot 100 from the other node
+        assert!(cluster.set_importing(100, node));
+        assert!(!cluster.set_importing(0, node));
+        assert_eq!(Route::Moved(100, node), cluster.route(100, false, || false));
+        assert_eq!(Route::Local, cluster.route(100, true, || false));
+
+        cluster.set_owner(100, Owner::Myself);
+        assert_eq!(Route::Local, cluster.route(100, false, || false));
+
+        cluster.set_stable(1);
+        assert_eq!(Route::Local, cluster.route(1, false, || false));
+    }
+
+    #[test]
+    fn topology() {
+        let myself = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234);
+        let node = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235);
+
+        assert_eq!(16, node_id(myself).len());
+        assert_ne!(node_id(myself), node_id(node));
+
+        // Our own address is the same as myself
+        let mut cluster = Cluster::new(
+            myself,
+            &parse_slot_ranges("0-99 127.0.0.1:1234 100-199 127.0.0.1:1235 200-299 myself")
+                .unwrap(),
+        );
+        assert_eq!(Route::Local, cluster.route(0, false, || false));
+
+        assert_eq!(
+            vec![
+                SlotRange {
+                    start: 0,
+                    end: 99,
+                    owner: Owner::Myself
+                },
+                SlotRange {
+                    start: 100,
+                    end: 199,
+                    owner: Owner::Node(node)
+                },
+                SlotRange {
+                    start: 200,
+                    end: 299,
+                    owner: Owner::Myself
+                },
+            ],
+            cluster.slot_ranges()
+        );
+        assert_eq!(vec![myself, node], cluster.nodes());
+        assert_eq!(
+            "cluster_state:fail\r\ncluster_slots_assigned:300\r\ncluster_known_nodes:2\r\ncluster_size:2\r\n",
+            cluster.info()
+        );
+
+        cluster.set_owner(150, Owner::Node(myself));
+        assert_eq!(5, cluster.slot_ranges().len());
+        assert_eq!(Route::Local, cluster.route(150, false, || false));
+
+        cluster.set_migrating(0, node);
+        assert_eq!(
+            format!(
+                "{} 127.0.0.1:1234 myself,master - 0 0 0 connected 0-99 150 200-299 [0->-{}]\n\
+                 {} 127.0.0.1:1235 master - 0 0 0 connected 100-149 151-199\n",
+                node_id(myself),
+                node_id(node),
+                node_id(node)
+            ),
+            cluster.describe_nodes()
+        );
+
+        let cluster = Cluster::new(myself, &parse_slot_ranges("0-16383 myself").unwrap());
+        assert!(cluster.info().starts_with("cluster_state:ok\r\n"));
+    }
+}
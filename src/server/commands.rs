@@ -0,0 +1,161 @@
+//! Registry of the commands executed by `do_request`, so that a command can be added without touching it.
+//!
+//! A command has an arity like in Redis, counting its name: a positive arity is the exact number of arguments, a
+//! negative one is the minimum. GET has an arity of 2, SET of -3.
+
+use crate::protocol;
+use onlyerror::Error;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("command {0} is already registered")]
+    AlreadyRegistered(String),
+    #[error("invalid arity {0}")]
+    InvalidArity(i32),
+}
+
+/// Executes a command with its arguments, without its name, and returns the number of keys it changed.
+pub type Handler<C> = Arc<dyn Fn(&C, &[&[u8]], &mut protocol::Writer) -> u64 + Send + Sync>;
+
+struct Command<C> {
+    arity: i32,
+    handler: Handler<C>,
+}
+
+impl<C> Command<C> {
+    fn accepts(&self, nb_args: usize) -> bool {
+        let nb_args = nb_args as i64 + 1;
+        let arity = self.arity as i64;
+
+        if arity > 0 {
+            nb_args == arity
+        } else {
+            nb_args >= -arity
+        }
+    }
+}
+
+pub struct Commands<C> {
+    commands: RwLock<HashMap<Vec<u8>, Command<C>>>,
+}
+
+impl<C> Commands<C> {
+    pub fn new() -> Self {
+        Self {
+            commands: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(
+        &self,
+        name: &str,
+        arity: i32,
+        handler: Handler<C>,
+    ) -> Result<(), RegisterError> {
+        if arity == 0 {
+            return Err(RegisterError::InvalidArity(arity));
+        }
+
+        let mut commands = self.commands.write().unwrap();
+        if commands.contains_key(name.as_bytes()) {
+            return Err(RegisterError::AlreadyRegistered(name.to_string()));
+        }
+
+        commands.insert(name.as_bytes().to_vec(), Command { arity, handler });
+
+        Ok(())
+    }
+
+    /// Returns true if `request` is a registered command with the right number of arguments.
+    pub fn accepts(&self, request: &[&[u8]]) -> bool {
+        let (cmd, args) = match request.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+
+        let commands = self.commands.read().unwrap();
+        commands
+            .get(*cmd)
+            .is_some_and(|command| command.accepts(args.len()))
+    }
+
+    /// Execute `cmd` if it's registered and `args` matches its arity, returning the number of keys it changed.
+    pub fn call(
+        &self,
+        context: &C,
+        cmd: &[u8],
+        args: &[&[u8]],
+        writer: &mut protocol::Writer,
+    ) -> Option<u64> {
+        // The lock isn't held while the command runs, it could take a while
+        let handler = {
+            let commands = self.commands.read().unwrap();
+            let command = commands.get(cmd)?;
+            if !command.accepts(args.len()) {
+                return None;
+            }
+
+            Arc::clone(&command.handler)
+        };
+
+        Some(handler(context, args, writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Commands, RegisterError};
+    use crate::protocol::{self, BUF_LEN};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn register_call() {
+        let commands = Commands::<AtomicU64>::new();
+
+        commands
+            .register(
+                "incrby",
+                -2,
+                Arc::new(|counter, args, writer| {
+                    let by = args.len() as u64;
+                    writer.push_int((counter.fetch_add(by, Ordering::Relaxed) + by) as usize);
+                    1
+                }),
+            )
+            .unwrap();
+        commands.register("noop", 1, Arc::new(|_, _, _| 0)).unwrap();
+
+        assert_eq!(
+            Err(RegisterError::AlreadyRegistered("noop".to_string())),
+            commands.register("noop", 1, Arc::new(|_, _, _| 0))
+        );
+        assert_eq!(
+            Err(RegisterError::InvalidArity(0)),
+            commands.register("zero", 0, Arc::new(|_, _, _| 0))
+        );
+
+        assert!(commands.accepts(&[b"incrby", b"a"]));
+        assert!(commands.accepts(&[b"incrby", b"a", b"b"]));
+        assert!(!commands.accepts(&[b"incrby"]));
+        assert!(commands.accepts(&[b"noop"]));
+        assert!(!commands.accepts(&[b"noop", b"a"]));
+        assert!(!commands.accepts(&[b"get", b"a"]));
+        assert!(!commands.accepts(&[]));
+
+        let counter = AtomicU64::new(10);
+        let mut buf = [0; BUF_LEN];
+        let mut writer = protocol::Writer::new(&mut buf);
+
+        assert_eq!(None, commands.call(&counter, b"incrby", &[], &mut writer));
+        assert_eq!(None, commands.call(&counter, b"unknown", &[], &mut writer));
+        assert_eq!(
+            Some(1),
+            commands.call(&counter, b"incrby", &[b"a", b"b"], &mut writer)
+        );
+        assert_eq!(12, counter.load(Ordering::Relaxed));
+        assert_eq!(9, writer.written() - 4);
+    }
+}
@@ -0,0 +1,1325 @@
+use crate::log;
+use crate::protocol::BUF_LEN;
+use crate::server::cluster::{self, SlotRange};
+use crate::server::glob;
+use onlyerror::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("unknown flag {0}")]
+    UnknownFlag(String),
+    #[error("missing value for flag {0}")]
+    MissingValue(String),
+    #[error("invalid value {value:?} for flag {flag}")]
+    InvalidValue { flag: String, value: String },
+    #[error("unknown parameter {0}")]
+    UnknownParameter(String),
+    #[error("parameter {0} is read-only")]
+    ReadOnlyParameter(String),
+    #[error("invalid value {value:?} for parameter {name}")]
+    InvalidParameterValue { name: String, value: String },
+    #[error("flag {0} can't be used with another mode")]
+    ConflictingMode(String),
+}
+
+/// What the server does once the data saved by a previous run is loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Accept connections and serve requests.
+    Serve,
+    /// Write the keyspace to a newline-delimited JSON file and exit.
+    ExportJson(PathBuf),
+    /// Read a newline-delimited JSON file in the keyspace, save it and exit.
+    ImportJson(PathBuf),
+}
+
+/// A parameter exposed through the CONFIG command.
+pub struct Parameter {
+    pub name: &'static str,
+    get: fn(&ServerConfig) -> String,
+    /// `None` if the parameter can't be changed at runtime.
+    set: Option<fn(&mut ServerConfig, &str) -> Option<()>>,
+}
+
+fn secs_to_timeout(secs: u64) -> Option<Duration> {
+    if secs > 0 {
+        Some(Duration::from_secs(secs))
+    } else {
+        None
+    }
+}
+
+/// Parse a number of bytes with an optional `kb`, `mb` or `gb` unit.
+fn parse_bytes(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value.as_str(), ""),
+    };
+
+    let unit = match unit {
+        "" | "b" => 1,
+        "kb" => 1024,
+        "mb" => 1024 * 1024,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses an IPv4 address, or resolves a host name to its first IPv4 address since the server only speaks IPv4.
+fn parse_host(value: &str) -> Option<Ipv4Addr> {
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+
+    crate::resolve(value, 0)
+        .ok()?
+        .iter()
+        .find_map(|addr| match addr.as_socket_addr() {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        })
+}
+
+/// Parses the primary to replicate from: `<host> <port>`, or `no one` to not replicate.
+///
+/// NOTE(vincent): a host name is resolved right away, and only once.
+pub fn parse_replica_of(value: &str) -> Option<Option<SocketAddrV4>> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+
+    match parts.as_slice() {
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Some(None),
+        [host, port] => Some(Some(SocketAddrV4::new(
+            parse_host(host)?,
+            port.parse().ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+/// Parses a list of `<host>:<port>` addresses separated by spaces.
+fn parse_addresses(value: &str) -> Option<Vec<SocketAddrV4>> {
+    value
+        .split_whitespace()
+        .map(|addr| {
+            let (host, port) = addr.rsplit_once(':')?;
+            Some(SocketAddrV4::new(parse_host(host)?, port.parse().ok()?))
+        })
+        .collect()
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/// Save a snapshot if there were at least `changes` changes since the last save and it was more than `after` ago.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SaveRule {
+    pub after: Duration,
+    pub changes: u64,
+}
+
+/// Parse a list of save rules: pairs of seconds and number of changes, like "3600 1 300 100".
+fn parse_save_rules(value: &str) -> Option<Vec<SaveRule>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+
+    numbers
+        .chunks(2)
+        .map(|pair| match pair {
+            [secs, changes] if *secs > 0 && *changes > 0 => Some(SaveRule {
+                after: Duration::from_secs(*secs),
+                changes: *changes,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// What to do when a write needs more memory than allowed by `max_memory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse the write.
+    NoEviction,
+    /// Evict the least recently used keys.
+    AllKeysLru,
+}
+
+impl EvictionPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "noeviction" => Some(Self::NoEviction),
+            "allkeys-lru" => Some(Self::AllKeysLru),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+        }
+    }
+}
+
+pub const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "bind",
+        get: |config| config.bind.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "port",
+        get: |config| config.port.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "tcp-backlog",
+        get: |config| config.backlog.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "maxclients",
+        get: |config| config.max_clients.to_string(),
+        set: Some(|config, value| {
+            config.max_clients = value.parse().ok()?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "client-buffer-limit",
+        get: |config| config.client_buffer_limit.to_string(),
+        set: Some(|config, value| {
+            config.client_buffer_limit = value.parse().ok().filter(|&limit| limit >= BUF_LEN)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "maxmemory",
+        get: |config| config.max_memory.to_string(),
+        set: Some(|config, value| {
+            config.max_memory = parse_bytes(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        get: |config| config.max_memory_policy.name().to_string(),
+        set: Some(|config, value| {
+            config.max_memory_policy = EvictionPolicy::parse(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "snapshot-path",
+        get: |config| config.snapshot_path.display().to_string(),
+        // NOTE(vincent): any client could make the next save write to a file of its choice.
+        set: None,
+    },
+    Parameter {
+        name: "save",
+        get: |config| {
+            let rules: Vec<String> = config
+                .save_rules
+                .iter()
+                .map(|rule| format!("{} {}", rule.after.as_secs(), rule.changes))
+                .collect();
+            rules.join(" ")
+        },
+        set: Some(|config, value| {
+            config.save_rules = parse_save_rules(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "appendonly",
+        get: |config| yes_no(config.appendonly),
+        set: None,
+    },
+    Parameter {
+        name: "aof-path",
+        get: |config| config.aof_path.display().to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "aof-load-truncated",
+        get: |config| yes_no(config.aof_load_truncated),
+        set: Some(|config, value| {
+            config.aof_load_truncated = parse_yes_no(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "requirepass",
+        get: |config| config.requirepass.clone().unwrap_or_default(),
+        set: Some(|config, value| {
+            config.requirepass = Some(value.to_string()).filter(|password| !password.is_empty());
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "masterauth",
+        get: |config| config.masterauth.clone().unwrap_or_default(),
+        set: Some(|config, value| {
+            config.masterauth = Some(value.to_string()).filter(|password| !password.is_empty());
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "replicaof",
+        get: |config| match config.replica_of {
+            Some(addr) => format!("{} {}", addr.ip(), addr.port()),
+            None => String::new(),
+        },
+        // Changed with the REPLICAOF command
+        set: None,
+    },
+    Parameter {
+        name: "replica-read-only",
+        get: |config| yes_no(config.replica_read_only),
+        set: Some(|config, value| {
+            config.replica_read_only = parse_yes_no(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "cluster-enabled",
+        get: |config| yes_no(config.cluster_enabled),
+        set: None,
+    },
+    Parameter {
+        name: "announce-ip",
+        get: |config| {
+            config
+                .announce_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_default()
+        },
+        set: None,
+    },
+    Parameter {
+        name: "cluster-slots",
+        get: |config| {
+            let ranges: Vec<String> = config
+                .cluster_slots
+                .iter()
+                .map(|range| format!("{}-{} {}", range.start, range.end, range.owner))
+                .collect();
+            ranges.join(" ")
+        },
+        // Changed with CLUSTER SETSLOT
+        set: None,
+    },
+    Parameter {
+        name: "failover-peers",
+        get: |config| {
+            let peers: Vec<String> = config
+                .failover_peers
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect();
+            peers.join(" ")
+        },
+        set: None,
+    },
+    Parameter {
+        name: "failover-down-after",
+        get: |config| config.failover_down_after.as_millis().to_string(),
+        set: Some(|config, value| {
+            let millis = value.parse().ok().filter(|&millis| millis > 0)?;
+            config.failover_down_after = Duration::from_millis(millis);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "threads",
+        get: |config| config.threads.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "latency-monitor-threshold",
+        get: |config| config.latency_monitor_threshold.as_millis().to_string(),
+        set: Some(|config, value| {
+            config.latency_monitor_threshold = Duration::from_millis(value.parse().ok()?);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "log-level",
+        get: |config| config.log_level.name().to_string(),
+        set: Some(|config, value| {
+            config.log_level = log::Level::parse(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "log-target",
+        get: |config| config.log_target.name(),
+        set: None,
+    },
+    Parameter {
+        name: "loadmodule",
+        get: |config| {
+            let paths: Vec<String> = config
+                .load_modules
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            paths.join(" ")
+        },
+        set: None,
+    },
+    Parameter {
+        name: "audit-log",
+        get: |config| {
+            config
+                .audit_log
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        },
+        set: None,
+    },
+    Parameter {
+        name: "admin-port",
+        get: |config| {
+            config
+                .admin_port
+                .map(|port| port.to_string())
+                .unwrap_or_default()
+        },
+        set: None,
+    },
+    Parameter {
+        name: "debug-hexdump",
+        get: |config| yes_no(config.debug_hexdump),
+        set: Some(|config, value| {
+            config.debug_hexdump = parse_yes_no(value)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "otlp-endpoint",
+        get: |config| {
+            config
+                .otlp_endpoint
+                .map(|addr| addr.to_string())
+                .unwrap_or_default()
+        },
+        set: None,
+    },
+    Parameter {
+        name: "timeout",
+        get: |config| {
+            let secs = config.idle_timeout.map(|t| t.as_secs()).unwrap_or(0);
+            secs.to_string()
+        },
+        set: Some(|config, value| {
+            config.idle_timeout = secs_to_timeout(value.parse().ok()?);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "read-timeout",
+        get: |config| {
+            let secs = config.read_timeout.map(|t| t.as_secs()).unwrap_or(0);
+            secs.to_string()
+        },
+        set: Some(|config, value| {
+            config.read_timeout = secs_to_timeout(value.parse().ok()?);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "write-timeout",
+        get: |config| {
+            let secs = config.write_timeout.map(|t| t.as_secs()).unwrap_or(0);
+            secs.to_string()
+        },
+        set: Some(|config, value| {
+            config.write_timeout = secs_to_timeout(value.parse().ok()?);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "tcp-keepalive",
+        get: |config| {
+            let secs = config.tcp_keepalive.map(|t| t.as_secs()).unwrap_or(0);
+            secs.to_string()
+        },
+        set: Some(|config, value| {
+            config.tcp_keepalive = secs_to_timeout(value.parse().ok()?);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "tcp-keepalive-interval",
+        get: |config| config.tcp_keepalive_interval.as_secs().to_string(),
+        set: Some(|config, value| {
+            let secs: u64 = value.parse().ok().filter(|&secs| secs > 0)?;
+            config.tcp_keepalive_interval = Duration::from_secs(secs);
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "tcp-keepalive-count",
+        get: |config| config.tcp_keepalive_count.to_string(),
+        set: Some(|config, value| {
+            config.tcp_keepalive_count = value.parse().ok().filter(|&count| count > 0)?;
+            Some(())
+        }),
+    },
+    Parameter {
+        name: "tcp-nodelay",
+        get: |config| yes_no(config.tcp_nodelay),
+        set: Some(|config, value| {
+            config.tcp_nodelay = parse_yes_no(value)?;
+            Some(())
+        }),
+    },
+];
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub mode: Mode,
+    pub bind: Ipv4Addr,
+    pub port: u16,
+    /// Maximum length of the queue of pending connections, see `listen(2)`.
+    pub backlog: i32,
+    /// Maximum number of connected clients, new connections are rejected once it's reached.
+    pub max_clients: usize,
+    /// Maximum size in bytes of the read and write buffers of a connection, it's closed if one grows larger.
+    pub client_buffer_limit: usize,
+    /// Maximum memory used by the keyspace in bytes, writes past this trigger `max_memory_policy`. Disabled if 0.
+    pub max_memory: usize,
+    pub max_memory_policy: EvictionPolicy,
+    /// Where BGSAVE writes the snapshots.
+    pub snapshot_path: PathBuf,
+    /// Save a snapshot in the background as soon as one of the rules is met. Disabled if empty.
+    pub save_rules: Vec<SaveRule>,
+    /// Log every write to the append only file and replay it at startup instead of loading the snapshot.
+    pub appendonly: bool,
+    pub aof_path: PathBuf,
+    /// Recover from an incomplete last request in the append only file instead of refusing to start.
+    pub aof_load_truncated: bool,
+    /// Password the clients must send with AUTH before any other command. Disabled if `None`.
+    pub requirepass: Option<String>,
+    /// Password sent to the primary before synchronizing, if it requires one.
+    pub masterauth: Option<String>,
+    /// The primary this server replicates from. Not a replica if `None`.
+    pub replica_of: Option<SocketAddrV4>,
+    /// Refuse the writes of the clients while replicating, they would be lost on the next synchronization.
+    pub replica_read_only: bool,
+    /// Only serve the keys of the slots we own and redirect the clients to the owners of the others.
+    pub cluster_enabled: bool,
+    /// The IP address the other nodes and the clients reach us at, in cluster mode and for the failover.
+    /// Defaults to the bind address if it's not the unspecified address, localhost otherwise.
+    pub announce_ip: Option<Ipv4Addr>,
+    /// The owners of the slots at startup, in cluster mode.
+    pub cluster_slots: Vec<SlotRange>,
+    /// The other servers monitoring the primary with us: its replicas and the primary itself.
+    /// Replicas elect a new primary among them when it's down. Disabled if empty.
+    pub failover_peers: Vec<SocketAddrV4>,
+    /// How long the primary must be unreachable before the replicas start an election.
+    pub failover_down_after: Duration,
+    /// Number of event loop threads.
+    /// With more than one thread each one owns a slice of the keyspace and its own listening socket.
+    pub threads: usize,
+    /// The commands taking at least this long are kept in the history of LATENCY HISTORY. Disabled if zero.
+    pub latency_monitor_threshold: Duration,
+    /// Events less severe than this are not logged, see [`log::Level`].
+    pub log_level: log::Level,
+    pub log_target: log::Target,
+    /// The file the administrative commands and the authentication attempts are appended to. Disabled if `None`.
+    pub audit_log: Option<PathBuf>,
+    /// Port of a second listener bound to localhost which only accepts PING, INFO and SHUTDOWN, and isn't subject to
+    /// `max_clients`. Disabled if `None`.
+    pub admin_port: Option<u16>,
+    /// Log every request and response frame as a hexdump, to debug the protocol.
+    pub debug_hexdump: bool,
+    /// The OpenTelemetry collector receiving a span per command, over OTLP/HTTP. Disabled if `None`.
+    pub otlp_endpoint: Option<SocketAddrV4>,
+    /// Close connections without any activity for this long. Disabled if `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Close connections taking longer than this to send a complete request. Disabled if `None`.
+    pub read_timeout: Option<Duration>,
+    /// Close connections taking longer than this to read a response. Disabled if `None`.
+    pub write_timeout: Option<Duration>,
+    /// Send TCP keepalive probes on connections without traffic for this long. Disabled if `None`.
+    pub tcp_keepalive: Option<Duration>,
+    /// Interval between two keepalive probes.
+    pub tcp_keepalive_interval: Duration,
+    /// Number of unanswered keepalive probes before the connection is dropped.
+    pub tcp_keepalive_count: u32,
+    /// Disable Nagle's algorithm on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Shared objects loaded at startup, registering their commands, see [`crate::module`].
+    pub load_modules: Vec<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Serve,
+            bind: Ipv4Addr::UNSPECIFIED,
+            port: 1234,
+            backlog: libc::SOMAXCONN,
+            max_clients: 10000,
+            client_buffer_limit: 1024 * 1024,
+            max_memory: 0,
+            max_memory_policy: EvictionPolicy::NoEviction,
+            snapshot_path: PathBuf::from("dump.snap"),
+            save_rules: Vec::new(),
+            appendonly: false,
+            aof_path: PathBuf::from("appendonly.aof"),
+            aof_load_truncated: true,
+            requirepass: None,
+            masterauth: None,
+            replica_of: None,
+            replica_read_only: true,
+            cluster_enabled: false,
+            announce_ip: None,
+            cluster_slots: Vec::new(),
+            failover_peers: Vec::new(),
+            failover_down_after: Duration::from_secs(5),
+            threads: 1,
+            latency_monitor_threshold: Duration::ZERO,
+            log_level: log::Level::Info,
+            log_target: log::Target::Stderr,
+            audit_log: None,
+            admin_port: None,
+            debug_hexdump: false,
+            otlp_endpoint: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            tcp_keepalive: Some(Duration::from_secs(300)),
+            tcp_keepalive_interval: Duration::from_secs(100),
+            tcp_keepalive_count: 3,
+            tcp_nodelay: true,
+            load_modules: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Start building a config from the default values, for the programs starting a server without a command line.
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: Self::default(),
+        }
+    }
+
+    /// The address the other nodes and the clients reach us at, in cluster mode and for the failover.
+    pub fn announce_address(&self) -> SocketAddrV4 {
+        let ip = match self.announce_ip {
+            Some(ip) => ip,
+            None if self.bind.is_unspecified() => Ipv4Addr::LOCALHOST,
+            None => self.bind,
+        };
+
+        SocketAddrV4::new(ip, self.port)
+    }
+
+    /// Build a config from the command line arguments, without the binary name.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--bind" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.bind = match parse_host(&value) {
+                        Some(bind) => bind,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--port" => config.port = parse_value(&flag, args.next())?,
+                "--backlog" => config.backlog = parse_value(&flag, args.next())?,
+                "--maxclients" => config.max_clients = parse_value(&flag, args.next())?,
+                "--threads" => {
+                    config.threads = parse_value(&flag, args.next())?;
+                    if config.threads == 0 {
+                        return Err(ConfigError::InvalidValue {
+                            flag,
+                            value: "0".to_string(),
+                        });
+                    }
+                }
+                "--appendonly" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.appendonly = match parse_yes_no(&value) {
+                        Some(appendonly) => appendonly,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--aof-path" => config.aof_path = parse_value(&flag, args.next())?,
+                "--snapshot-path" => config.snapshot_path = parse_value(&flag, args.next())?,
+                "--replicaof" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.replica_of = match parse_replica_of(&value) {
+                        Some(replica_of) => replica_of,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--cluster-enabled" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.cluster_enabled = match parse_yes_no(&value) {
+                        Some(enabled) => enabled,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--announce-ip" => {
+                    config.announce_ip = Some(parse_value(&flag, args.next())?);
+                }
+                "--cluster-slots" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.cluster_slots = match cluster::parse_slot_ranges(&value) {
+                        Some(ranges) => ranges,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--failover-peers" => {
+                    let value: String = parse_value(&flag, args.next())?;
+                    config.failover_peers = match parse_addresses(&value) {
+                        Some(peers) => peers,
+                        None => return Err(ConfigError::InvalidValue { flag, value }),
+                    };
+                }
+                "--export-json" | "--import-json" => {
+                    if config.mode != Mode::Serve {
+                        return Err(ConfigError::ConflictingMode(flag));
+                    }
+
+                    let path = parse_value(&flag, args.next())?;
+                    config.mode = if flag == "--export-json" {
+                        Mode::ExportJson(path)
+                    } else {
+                        Mode::ImportJson(path)
+                    };
+                }
+                "--log-target" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+
+                    config.log_target = log::Target::parse(&value)
+                        .ok_or(ConfigError::InvalidValue { flag, value })?;
+                }
+                "--audit-log" => {
+                    config.audit_log = Some(parse_value(&flag, args.next())?);
+                }
+                "--loadmodule" => {
+                    config.load_modules.push(parse_value(&flag, args.next())?);
+                }
+                "--admin-port" => {
+                    config.admin_port = Some(parse_value(&flag, args.next())?);
+                }
+                "--otlp-endpoint" => {
+                    config.otlp_endpoint = Some(parse_value(&flag, args.next())?);
+                }
+                "--idle-timeout" => {
+                    config.idle_timeout = secs_to_timeout(parse_value(&flag, args.next())?);
+                }
+                "--client-buffer-limit"
+                | "--maxmemory"
+                | "--maxmemory-policy"
+                | "--save"
+                | "--aof-load-truncated"
+                | "--requirepass"
+                | "--masterauth"
+                | "--replica-read-only"
+                | "--failover-down-after"
+                | "--latency-monitor-threshold"
+                | "--log-level"
+                | "--debug-hexdump"
+                | "--read-timeout"
+                | "--write-timeout"
+                | "--tcp-keepalive"
+                | "--tcp-keepalive-interval"
+                | "--tcp-keepalive-count"
+                | "--tcp-nodelay" => {
+                    let name = flag.trim_start_matches("--");
+                    let value = args
+                        .next()
+                        .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+
+                    config
+                        .set_parameter(name, &value)
+                        .map_err(|_| ConfigError::InvalidValue {
+                            flag: flag.clone(),
+                            value,
+                        })?;
+                }
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the parameters whose name matches the glob-style `pattern`, with their current value.
+    pub fn get_parameters(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|parameter| glob::matches(pattern.as_bytes(), parameter.name.as_bytes()))
+            .map(|parameter| (parameter.name, (parameter.get)(self)))
+            .collect()
+    }
+
+    /// Change the value of the parameter `name`, validating it first.
+    pub fn set_parameter(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let parameter = PARAMETERS
+            .iter()
+            .find(|parameter| parameter.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| ConfigError::UnknownParameter(name.to_string()))?;
+
+        let set = parameter
+            .set
+            .ok_or_else(|| ConfigError::ReadOnlyParameter(parameter.name.to_string()))?;
+
+        set(self, value).ok_or_else(|| ConfigError::InvalidParameterValue {
+            name: parameter.name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Builds a [`ServerConfig`], validating the values like the command line does.
+///
+/// The parameters without a method here are set on the built config directly.
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn addr(mut self, addr: SocketAddrV4) -> Self {
+        self.config.bind = *addr.ip();
+        self.config.port = addr.port();
+        self
+    }
+
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.config.backlog = backlog;
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    /// Must be at least [`BUF_LEN`].
+    pub fn client_buffer_limit(mut self, limit: usize) -> Self {
+        self.config.client_buffer_limit = limit;
+        self
+    }
+
+    pub fn max_memory(mut self, max_memory: usize, policy: EvictionPolicy) -> Self {
+        self.config.max_memory = max_memory;
+        self.config.max_memory_policy = policy;
+        self
+    }
+
+    pub fn snapshot_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.snapshot_path = path.into();
+        self
+    }
+
+    pub fn save_rules(mut self, rules: Vec<SaveRule>) -> Self {
+        self.config.save_rules = rules;
+        self
+    }
+
+    /// Enable the append only file at `path`.
+    pub fn appendonly(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.appendonly = true;
+        self.config.aof_path = path.into();
+        self
+    }
+
+    pub fn requirepass(mut self, password: &str) -> Self {
+        self.config.requirepass = Some(password.to_string());
+        self
+    }
+
+    /// Must be at least 1.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    pub fn admin_port(mut self, port: u16) -> Self {
+        self.config.admin_port = Some(port);
+        self
+    }
+
+    pub fn log_level(mut self, level: log::Level) -> Self {
+        self.config.log_level = level;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        let config = self.config;
+
+        if config.threads == 0 {
+            return Err(ConfigError::InvalidParameterValue {
+                name: "threads".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        if config.client_buffer_limit < BUF_LEN {
+            return Err(ConfigError::InvalidParameterValue {
+                name: "client-buffer-limit".to_string(),
+                value: config.client_buffer_limit.to_string(),
+            });
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(flag.to_string()))?;
+
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        flag: flag.to_string(),
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_bytes, parse_replica_of, parse_save_rules, ConfigError, EvictionPolicy, Mode,
+        SaveRule, ServerConfig,
+    };
+    use crate::log;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        ServerConfig::from_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn from_args() {
+        assert_eq!(1, parse(&[]).unwrap().threads);
+        assert_eq!(4, parse(&["--threads", "4"]).unwrap().threads);
+        assert_eq!(None, parse(&[]).unwrap().idle_timeout);
+        assert_eq!(None, parse(&["--idle-timeout", "0"]).unwrap().idle_timeout);
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            parse(&["--idle-timeout", "30"]).unwrap().idle_timeout
+        );
+
+        let config = parse(&["--bind", "127.0.0.1", "--port", "6379", "--backlog", "16"]).unwrap();
+        assert_eq!(Ipv4Addr::LOCALHOST, config.bind);
+        assert_eq!(6379, config.port);
+        assert_eq!(16, config.backlog);
+
+        let config = parse(&["--tcp-keepalive", "0", "--tcp-nodelay", "no"]).unwrap();
+        assert_eq!(None, config.tcp_keepalive);
+        assert!(!config.tcp_nodelay);
+
+        assert_eq!(
+            65536,
+            parse(&["--client-buffer-limit", "65536"])
+                .unwrap()
+                .client_buffer_limit
+        );
+        assert!(matches!(
+            parse(&["--client-buffer-limit", "1024"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        let config = parse(&["--maxmemory", "100mb", "--maxmemory-policy", "allkeys-lru"]).unwrap();
+        assert_eq!(100 * 1024 * 1024, config.max_memory);
+        assert_eq!(EvictionPolicy::AllKeysLru, config.max_memory_policy);
+
+        assert_eq!(
+            PathBuf::from("/tmp/foo.snap"),
+            parse(&["--snapshot-path", "/tmp/foo.snap"])
+                .unwrap()
+                .snapshot_path
+        );
+
+        assert_eq!(Mode::Serve, parse(&[]).unwrap().mode);
+        assert_eq!(
+            Mode::ExportJson(PathBuf::from("dump.json")),
+            parse(&["--export-json", "dump.json"]).unwrap().mode
+        );
+        assert!(matches!(
+            parse(&["--export-json", "dump.json", "--import-json", "dump.json"]),
+            Err(ConfigError::ConflictingMode(_))
+        ));
+
+        let config = parse(&["--appendonly", "yes", "--aof-load-truncated", "no"]).unwrap();
+        assert!(config.appendonly);
+        assert!(!config.aof_load_truncated);
+        assert!(matches!(
+            parse(&["--appendonly", "true"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        assert_eq!(
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6379)),
+            parse(&["--replicaof", "127.0.0.1 6379"])
+                .unwrap()
+                .replica_of
+        );
+        assert_eq!(None, parse(&["--replicaof", "no one"]).unwrap().replica_of);
+        assert!(matches!(
+            parse(&["--replicaof", "127.0.0.1"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(parse(&[]).unwrap().replica_read_only);
+
+        let config = parse(&[
+            "--cluster-enabled",
+            "yes",
+            "--cluster-slots",
+            "0-100 myself 101-16383 127.0.0.1:1235",
+        ])
+        .unwrap();
+        assert!(config.cluster_enabled);
+        assert_eq!(2, config.cluster_slots.len());
+        assert_eq!(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234),
+            config.announce_address()
+        );
+        assert_eq!(
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234),
+            parse(&["--bind", "10.0.0.1"]).unwrap().announce_address()
+        );
+        assert_eq!(
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234),
+            parse(&["--bind", "10.0.0.1", "--announce-ip", "10.0.0.2"])
+                .unwrap()
+                .announce_address()
+        );
+        assert_eq!(
+            vec![(
+                "cluster-slots",
+                "0-100 myself 101-16383 127.0.0.1:1235".to_string()
+            )],
+            config.get_parameters("cluster-slots")
+        );
+        assert!(matches!(
+            parse(&["--cluster-slots", "0-100"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        let config = parse(&[
+            "--failover-peers",
+            "localhost:1235 10.0.0.1:1234",
+            "--failover-down-after",
+            "2000",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235),
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234)
+            ],
+            config.failover_peers
+        );
+        assert_eq!(Duration::from_secs(2), config.failover_down_after);
+        assert_eq!(
+            Duration::from_secs(5),
+            parse(&[]).unwrap().failover_down_after
+        );
+        assert!(matches!(
+            parse(&["--failover-peers", "127.0.0.1"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--failover-down-after", "0"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        let config = parse(&["--requirepass", "foo", "--masterauth", "bar"]).unwrap();
+        assert_eq!(Some("foo".to_string()), config.requirepass);
+        assert_eq!(Some("bar".to_string()), config.masterauth);
+        assert_eq!(None, parse(&["--requirepass", ""]).unwrap().requirepass);
+        assert!(
+            !parse(&["--replica-read-only", "no"])
+                .unwrap()
+                .replica_read_only
+        );
+
+        assert_eq!(
+            Duration::from_millis(100),
+            parse(&["--latency-monitor-threshold", "100"])
+                .unwrap()
+                .latency_monitor_threshold
+        );
+
+        let config = parse(&["--log-level", "debug", "--log-target", "/tmp/server.log"]).unwrap();
+        assert_eq!(log::Level::Debug, config.log_level);
+        assert_eq!(
+            log::Target::File(PathBuf::from("/tmp/server.log")),
+            config.log_target
+        );
+        assert_eq!(log::Level::Info, parse(&[]).unwrap().log_level);
+
+        assert_eq!(
+            Some("127.0.0.1:4318".parse().unwrap()),
+            parse(&["--otlp-endpoint", "127.0.0.1:4318"])
+                .unwrap()
+                .otlp_endpoint
+        );
+        assert_eq!(
+            Some(PathBuf::from("audit.log")),
+            parse(&["--audit-log", "audit.log"]).unwrap().audit_log
+        );
+        assert_eq!(
+            vec![PathBuf::from("a.so"), PathBuf::from("b.so")],
+            parse(&["--loadmodule", "a.so", "--loadmodule", "b.so"])
+                .unwrap()
+                .load_modules
+        );
+        assert_eq!(
+            Some(6380),
+            parse(&["--admin-port", "6380"]).unwrap().admin_port
+        );
+        assert!(matches!(
+            parse(&["--otlp-endpoint", "localhost"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--log-level", "verbose"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        let config = parse(&["--read-timeout", "5", "--write-timeout", "0"]).unwrap();
+        assert_eq!(Some(Duration::from_secs(5)), config.read_timeout);
+        assert_eq!(None, config.write_timeout);
+
+        assert!(matches!(
+            parse(&["--tcp-nodelay", "1"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert_eq!(
+            Ipv4Addr::LOCALHOST,
+            parse(&["--bind", "localhost"]).unwrap().bind
+        );
+        assert!(matches!(
+            parse(&["--bind", "nonexistent.invalid"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--port", "65536"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--threads"]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            parse(&["--threads", "0"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--foobar"]),
+            Err(ConfigError::UnknownFlag(_))
+        ));
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(Some(100), parse_bytes("100"));
+        assert_eq!(Some(100), parse_bytes("100b"));
+        assert_eq!(Some(2048), parse_bytes("2kb"));
+        assert_eq!(Some(3 * 1024 * 1024), parse_bytes("3MB"));
+        assert_eq!(Some(1024 * 1024 * 1024), parse_bytes("1gb"));
+
+        assert_eq!(None, parse_bytes(""));
+        assert_eq!(None, parse_bytes("mb"));
+        assert_eq!(None, parse_bytes("10tb"));
+        assert_eq!(None, parse_bytes("-1"));
+    }
+
+    #[test]
+    fn save_rules() {
+        let secs = Duration::from_secs;
+
+        assert_eq!(Some(vec![]), parse_save_rules(""));
+        assert_eq!(
+            Some(vec![
+                SaveRule {
+                    after: secs(3600),
+                    changes: 1
+                },
+                SaveRule {
+                    after: secs(300),
+                    changes: 100
+                },
+            ]),
+            parse_save_rules("3600 1  300 100")
+        );
+
+        assert_eq!(None, parse_save_rules("3600"));
+        assert_eq!(None, parse_save_rules("3600 0"));
+        assert_eq!(None, parse_save_rules("foo 1"));
+
+        let mut config = ServerConfig::default();
+        config.set_parameter("save", "60 10 10 1000").unwrap();
+        assert_eq!(
+            vec![("save", "60 10 10 1000".to_string())],
+            config.get_parameters("save")
+        );
+    }
+
+    #[test]
+    fn replica_of() {
+        assert_eq!(Some(None), parse_replica_of("no one"));
+        assert_eq!(Some(None), parse_replica_of("NO ONE"));
+        assert_eq!(
+            Some(Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234))),
+            parse_replica_of("10.0.0.1  1234")
+        );
+
+        assert_eq!(None, parse_replica_of(""));
+        assert_eq!(
+            Some(Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234))),
+            parse_replica_of("localhost 1234")
+        );
+        assert_eq!(None, parse_replica_of("nonexistent.invalid 1234"));
+        assert_eq!(None, parse_replica_of("10.0.0.1 foo"));
+        assert_eq!(None, parse_replica_of("10.0.0.1 1234 5678"));
+
+        let config = ServerConfig {
+            replica_of: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234)),
+            ..ServerConfig::default()
+        };
+        assert_eq!(
+            vec![("replicaof", "127.0.0.1 1234".to_string())],
+            config.get_parameters("replicaof")
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let config = ServerConfig::builder()
+            .addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .max_memory(1024, EvictionPolicy::AllKeysLru)
+            .appendonly("/tmp/test.aof")
+            .threads(2)
+            .idle_timeout(Some(Duration::from_secs(30)))
+            .build()
+            .unwrap();
+        assert_eq!(Ipv4Addr::LOCALHOST, config.bind);
+        assert_eq!(0, config.port);
+        assert_eq!(1024, config.max_memory);
+        assert_eq!(EvictionPolicy::AllKeysLru, config.max_memory_policy);
+        assert!(config.appendonly);
+        assert_eq!(PathBuf::from("/tmp/test.aof"), config.aof_path);
+        assert_eq!(2, config.threads);
+        assert_eq!(Some(Duration::from_secs(30)), config.idle_timeout);
+        assert_eq!(None, config.read_timeout);
+
+        assert!(matches!(
+            ServerConfig::builder().threads(0).build(),
+            Err(ConfigError::InvalidParameterValue { name, .. }) if name == "threads"
+        ));
+        assert!(matches!(
+            ServerConfig::builder().client_buffer_limit(16).build(),
+            Err(ConfigError::InvalidParameterValue { name, .. }) if name == "client-buffer-limit"
+        ));
+    }
+
+    #[test]
+    fn parameters() {
+        let mut config = ServerConfig::default();
+
+        let parameters = config.get_parameters("tcp-*");
+        assert_eq!(
+            vec![
+                ("tcp-backlog", libc::SOMAXCONN.to_string()),
+                ("tcp-keepalive", "300".to_string()),
+                ("tcp-keepalive-interval", "100".to_string()),
+                ("tcp-keepalive-count", "3".to_string()),
+                ("tcp-nodelay", "yes".to_string()),
+            ],
+            parameters
+        );
+
+        config.set_parameter("tcp-nodelay", "no").unwrap();
+        assert!(!config.tcp_nodelay);
+        assert!(config.set_parameter("tcp-nodelay", "false").is_err());
+        assert!(config.set_parameter("tcp-keepalive-count", "0").is_err());
+
+        config.set_parameter("timeout", "20").unwrap();
+        assert_eq!(Some(Duration::from_secs(20)), config.idle_timeout);
+        assert_eq!(
+            vec![("timeout", "20".to_string())],
+            config.get_parameters("timeout")
+        );
+
+        config.set_parameter("write-timeout", "10").unwrap();
+        assert_eq!(Some(Duration::from_secs(10)), config.write_timeout);
+        assert_eq!(
+            vec![
+                ("timeout", "20".to_string()),
+                ("read-timeout", "0".to_string()),
+                ("write-timeout", "10".to_string()),
+            ],
+            config.get_parameters("*timeout")
+        );
+
+        assert!(matches!(
+            config.set_parameter("timeout", "foo"),
+            Err(ConfigError::InvalidParameterValue { .. })
+        ));
+        assert!(matches!(
+            config.set_parameter("port", "6379"),
+            Err(ConfigError::ReadOnlyParameter(_))
+        ));
+        assert!(matches!(
+            config.set_parameter("snapshot-path", "/etc/passwd"),
+            Err(ConfigError::ReadOnlyParameter(_))
+        ));
+        assert!(matches!(
+            config.set_parameter("foobar", "1"),
+            Err(ConfigError::UnknownParameter(_))
+        ));
+    }
+}
@@ -1,13 +1,25 @@
-use shared::protocol::BUF_LEN;
+use crate::protocol::BUF_LEN;
+use crate::{debug, trace};
+use onlyerror::Error;
+
+#[derive(Error, Debug)]
+pub enum BufferError {
+    #[error("buffer limit of {0} bytes reached")]
+    LimitReached(usize),
+}
 
+/// A buffer starting at `BUF_LEN` bytes and growing on demand, up to `limit` bytes.
 pub struct ConnectionBuffer {
     data: Vec<u8>,
     write_head: usize,
     read_head: usize,
+    limit: usize,
 }
 
 impl ConnectionBuffer {
-    pub fn new() -> Self {
+    pub fn new(limit: usize) -> Self {
+        assert!(limit >= BUF_LEN);
+
         let mut data = Vec::with_capacity(BUF_LEN);
         data.resize(BUF_LEN, 0xaa);
 
@@ -15,6 +27,7 @@ impl ConnectionBuffer {
             data,
             write_head: 0,
             read_head: 0,
+            limit,
         }
     }
 
@@ -24,7 +37,7 @@ impl ConnectionBuffer {
     }
 
     pub fn is_empty(&self) -> bool {
-        let remaining = self.read_head - self.write_head;
+        let remaining = self.write_head - self.read_head;
         remaining == 0
     }
 
@@ -38,7 +51,7 @@ impl ConnectionBuffer {
 
     pub fn update_write_head(&mut self, n: usize) {
         self.write_head += n;
-        assert!(self.write_head < self.data.len());
+        assert!(self.write_head <= self.data.len());
     }
 
     pub fn update_read_head(&mut self, n: usize) {
@@ -46,41 +59,102 @@ impl ConnectionBuffer {
         assert!(self.read_head <= self.write_head);
     }
 
+    /// Make sure at least `n` bytes are writable, first by moving the unprocessed bytes to the start of the buffer
+    /// then by growing it.
+    pub fn reserve(&mut self, n: usize) -> Result<(), BufferError> {
+        if self.data.len() - self.write_head >= n {
+            return Ok(());
+        }
+
+        self.remove_processed();
+
+        let needed = self.write_head + n;
+        if needed <= self.data.len() {
+            return Ok(());
+        }
+        if needed > self.limit {
+            return Err(BufferError::LimitReached(self.limit));
+        }
+
+        let new_len = (self.data.len() * 2).max(needed).min(self.limit);
+
+        debug!(
+            "growing buffer from {} to {} bytes",
+            self.data.len(),
+            new_len
+        );
+
+        self.data.resize(new_len, 0xaa);
+
+        Ok(())
+    }
+
     pub fn remove_processed(&mut self) {
         let remaining = self.write_head - self.read_head;
         if remaining == 0 {
+            self.reset();
+            return;
+        }
+        if self.read_head == 0 {
             return;
         }
 
         let next = self.read_head;
 
-        println!(
+        trace!(
             "move bytes from {:?} to the start of the read buf",
             next..next + remaining
         );
 
         self.data.copy_within(next..next + remaining, 0);
         self.read_head = 0;
+        self.write_head = remaining;
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ConnectionBuffer;
+    use crate::protocol::BUF_LEN;
+    use crate::server::ConnectionBuffer;
 
     #[test]
     fn connection_buffer() {
-        let mut buffer = ConnectionBuffer::new();
+        let mut buffer = ConnectionBuffer::new(BUF_LEN);
 
         let written = {
             let buf = buffer.writable();
             buf[0..6].copy_from_slice("foobar".as_bytes());
             buf[6..12].copy_from_slice("foobar".as_bytes());
 
-            12 as usize
+            12_usize
         };
         buffer.update_write_head(written);
 
         assert_eq!(b"foobarfoobar", buffer.readable());
     }
+
+    #[test]
+    fn reserve() {
+        let mut buffer = ConnectionBuffer::new(BUF_LEN * 3);
+
+        buffer.writable()[0..6].copy_from_slice(b"foobar");
+        buffer.update_write_head(BUF_LEN - 2);
+        buffer.update_read_head(3);
+
+        // Moving the unprocessed bytes is enough
+        buffer.reserve(4).unwrap();
+        assert_eq!(BUF_LEN - 5, buffer.readable().len());
+        assert_eq!(b"bar", &buffer.readable()[0..3]);
+        assert_eq!(5, buffer.writable().len());
+
+        // Growing is needed
+        buffer.reserve(BUF_LEN).unwrap();
+        assert_eq!(BUF_LEN + 5, buffer.writable().len());
+        assert_eq!(b"bar", &buffer.readable()[0..3]);
+
+        assert!(buffer.reserve(BUF_LEN * 3).is_err());
+
+        buffer.update_read_head(BUF_LEN - 5);
+        assert!(buffer.is_empty());
+    }
 }
@@ -0,0 +1,74 @@
+//! CRC-64/Jones, the variant Redis uses to check its RDB files.
+
+/// The polynomial 0xad93d23594c935a9, reflected.
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = make_table();
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// Computes a checksum incrementally, for data which is not in memory all at once.
+#[derive(Default)]
+pub struct Crc64 {
+    crc: u64,
+}
+
+impl Crc64 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc = TABLE[((self.crc ^ *byte as u64) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.crc
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut crc = Crc64::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Crc64};
+
+    #[test]
+    fn crc64() {
+        assert_eq!(0, checksum(b""));
+        assert_eq!(0xe9c6d914c4b8d9ca, checksum(b"123456789"));
+
+        let mut crc = Crc64::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(0xe9c6d914c4b8d9ca, crc.finish());
+    }
+}
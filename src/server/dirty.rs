@@ -0,0 +1,80 @@
+//! Accounting of the changes to the keyspace, the "dirty" keys.
+//!
+//! Every command which changes the keyspace records how many keys it changed, under its name. The total drives the
+//! save rules and the counters are reported by INFO.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub struct Dirty {
+    /// Number of keys changed since we started.
+    total: AtomicU64,
+    by_command: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl Dirty {
+    pub fn new() -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            by_command: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that `command` changed `keys` keys.
+    ///
+    /// The keys removed because they expired or to free memory are recorded under `expired` and `evicted`.
+    pub fn record(&self, command: &[u8], keys: u64) {
+        if keys == 0 {
+            return;
+        }
+
+        self.total.fetch_add(keys, Ordering::Relaxed);
+
+        let mut by_command = self.by_command.lock().unwrap();
+        match by_command.get_mut(command) {
+            Some(count) => *count += keys,
+            None => {
+                by_command.insert(command.to_vec(), keys);
+            }
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of keys changed by every command which changed at least one, sorted by command.
+    pub fn by_command(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .by_command
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(command, &count)| (String::from_utf8_lossy(command).into_owned(), count))
+            .collect();
+        counts.sort();
+
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dirty;
+
+    #[test]
+    fn record() {
+        let dirty = Dirty::new();
+        dirty.record(b"set", 1);
+        dirty.record(b"del", 0);
+        dirty.record(b"set", 1);
+        dirty.record(b"expired", 3);
+
+        assert_eq!(5, dirty.total());
+        assert_eq!(
+            vec![("expired".to_string(), 3), ("set".to_string(), 2)],
+            dirty.by_command()
+        );
+    }
+}
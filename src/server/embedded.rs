@@ -0,0 +1,547 @@
+//! The server running on a thread of the current process, which is how [`run`](crate::server::run) serves with a
+//! single event loop and how the other programs and the tests start a real server:
+//!
+//! ```
+//! use shared::client::Client;
+//! use shared::server::Server;
+//!
+//! let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+//! let mut client = Client::connect(&server.addr().to_string()).unwrap();
+//! client.set(b"a", b"1").unwrap();
+//! assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+//! server.shutdown().unwrap();
+//! ```
+//!
+//! Shutting down, or a client sending SHUTDOWN, stops the event loop and closes the connections and the listeners.
+//! The threads of the replication and of the failover are stopped too, the thread of a background save isn't.
+//!
+//! Unless [`Builder::persist`] is called the snapshot and the append only file are in a temporary directory, an
+//! embedded server starts empty and leaves nothing behind.
+
+use crate::server::config::ServerConfig;
+use crate::server::hooks::Flow;
+use crate::server::poller::DefaultPoller;
+use crate::server::stats::MetricsSnapshot;
+use crate::server::workers::{Mailbox, MailboxSender, WorkerPool};
+use crate::server::{
+    create_admin_listener, create_listener, execute_request, invalidate_tracked_keys, load_data,
+    load_modules, propagate_expirations, run_event_loop, start_failover, start_replication,
+    Context, Dispatch, Dispatcher, NB_SHARDS,
+};
+use crate::{info, protocol};
+use anyhow::Context as _;
+use std::net::SocketAddrV4;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{env, fs, io, process, thread};
+
+pub struct Builder {
+    config: ServerConfig,
+    bind: Option<String>,
+    persist: bool,
+}
+
+impl Builder {
+    /// Start from `config` instead of the default config.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listen on `addr` instead of the address of the config, with port 0 to let the kernel pick a free port.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.bind = Some(addr.to_string());
+        self
+    }
+
+    /// Load and save the snapshot and the append only file at the paths of the config.
+    ///
+    /// Otherwise they're in a new temporary directory removed once the server is stopped: nothing is loaded at startup
+    /// and nothing written by SAVE or BGSAVE is kept.
+    pub fn persist(mut self) -> Self {
+        self.persist = true;
+        self
+    }
+
+    /// Listen, load the data and start serving on a new thread.
+    pub fn spawn(self) -> anyhow::Result<Server> {
+        let mut config = self.config;
+        if let Some(bind) = &self.bind {
+            let addr: SocketAddrV4 = bind
+                .parse()
+                .with_context(|| format!("invalid address {}", bind))?;
+            config.bind = *addr.ip();
+            config.port = addr.port();
+        }
+
+        let listener = create_listener(
+            SocketAddrV4::new(config.bind, config.port),
+            config.backlog,
+            false,
+        )?;
+        // The port is only known now if it was 0
+        let addr = crate::local_addr(listener.as_raw_fd())?;
+        config.port = addr.port();
+
+        let admin_listener = match config.admin_port {
+            Some(port) => Some(create_admin_listener(&config, port)?),
+            None => None,
+        };
+
+        // Workers
+        //
+        // Commands are executed on a pool of workers if we have more than one core, otherwise on the event loop.
+
+        let dir = if self.persist {
+            None
+        } else {
+            let dir = TempDir::new()?;
+            config.snapshot_path = dir.0.join("dump.snap");
+            config.aof_path = dir.0.join("appendonly.aof");
+            Some(dir)
+        };
+
+        let context = Arc::new(Context::new(config.clone(), NB_SHARDS)?);
+        let server_context = Arc::clone(&context);
+        load_modules(&context, &config)?;
+        load_data(&context, &config)?;
+        propagate_expirations(&context);
+        invalidate_tracked_keys(&context);
+        let mut threads = vec![start_replication(&context)?];
+        threads.extend(start_failover(&context)?);
+
+        let nb_workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        let dispatch = if nb_workers > 1 {
+            info!("starting {} workers", nb_workers);
+
+            let handler_context = Arc::clone(&context);
+            let pool = WorkerPool::new(nb_workers, move |body| {
+                execute_request(&handler_context, body)
+            })?;
+
+            Dispatch::Workers(Arc::new(pool))
+        } else {
+            Dispatch::Inline
+        };
+
+        let dispatcher = Dispatcher {
+            dispatch,
+            completions: Mailbox::new()?,
+            messages: Mailbox::new()?,
+            stop: Mailbox::new()?,
+        };
+        let stop = dispatcher.stop.sender();
+
+        // Event loop
+
+        let thread = thread::Builder::new()
+            .name("event-loop".to_string())
+            .spawn(move || {
+                let mut poller = DefaultPoller::new()?;
+
+                run_event_loop(
+                    &mut poller,
+                    listener.as_raw_fd(),
+                    admin_listener.as_ref().map(AsRawFd::as_raw_fd),
+                    &context,
+                    &dispatcher,
+                )
+            })?;
+
+        Ok(Server {
+            addr,
+            context: server_context,
+            stop,
+            thread: Some(thread),
+            threads,
+            _dir: dir,
+        })
+    }
+}
+
+/// A running server, stopped when dropped.
+pub struct Server {
+    addr: SocketAddrV4,
+    context: Arc<Context>,
+    stop: MailboxSender<()>,
+    thread: Option<thread::JoinHandle<anyhow::Result<()>>>,
+    /// The threads of the replication and of the failover.
+    threads: Vec<thread::JoinHandle<()>>,
+    /// Removed once the threads are stopped, see [`Builder::persist`].
+    _dir: Option<TempDir>,
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder {
+            config: ServerConfig::default(),
+            bind: None,
+            persist: false,
+        }
+    }
+
+    /// The address the server listens on, with the port picked by the kernel if it was 0.
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// Returns the current metrics of the server, the same as INFO reports.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.context.metrics()
+    }
+
+    /// Call `before` before executing every request of a client and `after` once its response is written.
+    ///
+    /// `before` can write the response itself and return [`Flow::Stop`] to skip the command. The hooks are called in
+    /// the order they were added, the requests of the primary and those replayed from the append only file don't go
+    /// through them.
+    pub fn on_command<B, A>(&self, before: B, after: A)
+    where
+        B: Fn(&[u8], &[&[u8]], &mut protocol::Writer) -> Flow + Send + Sync + 'static,
+        A: Fn(&[u8], &[&[u8]], &[u8]) + Send + Sync + 'static,
+    {
+        self.context.on_command(before, after);
+    }
+
+    /// Wait until the event loop returns, which only happens if it fails or after a shutdown.
+    pub fn wait(mut self) -> anyhow::Result<()> {
+        self.join()
+    }
+
+    /// Stop the event loop and wait for it to return.
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.stop.send(());
+        self.join()
+    }
+
+    fn join(&mut self) -> anyhow::Result<()> {
+        let result = match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("the event loop panicked")),
+            },
+            None => Ok(()),
+        };
+
+        // They hold the context too, which would never be dropped otherwise
+        self.context.replication.stop();
+        self.context.failover.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+
+        result
+    }
+}
+
+/// A directory removed when dropped, with the files of a server which doesn't persist them.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "my-own-redis-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop.send(());
+            let _ = self.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Builder, Server};
+    use crate::client::{Client, Error, Value};
+    use crate::server::config::ServerConfig;
+    use crate::server::hooks::Flow;
+    use crate::ResponseCode;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs, process};
+
+    #[test]
+    fn embedded() {
+        let first = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+        let second = Server::builder()
+            .config(
+                ServerConfig::builder()
+                    .addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                    .build()
+                    .unwrap(),
+            )
+            .spawn()
+            .unwrap();
+        assert_ne!(0, first.addr().port());
+        assert_ne!(first.addr(), second.addr());
+
+        let mut client = Client::connect(&first.addr().to_string()).unwrap();
+        client.set(b"a", b"1").unwrap();
+        assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+
+        // Each server has its own keyspace
+        let mut other = Client::connect(&second.addr().to_string()).unwrap();
+        assert_eq!(None, other.get(b"a").unwrap());
+
+        let addr = first.addr().to_string();
+        first.shutdown().unwrap();
+        assert!(client.get(b"a").is_err());
+        assert!(Client::connect(&addr).is_err());
+
+        assert_eq!(
+            Value::Bytes(b"PONG".to_vec()),
+            other.execute("ping", &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn metrics() {
+        let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+        assert_eq!(None, server.metrics().hit_ratio());
+
+        let mut client = Client::connect(&server.addr().to_string()).unwrap();
+        client.set(b"a", b"1").unwrap();
+        client.get(b"a").unwrap();
+        client.get(b"b").unwrap();
+        client.get(b"c").unwrap();
+
+        let metrics = server.metrics();
+        assert_eq!(4, metrics.total_commands);
+        assert_eq!(1, metrics.total_connections);
+        assert_eq!(1, metrics.connected_clients);
+        assert_eq!(1, metrics.keys);
+        assert!(metrics.used_memory > 0);
+        assert!(metrics.peak_memory >= metrics.used_memory);
+        assert_eq!((0, 0), (metrics.expired_keys, metrics.evicted_keys));
+        assert!(metrics.net_input_bytes > 0);
+        assert!(metrics.net_output_bytes > 0);
+        assert_eq!(Some(1.0 / 3.0), metrics.hit_ratio());
+
+        let calls: Vec<_> = metrics
+            .commands
+            .iter()
+            .map(|summary| (summary.command.as_str(), summary.calls))
+            .collect();
+        assert_eq!(vec![("get", 3), ("set", 1)], calls);
+    }
+
+    #[test]
+    fn hooks() {
+        let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+
+        let audit = Arc::new(Mutex::new(Vec::new()));
+        let after_audit = Arc::clone(&audit);
+        server.on_command(
+            |cmd, args, writer| match (cmd, args) {
+                (b"del", _) => {
+                    writer.push_err(ResponseCode::Unknown, "del is disabled");
+                    Flow::Stop
+                }
+                (b"get", [b"cached"]) => {
+                    writer.push_string("from the cache");
+                    Flow::Stop
+                }
+                _ => Flow::Continue,
+            },
+            move |cmd, _, response| {
+                let reply = Value::decode(response).unwrap();
+                after_audit
+                    .lock()
+                    .unwrap()
+                    .push((String::from_utf8_lossy(cmd).into_owned(), reply));
+            },
+        );
+
+        let mut client = Client::connect(&server.addr().to_string()).unwrap();
+        client.set(b"a", b"1").unwrap();
+        assert!(matches!(
+            client.del(&[b"a"]),
+            Err(Error::Reply { message, .. }) if message == "del is disabled"
+        ));
+        assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+        assert_eq!(
+            Some(b"from the cache".to_vec()),
+            client.get(b"cached").unwrap()
+        );
+
+        // The rejected and the cached commands aren't executed
+        let metrics = server.metrics();
+        assert_eq!(
+            vec![("get", 1), ("set", 1)],
+            metrics
+                .commands
+                .iter()
+                .map(|summary| (summary.command.as_str(), summary.calls))
+                .collect::<Vec<_>>()
+        );
+
+        let audit = audit.lock().unwrap();
+        let commands: Vec<_> = audit.iter().map(|(cmd, _)| cmd.as_str()).collect();
+        assert_eq!(vec!["set", "del", "get", "get"], commands);
+        assert_eq!(Value::Bytes(b"1".to_vec()), audit[2].1);
+    }
+
+    #[test]
+    fn panics() {
+        let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+        server
+            .context
+            .register_command("crash", 1, |_, _, _| panic!("crashed on purpose"))
+            .unwrap();
+
+        let mut client = Client::connect(&server.addr().to_string()).unwrap();
+        let internal_error = |result: Result<Value, Error>| matches!(result, Ok(Value::Error { message, .. }) if message == "internal error");
+
+        // Run by the event loop itself
+        client.execute("multi", &[]).unwrap();
+        client.execute("crash", &[]).unwrap();
+        assert!(internal_error(client.execute("exec", &[])));
+        assert!(internal_error(
+            client.execute("eval", &[b"return redis.call('crash')", b"0"])
+        ));
+        // Run by a worker, or by the event loop if there's a single core
+        assert!(internal_error(client.execute("crash", &[])));
+
+        assert_eq!(
+            Value::Bytes(b"PONG".to_vec()),
+            client.execute("ping", &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn audit() {
+        let path = env::temp_dir().join(format!("audit-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let config = ServerConfig {
+            audit_log: Some(path.clone()),
+            ..ServerConfig::default()
+        };
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .spawn()
+            .unwrap();
+
+        let mut client = Client::connect(&server.addr().to_string()).unwrap();
+
+        // Discarded, never executed
+        client.execute("multi", &[]).unwrap();
+        client
+            .execute("config", &[b"set", b"maxmemory", b"1mb"])
+            .unwrap();
+        assert_eq!(Value::Nil, client.execute("discard", &[]).unwrap());
+
+        // Executed by a script
+        client
+            .execute(
+                "eval",
+                &[
+                    b"return redis.call('config', 'set', 'maxmemory', '2mb')",
+                    b"0",
+                ],
+            )
+            .unwrap();
+        client
+            .execute("config", &[b"set", b"maxmemory", b"3mb"])
+            .unwrap();
+
+        server.shutdown().unwrap();
+
+        let events = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let events: Vec<_> = events
+            .lines()
+            .map(|line| line.split_once(" id=").unwrap().1)
+            .collect();
+        assert_eq!(
+            vec!["0 config set maxmemory 2mb", "0 config set maxmemory 3mb",],
+            events
+        );
+    }
+
+    #[test]
+    fn stopped_threads() {
+        // Nothing listens on port 1, the replication and the failover keep failing
+        let unreachable = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+        let config = ServerConfig {
+            replica_of: Some(unreachable),
+            failover_peers: vec![unreachable],
+            ..ServerConfig::default()
+        };
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .spawn()
+            .unwrap();
+        let context = Arc::downgrade(&server.context);
+
+        server.shutdown().unwrap();
+        assert!(context.upgrade().is_none());
+    }
+
+    #[test]
+    fn persistence() {
+        let spawn = |builder: Builder| {
+            let server = builder.bind("127.0.0.1:0").spawn().unwrap();
+            let client = Client::connect(&server.addr().to_string()).unwrap();
+            (server, client)
+        };
+
+        // Saved in a temporary directory, not loaded by the next server
+        let (server, mut client) = spawn(Server::builder());
+        client.set(b"a", b"1").unwrap();
+        client.execute("shutdown", &[b"save"]).unwrap();
+        server.wait().unwrap();
+
+        let (_server, mut client) = spawn(Server::builder());
+        assert_eq!(None, client.get(b"a").unwrap());
+
+        // Saved at the path of the config
+        let path = env::temp_dir().join(format!("embedded-test-{}.snap", process::id()));
+        let persisted = || {
+            let config = ServerConfig {
+                snapshot_path: path.clone(),
+                ..ServerConfig::default()
+            };
+            Server::builder().config(config).persist()
+        };
+
+        let (server, mut client) = spawn(persisted());
+        client.set(b"a", b"1").unwrap();
+        client.execute("shutdown", &[b"save"]).unwrap();
+        server.wait().unwrap();
+
+        let (_server, mut client) = spawn(persisted());
+        assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn invalid_address() {
+        assert!(Server::builder().bind("localhost").spawn().is_err());
+    }
+}
@@ -0,0 +1,445 @@
+//! Automatic failover, in the spirit of Redis Sentinel but without dedicated processes: the primary and its replicas
+//! know the addresses of each other and check their roles every second.
+//!
+//! When a replica can't reach its primary for long enough it starts an election: it bumps the epoch and asks the
+//! other peers for their vote. A peer votes at most once per epoch and only if it sees the primary as down too.
+//! The replica promotes itself with the votes of a majority of the peers, the unreachable primary included, so two
+//! sides of a network partition can't both elect a primary.
+//!
+//! Every node then follows the reachable primary with the greatest epoch: the other replicas move to the new primary
+//! and the old one steps down when it comes back.
+
+use crate::protocol::{self, DataType};
+use crate::server::peer::{Peer, PeerError};
+use crate::{info, warn};
+use onlyerror::Error;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interval between two checks of the roles of the peers.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for a peer to answer.
+const PEER_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum FailoverError {
+    #[error("request failed")]
+    Peer(#[from] PeerError),
+    #[error("invalid response")]
+    Protocol(#[from] protocol::Error),
+    #[error("unexpected reply of type {0}")]
+    UnexpectedReply(DataType),
+    #[error("invalid role {0:?}")]
+    InvalidRole(String),
+}
+
+/// The role of a server, as returned by ROLE.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Role {
+    Primary { epoch: u64 },
+    Replica { primary: SocketAddrV4, epoch: u64 },
+}
+
+impl Role {
+    /// Parse the response to ROLE: `["master", epoch]` or `["slave", ip, port, epoch]`.
+    pub fn parse(body: &[u8]) -> Result<Self, FailoverError> {
+        let mut reader = protocol::Reader::new(body);
+
+        expect(&mut reader, DataType::Arr)?;
+        let length = reader.read_arr_length()?;
+
+        expect(&mut reader, DataType::Str)?;
+        let role = reader.read_string()?;
+
+        match (role, length) {
+            (b"master", 2) => {
+                expect(&mut reader, DataType::Int)?;
+                let epoch = reader.read_int()?;
+
+                Ok(Self::Primary { epoch })
+            }
+            (b"slave", 4) => {
+                expect(&mut reader, DataType::Str)?;
+                let ip = reader.read_string()?;
+                let ip = std::str::from_utf8(ip)
+                    .ok()
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or_else(|| {
+                        FailoverError::InvalidRole(String::from_utf8_lossy(ip).into_owned())
+                    })?;
+
+                expect(&mut reader, DataType::Int)?;
+                let port = reader.read_int()?;
+                let port = u16::try_from(port)
+                    .map_err(|_| FailoverError::InvalidRole(format!("port {}", port)))?;
+
+                expect(&mut reader, DataType::Int)?;
+                let epoch = reader.read_int()?;
+
+                Ok(Self::Replica {
+                    primary: SocketAddrV4::new(ip, port),
+                    epoch,
+                })
+            }
+            _ => Err(FailoverError::InvalidRole(
+                String::from_utf8_lossy(role).into_owned(),
+            )),
+        }
+    }
+}
+
+fn expect(reader: &mut protocol::Reader, want: DataType) -> Result<(), FailoverError> {
+    match reader.read_data_type()? {
+        data_type if data_type == want => Ok(()),
+        data_type => Err(FailoverError::UnexpectedReply(data_type)),
+    }
+}
+
+/// The server the failover runs for.
+pub trait Node {
+    /// The address the peers reach us at.
+    fn address(&self) -> SocketAddrV4;
+    /// The primary we replicate from, if we're a replica.
+    fn primary(&self) -> Option<SocketAddrV4>;
+    /// Sent with AUTH to the peers, if they require a password.
+    fn password(&self) -> Option<String>;
+    /// How long the primary must be unreachable before starting an election.
+    fn down_after(&self) -> Duration;
+    /// Stop replicating and accept writes.
+    fn promote(&self);
+    /// Replicate from `primary`.
+    fn follow(&self, primary: SocketAddrV4);
+}
+
+struct State {
+    /// Bumped by every election, the primary elected in the greatest epoch wins.
+    epoch: u64,
+    /// The last epoch we voted in.
+    voted_epoch: u64,
+    /// The primary we're replicating from and the last time we reached it.
+    primary: Option<SocketAddrV4>,
+    primary_seen: Instant,
+    /// Set by [`Failover::stop`] to make [`Failover::run`] return.
+    stopped: bool,
+}
+
+pub struct Failover {
+    state: Mutex<State>,
+    stopped: Condvar,
+}
+
+impl Failover {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                epoch: 0,
+                voted_epoch: 0,
+                primary: None,
+                primary_seen: Instant::now(),
+                stopped: false,
+            }),
+            stopped: Condvar::new(),
+        }
+    }
+
+    /// Make [`Failover::run`] return once the check in progress, if any, is done.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.stopped.notify_all();
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Returns true if we can't reach `primary` since at least `down_after`.
+    fn primary_down(state: &State, primary: Option<SocketAddrV4>, down_after: Duration) -> bool {
+        // NOTE(vincent): the clock restarts when we switch primaries, see `check`
+        primary.is_some() && state.primary == primary && state.primary_seen.elapsed() >= down_after
+    }
+
+    /// Vote for a candidate in `epoch`, returning true if the vote is granted.
+    ///
+    /// `primary` is the primary we replicate from: the vote is refused if we're not a replica or if we saw the primary
+    /// less than `down_after` ago.
+    pub fn vote(&self, epoch: u64, primary: Option<SocketAddrV4>, down_after: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if epoch <= state.voted_epoch
+            || epoch < state.epoch
+            || !Self::primary_down(&state, primary, down_after)
+        {
+            return false;
+        }
+
+        state.voted_epoch = epoch;
+        state.epoch = epoch;
+
+        true
+    }
+
+    /// Start an election in a new epoch, voting for ourselves. Returns the epoch.
+    fn start_election(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+
+        state.epoch = state.epoch.max(state.voted_epoch) + 1;
+        state.voted_epoch = state.epoch;
+
+        state.epoch
+    }
+
+    /// Monitor the peers until [`Failover::stop`] is called. Returns right away if `peers` is empty.
+    pub fn run<N: Node>(&self, node: &N, peers: &[SocketAddrV4]) {
+        let peers: Vec<SocketAddrV4> = peers
+            .iter()
+            .copied()
+            .filter(|&peer| peer != node.address())
+            .collect();
+        if peers.is_empty() {
+            return;
+        }
+
+        loop {
+            let state = self.state.lock().unwrap();
+            let (state, _) = self
+                .stopped
+                .wait_timeout_while(state, PING_INTERVAL, |state| !state.stopped)
+                .unwrap();
+            if state.stopped {
+                return;
+            }
+            drop(state);
+
+            self.check(node, &peers);
+        }
+    }
+
+    fn check<N: Node>(&self, node: &N, peers: &[SocketAddrV4]) {
+        let password = node.password();
+        let primary = node.primary();
+
+        // The primary may not be one of the peers if it was changed with REPLICAOF
+        let unknown_primary = primary.filter(|primary| !peers.contains(primary));
+
+        let mut roles = HashMap::new();
+        for &peer in peers.iter().chain(unknown_primary.iter()) {
+            match query_role(peer, password.as_deref()) {
+                Ok(role) => {
+                    roles.insert(peer, role);
+                }
+                Err(err) => warn!("unable to get the role of peer {}, err: {}", peer, err),
+            }
+        }
+
+        // Follow the reachable primary with the greatest epoch, preferring ours
+        let best = roles
+            .iter()
+            .filter_map(|(&addr, role)| match role {
+                Role::Primary { epoch } => Some((addr, *epoch)),
+                Role::Replica { .. } => None,
+            })
+            .max_by_key(|&(addr, epoch)| (epoch, Some(addr) == primary));
+
+        let mut state = self.state.lock().unwrap();
+
+        if let Some((addr, epoch)) = best {
+            let newer = match primary {
+                Some(primary) => addr != primary && epoch >= state.epoch,
+                None => epoch > state.epoch,
+            };
+            if newer {
+                info!("following primary {} elected in epoch {}", addr, epoch);
+                node.follow(addr);
+            }
+
+            state.epoch = state.epoch.max(epoch);
+        }
+
+        let primary = node.primary();
+        if state.primary != primary {
+            state.primary = primary;
+            state.primary_seen = Instant::now();
+        }
+
+        let primary = match primary {
+            Some(primary) => primary,
+            None => return,
+        };
+        if roles.contains_key(&primary) {
+            state.primary_seen = Instant::now();
+            return;
+        }
+
+        // Replicas wait more the greater their address is, so that they don't all run for the same epoch
+        let mut replicas: Vec<SocketAddrV4> = roles
+            .iter()
+            .filter(|(_, role)| matches!(role, Role::Replica { primary: p, .. } if *p == primary))
+            .map(|(&addr, _)| addr)
+            .chain([node.address()])
+            .collect();
+        replicas.sort();
+        let rank = replicas
+            .iter()
+            .position(|&addr| addr == node.address())
+            .unwrap_or(0);
+
+        let down_after = node.down_after() + PING_INTERVAL * rank as u32;
+        if !Self::primary_down(&state, Some(primary), down_after) {
+            return;
+        }
+        drop(state);
+
+        let epoch = self.start_election();
+        info!(
+            "primary {} is down, running for election in epoch {}",
+            primary, epoch
+        );
+
+        let me = node.address().to_string();
+        let votes = 1 + peers
+            .iter()
+            .filter(|&&peer| peer != primary)
+            .filter(
+                |&&peer| match request_vote(peer, password.as_deref(), epoch, &me) {
+                    Ok(granted) => granted,
+                    Err(err) => {
+                        warn!("unable to get the vote of peer {}, err: {}", peer, err);
+                        false
+                    }
+                },
+            )
+            .count();
+
+        // The primary counts in the majority even though it can't vote
+        let nb_nodes = peers.len() + 1;
+        let quorum = nb_nodes / 2 + 1;
+        if votes >= quorum {
+            info!(
+                "elected in epoch {} with {} votes, promoting to primary",
+                epoch, votes
+            );
+            node.promote();
+        } else {
+            info!(
+                "lost the election in epoch {} with {} votes out of {} needed",
+                epoch, votes, quorum
+            );
+        }
+    }
+}
+
+fn connect(peer: SocketAddrV4, password: Option<&str>) -> Result<Peer, FailoverError> {
+    let peer = Peer::connect(peer, PEER_TIMEOUT)?;
+
+    if let Some(password) = password {
+        peer.request(&[b"auth", password.as_bytes()])?;
+    }
+
+    Ok(peer)
+}
+
+fn query_role(peer: SocketAddrV4, password: Option<&str>) -> Result<Role, FailoverError> {
+    let body = connect(peer, password)?.request(&[b"role"])?;
+    Role::parse(&body)
+}
+
+fn request_vote(
+    peer: SocketAddrV4,
+    password: Option<&str>,
+    epoch: u64,
+    candidate: &str,
+) -> Result<bool, FailoverError> {
+    let epoch = epoch.to_string();
+    let body = connect(peer, password)?.request(&[
+        b"failover-vote",
+        epoch.as_bytes(),
+        candidate.as_bytes(),
+    ])?;
+
+    let mut reader = protocol::Reader::new(&body);
+    expect(&mut reader, DataType::Int)?;
+
+    Ok(reader.read_int()? == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failover, FailoverError, Role};
+    use crate::protocol::{Writer, BUF_LEN};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    #[test]
+    fn vote() {
+        let primary = Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234));
+        let failover = Failover::new();
+
+        // Not a replica
+        assert!(!failover.vote(1, None, Duration::ZERO));
+        // The primary isn't down yet, it was just seen
+        failover.state.lock().unwrap().primary = primary;
+        assert!(!failover.vote(1, primary, Duration::from_secs(60)));
+
+        assert!(failover.vote(1, primary, Duration::ZERO));
+        assert_eq!(1, failover.epoch());
+        // Only one vote per epoch
+        assert!(!failover.vote(1, primary, Duration::ZERO));
+        assert!(failover.vote(3, primary, Duration::ZERO));
+        assert!(!failover.vote(2, primary, Duration::ZERO));
+        assert_eq!(3, failover.epoch());
+
+        // We voted for ourselves in the new epoch
+        assert_eq!(4, failover.start_election());
+        assert!(!failover.vote(4, primary, Duration::ZERO));
+        assert!(failover.vote(5, primary, Duration::ZERO));
+    }
+
+    #[test]
+    fn role() {
+        let mut buf = vec![0; BUF_LEN];
+        let mut encode = |f: &dyn Fn(&mut Writer)| {
+            let mut writer = Writer::new(&mut buf);
+            f(&mut writer);
+            writer.finish();
+            let written = writer.written();
+            buf[4..written].to_vec()
+        };
+
+        let body = encode(&|w| {
+            w.push_arr(2);
+            w.push_string("master");
+            w.push_int(3);
+        });
+        assert_eq!(Role::Primary { epoch: 3 }, Role::parse(&body).unwrap());
+
+        let body = encode(&|w| {
+            w.push_arr(4);
+            w.push_string("slave");
+            w.push_string("127.0.0.1");
+            w.push_int(1235);
+            w.push_int(2);
+        });
+        assert_eq!(
+            Role::Replica {
+                primary: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1235),
+                epoch: 2
+            },
+            Role::parse(&body).unwrap()
+        );
+
+        let body = encode(&|w| {
+            w.push_arr(1);
+            w.push_string("sentinel");
+        });
+        assert!(matches!(
+            Role::parse(&body),
+            Err(FailoverError::InvalidRole(role)) if role == "sentinel"
+        ));
+        assert!(matches!(
+            Role::parse(&encode(&|w| w.push_nil())),
+            Err(FailoverError::UnexpectedReply(_))
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+/// Returns true if `value` matches the glob-style `pattern`.
+///
+/// Supported syntax:
+/// * `*` matches any sequence of characters, including an empty one
+/// * `?` matches any single character
+/// * `\` escapes the next character
+pub fn matches(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+
+    // Position in the pattern after the last `*` seen and position in the value it matched up to.
+    // Used to backtrack when the rest of the pattern doesn't match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, v));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                v += 1;
+                continue;
+            }
+            Some(b'\\') if p + 1 < pattern.len() && pattern[p + 1] == value[v] => {
+                p += 2;
+                v += 1;
+                continue;
+            }
+            Some(&c) if c != b'\\' && c == value[v] => {
+                p += 1;
+                v += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Mismatch: let the last `*` consume one more character, if any
+        match backtrack {
+            Some((star_p, star_v)) => {
+                p = star_p;
+                v = star_v + 1;
+                backtrack = Some((star_p, star_v + 1));
+            }
+            None => return false,
+        }
+    }
+
+    // Trailing stars match the empty string
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn glob() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"foobar"));
+        assert!(matches(b"foo*", b"foobar"));
+        assert!(matches(b"*bar", b"foobar"));
+        assert!(matches(b"f*b*r", b"foobar"));
+        assert!(matches(b"f?obar", b"foobar"));
+        assert!(matches(b"foo\\*", b"foo*"));
+
+        assert!(!matches(b"", b"foobar"));
+        assert!(!matches(b"foo", b"foobar"));
+        assert!(!matches(b"*baz", b"foobar"));
+        assert!(!matches(b"f?bar", b"foobar"));
+        assert!(!matches(b"foo\\*", b"foobar"));
+    }
+}
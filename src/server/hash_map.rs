@@ -1,3 +1,4 @@
+use crate::debug;
 use std::borrow::Borrow;
 use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
@@ -44,7 +45,7 @@ impl<K, V> HashMap<K, V> {
         self.size
     }
 
-    fn insert(&mut self, key: K, value: V)
+    fn insert(&mut self, key: K, value: V) -> Option<V>
     where
         K: Hash + Eq,
     {
@@ -56,16 +57,18 @@ impl<K, V> HashMap<K, V> {
         // Try to update the value first
         for entry in list.iter_mut() {
             if entry.key == key {
-                entry.value = value;
-                return;
+                return Some(mem::replace(&mut entry.value, value));
             }
         }
 
         // Otherwise insert it
         list.push(Entry { key, value });
-        self.size += 1
+        self.size += 1;
+
+        None
     }
 
+    #[allow(dead_code)]
     fn get<Q>(&self, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
@@ -81,6 +84,21 @@ impl<K, V> HashMap<K, V> {
             .map(|entry| &entry.value)
     }
 
+    fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let pos = (calculate_hash(&key) & self.mask) as usize;
+
+        // NOTE(vincent): safe because we always initialize `data`
+        let list = self.data.get_mut(pos).unwrap();
+
+        list.iter_mut()
+            .find(|entry| entry.key.borrow() == key)
+            .map(|entry| &mut entry.value)
+    }
+
     fn remove<Q>(&mut self, key: &Q) -> Option<V>
     where
         K: Borrow<Q>,
@@ -94,6 +112,7 @@ impl<K, V> HashMap<K, V> {
         for (i, entry) in list.iter().enumerate() {
             if entry.key.borrow() == key {
                 let entry = list.swap_remove(i);
+                self.size -= 1;
                 return Some(entry.value);
             }
         }
@@ -104,31 +123,31 @@ impl<K, V> HashMap<K, V> {
 
 #[allow(dead_code)]
 fn dump_hashmap<K: Hash + Eq + Debug, V: Eq + Debug>(name: &str, map: &HashMap<K, V>) {
-    println!("map {}", name);
+    debug!("map {}", name);
 
     for (i, list) in map.data.iter().enumerate() {
-        println!("  bucket #{}", i);
+        debug!("  bucket #{}", i);
         for entry in list.iter() {
-            println!("    {:?}: {:?}", entry.key, entry.value);
+            debug!("    {:?}: {:?}", entry.key, entry.value);
         }
     }
 }
 
 #[allow(dead_code)]
 fn dump_superhashmap<K: Hash + Eq + Debug, V: Eq + Debug>(map: &SuperHashMap<K, V>) {
-    println!(
+    debug!(
         "superhashmap: size={} buckets={}",
         map.map1.len() + map.map2.as_ref().map(|m| m.len()).unwrap_or_default(),
         map.map1.data.len() + map.map2.as_ref().map(|m| m.data.len()).unwrap_or_default(),
     );
 
     let dump = |name: &str, map: &HashMap<K, V>| {
-        println!("    map {}", name);
+        debug!("    map {}", name);
 
         for (i, list) in map.data.iter().enumerate() {
-            println!("         bucket #{}", i);
+            debug!("         bucket #{}", i);
             for entry in list.iter() {
-                println!("            {:?}: {:?}", entry.key, entry.value);
+                debug!("            {:?}: {:?}", entry.key, entry.value);
             }
         }
     };
@@ -245,13 +264,30 @@ where
         }
     }
 
-    pub fn key_iter(&self) -> KeyIter<K, V> {
+    pub fn key_iter(&self) -> KeyIter<'_, K, V> {
         KeyIter {
             data: self,
             current: (0, 0, 0),
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.map1.len() + self.map2.as_ref().map(|m| m.len()).unwrap_or_default()
+    }
+
+    /// Returns the memory used by the buckets themselves: their headers and their slots not used by an entry.
+    pub fn buckets_size(&self) -> usize {
+        std::iter::once(&self.map1)
+            .chain(self.map2.as_ref())
+            .flat_map(|m| m.data.iter())
+            .map(|bucket| {
+                mem::size_of::<Vec<Entry<K, V>>>()
+                    + (bucket.capacity() - bucket.len()) * mem::size_of::<Entry<K, V>>()
+            })
+            .sum()
+    }
+
+    #[allow(dead_code)]
     pub fn get<Q>(&self, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
@@ -268,11 +304,60 @@ where
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V)
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(value) = self.map1.get_mut(key) {
+            return Some(value);
+        }
+
+        self.map2.as_mut().and_then(|m| m.get_mut(key))
+    }
+
+    /// Returns an iterator over every entry, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        std::iter::once(&self.map1)
+            .chain(self.map2.as_ref())
+            .flat_map(|m| m.data.iter())
+            .flatten()
+            .map(|entry| (&entry.key, &entry.value))
+    }
+
+    /// Returns up to `n` entries, starting at the bucket `start` (modulo the number of buckets).
+    ///
+    /// With a random `start` this gives a cheap, if not uniform, random sample of the entries.
+    pub fn sample(&self, start: usize, n: usize) -> Vec<(&K, &V)> {
+        let mut result = Vec::with_capacity(n);
+
+        for m in std::iter::once(&self.map1).chain(self.map2.as_ref()) {
+            let nb_buckets = m.data.len();
+
+            for i in 0..nb_buckets {
+                let bucket = &m.data[(start + i) % nb_buckets];
+
+                for entry in bucket {
+                    if result.len() >= n {
+                        return result;
+                    }
+                    result.push((&entry.key, &entry.value));
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Insert the value, returning the previous one if the key was already present.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>
     where
         K: Hash + Eq,
     {
-        self.map1.insert(key, value);
+        // The key may not have been moved to the new table yet
+        let previous = self.map2.as_mut().and_then(|m| m.remove(&key));
+
+        let previous = self.map1.insert(key, value).or(previous);
 
         {
             let load_factor = self.map1.size / (self.map1.mask + 1) as usize;
@@ -282,6 +367,8 @@ where
         }
 
         self.help_resizing();
+
+        previous
     }
 
     pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
@@ -337,7 +424,7 @@ const MAX_LOAD_FACTOR: usize = 8;
 
 #[cfg(test)]
 mod tests {
-    use crate::hash_map::dump_superhashmap;
+    use crate::server::hash_map::dump_superhashmap;
 
     use super::{HashMap, SuperHashMap};
 
@@ -364,6 +451,9 @@ mod tests {
 
         assert_eq!(table.get("foobar"), Some(&"hello"));
         assert_eq!(table.len(), 1);
+
+        assert_eq!(table.remove("foobar"), Some("hello"));
+        assert_eq!(table.len(), 0);
     }
 
     #[test]
@@ -399,6 +489,50 @@ mod tests {
         dump_superhashmap(&map);
     }
 
+    #[test]
+    fn super_hashmap_insert_while_resizing() {
+        let mut map = SuperHashMap::new(1);
+
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i * 2), Some(i));
+        }
+
+        assert_eq!(1000, map.key_iter().len());
+        assert_eq!(map.get(&10), Some(&20));
+    }
+
+    #[test]
+    fn super_hashmap_sample() {
+        let mut map = SuperHashMap::new(4);
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(5, map.sample(3, 5).len());
+        assert_eq!(100, map.sample(42, 1000).len());
+
+        let empty = SuperHashMap::<i32, i32>::new(4);
+        assert!(empty.sample(0, 5).is_empty());
+    }
+
+    #[test]
+    fn super_hashmap_iter() {
+        let mut map = SuperHashMap::new(1);
+
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+
+        let mut entries: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        entries.sort();
+
+        assert_eq!((0..100).map(|i| (i, i * 2)).collect::<Vec<_>>(), entries);
+    }
+
     #[test]
     fn super_hashmap_key_iter() {
         let mut map = SuperHashMap::new(1);
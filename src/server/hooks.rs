@@ -0,0 +1,128 @@
+//! Hooks called around the requests of the clients, to audit them, answer them from a cache or reject them without
+//! touching `do_request`.
+//!
+//! The hooks of [`Hooks::add`] are called in the order they were added. The requests of the primary and those
+//! replayed from the append only file don't go through them.
+
+use crate::protocol;
+use std::sync::{Arc, RwLock};
+
+/// What to do with a request after a before hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Call the next hook then execute the command. The hook must not have written anything.
+    Continue,
+    /// Don't execute the command, the hook wrote its response: an error to reject it or the cached value.
+    Stop,
+}
+
+/// Called with the name and the arguments of a command before it's executed.
+pub type BeforeHook = Arc<dyn Fn(&[u8], &[&[u8]], &mut protocol::Writer) -> Flow + Send + Sync>;
+/// Called with the name and the arguments of a command and the body of its response, even if a hook stopped it.
+pub type AfterHook = Arc<dyn Fn(&[u8], &[&[u8]], &[u8]) + Send + Sync>;
+
+pub struct Hooks {
+    // NOTE(vincent): replaced on every add so that the lock isn't held while the hooks run, a hook can add another
+    hooks: RwLock<Arc<Vec<(BeforeHook, AfterHook)>>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self {
+            hooks: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+
+    pub fn add(&self, before: BeforeHook, after: AfterHook) {
+        let mut hooks = self.hooks.write().unwrap();
+
+        let mut added = Vec::clone(&hooks);
+        added.push((before, after));
+        *hooks = Arc::new(added);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.read().unwrap().is_empty()
+    }
+
+    /// Call the before hooks until one stops the command, returning what to do with it.
+    pub fn before(&self, cmd: &[u8], args: &[&[u8]], writer: &mut protocol::Writer) -> Flow {
+        let hooks = Arc::clone(&self.hooks.read().unwrap());
+
+        for (before, _) in hooks.iter() {
+            if let Flow::Stop = before(cmd, args, writer) {
+                return Flow::Stop;
+            }
+        }
+
+        Flow::Continue
+    }
+
+    /// Call every after hook with `response`, the body of the response message.
+    pub fn after(&self, cmd: &[u8], args: &[&[u8]], response: &[u8]) {
+        let hooks = Arc::clone(&self.hooks.read().unwrap());
+
+        for (_, after) in hooks.iter() {
+            after(cmd, args, response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flow, Hooks};
+    use crate::protocol::{self, BUF_LEN};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn before_after() {
+        let hooks = Hooks::new();
+        assert!(hooks.is_empty());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for name in ["first", "second"] {
+            let (before_seen, after_seen) = (Arc::clone(&seen), Arc::clone(&seen));
+            hooks.add(
+                Arc::new(move |cmd, _, writer| {
+                    before_seen.lock().unwrap().push(format!("{} before", name));
+                    if cmd == b"flushall" {
+                        writer.push_err(1u32, "denied");
+                        return Flow::Stop;
+                    }
+                    Flow::Continue
+                }),
+                Arc::new(move |cmd, args, response| {
+                    after_seen.lock().unwrap().push(format!(
+                        "{} after {} {} {:?}",
+                        name,
+                        String::from_utf8_lossy(cmd),
+                        args.len(),
+                        response
+                    ));
+                }),
+            );
+        }
+        assert!(!hooks.is_empty());
+
+        let mut buf = [0; BUF_LEN];
+        let mut writer = protocol::Writer::new(&mut buf);
+        assert_eq!(Flow::Continue, hooks.before(b"get", &[b"a"], &mut writer));
+        assert_eq!(4, writer.written());
+        hooks.after(b"get", &[b"a"], b"\x00");
+
+        // The first hook stops the command, the second one isn't called
+        assert_eq!(Flow::Stop, hooks.before(b"flushall", &[], &mut writer));
+        assert!(writer.is_err());
+
+        assert_eq!(
+            vec![
+                "first before",
+                "second before",
+                "first after get 1 [0]",
+                "second after get 1 [0]",
+                "first before",
+            ],
+            *seen.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! Export and import of the keyspace as newline-delimited JSON, one object per entry:
+//!
+//! `{"key":"foo","value":"bar","expires_at":1700000000000}`
+//!
+//! `expires_at` is the expiration time in milliseconds since the Unix epoch, it's omitted for keys which never expire.
+//! Unknown fields are ignored when importing.
+
+use crate::server::keyspace::{self, Entry, Keyspace};
+use crate::{command, protocol};
+use onlyerror::Error;
+use std::io::{self, BufRead, Write};
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("unexpected end of line")]
+    UnexpectedEnd,
+    #[error("unexpected character {character:?} at column {column}")]
+    UnexpectedCharacter { character: char, column: usize },
+    #[error("invalid escape sequence at column {0}")]
+    InvalidEscape(usize),
+    #[error("invalid number at column {0}, only non-negative integers are supported")]
+    InvalidNumber(usize),
+    #[error("invalid type for field {0}")]
+    InvalidType(&'static str),
+    #[error("missing field {0}")]
+    MissingField(&'static str),
+    #[error("entry too large ({0} bytes)")]
+    TooLarge(usize),
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("invalid entry at line {line}: {error}")]
+    InvalidEntry { line: usize, error: ParseError },
+}
+
+pub fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+
+    for c in value.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if c.is_control() => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+
+    writer.write_all(b"\"")
+}
+
+fn write_entry<W: Write>(writer: &mut W, entry: &Entry) -> io::Result<()> {
+    let (key, value, expires_at) = entry;
+
+    writer.write_all(b"{\"key\":")?;
+    write_string(writer, key)?;
+    writer.write_all(b",\"value\":")?;
+    write_string(writer, value)?;
+    if let Some(at) = expires_at {
+        write!(writer, ",\"expires_at\":{}", keyspace::to_unix_millis(*at))?;
+    }
+    writer.write_all(b"}\n")
+}
+
+/// Write every entry of the keyspace to `writer`, returning the number of entries written.
+pub fn export<W: Write>(keyspace: &Keyspace, writer: &mut W) -> io::Result<usize> {
+    let mut nb_entries = 0;
+
+    for index in 0..keyspace.nb_shards() {
+        for entry in keyspace.shard_entries(index) {
+            write_entry(writer, &entry)?;
+            nb_entries += 1;
+        }
+    }
+
+    Ok(nb_entries)
+}
+
+enum Value {
+    String(String),
+    Number(u64),
+    Other,
+}
+
+/// Parses a single line, only as much of JSON as needed for the entries.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(line: &'a str) -> Self {
+        Self {
+            chars: line.char_indices().peekable(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn next(&mut self) -> Result<(usize, char), ParseError> {
+        self.chars.next().ok_or(ParseError::UnexpectedEnd)
+    }
+
+    fn peek(&mut self) -> Result<char, ParseError> {
+        self.skip_whitespace();
+        self.chars
+            .peek()
+            .map(|(_, c)| *c)
+            .ok_or(ParseError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+
+        match self.next()? {
+            (_, c) if c == expected => Ok(()),
+            (column, character) => Err(ParseError::UnexpectedCharacter { character, column }),
+        }
+    }
+
+    fn parse_hex4(&mut self, column: usize) -> Result<u32, ParseError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let (_, c) = self.next()?;
+            let digit = c.to_digit(16).ok_or(ParseError::InvalidEscape(column))?;
+            value = value * 16 + digit;
+        }
+
+        Ok(value)
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+
+        let mut result = String::new();
+
+        loop {
+            match self.next()? {
+                (_, '"') => return Ok(result),
+                (column, '\\') => {
+                    let c = match self.next()?.1 {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.parse_unicode_escape(column)?,
+                        _ => return Err(ParseError::InvalidEscape(column)),
+                    };
+                    result.push(c);
+                }
+                (_, c) => result.push(c),
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self, column: usize) -> Result<char, ParseError> {
+        let high = self.parse_hex4(column)?;
+
+        // Characters outside of the basic multilingual plane are escaped as a surrogate pair
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if self.next()?.1 != '\\' || self.next()?.1 != 'u' {
+                return Err(ParseError::InvalidEscape(column));
+            }
+
+            let low = self.parse_hex4(column)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(ParseError::InvalidEscape(column));
+            }
+
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+
+        char::from_u32(code).ok_or(ParseError::InvalidEscape(column))
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek()? {
+            '"' => Ok(Value::String(self.parse_string()?)),
+            '0'..='9' | '-' => {
+                let (column, _) = *self.chars.peek().unwrap();
+
+                let mut number = String::new();
+                while let Some((_, c)) = self
+                    .chars
+                    .next_if(|(_, c)| c.is_ascii_digit() || "+-.eE".contains(*c))
+                {
+                    number.push(c);
+                }
+
+                number
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| ParseError::InvalidNumber(column))
+            }
+            _ => {
+                for literal in ["null", "true", "false"] {
+                    let mut chars = self.chars.clone();
+                    if literal
+                        .chars()
+                        .all(|c| chars.next().is_some_and(|(_, n)| n == c))
+                    {
+                        self.chars = chars;
+                        return Ok(Value::Other);
+                    }
+                }
+
+                let (column, character) = self.next()?;
+                Err(ParseError::UnexpectedCharacter { character, column })
+            }
+        }
+    }
+
+    fn parse_entry(&mut self) -> Result<Entry, ParseError> {
+        let mut key = None;
+        let mut value = None;
+        let mut expires_at = None;
+
+        self.expect('{')?;
+
+        if self.peek()? == '}' {
+            self.next()?;
+        } else {
+            loop {
+                let name = self.parse_string()?;
+                self.expect(':')?;
+
+                match (name.as_str(), self.parse_value()?) {
+                    ("key", Value::String(s)) => key = Some(s),
+                    ("key", _) => return Err(ParseError::InvalidType("key")),
+                    ("value", Value::String(s)) => value = Some(s),
+                    ("value", _) => return Err(ParseError::InvalidType("value")),
+                    ("expires_at", Value::Number(millis)) => {
+                        expires_at = Some(keyspace::from_unix_millis(millis))
+                    }
+                    ("expires_at", Value::Other) => expires_at = None,
+                    ("expires_at", _) => return Err(ParseError::InvalidType("expires_at")),
+                    _ => {}
+                }
+
+                self.skip_whitespace();
+                match self.next()? {
+                    (_, ',') => continue,
+                    (_, '}') => break,
+                    (column, character) => {
+                        return Err(ParseError::UnexpectedCharacter { character, column })
+                    }
+                }
+            }
+        }
+
+        self.skip_whitespace();
+        if let Some((column, character)) = self.chars.next() {
+            return Err(ParseError::UnexpectedCharacter { character, column });
+        }
+
+        let key = key.ok_or(ParseError::MissingField("key"))?;
+        let value = value.ok_or(ParseError::MissingField("value"))?;
+
+        // NOTE(vincent): an entry a client couldn't have set would also break the append only file.
+        let size = command::encode(&[b"set", key.as_bytes(), value.as_bytes()]).len();
+        if size > protocol::MAX_MSG_LEN {
+            return Err(ParseError::TooLarge(size));
+        }
+
+        Ok((key, value, expires_at))
+    }
+}
+
+fn parse_entry(line: &str) -> Result<Entry, ParseError> {
+    Parser::new(line).parse_entry()
+}
+
+/// Call `insert` with every entry read from `reader`, returning the number of entries read.
+/// Empty lines are skipped.
+pub fn import<R, F>(reader: R, mut insert: F) -> Result<usize, ImportError>
+where
+    R: BufRead,
+    F: FnMut(Entry),
+{
+    let mut nb_entries = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry =
+            parse_entry(&line).map_err(|error| ImportError::InvalidEntry { line: i + 1, error })?;
+
+        insert(entry);
+        nb_entries += 1;
+    }
+
+    Ok(nb_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import, parse_entry, ImportError, ParseError};
+    use crate::server::keyspace::{self, Keyspace};
+
+    #[test]
+    fn export_import() {
+        let keyspace = Keyspace::new(4, 1, None);
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        keyspace.insert_with_expiry(
+            "quote\"d\n".to_string(),
+            "\u{1}\\ é 😀".to_string(),
+            Some(keyspace::from_unix_millis(4_000_000_000_000)),
+        );
+
+        let mut buf = Vec::new();
+        assert_eq!(2, export(&keyspace, &mut buf).unwrap());
+
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.contains("{\"key\":\"foo\",\"value\":\"bar\"}\n"));
+        assert!(text.contains(
+            "{\"key\":\"quote\\\"d\\n\",\"value\":\"\\u0001\\\\ é 😀\",\"expires_at\":4000000000000}\n"
+        ));
+
+        let mut entries = Vec::new();
+        assert_eq!(2, import(&buf[..], |entry| entries.push(entry)).unwrap());
+        entries.sort();
+
+        assert_eq!(
+            vec![
+                ("foo".to_string(), "bar".to_string(), None),
+                (
+                    "quote\"d\n".to_string(),
+                    "\u{1}\\ é 😀".to_string(),
+                    Some(keyspace::from_unix_millis(4_000_000_000_000))
+                ),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let (key, value, expires_at) = parse_entry(
+            r#" { "value" : "bé😀", "type": "string", "key":"a", "expires_at": null } "#,
+        )
+        .unwrap();
+        assert_eq!("a", key);
+        assert_eq!("bé😀", value);
+        assert_eq!(None, expires_at);
+
+        assert!(matches!(
+            parse_entry(r#"{"key":"a"}"#),
+            Err(ParseError::MissingField("value"))
+        ));
+        assert!(matches!(
+            parse_entry(r#"{"key":1,"value":"b"}"#),
+            Err(ParseError::InvalidType("key"))
+        ));
+        assert!(matches!(
+            parse_entry(r#"{"key":"a","value":"b","expires_at":-1}"#),
+            Err(ParseError::InvalidNumber(36))
+        ));
+        assert!(matches!(
+            parse_entry(r#"{"key":"a","value":"b"} x"#),
+            Err(ParseError::UnexpectedCharacter {
+                character: 'x',
+                column: 24
+            })
+        ));
+        assert!(matches!(
+            parse_entry(r#"{"key":"a","value":"b\x"}"#),
+            Err(ParseError::InvalidEscape(_))
+        ));
+        assert!(matches!(
+            parse_entry(r#"{"key":"a","value":"b"#),
+            Err(ParseError::UnexpectedEnd)
+        ));
+
+        let large = format!(r#"{{"key":"a","value":"{}"}}"#, "b".repeat(4096));
+        assert!(matches!(parse_entry(&large), Err(ParseError::TooLarge(_))));
+
+        let lines = "{\"key\":\"a\",\"value\":\"b\"}\n\n{\"key\":\"a\"}\n";
+        assert!(matches!(
+            import(lines.as_bytes(), |_| {}),
+            Err(ImportError::InvalidEntry { line: 3, .. })
+        ));
+    }
+}
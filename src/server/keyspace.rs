@@ -0,0 +1,799 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::server::hash_map::SuperHashMap;
+use crate::server::lazy_free::LazyFree;
+
+/// Number of keys sampled to find the least recently used one when evicting.
+const EVICTION_SAMPLES: usize = 5;
+/// Values at least this large are dropped by the lazy free thread instead of the caller.
+const LAZY_FREE_THRESHOLD: usize = 16 * 1024;
+
+struct Value {
+    data: String,
+    last_access: Instant,
+    /// Wall clock time so the expiration survives a restart, see the snapshot and the append only file.
+    expires_at: Option<SystemTime>,
+    /// Changed every time the key is written, see [`Keyspace::version`].
+    version: u64,
+}
+
+impl Value {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+type Shard = SuperHashMap<String, Value>;
+
+/// Called with every key removed because it expired.
+pub type ExpireHook = Box<dyn Fn(&str) + Send + Sync>;
+/// Called with every key written, expired, evicted or removed.
+pub type WriteHook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// Approximate memory used by an entry: its key, its value and the bookkeeping around them.
+///
+/// This is what MEMORY USAGE reports and what the eviction accounts for.
+fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len() + mem::size_of::<String>() + mem::size_of::<Value>()
+}
+
+/// Returns the number of milliseconds between the Unix epoch and `time`, as stored on disk.
+pub fn to_unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// An entry copied out of the keyspace: the key, the value and when it expires.
+pub type Entry = (String, String, Option<SystemTime>);
+
+/// The keyspace, split in multiple shards each protected by its own lock.
+///
+/// Commands touching different shards can run concurrently; a command only ever holds one shard lock at a time.
+pub struct Keyspace {
+    shards: Vec<Mutex<Shard>>,
+    /// Approximate memory used by every entry, see [`entry_size`].
+    used_memory: AtomicUsize,
+    /// Highest `used_memory` since we started.
+    peak_memory: AtomicUsize,
+    /// Drops the large values removed from the keyspace, if any.
+    lazy_free: Option<LazyFree>,
+    /// If false the expired keys are hidden but stay in memory, see [`Keyspace::set_expire_keys`].
+    expire_keys: AtomicBool,
+    expire_hook: OnceLock<ExpireHook>,
+    write_hook: OnceLock<WriteHook>,
+    next_version: AtomicU64,
+
+    random_state: RandomState,
+    random_counter: AtomicU64,
+}
+
+impl Keyspace {
+    pub fn new(nb_shards: usize, capacity: usize, lazy_free: Option<LazyFree>) -> Self {
+        assert!(nb_shards > 0);
+
+        let shards = (0..nb_shards)
+            .map(|_| Mutex::new(SuperHashMap::new(capacity)))
+            .collect();
+
+        Self {
+            shards,
+            used_memory: AtomicUsize::new(0),
+            peak_memory: AtomicUsize::new(0),
+            lazy_free,
+            expire_keys: AtomicBool::new(true),
+            expire_hook: OnceLock::new(),
+            write_hook: OnceLock::new(),
+            next_version: AtomicU64::new(0),
+            random_state: RandomState::new(),
+            random_counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the index of the shard owning `key`.
+    pub fn shard_index(&self, key: &str) -> usize {
+        let mut s = DefaultHasher::new();
+        key.hash(&mut s);
+
+        (s.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// Drop a value removed from the keyspace, on the lazy free thread if it's large.
+    fn free(&self, value: Value) {
+        match &self.lazy_free {
+            Some(lazy_free) if value.data.capacity() >= LAZY_FREE_THRESHOLD => {
+                lazy_free.free(value)
+            }
+            _ => drop(value),
+        }
+    }
+
+    /// Enable or disable removing the expired keys.
+    ///
+    /// Replicas disable it and wait for their primary to delete the expired keys instead, otherwise they could
+    /// disagree on when a key expired.
+    pub fn set_expire_keys(&self, enabled: bool) {
+        self.expire_keys.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set the hook called with every key removed because it expired. It can only be set once.
+    ///
+    /// The hook is called with the key's shard locked so no other command can change the key in the meantime.
+    pub fn set_expire_hook(&self, hook: ExpireHook) {
+        if self.expire_hook.set(hook).is_err() {
+            panic!("expire hook already set");
+        }
+    }
+
+    /// Set the hook called with every key changed in any way. It can only be set once.
+    ///
+    /// Like the expire hook, it's called with the key's shard locked.
+    pub fn set_write_hook(&self, hook: WriteHook) {
+        if self.write_hook.set(hook).is_err() {
+            panic!("write hook already set");
+        }
+    }
+
+    fn written(&self, key: &str) {
+        if let Some(hook) = self.write_hook.get() {
+            hook(key);
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        // NOTE(vincent): a poisoned lock means a thread panicked while modifying the shard, nothing we can do.
+        self.shards[self.shard_index(key)].lock().unwrap()
+    }
+
+    /// Remove `key` from `shard` if it expired and expiring keys is enabled, returning true if it expired.
+    ///
+    /// Keys are only expired when accessed: an expired key stays in memory until then, but is never visible.
+    fn expire_if_needed(&self, shard: &mut Shard, key: &str) -> bool {
+        let now = SystemTime::now();
+
+        if !shard
+            .get_mut(key)
+            .is_some_and(|value| value.is_expired(now))
+        {
+            return false;
+        }
+
+        if !self.expire_keys.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        if let Some(value) = shard.remove(key) {
+            self.used_memory
+                .fetch_sub(entry_size(key, &value.data), Ordering::Relaxed);
+            self.free(value);
+        }
+
+        if let Some(hook) = self.expire_hook.get() {
+            hook(key);
+        }
+        self.written(key);
+
+        true
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return None;
+        }
+
+        let value = shard.get_mut(key)?;
+        value.last_access = Instant::now();
+
+        Some(value.data.clone())
+    }
+
+    /// Returns the version of the key, without counting as an access, or `None` if it doesn't exist.
+    ///
+    /// Every write gives the key a version it never had, so a key was modified between two calls if they return
+    /// different versions. This is what WATCH relies on.
+    ///
+    /// NOTE(vincent): a key created then removed between the two calls doesn't exist either time, so it's not seen
+    /// as modified.
+    pub fn version(&self, key: &str) -> Option<u64> {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return None;
+        }
+
+        shard.get(key).map(|value| value.version)
+    }
+
+    fn next_version(&self) -> u64 {
+        self.next_version.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the memory used by the key, see [`entry_size`], without counting as an access.
+    pub fn memory_usage(&self, key: &str) -> Option<usize> {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return None;
+        }
+
+        shard.get(key).map(|value| entry_size(key, &value.data))
+    }
+
+    /// Returns true if the key exists, without counting as an access.
+    pub fn contains(&self, key: &str) -> bool {
+        let mut shard = self.shard(key);
+        !self.expire_if_needed(&mut shard, key) && shard.get(key).is_some()
+    }
+
+    pub fn insert(&self, key: String, value: String) {
+        self.insert_with_expiry(key, value, None)
+    }
+
+    /// Insert the key, replacing the previous value and expiration if any.
+    pub fn insert_with_expiry(&self, key: String, value: String, expires_at: Option<SystemTime>) {
+        let size = entry_size(&key, &value);
+        let value_len = value.len();
+
+        let value = Value {
+            data: value,
+            last_access: Instant::now(),
+            expires_at,
+            version: self.next_version(),
+        };
+
+        let mut shard = self.shard(&key);
+        self.written(&key);
+        let previous = shard.insert(key, value);
+        drop(shard);
+
+        match previous {
+            // Same key, only the value changed
+            Some(previous) => {
+                let previous_len = previous.data.len();
+                if value_len >= previous_len {
+                    self.add_used_memory(value_len - previous_len);
+                } else {
+                    self.used_memory
+                        .fetch_sub(previous_len - value_len, Ordering::Relaxed);
+                }
+
+                self.free(previous);
+            }
+            None => self.add_used_memory(size),
+        }
+    }
+
+    /// Insert the key only if it doesn't exist, returning false if it does.
+    pub fn try_insert(&self, key: String, value: String, expires_at: Option<SystemTime>) -> bool {
+        let mut shard = self.shard(&key);
+        if !self.expire_if_needed(&mut shard, &key) && shard.get(&key).is_some() {
+            return false;
+        }
+
+        self.add_used_memory(entry_size(&key, &value));
+
+        let value = Value {
+            data: value,
+            last_access: Instant::now(),
+            expires_at,
+            version: self.next_version(),
+        };
+
+        // An expired key is still there if expiring keys is disabled
+        let previous = shard.insert(key.clone(), value);
+        self.written(&key);
+        drop(shard);
+
+        if let Some(previous) = previous {
+            self.used_memory
+                .fetch_sub(entry_size(&key, &previous.data), Ordering::Relaxed);
+            self.free(previous);
+        }
+
+        true
+    }
+
+    /// Returns the value, the expiration and the version of the key, see [`Keyspace::version`], without counting as
+    /// an access.
+    pub fn get_versioned(&self, key: &str) -> Option<(String, Option<SystemTime>, u64)> {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return None;
+        }
+
+        shard
+            .get(key)
+            .map(|value| (value.data.clone(), value.expires_at, value.version))
+    }
+
+    /// Remove the key only if it still has `version`, returning false if it was changed or removed since.
+    pub fn remove_if_version(&self, key: &str, version: u64) -> bool {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return false;
+        }
+
+        match shard.get(key) {
+            Some(value) if value.version == version => {}
+            _ => return false,
+        }
+
+        let value = match shard.remove(key) {
+            Some(value) => value,
+            None => return false,
+        };
+        self.written(key);
+        drop(shard);
+
+        self.used_memory
+            .fetch_sub(entry_size(key, &value.data), Ordering::Relaxed);
+        self.free(value);
+
+        true
+    }
+
+    /// Remove the key, returning true if it existed.
+    ///
+    /// An expired key is removed even if expiring keys is disabled, but it didn't exist anymore.
+    pub fn remove(&self, key: &str) -> bool {
+        let mut shard = self.shard(key);
+        let expired = self.expire_if_needed(&mut shard, key);
+
+        let value = match shard.remove(key) {
+            Some(value) => value,
+            None => return false,
+        };
+        self.written(key);
+        drop(shard);
+
+        self.used_memory
+            .fetch_sub(entry_size(key, &value.data), Ordering::Relaxed);
+        self.free(value);
+
+        !expired
+    }
+
+    /// Set the time at which the key expires, returning false if it doesn't exist.
+    pub fn expire(&self, key: &str, at: SystemTime) -> bool {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return false;
+        }
+
+        match shard.get_mut(key) {
+            Some(value) => {
+                value.expires_at = Some(at);
+                value.version = self.next_version();
+                self.written(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the time left before the key expires: `None` if it doesn't exist, `Some(None)` if it never expires.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let mut shard = self.shard(key);
+        if self.expire_if_needed(&mut shard, key) {
+            return None;
+        }
+
+        let value = shard.get_mut(key)?;
+
+        Some(
+            value
+                .expires_at
+                .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default()),
+        )
+    }
+
+    /// Returns a copy of every key, one shard at a time.
+    pub fn keys(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut result = Vec::new();
+
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            result.reserve(shard.key_iter().len());
+
+            let keys = shard
+                .iter()
+                .filter(|(_, value)| !value.is_expired(now))
+                .map(|(key, _)| key.clone());
+            result.extend(keys);
+        }
+
+        result
+    }
+
+    /// Remove every key.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let old = mem::replace(&mut *shard, SuperHashMap::new(16));
+            for key in old.key_iter() {
+                self.written(key);
+            }
+            drop(shard);
+
+            let size: usize = old
+                .iter()
+                .map(|(key, value)| entry_size(key, &value.data))
+                .sum();
+            self.used_memory.fetch_sub(size, Ordering::Relaxed);
+
+            match &self.lazy_free {
+                Some(lazy_free) => lazy_free.free(old),
+                None => drop(old),
+            }
+        }
+    }
+
+    pub fn nb_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns a copy of every entry of the shard `index` which didn't expire.
+    ///
+    /// The shard is locked while copying, the other shards are still available.
+    pub fn shard_entries(&self, index: usize) -> Vec<Entry> {
+        let now = SystemTime::now();
+        let shard = self.shards[index].lock().unwrap();
+
+        shard
+            .iter()
+            .filter(|(_, value)| !value.is_expired(now))
+            .map(|(key, value)| (key.clone(), value.data.clone(), value.expires_at))
+            .collect()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory.load(Ordering::Relaxed)
+    }
+
+    fn add_used_memory(&self, size: usize) {
+        let used = self.used_memory.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_memory.fetch_max(used, Ordering::Relaxed);
+    }
+
+    /// Returns the number of keys, including the expired keys not removed yet.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Returns the memory used by the hash tables of the shards, on top of the memory used by the entries.
+    pub fn overhead(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().buckets_size())
+            .sum()
+    }
+
+    fn random(&self) -> usize {
+        let mut s = self.random_state.build_hasher();
+        s.write_u64(self.random_counter.fetch_add(1, Ordering::Relaxed));
+
+        s.finish() as usize
+    }
+
+    /// Evict keys until the memory used is at most `max_memory`, returning the keys evicted.
+    ///
+    /// This is an approximated LRU: a few keys of a random shard are sampled and the least recently used one
+    /// is evicted.
+    pub fn evict(&self, max_memory: usize) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while self.used_memory() > max_memory {
+            let first = self.random();
+
+            // Find a shard with something to evict, starting from a random one
+            let victim = (0..self.shards.len()).find_map(|i| {
+                let index = (first + i) % self.shards.len();
+                let mut shard = self.shards[index].lock().unwrap();
+
+                let key = shard
+                    .sample(self.random(), EVICTION_SAMPLES)
+                    .into_iter()
+                    .min_by_key(|(_, value)| value.last_access)
+                    .map(|(key, _)| key.clone())?;
+
+                let value = shard.remove(&key)?;
+                self.written(&key);
+
+                Some((key, value))
+            });
+
+            let (key, value) = match victim {
+                Some(victim) => victim,
+                // Nothing left to evict
+                None => break,
+            };
+
+            self.used_memory
+                .fetch_sub(entry_size(&key, &value.data), Ordering::Relaxed);
+            self.free(value);
+            evicted.push(key);
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_unix_millis, to_unix_millis, Keyspace, LAZY_FREE_THRESHOLD};
+    use crate::server::lazy_free::LazyFree;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn keyspace() {
+        let keyspace = Keyspace::new(4, 1, None);
+
+        for i in 0..100 {
+            keyspace.insert(format!("foo{}", i), format!("bar{}", i));
+        }
+
+        assert_eq!(Some("bar10".to_string()), keyspace.get("foo10"));
+        assert_eq!(100, keyspace.keys().len());
+
+        assert!(keyspace.remove("foo10"));
+        assert!(!keyspace.remove("foo10"));
+        assert_eq!(None, keyspace.get("foo10"));
+        assert_eq!(99, keyspace.keys().len());
+    }
+
+    #[test]
+    fn expire() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        keyspace.insert_with_expiry("bar".to_string(), "baz".to_string(), Some(now + hour));
+        assert_eq!(Some(None), keyspace.ttl("foo"));
+        assert!(keyspace.ttl("bar").unwrap().unwrap() <= hour);
+        assert_eq!(None, keyspace.ttl("baz"));
+
+        assert!(keyspace.expire("foo", now - hour));
+        assert!(!keyspace.expire("baz", now + hour));
+
+        // Expired keys are never visible
+        assert_eq!(vec!["bar".to_string()], keyspace.keys());
+        assert_eq!(1, keyspace.shard_entries(keyspace.shard_index("bar")).len());
+        assert!(keyspace
+            .shard_entries(keyspace.shard_index("foo"))
+            .iter()
+            .all(|(key, _, _)| key != "foo"));
+
+        assert_eq!(None, keyspace.get("foo"));
+        assert!(!keyspace.contains("foo"));
+        assert!(!keyspace.remove("foo"));
+        assert_eq!(None, keyspace.ttl("foo"));
+        assert!(keyspace.contains("bar"));
+
+        // Setting the value again clears the expiration
+        keyspace.insert("bar".to_string(), "qux".to_string());
+        assert_eq!(Some(None), keyspace.ttl("bar"));
+
+        assert!(keyspace.remove("bar"));
+        assert_eq!(0, keyspace.used_memory());
+    }
+
+    #[test]
+    fn expire_hook() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let past = SystemTime::now() - Duration::from_secs(1);
+
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        {
+            let expired = Arc::clone(&expired);
+            keyspace.set_expire_hook(Box::new(move |key| {
+                expired.lock().unwrap().push(key.to_string())
+            }));
+        }
+
+        keyspace.insert_with_expiry("foo".to_string(), "bar".to_string(), Some(past));
+        keyspace.insert_with_expiry("bar".to_string(), "baz".to_string(), Some(past));
+
+        assert_eq!(None, keyspace.get("foo"));
+        assert_eq!(None, keyspace.get("foo"));
+        assert_eq!(vec!["foo".to_string()], *expired.lock().unwrap());
+
+        // Expired keys are hidden but only removed explicitly
+        keyspace.set_expire_keys(false);
+
+        assert_eq!(None, keyspace.get("bar"));
+        assert_eq!(None, keyspace.ttl("bar"));
+        assert!(!keyspace.expire("bar", SystemTime::now()));
+        assert!(keyspace.used_memory() > 0);
+
+        assert!(!keyspace.remove("bar"));
+        assert_eq!(0, keyspace.used_memory());
+        assert_eq!(1, expired.lock().unwrap().len());
+    }
+
+    #[test]
+    fn write_hook() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let past = SystemTime::now() - Duration::from_secs(1);
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        {
+            let written = Arc::clone(&written);
+            keyspace.set_write_hook(Box::new(move |key| {
+                written.lock().unwrap().push(key.to_string())
+            }));
+        }
+
+        keyspace.insert("a".to_string(), "1".to_string());
+        assert!(keyspace.try_insert("b".to_string(), "2".to_string(), None));
+        assert!(!keyspace.try_insert("b".to_string(), "3".to_string(), None));
+        assert!(keyspace.expire("a", past));
+        // Reads only write the keys which expired
+        assert_eq!(None, keyspace.get("a"));
+        assert_eq!(Some("2".to_string()), keyspace.get("b"));
+        assert!(keyspace.remove("b"));
+        assert!(!keyspace.remove("b"));
+
+        keyspace.insert("c".to_string(), "3".to_string());
+        keyspace.clear();
+
+        assert_eq!(
+            vec!["a", "b", "a", "a", "b", "c", "c"],
+            *written.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn try_insert_remove_if_version() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let past = SystemTime::now() - Duration::from_secs(1);
+
+        assert!(keyspace.try_insert("foo".to_string(), "bar".to_string(), None));
+        assert!(!keyspace.try_insert("foo".to_string(), "baz".to_string(), None));
+        assert_eq!(Some("bar".to_string()), keyspace.get("foo"));
+
+        // Expired keys don't exist
+        keyspace.insert_with_expiry("bar".to_string(), "baz".to_string(), Some(past));
+        assert!(keyspace.try_insert("bar".to_string(), "qux".to_string(), None));
+        assert_eq!(Some("qux".to_string()), keyspace.get("bar"));
+
+        let (value, expires_at, version) = keyspace.get_versioned("foo").unwrap();
+        assert_eq!(("bar", None), (value.as_str(), expires_at));
+
+        // Changed in the meantime
+        keyspace.insert("foo".to_string(), "baz".to_string());
+        assert!(!keyspace.remove_if_version("foo", version));
+        assert!(keyspace.contains("foo"));
+
+        let (_, _, version) = keyspace.get_versioned("foo").unwrap();
+        assert!(keyspace.remove_if_version("foo", version));
+        assert!(!keyspace.contains("foo"));
+        assert!(!keyspace.remove_if_version("foo", version));
+        assert_eq!(None, keyspace.get_versioned("foo"));
+
+        keyspace.remove("bar");
+        assert_eq!(0, keyspace.used_memory());
+    }
+
+    #[test]
+    fn version() {
+        let keyspace = Keyspace::new(4, 1, None);
+        assert_eq!(None, keyspace.version("foo"));
+
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        let first = keyspace.version("foo");
+        assert!(first.is_some());
+
+        // Reading doesn't change it
+        keyspace.get("foo");
+        assert_eq!(first, keyspace.version("foo"));
+
+        // Writing the same value does
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        let second = keyspace.version("foo");
+        assert_ne!(first, second);
+
+        keyspace.expire("foo", SystemTime::now() + Duration::from_secs(3600));
+        assert_ne!(second, keyspace.version("foo"));
+
+        // A removed then recreated key has a new version
+        let third = keyspace.version("foo");
+        keyspace.remove("foo");
+        assert_eq!(None, keyspace.version("foo"));
+        assert!(keyspace.try_insert("foo".to_string(), "bar".to_string(), None));
+        assert_ne!(third, keyspace.version("foo"));
+    }
+
+    #[test]
+    fn unix_millis() {
+        let time = from_unix_millis(1_700_000_000_123);
+        assert_eq!(1_700_000_000_123, to_unix_millis(time));
+    }
+
+    #[test]
+    fn used_memory() {
+        let keyspace = Keyspace::new(4, 1, None);
+        assert_eq!(0, keyspace.used_memory());
+
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        let used = keyspace.used_memory();
+        assert!(used > 6);
+
+        keyspace.insert("foo".to_string(), "barbaz".to_string());
+        assert_eq!(used + 3, keyspace.used_memory());
+
+        assert_eq!(Some(used + 3), keyspace.memory_usage("foo"));
+        assert_eq!(None, keyspace.memory_usage("bar"));
+
+        keyspace.remove("foo");
+        assert_eq!(0, keyspace.used_memory());
+        assert_eq!(used + 3, keyspace.peak_memory());
+    }
+
+    #[test]
+    fn lazy_free() {
+        let keyspace = Keyspace::new(4, 1, Some(LazyFree::new().unwrap()));
+
+        keyspace.insert("foo".to_string(), "a".repeat(LAZY_FREE_THRESHOLD));
+        keyspace.insert("foo".to_string(), "b".repeat(LAZY_FREE_THRESHOLD));
+        keyspace.insert("bar".to_string(), "c".to_string());
+
+        assert!(keyspace.remove("foo"));
+        assert!(keyspace.remove("bar"));
+        assert_eq!(0, keyspace.used_memory());
+    }
+
+    #[test]
+    fn evict() {
+        let keyspace = Keyspace::new(4, 1, None);
+
+        for i in 0..100 {
+            keyspace.insert(format!("foo{}", i), format!("bar{}", i));
+        }
+
+        let max_memory = keyspace.used_memory() / 2;
+        let evicted = keyspace.evict(max_memory);
+
+        assert!(!evicted.is_empty());
+        assert!(keyspace.used_memory() <= max_memory);
+        assert_eq!(100 - evicted.len(), keyspace.keys().len());
+        assert!(evicted.iter().all(|key| keyspace.get(key).is_none()));
+
+        assert!(!keyspace.evict(0).is_empty());
+        assert!(keyspace.keys().is_empty());
+        assert_eq!(0, keyspace.used_memory());
+    }
+
+    #[test]
+    fn clear() {
+        let keyspace = Keyspace::new(4, 1, Some(LazyFree::new().unwrap()));
+
+        for i in 0..100 {
+            keyspace.insert(format!("foo{}", i), format!("bar{}", i));
+        }
+
+        keyspace.clear();
+        assert!(keyspace.keys().is_empty());
+        assert_eq!(0, keyspace.used_memory());
+
+        keyspace.insert("foo".to_string(), "bar".to_string());
+        assert_eq!(Some("bar".to_string()), keyspace.get("foo"));
+    }
+}
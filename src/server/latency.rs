@@ -0,0 +1,291 @@
+//! Latency of the commands, for the LATENCY command and INFO.
+//!
+//! Every execution is recorded in a histogram per command, from which the percentiles are computed, along with the
+//! total time spent in the command and the number of executions which failed. The executions
+//! slower than the monitor threshold are also kept in a short history per command, like the latency monitor of Redis.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Number of buckets per power of two, values are recorded with a relative error below 1/16th.
+const SUB_BUCKETS: usize = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// Enough buckets for every u64.
+const NB_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+/// Number of samples kept in the history of a command.
+const HISTORY_LEN: usize = 160;
+
+/// Index of the bucket of `value`. The values below [`SUB_BUCKETS`] have a bucket of their own, the others share
+/// buckets whose width doubles with every power of two.
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    let exponent = 63 - value.leading_zeros();
+    let mantissa = (value >> (exponent - SUB_BUCKET_BITS)) as usize;
+
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + mantissa - SUB_BUCKETS
+}
+
+/// Highest value recorded in the bucket at `index`.
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let mantissa = (index % SUB_BUCKETS + SUB_BUCKETS) as u64;
+
+    ((mantissa + 1) << shift).wrapping_sub(1)
+}
+
+/// Counts of values, in buckets of logarithmic width like an HDR histogram.
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; NB_BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Returns the value below which `percentile` percent of the values are, 0 if there are none.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_upper_bound(index).min(self.max);
+            }
+        }
+
+        0
+    }
+}
+
+/// Percentiles of the latency of a command, in microseconds.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub command: String,
+    pub calls: u64,
+    /// Executions which replied with an error.
+    pub failed_calls: u64,
+    pub total: u64,
+    pub p50: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+struct CommandLatency {
+    histogram: Histogram,
+    failed_calls: u64,
+    /// Sum of the latencies in microseconds.
+    total: u64,
+    /// Unix timestamp in seconds and the slowest latency in milliseconds of that second, oldest first.
+    history: VecDeque<(u64, u64)>,
+}
+
+pub struct Latency {
+    by_command: Mutex<HashMap<Vec<u8>, CommandLatency>>,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self {
+            by_command: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an execution of `command`, adding it to its history if it took at least `threshold`.
+    /// The history is disabled if `threshold` is zero.
+    pub fn record(&self, command: &[u8], latency: Duration, failed: bool, threshold: Duration) {
+        let mut by_command = self.by_command.lock().unwrap();
+
+        let entry = match by_command.get_mut(command) {
+            Some(entry) => entry,
+            None => by_command
+                .entry(command.to_vec())
+                .or_insert_with(|| CommandLatency {
+                    histogram: Histogram::new(),
+                    failed_calls: 0,
+                    total: 0,
+                    history: VecDeque::new(),
+                }),
+        };
+
+        let micros = latency.as_micros() as u64;
+        entry.histogram.record(micros);
+        entry.total += micros;
+        if failed {
+            entry.failed_calls += 1;
+        }
+
+        if threshold.is_zero() || latency < threshold {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let millis = latency.as_millis() as u64;
+
+        // One sample per second, the slowest
+        match entry.history.back_mut() {
+            Some((time, max)) if *time == now => *max = (*max).max(millis),
+            _ => {
+                if entry.history.len() == HISTORY_LEN {
+                    entry.history.pop_front();
+                }
+                entry.history.push_back((now, millis));
+            }
+        }
+    }
+
+    /// Returns the samples of the history of `command`, oldest first.
+    pub fn history(&self, command: &[u8]) -> Vec<(u64, u64)> {
+        match self.by_command.lock().unwrap().get(command) {
+            Some(entry) => entry.history.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the summaries of `commands`, or of every command executed if empty, sorted by command.
+    pub fn summaries(&self, commands: &[&[u8]]) -> Vec<Summary> {
+        let by_command = self.by_command.lock().unwrap();
+
+        let mut summaries: Vec<Summary> = by_command
+            .iter()
+            .filter(|(command, _)| commands.is_empty() || commands.contains(&command.as_slice()))
+            .map(|(command, entry)| Summary {
+                command: String::from_utf8_lossy(command).into_owned(),
+                calls: entry.histogram.count(),
+                failed_calls: entry.failed_calls,
+                total: entry.total,
+                p50: entry.histogram.percentile(50.0),
+                p99: entry.histogram.percentile(99.0),
+                p999: entry.histogram.percentile(99.9),
+                max: entry.histogram.max(),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.command.cmp(&b.command));
+
+        summaries
+    }
+
+    /// Forget the latencies of `commands`, or of every command if empty, returning how many were forgotten.
+    pub fn reset(&self, commands: &[&[u8]]) -> usize {
+        let mut by_command = self.by_command.lock().unwrap();
+
+        if commands.is_empty() {
+            let nb_commands = by_command.len();
+            by_command.clear();
+            return nb_commands;
+        }
+
+        commands
+            .iter()
+            .filter(|command| by_command.remove(**command).is_some())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_index, bucket_upper_bound, Histogram, Latency, NB_BUCKETS};
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        for value in 0..16 {
+            assert_eq!(value as usize, bucket_index(value));
+        }
+        assert_eq!(16, bucket_index(16));
+        assert_eq!(31, bucket_index(31));
+        assert_eq!(32, bucket_index(32));
+        assert_eq!(32, bucket_index(33));
+        assert_eq!(NB_BUCKETS - 1, bucket_index(u64::MAX));
+
+        // Every value is in a bucket whose upper bound is close to it
+        for value in [17, 100, 1000, 12345, 1 << 40, u64::MAX] {
+            let upper = bucket_upper_bound(bucket_index(value));
+            assert!(upper >= value);
+            assert!(upper - value <= value / 16);
+        }
+        assert_eq!(u64::MAX, bucket_upper_bound(NB_BUCKETS - 1));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(0, histogram.percentile(50.0));
+
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        assert_eq!(1000, histogram.count());
+        assert_eq!(1000, histogram.max());
+
+        let p50 = histogram.percentile(50.0);
+        assert!((500..=532).contains(&p50), "p50 {}", p50);
+        let p99 = histogram.percentile(99.0);
+        assert!((990..=1000).contains(&p99), "p99 {}", p99);
+        assert_eq!(1000, histogram.percentile(100.0));
+    }
+
+    #[test]
+    fn history() {
+        let latency = Latency::new();
+        let threshold = Duration::from_millis(10);
+
+        latency.record(b"get", Duration::from_millis(1), false, threshold);
+        latency.record(b"get", Duration::from_millis(20), true, threshold);
+        latency.record(b"get", Duration::from_millis(15), false, threshold);
+        latency.record(b"set", Duration::from_millis(50), false, Duration::ZERO);
+
+        // Both slow executions happened in the same second
+        let history = latency.history(b"get");
+        assert_eq!(1, history.len());
+        assert_eq!(20, history[0].1);
+        assert!(latency.history(b"set").is_empty());
+
+        let summaries = latency.summaries(&[]);
+        assert_eq!(2, summaries.len());
+        assert_eq!("get", summaries[0].command);
+        assert_eq!(3, summaries[0].calls);
+        assert_eq!(1, summaries[0].failed_calls);
+        assert_eq!(36_000, summaries[0].total);
+        assert_eq!(20_000, summaries[0].max);
+
+        assert_eq!(1, latency.reset(&[b"set", b"del"]));
+        assert_eq!(1, latency.summaries(&[]).len());
+        assert_eq!(1, latency.reset(&[]));
+        assert!(latency.history(b"get").is_empty());
+    }
+}
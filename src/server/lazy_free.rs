@@ -0,0 +1,82 @@
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+type Garbage = Box<dyn Send>;
+
+/// Drops values on a background thread, so freeing large values doesn't block the thread which removed them.
+pub struct LazyFree {
+    garbage: Option<mpsc::Sender<Garbage>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LazyFree {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Garbage>();
+
+        let thread = thread::Builder::new()
+            .name("lazy-free".to_string())
+            .spawn(move || {
+                // Dropping the values is the whole point
+                for garbage in receiver {
+                    drop(garbage);
+                }
+            })?;
+
+        Ok(Self {
+            garbage: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Hand `value` to the background thread which will drop it.
+    pub fn free<T: Send + 'static>(&self, value: T) {
+        // NOTE(vincent): safe because the sender is only taken when dropping
+        let garbage = self.garbage.as_ref().unwrap();
+
+        if let Err(mpsc::SendError(value)) = garbage.send(Box::new(value)) {
+            // The thread is gone, drop it here
+            drop(value);
+        }
+    }
+}
+
+impl Drop for LazyFree {
+    fn drop(&mut self) {
+        // Closing the channel makes the thread exit once everything was dropped
+        drop(self.garbage.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LazyFree;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Tracked(Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn lazy_free() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let lazy_free = LazyFree::new().unwrap();
+        for _ in 0..10 {
+            lazy_free.free(Tracked(Arc::clone(&dropped)));
+        }
+
+        // Everything sent is dropped before the thread exits
+        drop(lazy_free);
+        assert_eq!(10, dropped.load(Ordering::SeqCst));
+    }
+}
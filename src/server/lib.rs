@@ -0,0 +1,3895 @@
+//! The server, run by the `server` binary or started on a thread of another program with [`Server`].
+
+use crate::{command, debug, error, info, log, protocol, trace, warn};
+use crate::{OwnedSocket, ResponseCode};
+use anyhow::Context as _;
+use aof::AppendOnlyFile;
+use audit::AuditLog;
+use clients::{Client, Clients, KillFilter};
+use cluster::{Cluster, Owner, Route};
+use commands::{Commands, RegisterError};
+use config::{EvictionPolicy, Mode};
+use connection_buffer::{BufferError, ConnectionBuffer};
+use dirty::Dirty;
+use error_iter::ErrorIter as _;
+use failover::Failover;
+use hooks::Hooks;
+use keyspace::Keyspace;
+use latency::Latency;
+use lazy_free::LazyFree;
+use libc::{SO_REUSEADDR, SO_REUSEPORT};
+use onlyerror::Error;
+use peer::PeerError;
+use poller::{DefaultPoller, Event, Interest, Poller};
+use pubsub::PubSub;
+use replication::Replication;
+use script::Script;
+use snapshot::BackgroundSaver;
+use stats::Stats;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use timer_wheel::TimerWheel;
+use tracking::Tracking;
+use workers::{Completion, Job, Mailbox, MailboxSender, WorkerPool};
+use write_queue::WriteQueue;
+
+mod aof;
+mod audit;
+mod clients;
+mod cluster;
+mod commands;
+mod config;
+mod connection_buffer;
+mod crc64;
+mod dirty;
+mod embedded;
+mod failover;
+mod glob;
+mod hash_map;
+mod hooks;
+mod json;
+mod keyspace;
+mod latency;
+mod lazy_free;
+mod migrate;
+mod modules;
+mod otlp;
+mod peer;
+mod poller;
+mod pubsub;
+mod replication;
+mod script;
+mod sha1;
+mod snapshot;
+mod stats;
+mod timer_wheel;
+mod tracking;
+mod workers;
+mod write_queue;
+
+pub use config::ServerConfig;
+pub use embedded::{Builder, Server};
+pub use hooks::Flow;
+pub use latency::Summary;
+pub use stats::MetricsSnapshot;
+
+const NB_SHARDS: usize = 16;
+/// Longest string which fits in a response, with its data type and length.
+const MAX_RESPONSE_STRING_LEN: usize = protocol::MAX_MSG_LEN - 5;
+const POLL_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Maximum number of responses sent with a single `writev(2)` call.
+const MAX_IOVECS: usize = 64;
+const TIMERS_SLOTS: usize = 64;
+const TIMERS_RESOLUTION: Duration = Duration::from_millis(100);
+/// How often to check a connection without any deadline, in case a timeout was enabled since.
+const TIMERS_DISABLED_CHECK: Duration = Duration::from_secs(1);
+/// Longest a MIGRATE waits for every network operation, whatever timeout the client asked for.
+const MAX_MIGRATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Context {
+    config: RwLock<ServerConfig>,
+    data: Arc<Keyspace>,
+    /// Number of connected clients, across every event loop.
+    nb_clients: AtomicUsize,
+    clients: Clients,
+    /// Number of keys changed, for the save rules and INFO.
+    dirty: Dirty,
+    /// Latency of every command, for LATENCY and INFO.
+    latency: Latency,
+    stats: Stats,
+    saver: BackgroundSaver,
+    /// Opened once the data is loaded at startup, if enabled.
+    aof: Mutex<Option<AppendOnlyFile>>,
+    replication: Replication,
+    /// Set in cluster mode only.
+    cluster: Option<RwLock<Cluster>>,
+    failover: Failover,
+    /// Shard channels, for SSUBSCRIBE and SPUBLISH.
+    pubsub: PubSub,
+    /// Keys read by the clients with tracking enabled, for CLIENT TRACKING.
+    tracking: Tracking,
+    /// Held for reading by the client requests and for writing by EXEC and the scripts, so that they run alone.
+    transaction: RwLock<()>,
+    /// Scripts loaded by SCRIPT LOAD or EVAL, by their SHA-1 digest.
+    scripts: Mutex<HashMap<String, Arc<Script>>>,
+    /// Set if the spans are exported.
+    otlp: Option<otlp::Exporter>,
+    /// Set if the administrative commands are audited.
+    audit: Option<AuditLog>,
+    /// Commands executed by `do_request` without being part of its chain, see [`Context::register_command`].
+    commands: Commands<Context>,
+    /// Called around the requests of the clients, see [`Context::on_command`].
+    hooks: Hooks,
+    /// Wake up every event loop to stop it, for SHUTDOWN.
+    event_loops: Mutex<Vec<MailboxSender<()>>>,
+}
+
+impl Context {
+    fn new(config: ServerConfig, nb_shards: usize) -> io::Result<Self> {
+        let replication = Replication::new(config.replica_of);
+        let cluster = config.cluster_enabled.then(|| {
+            RwLock::new(Cluster::new(
+                config.announce_address(),
+                &config.cluster_slots,
+            ))
+        });
+
+        let audit = match &config.audit_log {
+            Some(path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
+        let otlp = match config.otlp_endpoint {
+            Some(endpoint) => Some(otlp::Exporter::new(endpoint)?),
+            None => None,
+        };
+
+        let data = Keyspace::new(nb_shards, 16, Some(LazyFree::new()?));
+        data.set_expire_keys(config.replica_of.is_none());
+
+        let context = Self {
+            config: RwLock::new(config),
+            data: Arc::new(data),
+            nb_clients: AtomicUsize::new(0),
+            clients: Clients::new(),
+            dirty: Dirty::new(),
+            latency: Latency::new(),
+            stats: Stats::new(),
+            saver: BackgroundSaver::new(),
+            aof: Mutex::new(None),
+            replication,
+            cluster,
+            failover: Failover::new(),
+            pubsub: PubSub::new(),
+            tracking: Tracking::new(),
+            transaction: RwLock::new(()),
+            scripts: Mutex::new(HashMap::new()),
+            otlp,
+            audit,
+            commands: Commands::new(),
+            hooks: Hooks::new(),
+            event_loops: Mutex::new(Vec::new()),
+        };
+
+        // NOTE(vincent): safe because they're registered once with a valid arity
+        context
+            .register_command("get", 2, |context, args, writer| {
+                do_get(context, args, writer);
+                0
+            })
+            .unwrap();
+        context.register_command("set", -3, do_set).unwrap();
+        context.register_command("del", -2, do_del).unwrap();
+
+        Ok(context)
+    }
+
+    /// Register the command `name` with its `arity`, counting the name, see [`commands`].
+    ///
+    /// `handler` is called with the arguments and returns the number of keys it changed, these changes are then
+    /// propagated and counted for the save rules like those of any other command.
+    ///
+    /// NOTE(vincent): a registered command isn't routed in cluster mode and isn't refused by a read-only replica,
+    /// only the commands of `request_key` and `is_write_command` are.
+    fn register_command<F>(&self, name: &str, arity: i32, handler: F) -> Result<(), RegisterError>
+    where
+        F: Fn(&Context, &[&[u8]], &mut protocol::Writer) -> u64 + Send + Sync + 'static,
+    {
+        self.commands.register(name, arity, Arc::new(handler))
+    }
+
+    /// Call `before` before executing every request of a client and `after` once its response is written, see
+    /// [`hooks`].
+    fn on_command<B, A>(&self, before: B, after: A)
+    where
+        B: Fn(&[u8], &[&[u8]], &mut protocol::Writer) -> Flow + Send + Sync + 'static,
+        A: Fn(&[u8], &[&[u8]], &[u8]) + Send + Sync + 'static,
+    {
+        self.hooks.add(Arc::new(before), Arc::new(after));
+    }
+
+    fn metrics(&self) -> MetricsSnapshot {
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        MetricsSnapshot {
+            total_commands: counter(&self.stats.total_commands),
+            total_connections: counter(&self.stats.total_connections),
+            connected_clients: self.nb_clients.load(Ordering::Relaxed),
+            keys: self.data.len(),
+            used_memory: self.data.used_memory(),
+            peak_memory: self.data.peak_memory(),
+            keyspace_hits: counter(&self.stats.keyspace_hits),
+            keyspace_misses: counter(&self.stats.keyspace_misses),
+            expired_keys: counter(&self.stats.expired_keys),
+            evicted_keys: counter(&self.stats.evicted_keys),
+            net_input_bytes: counter(&self.stats.net_input_bytes),
+            net_output_bytes: counter(&self.stats.net_output_bytes),
+            commands: self.latency.summaries(&[]),
+        }
+    }
+}
+
+impl modules::Host for Context {
+    fn get(&self, key: &str) -> Option<String> {
+        let value = self.data.get(key);
+        self.stats.record_lookup(value.is_some());
+        value
+    }
+
+    fn set(&self, key: String, value: String) -> bool {
+        if !reclaim_memory(self) {
+            return false;
+        }
+
+        self.data.insert(key, value);
+        true
+    }
+
+    fn del(&self, key: &str) -> bool {
+        self.data.remove(key)
+    }
+}
+
+impl failover::Node for Context {
+    fn address(&self) -> SocketAddrV4 {
+        self.config.read().unwrap().announce_address()
+    }
+
+    fn primary(&self) -> Option<SocketAddrV4> {
+        self.config.read().unwrap().replica_of
+    }
+
+    fn password(&self) -> Option<String> {
+        self.config.read().unwrap().masterauth.clone()
+    }
+
+    fn down_after(&self) -> Duration {
+        self.config.read().unwrap().failover_down_after
+    }
+
+    fn promote(&self) {
+        change_primary(self, None);
+    }
+
+    fn follow(&self, primary: SocketAddrV4) {
+        change_primary(self, Some(primary));
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    ReadRequest,
+    /// A request was handed to the worker pool and we're waiting for its response.
+    Processing,
+    SendResponse,
+    /// The client is a replica which sent PSYNC, the connection is about to be handed to the replication.
+    Replica(protocol::Psync),
+}
+
+impl State {
+    fn interest(&self) -> Interest {
+        match self {
+            State::ReadRequest => Interest::Read,
+            State::Processing => Interest::None,
+            State::SendResponse => Interest::Write,
+            State::Replica(_) => Interest::None,
+        }
+    }
+}
+
+/// How requests are executed.
+enum Dispatch {
+    /// Execute every request on the event loop.
+    Inline,
+    /// Execute every request on the worker pool.
+    Workers(Arc<WorkerPool>),
+    /// Execute the requests whose key is owned by this event loop right away and forward the others to the event loop
+    /// owning them. Requests without a key are executed right away.
+    Sharded {
+        index: usize,
+        peers: Vec<MailboxSender<Job>>,
+        /// Receives the requests forwarded by the other event loops.
+        jobs: Mailbox<Job>,
+    },
+}
+
+struct Dispatcher {
+    dispatch: Dispatch,
+    /// Receives the responses to the requests executed outside of the event loop.
+    completions: Mailbox<Completion>,
+    /// Receives the messages published to the channels the connections subscribed to.
+    messages: Mailbox<pubsub::Message>,
+    /// Receives the request to stop the event loop, from the handle of an embedded server.
+    stop: Mailbox<()>,
+}
+
+impl Dispatcher {
+    /// Hand the request to whoever is responsible for executing it.
+    /// Returns `false` if the request must be executed by the caller.
+    fn submit(&self, context: &Context, connection: &Connection, body: &[u8]) -> bool {
+        let job = || Job {
+            conn_id: connection.id,
+            fd: connection.fd,
+            body: body.to_vec(),
+            completions: self.completions.sender(),
+        };
+
+        match &self.dispatch {
+            Dispatch::Inline => false,
+            Dispatch::Workers(pool) => {
+                pool.submit(job());
+                true
+            }
+            Dispatch::Sharded { index, peers, .. } => {
+                let owner = match request_key(body) {
+                    Some(key) => context.data.shard_index(key),
+                    None => return false,
+                };
+
+                if owner == *index {
+                    return false;
+                }
+
+                peers[owner].send(job());
+                true
+            }
+        }
+    }
+}
+
+/// Returns the key of the request, if the command has one.
+fn request_key(body: &[u8]) -> Option<&str> {
+    let request = command::parse(body).ok()?;
+
+    match request[..] {
+        // The invalidations of CLIENT TRACKING are sent by every node
+        [b"ssubscribe", channel, ..] if channel == tracking::INVALIDATE_CHANNEL => None,
+        [b"get", key, ..]
+        | [b"set", key, ..]
+        | [b"setex", key, ..]
+        | [b"expire", key, ..]
+        | [b"pexpireat", key, ..]
+        | [b"ttl", key, ..]
+        | [b"del", key, ..]
+        | [b"dump", key, ..]
+        | [b"restore", key, ..]
+        | [b"migrate", _, _, key, ..]
+        | [b"watch", key, ..]
+        // Shard channels are routed like keys
+        | [b"ssubscribe", key, ..]
+        | [b"spublish", key, ..] => std::str::from_utf8(key).ok(),
+        // The keys of a script come first in its arguments, if it has any
+        [b"eval" | b"evalsha", _, numkeys, key, ..] if numkeys != b"0" => {
+            std::str::from_utf8(key).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Returns the number of keys `request` reads or writes.
+fn nb_keys(request: &[&[u8]]) -> usize {
+    match request {
+        [b"del", keys @ ..] => keys.len(),
+        [b"get", ..]
+        | [b"set", ..]
+        | [b"setex", ..]
+        | [b"expire", ..]
+        | [b"pexpireat", ..]
+        | [b"ttl", ..]
+        | [b"dump", ..]
+        | [b"restore", ..]
+        | [b"migrate", ..]
+        | [b"memory", b"usage", ..] => 1,
+        _ => 0,
+    }
+}
+
+/// The timeouts applied to connections, read from the config at every iteration of the event loop.
+struct Timeouts {
+    idle: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+}
+
+impl Timeouts {
+    fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            idle: config.idle_timeout,
+            read: config.read_timeout,
+            write: config.write_timeout,
+        }
+    }
+}
+
+struct Connection {
+    id: u64,
+    fd: i32,
+    addr: SocketAddrV4,
+    /// Metadata shared with the CLIENT command.
+    client: Arc<Client>,
+    state: State,
+
+    /// Length of the request currently processed by the worker pool, consumed from `read_buf` once it completes.
+    in_flight: usize,
+    last_activity: Instant,
+    /// When the first bytes of the partial request in `read_buf` were received.
+    request_started: Option<Instant>,
+    /// When the connection started waiting for the client to read the responses in `write_buf`.
+    response_started: Option<Instant>,
+    /// Deadline of the connection's live timer, the other timers are stale.
+    next_check: Option<Instant>,
+    /// Set once the client sent the right password with AUTH, only checked if a password is required.
+    authenticated: bool,
+    /// Set by ASKING, the next request is executed even if its slot is only being imported.
+    asking: bool,
+    /// Accepted on the admin listener, only the admin commands are allowed.
+    admin: bool,
+    /// Set between MULTI and EXEC or DISCARD.
+    transaction: Option<Transaction>,
+    /// Set by CLIENT TRACKING, the client the invalidations of the keys read are redirected to.
+    tracking_redirect: Option<u64>,
+    /// Keys watched with WATCH and their version at the time, see [`Keyspace::version`].
+    watched: Vec<(String, Option<u64>)>,
+    /// Shard channels subscribed to with SSUBSCRIBE, in the order they were subscribed to.
+    /// Only the subscription commands are allowed while there are some.
+    channels: Vec<Vec<u8>>,
+
+    read_buf: ConnectionBuffer,
+    write_buf: WriteQueue,
+}
+
+/// The requests queued between MULTI and EXEC.
+#[derive(Default)]
+struct Transaction {
+    requests: Vec<Vec<u8>>,
+    /// Set if a request couldn't be queued, EXEC then fails.
+    aborted: bool,
+}
+
+impl Connection {
+    /// Returns the earliest deadline of the connection with the reason to close it once reached, if any.
+    fn deadline(&self, timeouts: &Timeouts) -> Option<(Instant, &'static str)> {
+        let idle = match self.state {
+            // The connection is waiting on us, not the other way around
+            State::Processing | State::Replica(_) => None,
+            // Subscribers are expected to wait for messages
+            _ if !self.channels.is_empty() => None,
+            _ => timeouts
+                .idle
+                .map(|idle| (self.last_activity + idle, "idle")),
+        };
+        let read = timeouts
+            .read
+            .zip(self.request_started)
+            .map(|(read, started)| (started + read, "read timeout"));
+        let write = timeouts
+            .write
+            .zip(self.response_started)
+            .map(|(write, started)| (started + write, "write timeout"));
+
+        [idle, read, write]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+}
+
+/// Why reading or processing the requests of a connection failed, the connection is closed after any of them.
+#[derive(Error, Debug)]
+enum RequestError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("end of stream")]
+    EndOfStream,
+    #[error("protocol error")]
+    Protocol(#[from] protocol::Error),
+    #[error("connection buffer full")]
+    Buffer(#[from] BufferError),
+}
+
+fn try_fill_buffer(
+    context: &Context,
+    connection: &mut Connection,
+    dispatcher: &Dispatcher,
+) -> Result<bool, RequestError> {
+    // Remove the already processed requests from the buffer and make room for a full request
+    connection.read_buf.reserve(protocol::BUF_LEN)?;
+
+    let read = {
+        let buf = connection.read_buf.writable();
+        match crate::read(connection.fd, buf) {
+            Ok(data) => {
+                if data.is_empty() {
+                    return Err(RequestError::EndOfStream);
+                } else {
+                    data.len()
+                }
+            }
+            Err(err) => {
+                if err.raw_os_error().unwrap() != libc::EAGAIN {
+                    return Err(RequestError::IO(err));
+                }
+                return Ok(false);
+            }
+        }
+    };
+
+    connection.read_buf.update_write_head(read);
+    context
+        .stats
+        .net_input_bytes
+        .fetch_add(read as u64, Ordering::Relaxed);
+
+    process_requests(context, connection, dispatcher)?;
+
+    if let State::ReadRequest = connection.state {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Process every complete request in the read buffer, then try to send the responses.
+fn process_requests(
+    context: &Context,
+    connection: &mut Connection,
+    dispatcher: &Dispatcher,
+) -> Result<(), RequestError> {
+    loop {
+        while try_one_request(context, connection, dispatcher)? {}
+
+        // A worker is processing a request, the responses will be sent once it's done
+        if let State::Processing | State::Replica(_) = connection.state {
+            return Ok(());
+        }
+
+        // The requests left are only processed once the client read the responses
+        let paused = connection.write_buf.is_full();
+
+        // Start the read deadline of the partial request left, if any
+        if connection.read_buf.is_empty() || paused {
+            connection.request_started = None;
+        } else {
+            connection.request_started.get_or_insert_with(Instant::now);
+        }
+
+        // Try to send the responses
+
+        connection.state = State::SendResponse;
+        connection.response_started.get_or_insert_with(Instant::now);
+        do_send_responses(context, connection);
+
+        // Resume right away if the responses were all sent
+        if !paused || !matches!(connection.state, State::ReadRequest) {
+            return Ok(());
+        }
+    }
+}
+
+/// Write the response of the request processed by a worker and resume processing the connection's requests.
+fn complete_request(
+    context: &Context,
+    connection: &mut Connection,
+    dispatcher: &Dispatcher,
+    response: Result<Vec<u8>, String>,
+) -> Result<(), RequestError> {
+    let response = match response {
+        Ok(response) => response,
+        Err(message) => {
+            let body = match protocol::parse_message(connection.read_buf.readable()) {
+                Ok((_, body)) => body,
+                Err(_) => &[],
+            };
+            report_crash(context, connection, body, &message);
+
+            crash_response()
+        }
+    };
+
+    push_response(context, connection, response)?;
+
+    // "consume" the bytes of the request
+    connection.read_buf.update_read_head(connection.in_flight);
+    connection.in_flight = 0;
+
+    connection.state = State::ReadRequest;
+
+    process_requests(context, connection, dispatcher)
+}
+
+fn try_one_request(
+    context: &Context,
+    connection: &mut Connection,
+    dispatcher: &Dispatcher,
+) -> Result<bool, RequestError> {
+    // Don't produce more responses until the client reads the ones already queued
+    if connection.write_buf.is_full() {
+        return Ok(false);
+    }
+
+    // Parse the request
+
+    let (parsed, message) = match protocol::parse_message(connection.read_buf.readable()) {
+        Ok(request) => request,
+        Err(err) => match err {
+            protocol::Error::MessageTooLong(_)
+            | protocol::Error::InvalidDataType(_)
+            | protocol::Error::InvalidResponseCode(_)
+            | protocol::Error::IncoherentDataType { .. }
+            | protocol::Error::UnexpectedFrame(_) => return Err(err.into()),
+            protocol::Error::InputTooShort(_) => return Ok(false),
+        },
+    };
+
+    // The request was fully received
+    connection.request_started = None;
+
+    dump_frame(
+        context,
+        connection,
+        "request from",
+        &connection.read_buf.readable()[..parsed],
+    );
+
+    // Until the client is authenticated only AUTH is allowed
+    let request = command::parse(message).unwrap_or_default();
+    context.stats.total_commands.fetch_add(1, Ordering::Relaxed);
+    if let Some(name) = request.first() {
+        connection.client.record_command(
+            name,
+            connection.read_buf.readable().len() - parsed,
+            connection.write_buf.len(),
+        );
+    }
+
+    // The closure below can't borrow the whole connection, the request is in its read buffer
+    let authenticated = is_authenticated(context, connection);
+
+    // The commands run right away here can panic like the others, see `report_crash`
+    let response = workers::catch_panic(|| match request.as_slice() {
+        [b"auth", args @ ..] if matches!(args.len(), 1 | 2) => {
+            let (user, password) = match args {
+                [user, password] => (*user, *password),
+                _ => (b"default".as_slice(), args[0]),
+            };
+
+            let (authenticated, response) = do_auth(context, user, password);
+            connection.authenticated |= authenticated;
+            if let Some(audit) = &context.audit {
+                audit.record_auth(connection.addr, connection.id, authenticated);
+            }
+            Some(response)
+        }
+        _ if connection.admin && !is_admin_command(&request) => Some(build_response(|writer| {
+            writer.push_err(
+                ResponseCode::Unknown,
+                "only PING, INFO and SHUTDOWN are allowed on the admin port",
+            )
+        })),
+        _ if !authenticated => Some(build_response(|writer| {
+            writer.push_err(ResponseCode::NoAuth, "Authentication required.")
+        })),
+        _ if !connection.channels.is_empty() && !is_subscriber_command(&request) => {
+            Some(build_response(|writer| {
+                writer.push_err(
+                    ResponseCode::Unknown,
+                    "only SSUBSCRIBE, SUNSUBSCRIBE and PING are allowed while subscribed",
+                )
+            }))
+        }
+        [b"multi"] => Some(match connection.transaction {
+            Some(_) => build_response(|writer| {
+                writer.push_err(ResponseCode::Unknown, "MULTI calls can not be nested")
+            }),
+            None => {
+                connection.transaction = Some(Transaction::default());
+                build_response(|writer| writer.push_nil())
+            }
+        }),
+        [b"watch", keys @ ..] if !keys.is_empty() => Some(match connection.transaction {
+            Some(_) => build_response(|writer| {
+                writer.push_err(ResponseCode::Unknown, "WATCH inside MULTI is not allowed")
+            }),
+            None => match redirect(context, message, mem::take(&mut connection.asking)) {
+                Some(response) => response,
+                None => do_watch(context, &mut connection.watched, keys),
+            },
+        }),
+        [b"unwatch"] => {
+            connection.watched.clear();
+            Some(build_response(|writer| writer.push_nil()))
+        }
+        [b"exec"] => Some(match connection.transaction.take() {
+            Some(transaction) => {
+                let watched = mem::take(&mut connection.watched);
+                do_exec(context, &connection.client, transaction, &watched)
+            }
+            None => build_response(|writer| {
+                writer.push_err(ResponseCode::Unknown, "EXEC without MULTI")
+            }),
+        }),
+        [b"discard"] => Some(match connection.transaction.take() {
+            Some(_) => {
+                connection.watched.clear();
+                build_response(|writer| writer.push_nil())
+            }
+            None => build_response(|writer| {
+                writer.push_err(ResponseCode::Unknown, "DISCARD without MULTI")
+            }),
+        }),
+        _ if connection.transaction.is_some() => {
+            let asking = mem::take(&mut connection.asking);
+            // NOTE(vincent): safe because of the guard
+            let transaction = connection.transaction.as_mut().unwrap();
+
+            Some(queue_request(
+                context,
+                transaction,
+                &request,
+                message,
+                asking,
+            ))
+        }
+        [b"ssubscribe", channels @ ..] if !channels.is_empty() => {
+            match redirect(context, message, mem::take(&mut connection.asking)) {
+                Some(response) => Some(response),
+                None => Some(do_ssubscribe(
+                    context,
+                    dispatcher,
+                    (connection.id, connection.fd),
+                    &mut connection.channels,
+                    channels,
+                )),
+            }
+        }
+        [b"sunsubscribe", channels @ ..] => Some(do_sunsubscribe(
+            context,
+            connection.id,
+            &mut connection.channels,
+            channels,
+        )),
+        [b"eval", source, numkeys, args @ ..] => Some(
+            match redirect(context, message, mem::take(&mut connection.asking)) {
+                Some(response) => response,
+                None => do_eval(context, &connection.client, source, numkeys, args),
+            },
+        ),
+        [b"evalsha", sha, numkeys, args @ ..] => Some(
+            match redirect(context, message, mem::take(&mut connection.asking)) {
+                Some(response) => response,
+                None => do_evalsha(context, &connection.client, sha, numkeys, args),
+            },
+        ),
+        [b"script", args @ ..] => Some(do_script(context, args)),
+        [b"asking"] => {
+            connection.asking = true;
+            Some(build_response(|writer| writer.push_nil()))
+        }
+        [b"client", args @ ..] => Some(build_response(|writer| {
+            audit_request(context, &connection.client, &request);
+            do_client(
+                context,
+                &connection.client,
+                &mut connection.tracking_redirect,
+                args,
+                writer,
+            )
+        })),
+        _ => redirect(context, message, mem::take(&mut connection.asking)),
+    });
+    let response = match response {
+        Ok(response) => response,
+        Err(panic) => {
+            report_crash(context, connection, message, &panic);
+            Some(crash_response())
+        }
+    };
+
+    if let Some(response) = response {
+        push_response(context, connection, response)?;
+        connection.read_buf.update_read_head(parsed);
+
+        return Ok(true);
+    }
+
+    // The client is a replica, the connection now belongs to the replication
+    if let Some(psync) = replication::parse_psync(message) {
+        connection.read_buf.update_read_head(parsed);
+        connection.state = State::Replica(psync);
+
+        return Ok(false);
+    }
+
+    if let Some(redirect) = connection.tracking_redirect {
+        if let (Some(key), Some(cmd)) = (request_key(message), request.first()) {
+            if !is_write_command(cmd) {
+                context.tracking.track(key, connection.id, redirect);
+            }
+        }
+    }
+
+    audit_request(context, &connection.client, &request);
+
+    // Hand the request to a worker or to the event loop owning its key
+    if dispatcher.submit(context, connection, message) {
+        connection.in_flight = parsed;
+        connection.state = State::Processing;
+
+        return Ok(false);
+    }
+
+    // Otherwise process the request right away
+    {
+        let mut buf = vec![0; protocol::BUF_LEN];
+        match workers::catch_panic(|| do_client_request(context, message, &mut buf)) {
+            Ok(result) => buf.truncate(result?),
+            Err(panic) => {
+                report_crash(context, connection, message, &panic);
+                buf = crash_response();
+            }
+        }
+
+        push_response(context, connection, buf)?;
+
+        trace!(
+            "write buf in try_one_request: {} bytes",
+            connection.write_buf.len()
+        );
+    }
+
+    // "consume" the bytes of the current request
+    connection.read_buf.update_read_head(parsed);
+
+    // Continue the outer loop if the request was fully processed
+    match connection.state {
+        State::ReadRequest => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Queue `response` to be sent to the client.
+fn push_response(
+    context: &Context,
+    connection: &mut Connection,
+    response: Vec<u8>,
+) -> Result<(), BufferError> {
+    dump_frame(context, connection, "response to", &response);
+
+    connection.write_buf.push(response)
+}
+
+/// Log `frame` as a hexdump if enabled.
+fn dump_frame(context: &Context, connection: &Connection, direction: &str, frame: &[u8]) {
+    // Don't take the lock of the config for every frame if the events aren't logged anyway
+    if !log::enabled(log::Level::Info) || !context.config.read().unwrap().debug_hexdump {
+        return;
+    }
+
+    info!(
+        "{} {}, {} bytes:\n{}",
+        direction,
+        connection.addr,
+        frame.len(),
+        log::hexdump(frame)
+    );
+}
+
+/// Log what's known about the connection whose request `body` panicked, before replying with an error.
+///
+/// NOTE(vincent): only the request fails, but a lock held by the request is now poisoned and every request using it
+/// will crash too.
+fn report_crash(context: &Context, connection: &Connection, body: &[u8], panic: &str) {
+    error!(
+        "request from {} crashed, replying with an error, panic: {}",
+        connection.addr, panic
+    );
+    error!(
+        "  client: {}",
+        context.clients.describe(&connection.client).trim_end()
+    );
+    error!("  request: {}", body.escape_ascii());
+    error!(
+        "  read buffer: {} bytes, write buffer: {} bytes",
+        connection.read_buf.readable().len(),
+        connection.write_buf.len()
+    );
+}
+
+/// The response to a request which panicked.
+fn crash_response() -> Vec<u8> {
+    build_response(|writer| writer.push_err(ResponseCode::Unknown, "internal error"))
+}
+
+/// Execute a request outside of the connection's event loop, returning the serialized response.
+fn execute_request(context: &Context, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; protocol::BUF_LEN];
+
+    let written = match do_client_request(context, body, &mut buf) {
+        Ok(written) => written,
+        Err(err) => {
+            warn!("do_request failed, err: {}", err);
+
+            let mut writer = protocol::Writer::new(&mut buf);
+            writer.push_err(ResponseCode::Unknown, "internal error");
+            writer.finish();
+            writer.written()
+        }
+    };
+
+    buf.truncate(written);
+    buf
+}
+
+/// Serialize a response written by `f`.
+fn build_response<F: FnOnce(&mut protocol::Writer)>(f: F) -> Vec<u8> {
+    let mut buf = vec![0; protocol::BUF_LEN];
+
+    let written = {
+        let mut writer = protocol::Writer::new(&mut buf);
+        f(&mut writer);
+        writer.finish();
+        writer.written()
+    };
+
+    buf.truncate(written);
+    buf
+}
+
+fn is_authenticated(context: &Context, connection: &Connection) -> bool {
+    connection.authenticated || context.config.read().unwrap().requirepass.is_none()
+}
+
+/// Check the password sent with AUTH, returning whether it's the right one along with the response.
+///
+/// There are no users like in Redis 6, only the `default` one whose password is `requirepass`.
+fn do_auth(context: &Context, user: &[u8], password: &[u8]) -> (bool, Vec<u8>) {
+    debug!("do_auth");
+
+    let config = context.config.read().unwrap();
+
+    match &config.requirepass {
+        Some(_) if user != b"default" => (
+            false,
+            build_response(|writer| {
+                writer.push_err(ResponseCode::WrongPass, "invalid username-password pair")
+            }),
+        ),
+        Some(expected) if expected.as_bytes() == password => {
+            (true, build_response(|writer| writer.push_nil()))
+        }
+        Some(_) => (
+            false,
+            build_response(|writer| writer.push_err(ResponseCode::WrongPass, "invalid password")),
+        ),
+        None => (
+            false,
+            build_response(|writer| {
+                writer.push_err(
+                    ResponseCode::Unknown,
+                    "AUTH called without any password configured",
+                )
+            }),
+        ),
+    }
+}
+
+/// Returns the redirection to send instead of executing the request if its key is not ours, in cluster mode.
+fn redirect(context: &Context, body: &[u8], asking: bool) -> Option<Vec<u8>> {
+    let cluster = context.cluster.as_ref()?;
+    let key = request_key(body)?;
+
+    let slot = cluster::key_slot(key.as_bytes());
+    let route = cluster
+        .read()
+        .unwrap()
+        .route(slot, asking, || context.data.contains(key));
+
+    let (code, message) = match route {
+        Route::Local => return None,
+        Route::Moved(slot, addr) => (ResponseCode::Moved, format!("{} {}", slot, addr)),
+        Route::Ask(slot, addr) => (ResponseCode::Ask, format!("{} {}", slot, addr)),
+        Route::Down(slot) => (
+            ResponseCode::ClusterDown,
+            format!("Hash slot {} not served", slot),
+        ),
+    };
+
+    Some(build_response(|writer| writer.push_err(code, message)))
+}
+
+/// Returns true if `request` is allowed while the client is subscribed to shard channels.
+fn is_subscriber_command(request: &[&[u8]]) -> bool {
+    matches!(
+        request.first(),
+        Some(&(b"ssubscribe" | b"sunsubscribe" | b"ping"))
+    )
+}
+
+/// Returns an error response if this node is part of a cluster and `keys` don't all hash to the same slot.
+fn check_same_slot(context: &Context, keys: &[&[u8]]) -> Option<Vec<u8>> {
+    context.cluster.as_ref()?;
+
+    let slot = cluster::key_slot(keys[0]);
+    if keys.iter().all(|key| cluster::key_slot(key) == slot) {
+        return None;
+    }
+
+    Some(build_response(|writer| {
+        writer.push_err(
+            ResponseCode::CrossSlot,
+            "Keys in request don't hash to the same slot",
+        )
+    }))
+}
+
+/// Subscribe the connection to `channels`, replying with one `[ssubscribe, channel, count]` array per channel where
+/// count is the number of channels the connection is subscribed to.
+fn do_ssubscribe(
+    context: &Context,
+    dispatcher: &Dispatcher,
+    (conn_id, fd): (u64, i32),
+    subscribed: &mut Vec<Vec<u8>>,
+    channels: &[&[u8]],
+) -> Vec<u8> {
+    debug!("do_ssubscribe, channels: {:?}", channels);
+
+    // In cluster mode the channels must be served by the same node, the first one was already routed
+    if let Some(response) = check_same_slot(context, channels) {
+        return response;
+    }
+
+    let sender = dispatcher.messages.sender();
+
+    let mut response = Vec::new();
+    for channel in channels {
+        if context.pubsub.subscribe(channel, conn_id, fd, &sender) {
+            subscribed.push(channel.to_vec());
+        }
+
+        response.extend(build_response(|writer| {
+            writer.push_arr(3);
+            writer.push_string("ssubscribe");
+            writer.push_string(channel);
+            writer.push_int(subscribed.len());
+        }));
+    }
+
+    response
+}
+
+/// Unsubscribe the connection from `channels`, or from every channel if empty, replying like [`do_ssubscribe`].
+fn do_sunsubscribe(
+    context: &Context,
+    conn_id: u64,
+    subscribed: &mut Vec<Vec<u8>>,
+    channels: &[&[u8]],
+) -> Vec<u8> {
+    debug!("do_sunsubscribe, channels: {:?}", channels);
+
+    let channels: Vec<Vec<u8>> = if channels.is_empty() {
+        subscribed.clone()
+    } else {
+        channels.iter().map(|channel| channel.to_vec()).collect()
+    };
+
+    if channels.is_empty() {
+        return build_response(|writer| {
+            writer.push_arr(3);
+            writer.push_string("sunsubscribe");
+            writer.push_nil();
+            writer.push_int(0);
+        });
+    }
+
+    let mut response = Vec::new();
+    for channel in channels {
+        context.pubsub.unsubscribe(&channel, conn_id);
+        subscribed.retain(|c| *c != channel);
+
+        response.extend(build_response(|writer| {
+            writer.push_arr(3);
+            writer.push_string("sunsubscribe");
+            writer.push_string(&channel);
+            writer.push_int(subscribed.len());
+        }));
+    }
+
+    response
+}
+
+/// Returns true if `request` is allowed on the admin listener.
+fn is_admin_command(request: &[&[u8]]) -> bool {
+    matches!(request.first(), Some(&(b"ping" | b"info" | b"shutdown")))
+}
+
+/// Returns true if the command changes the keyspace.
+fn is_write_command(cmd: &[u8]) -> bool {
+    matches!(
+        cmd,
+        b"set" | b"setex" | b"expire" | b"pexpireat" | b"del" | b"restore" | b"migrate"
+    )
+}
+
+/// Returns true if this is a replica refusing the write commands.
+fn refuses_writes(context: &Context) -> bool {
+    let config = context.config.read().unwrap();
+    config.replica_of.is_some() && config.replica_read_only
+}
+
+/// Queue `request` in the transaction, or reply with why it can't be and abort the transaction.
+fn queue_request(
+    context: &Context,
+    transaction: &mut Transaction,
+    request: &[&[u8]],
+    body: &[u8],
+    asking: bool,
+) -> Vec<u8> {
+    let error = if !is_valid_request(context, request) {
+        let cmd = String::from_utf8_lossy(request.first().copied().unwrap_or_default());
+        Some(build_response(|writer| {
+            writer.push_err(
+                ResponseCode::Unknown,
+                format!("invalid command {} in a transaction", cmd),
+            )
+        }))
+    } else if refuses_writes(context) && is_write_command(request[0]) {
+        Some(build_response(|writer| {
+            writer.push_err(
+                ResponseCode::ReadOnly,
+                "You can't write against a read only replica.",
+            )
+        }))
+    } else {
+        redirect(context, body, asking)
+    };
+
+    if let Some(error) = error {
+        transaction.aborted = true;
+        return error;
+    }
+
+    transaction.requests.push(body.to_vec());
+
+    build_response(|writer| writer.push_string("QUEUED"))
+}
+
+/// Returns true if `request` is executed by `do_request` and has enough arguments, to check it before queuing it in
+/// a transaction.
+fn is_valid_request(context: &Context, request: &[&[u8]]) -> bool {
+    if context.commands.accepts(request) {
+        return true;
+    }
+
+    let (cmd, args) = match request.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    match *cmd {
+        b"keys" | b"bgsave" | b"info" | b"role" | b"ping" | b"shutdown" => true,
+        b"ttl" | b"dump" | b"cluster" | b"memory" | b"latency" | b"pubsub" => !args.is_empty(),
+        b"expire" | b"pexpireat" | b"replicaof" | b"failover-vote" => args.len() >= 2,
+        b"setex" | b"restore" => args.len() >= 3,
+        b"migrate" => args.len() >= 4,
+        b"spublish" => args.len() == 2,
+        b"config" => matches!(args, [b"get", _, ..] | [b"set", _, _, ..]),
+        _ => false,
+    }
+}
+
+/// Watch `keys` until the next EXEC, which aborts the transaction if one of them was modified in the meantime.
+fn do_watch(
+    context: &Context,
+    watched: &mut Vec<(String, Option<u64>)>,
+    keys: &[&[u8]],
+) -> Vec<u8> {
+    debug!("do_watch, keys: {:?}", keys);
+
+    // In cluster mode the keys must be served by the same node, the first one was already routed
+    if let Some(response) = check_same_slot(context, keys) {
+        return response;
+    }
+
+    for key in keys {
+        let key = match std::str::from_utf8(key) {
+            Ok(key) => key,
+            Err(_) => {
+                return build_response(|writer| {
+                    writer.push_err(ResponseCode::Unknown, "invalid key")
+                })
+            }
+        };
+
+        // Watching a key again doesn't forget a modification made since the first time
+        if !watched.iter().any(|(watched, _)| watched == key) {
+            watched.push((key.to_string(), context.data.version(key)));
+        }
+    }
+
+    build_response(|writer| writer.push_nil())
+}
+
+/// Execute the requests of the transaction without any other request running, replying with an array of their
+/// responses, or with nil if one of the `watched` keys was modified.
+///
+/// NOTE(vincent): the requests are propagated one by one, a replica could execute only part of the transaction if
+/// the link breaks in the middle.
+fn do_exec(
+    context: &Context,
+    client: &Client,
+    transaction: Transaction,
+    watched: &[(String, Option<u64>)],
+) -> Vec<u8> {
+    debug!("do_exec, {} requests", transaction.requests.len());
+
+    if transaction.aborted {
+        return build_response(|writer| {
+            writer.push_err(
+                ResponseCode::Unknown,
+                "EXECABORT Transaction discarded because of previous errors.",
+            )
+        });
+    }
+
+    // The lock protects nothing but the exclusion, a panic while holding it doesn't matter
+    let _transaction = context
+        .transaction
+        .write()
+        .unwrap_or_else(|err| err.into_inner());
+
+    if watched
+        .iter()
+        .any(|(key, version)| context.data.version(key) != *version)
+    {
+        debug!("do_exec, watched key modified");
+        return build_response(|writer| writer.push_nil());
+    }
+
+    let responses: Vec<Vec<u8>> = transaction
+        .requests
+        .iter()
+        .map(|body| run_request(context, client, body))
+        .collect();
+
+    let size: usize = 5 + responses.iter().map(|r| r.len()).sum::<usize>();
+    if size > protocol::MAX_MSG_LEN {
+        return build_response(|writer| {
+            writer.push_err(ResponseCode::TooBig, "response too large")
+        });
+    }
+
+    build_response(|writer| {
+        writer.push_arr(responses.len());
+        for response in &responses {
+            // NOTE(vincent): safe because do_request always writes a whole message
+            let (_, body) = protocol::parse_message(response).unwrap();
+            writer.push_message(body);
+        }
+    })
+}
+
+/// Record `request` in the audit log if enabled, right before it's executed.
+fn audit_request(context: &Context, client: &Client, request: &[&[u8]]) {
+    if let Some(audit) = &context.audit {
+        audit.record_request(client.addr, client.id, request);
+    }
+}
+
+/// Execute `body` with `do_request` on behalf of a transaction or a script of `client`, returning the whole response
+/// message.
+fn run_request(context: &Context, client: &Client, body: &[u8]) -> Vec<u8> {
+    if let Ok(request) = command::parse(body) {
+        audit_request(context, client, &request);
+    }
+
+    let mut buf = vec![0; protocol::BUF_LEN];
+
+    let written = match do_request(context, body, &mut buf) {
+        Ok(written) => written,
+        Err(err) => {
+            warn!("do_request failed, err: {}", err);
+
+            let mut writer = protocol::Writer::new(&mut buf);
+            writer.push_err(ResponseCode::Unknown, "internal error");
+            writer.finish();
+            writer.written()
+        }
+    };
+
+    buf.truncate(written);
+    buf
+}
+
+/// Run `source`, compiling it and caching it like SCRIPT LOAD if it's not cached already.
+fn do_eval(
+    context: &Context,
+    client: &Client,
+    source: &[u8],
+    numkeys: &[u8],
+    args: &[&[u8]],
+) -> Vec<u8> {
+    debug!("do_eval, numkeys: {:?}", String::from_utf8_lossy(numkeys));
+
+    let script = match load_script(context, source) {
+        Ok((_, script)) => script,
+        Err(response) => return response,
+    };
+
+    run_script(context, client, &script, numkeys, args)
+}
+
+/// Run the script cached with the SHA-1 digest `sha`.
+fn do_evalsha(
+    context: &Context,
+    client: &Client,
+    sha: &[u8],
+    numkeys: &[u8],
+    args: &[&[u8]],
+) -> Vec<u8> {
+    debug!("do_evalsha, sha: {:?}", String::from_utf8_lossy(sha));
+
+    let sha = String::from_utf8_lossy(sha).to_ascii_lowercase();
+    let script = context.scripts.lock().unwrap().get(&sha).cloned();
+
+    match script {
+        Some(script) => run_script(context, client, &script, numkeys, args),
+        None => build_response(|writer| {
+            writer.push_err(
+                ResponseCode::NoScript,
+                "No matching script. Please use EVAL.",
+            )
+        }),
+    }
+}
+
+fn do_script(context: &Context, args: &[&[u8]]) -> Vec<u8> {
+    debug!("do_script, args: {:?}", args);
+
+    match args {
+        [b"load", source] => match load_script(context, source) {
+            Ok((sha, _)) => build_response(|writer| writer.push_string(sha)),
+            Err(response) => response,
+        },
+        [b"exists", shas @ ..] if !shas.is_empty() => {
+            let scripts = context.scripts.lock().unwrap();
+
+            build_response(|writer| {
+                writer.push_arr(shas.len());
+                for sha in shas {
+                    let sha = String::from_utf8_lossy(sha).to_ascii_lowercase();
+                    writer.push_int(scripts.contains_key(&sha) as usize);
+                }
+            })
+        }
+        [b"flush"] => {
+            context.scripts.lock().unwrap().clear();
+            build_response(|writer| writer.push_nil())
+        }
+        _ => build_response(|writer| {
+            writer.push_err(ResponseCode::Unknown, "invalid SCRIPT subcommand")
+        }),
+    }
+}
+
+/// Returns the digest and the compiled script of `source`, compiling and caching it if needed, or the response
+/// to reply if it doesn't compile.
+fn load_script(context: &Context, source: &[u8]) -> Result<(String, Arc<Script>), Vec<u8>> {
+    let sha = sha1::hex_digest(source);
+
+    if let Some(script) = context.scripts.lock().unwrap().get(&sha) {
+        return Ok((sha, Arc::clone(script)));
+    }
+
+    // Compiled without the lock, two clients loading the same script at once both compile it
+    let script = match Script::compile(source) {
+        Ok(script) => Arc::new(script),
+        Err(err) => {
+            return Err(build_response(|writer| {
+                writer.push_err(
+                    ResponseCode::Unknown,
+                    format!("Error compiling script: {}", err),
+                )
+            }))
+        }
+    };
+
+    context
+        .scripts
+        .lock()
+        .unwrap()
+        .insert(sha.clone(), Arc::clone(&script));
+
+    Ok((sha, script))
+}
+
+/// Run `script` with the first `numkeys` arguments of `args` as its keys, without any other request running.
+fn run_script(
+    context: &Context,
+    client: &Client,
+    script: &Script,
+    numkeys: &[u8],
+    args: &[&[u8]],
+) -> Vec<u8> {
+    let nb_keys = match parse_u64(numkeys) {
+        Some(nb_keys) if nb_keys <= args.len() as u64 => nb_keys as usize,
+        Some(_) => {
+            return build_response(|writer| {
+                writer.push_err(
+                    ResponseCode::Unknown,
+                    "Number of keys can't be greater than number of args",
+                )
+            })
+        }
+        None => {
+            return build_response(|writer| {
+                writer.push_err(ResponseCode::Unknown, "invalid number of keys")
+            })
+        }
+    };
+    let (keys, args) = args.split_at(nb_keys);
+
+    // In cluster mode the keys must be served by the same node, the first one was already routed
+    if !keys.is_empty() {
+        if let Some(response) = check_same_slot(context, keys) {
+            return response;
+        }
+    }
+
+    // The lock protects nothing but the exclusion, a panic while holding it doesn't matter
+    let _transaction = context
+        .transaction
+        .write()
+        .unwrap_or_else(|err| err.into_inner());
+
+    let value = match script.run(keys, args, |command| {
+        run_script_command(context, client, command)
+    }) {
+        Ok(value) => value,
+        Err(err) => {
+            return build_response(|writer| {
+                writer.push_err(
+                    ResponseCode::Unknown,
+                    format!("Error running script: {}", err),
+                )
+            })
+        }
+    };
+
+    match value.reply_size() {
+        Some(size) if size <= protocol::MAX_MSG_LEN => {
+            build_response(|writer| value.push_reply(writer))
+        }
+        _ => build_response(|writer| writer.push_err(ResponseCode::TooBig, "response too large")),
+    }
+}
+
+/// Run a command called by a script with `redis.call`, returning the body of its response.
+///
+/// Each write is propagated on its own like the writes of a transaction.
+fn run_script_command(context: &Context, client: &Client, command: &[Vec<u8>]) -> Vec<u8> {
+    let request: Vec<&[u8]> = command.iter().map(Vec::as_slice).collect();
+    let body = command::encode(&request);
+
+    // SHUTDOWN would stop the server in the middle of the script
+    let error = if !is_valid_request(context, &request) || request[0] == b"shutdown" {
+        Some(build_response(|writer| {
+            writer.push_err(
+                ResponseCode::Unknown,
+                format!(
+                    "invalid command {} in a script",
+                    String::from_utf8_lossy(request[0])
+                ),
+            )
+        }))
+    } else if refuses_writes(context) && is_write_command(request[0]) {
+        Some(build_response(|writer| {
+            writer.push_err(
+                ResponseCode::ReadOnly,
+                "You can't write against a read only replica.",
+            )
+        }))
+    } else {
+        redirect(context, &body, false)
+    };
+
+    let response = error.unwrap_or_else(|| run_request(context, client, &body));
+
+    // NOTE(vincent): safe because a response is always a whole message
+    let (_, body) = protocol::parse_message(&response).unwrap();
+    body.to_vec()
+}
+
+/// Execute a request sent by a client, refusing the writes if we're a read-only replica.
+///
+/// The requests sent by the primary or replayed from the append only file go through `do_request` directly.
+fn do_client_request(
+    context: &Context,
+    body: &[u8],
+    write_buf: &mut [u8],
+) -> Result<usize, RequestError> {
+    if refuses_writes(context) {
+        let is_write = match command::parse(body) {
+            Ok(request) => request.first().is_some_and(|cmd| is_write_command(cmd)),
+            // Reported by do_request
+            Err(_) => false,
+        };
+
+        if is_write {
+            let mut writer = protocol::Writer::new(write_buf);
+            writer.push_err(
+                ResponseCode::ReadOnly,
+                "You can't write against a read only replica.",
+            );
+            writer.finish();
+
+            return Ok(writer.written());
+        }
+    }
+
+    // The lock protects nothing but the exclusion, a panic while holding it doesn't matter
+    let _transaction = context
+        .transaction
+        .read()
+        .unwrap_or_else(|err| err.into_inner());
+
+    // Only parsed again if there are hooks, an invalid request is reported by do_request
+    let request = if context.hooks.is_empty() {
+        None
+    } else {
+        command::parse(body).ok()
+    };
+    let hooked = request.as_ref().and_then(|request| request.split_first());
+
+    if let Some((cmd, args)) = hooked {
+        let mut writer = protocol::Writer::new(write_buf);
+        if context.hooks.before(cmd, args, &mut writer) == Flow::Stop {
+            writer.finish();
+            let written = writer.written();
+
+            after_hooks(context, cmd, args, &write_buf[..written]);
+            return Ok(written);
+        }
+    }
+
+    let written = match &context.otlp {
+        Some(exporter) => {
+            let start = SystemTime::now();
+            let timer = Instant::now();
+            let written = do_request(context, body, write_buf)?;
+
+            if let Ok(request) = command::parse(body) {
+                exporter.record(otlp::Span {
+                    command: String::from_utf8_lossy(request.first().copied().unwrap_or_default())
+                        .into_owned(),
+                    nb_keys: nb_keys(&request),
+                    bytes_in: body.len(),
+                    bytes_out: written,
+                    start,
+                    duration: timer.elapsed(),
+                });
+            }
+
+            written
+        }
+        None => do_request(context, body, write_buf)?,
+    };
+
+    if let Some((cmd, args)) = hooked {
+        after_hooks(context, cmd, args, &write_buf[..written]);
+    }
+
+    Ok(written)
+}
+
+/// Call the after hooks with the body of `response`, a whole message.
+fn after_hooks(context: &Context, cmd: &[u8], args: &[&[u8]], response: &[u8]) {
+    // NOTE(vincent): safe because a response is always a whole message
+    let (_, body) = protocol::parse_message(response).unwrap();
+    context.hooks.after(cmd, args, body);
+}
+
+fn do_request(context: &Context, body: &[u8], write_buf: &mut [u8]) -> Result<usize, RequestError> {
+    let mut writer = protocol::Writer::new(write_buf);
+
+    let request = match command::parse(body) {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("got error {}", err);
+            for source in err.sources().skip(1) {
+                warn!("  Caused by: {source}");
+            }
+
+            writer.push_err(ResponseCode::Unknown, "internal error");
+            writer.finish();
+
+            return Ok(writer.written());
+        }
+    };
+
+    let (cmd, args) = (request[0], &request[1..]);
+
+    // Set by the commands which changed the keyspace, to the number of keys they changed
+    let mut dirty = 0;
+    // Only the latency of the known commands is recorded
+    let mut known = true;
+    let start = Instant::now();
+
+    if let Some(changed) = context.commands.call(context, cmd, args, &mut writer) {
+        dirty = changed;
+    } else if cmd == b"setex" && args.len() >= 3 {
+        dirty = do_setex(context, args, &mut writer);
+    } else if cmd == b"expire" && args.len() >= 2 {
+        dirty = do_expire(context, args, &mut writer);
+    } else if cmd == b"pexpireat" && args.len() >= 2 {
+        dirty = do_pexpireat(context, args, &mut writer);
+    } else if cmd == b"ttl" && !args.is_empty() {
+        do_ttl(context, args, &mut writer);
+    } else if cmd == b"dump" && !args.is_empty() {
+        do_dump(context, args, &mut writer);
+    } else if cmd == b"restore" && args.len() >= 3 {
+        dirty = do_restore(context, args, &mut writer);
+    } else if cmd == b"migrate" && args.len() >= 4 {
+        dirty = do_migrate(context, args, &mut writer);
+    } else if cmd == b"spublish" && args.len() == 2 {
+        do_spublish(context, args, &mut writer);
+    } else if cmd == b"pubsub" && !args.is_empty() {
+        do_pubsub(context, args, &mut writer);
+    } else if cmd == b"ping" {
+        do_ping(args, &mut writer);
+    } else if cmd == b"shutdown" {
+        do_shutdown(context, args, &mut writer);
+    } else if cmd == b"keys" {
+        do_keys(context, args, &mut writer);
+    } else if cmd == b"bgsave" {
+        do_bgsave(context, args, &mut writer);
+    } else if cmd == b"config" && args.len() >= 2 && args[0] == b"get" {
+        do_config_get(context, &args[1..], &mut writer);
+    } else if cmd == b"config" && args.len() >= 3 && args[0] == b"set" {
+        do_config_set(context, &args[1..], &mut writer);
+    } else if cmd == b"cluster" && !args.is_empty() {
+        do_cluster(context, args, &mut writer);
+    } else if cmd == b"replicaof" && args.len() >= 2 {
+        do_replica_of(context, args, &mut writer);
+    } else if cmd == b"info" {
+        do_info(context, args, &mut writer);
+    } else if cmd == b"role" {
+        do_role(context, args, &mut writer);
+    } else if cmd == b"failover-vote" && args.len() >= 2 {
+        do_failover_vote(context, args, &mut writer);
+    } else if cmd == b"memory" && !args.is_empty() {
+        do_memory(context, args, &mut writer);
+    } else if cmd == b"latency" && !args.is_empty() {
+        do_latency(context, args, &mut writer);
+    } else {
+        known = false;
+        writer.push_err(
+            ResponseCode::Unknown,
+            format!("invalid command {}", String::from_utf8_lossy(cmd)),
+        );
+    }
+
+    if known {
+        let threshold = context.config.read().unwrap().latency_monitor_threshold;
+        context
+            .latency
+            .record(cmd, start.elapsed(), writer.is_err(), threshold);
+    }
+
+    if dirty > 0 {
+        context.dirty.record(cmd, dirty);
+
+        if !propagates_itself(cmd) {
+            propagate(context, body);
+        }
+    }
+
+    writer.finish();
+    Ok(writer.written())
+}
+
+/// Returns true if the command propagates requests of its own instead of the original one, see `propagate`.
+fn propagates_itself(cmd: &[u8]) -> bool {
+    matches!(cmd, b"setex" | b"expire" | b"restore" | b"migrate")
+}
+
+/// Forward a request which changed the keyspace: log it to the append only file if enabled and send it to the
+/// replicas.
+///
+/// Commands with a relative expiration propagate equivalent requests with an absolute one instead, otherwise
+/// replaying them later would extend the expiration.
+fn propagate(context: &Context, body: &[u8]) {
+    let mut aof = context.aof.lock().unwrap();
+
+    if let Some(aof) = aof.as_mut() {
+        if let Err(err) = aof.append(body) {
+            warn!("unable to write to the append only file, err: {}", err);
+        }
+    }
+    drop(aof);
+
+    context.replication.feed(body);
+}
+
+fn do_get(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_get; args: {:?}", args);
+
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return;
+        }
+    };
+
+    let value = context.data.get(key);
+    context.stats.record_lookup(value.is_some());
+
+    match value {
+        None => {
+            response_writer.push_nil();
+        }
+        Some(value) => {
+            response_writer.push_string(value);
+        }
+    }
+}
+
+fn do_set(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_set, args: {:?}", args);
+
+    // TODO(vincent): avoid cloning ?
+    let key = match String::from_utf8(args[0].to_vec()) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let value = match String::from_utf8(args[1].to_vec()) {
+        Ok(value) => value,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    if !reclaim_memory(context) {
+        response_writer.push_err(
+            ResponseCode::OutOfMemory,
+            "command not allowed when used memory > 'maxmemory'",
+        );
+        return 0;
+    }
+
+    context.data.insert(key, value);
+
+    response_writer.push_nil();
+    1
+}
+
+fn parse_u64(arg: &[u8]) -> Option<u64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn do_setex(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_setex, args: {:?}", args);
+
+    let key = match String::from_utf8(args[0].to_vec()) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let expires_at = match parse_u64(args[1]) {
+        Some(seconds) if seconds > 0 => SystemTime::now().checked_add(Duration::from_secs(seconds)),
+        _ => None,
+    };
+    let expires_at = match expires_at {
+        Some(expires_at) => expires_at,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid expire time");
+            return 0;
+        }
+    };
+
+    let value = match String::from_utf8(args[2].to_vec()) {
+        Ok(value) => value,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid value");
+            return 0;
+        }
+    };
+
+    if !reclaim_memory(context) {
+        response_writer.push_err(
+            ResponseCode::OutOfMemory,
+            "command not allowed when used memory > 'maxmemory'",
+        );
+        return 0;
+    }
+
+    let millis = keyspace::to_unix_millis(expires_at).to_string();
+
+    context
+        .data
+        .insert_with_expiry(key, value, Some(expires_at));
+
+    // NOTE(vincent): two requests so that each one is never longer than the original request.
+    propagate(context, &command::encode(&[b"set", args[0], args[2]]));
+    propagate(
+        context,
+        &command::encode(&[b"pexpireat", args[0], millis.as_bytes()]),
+    );
+
+    response_writer.push_nil();
+    1
+}
+
+fn do_expire(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_expire, args: {:?}", args);
+
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let expires_at = match parse_u64(args[1])
+        .and_then(|seconds| SystemTime::now().checked_add(Duration::from_secs(seconds)))
+    {
+        Some(expires_at) => expires_at,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid expire time");
+            return 0;
+        }
+    };
+
+    if context.data.expire(key, expires_at) {
+        let millis = keyspace::to_unix_millis(expires_at).to_string();
+        propagate(
+            context,
+            &command::encode(&[b"pexpireat", args[0], millis.as_bytes()]),
+        );
+
+        response_writer.push_int(1);
+        1
+    } else {
+        response_writer.push_int(0);
+        0
+    }
+}
+
+fn do_pexpireat(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_pexpireat, args: {:?}", args);
+
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let millis = match parse_u64(args[1]) {
+        Some(millis) => millis,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid expire time");
+            return 0;
+        }
+    };
+
+    if context.data.expire(key, keyspace::from_unix_millis(millis)) {
+        response_writer.push_int(1);
+        1
+    } else {
+        response_writer.push_int(0);
+        0
+    }
+}
+
+/// Reply with the number of seconds before the key expires, or nil if it doesn't exist or never expires.
+fn do_ttl(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_ttl, args: {:?}", args);
+
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return;
+        }
+    };
+
+    match context.data.ttl(key) {
+        Some(Some(ttl)) => {
+            // Rounded like Redis does
+            let seconds = (ttl.as_millis() + 500) / 1000;
+            response_writer.push_int(seconds as usize);
+        }
+        // NOTE(vincent): integers are unsigned in our protocol, we can't reply -1 and -2 like Redis.
+        Some(None) | None => response_writer.push_nil(),
+    }
+}
+
+/// Make sure the memory used is under the configured limit before a write, evicting keys if allowed.
+/// Returns false if the write must be refused.
+fn reclaim_memory(context: &Context) -> bool {
+    let (max_memory, policy) = {
+        let config = context.config.read().unwrap();
+        (config.max_memory, config.max_memory_policy)
+    };
+
+    if max_memory == 0 || context.data.used_memory() <= max_memory {
+        return true;
+    }
+
+    match policy {
+        EvictionPolicy::NoEviction => false,
+        EvictionPolicy::AllKeysLru => {
+            let evicted = context.data.evict(max_memory);
+            debug!("evicted {} keys", evicted.len());
+            context.dirty.record(b"evicted", evicted.len() as u64);
+            context
+                .stats
+                .evicted_keys
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+
+            // Otherwise the evicted keys would come back when replaying the append only file or on the replicas
+            for key in evicted {
+                propagate(context, &command::encode(&[b"del", key.as_bytes()]));
+            }
+
+            context.data.used_memory() <= max_memory
+        }
+    }
+}
+
+fn do_del(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_del, args: {:?}", args);
+
+    // TODO(vincent): avoid cloning ?
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    if context.data.remove(key) {
+        response_writer.push_int(1);
+        1
+    } else {
+        response_writer.push_int(0);
+        0
+    }
+}
+
+fn do_dump(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_dump, args: {:?}", args);
+
+    let key = match std::str::from_utf8(args[0]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return;
+        }
+    };
+
+    let value = context.data.get(key);
+    context.stats.record_lookup(value.is_some());
+
+    match value {
+        Some(value) => response_writer.push_string(migrate::dump(&value)),
+        None => response_writer.push_nil(),
+    }
+}
+
+/// RESTORE <key> <ttl> <payload> [REPLACE] [ABSTTL]
+///
+/// The ttl is in milliseconds, 0 if the key doesn't expire. With ABSTTL it's a Unix time in milliseconds instead.
+fn do_restore(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_restore, args: {:?}", args);
+
+    let key = match String::from_utf8(args[0].to_vec()) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let ttl = match parse_u64(args[1]) {
+        Some(ttl) => ttl,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid TTL value");
+            return 0;
+        }
+    };
+
+    let value = match migrate::restore(args[2]) {
+        Ok(value) => value,
+        Err(err) => {
+            response_writer.push_err(
+                ResponseCode::Unknown,
+                format!("invalid DUMP payload: {}", err),
+            );
+            return 0;
+        }
+    };
+
+    let (mut replace, mut absolute) = (false, false);
+    for option in &args[3..] {
+        match *option {
+            b"replace" => replace = true,
+            b"absttl" => absolute = true,
+            _ => {
+                response_writer.push_err(ResponseCode::Unknown, "syntax error");
+                return 0;
+            }
+        }
+    }
+
+    let expires_at = match ttl {
+        0 => None,
+        ttl if absolute => Some(keyspace::from_unix_millis(ttl)),
+        ttl => match SystemTime::now().checked_add(Duration::from_millis(ttl)) {
+            Some(expires_at) => Some(expires_at),
+            None => {
+                response_writer.push_err(ResponseCode::Unknown, "invalid TTL value");
+                return 0;
+            }
+        },
+    };
+
+    // Already expired, like Redis the key is not created
+    if expires_at.is_some_and(|at| at <= SystemTime::now()) {
+        response_writer.push_nil();
+        return 0;
+    }
+
+    if !reclaim_memory(context) {
+        response_writer.push_err(
+            ResponseCode::OutOfMemory,
+            "command not allowed when used memory > 'maxmemory'",
+        );
+        return 0;
+    }
+
+    let set = command::encode(&[b"set", key.as_bytes(), value.as_bytes()]);
+
+    if replace {
+        context
+            .data
+            .insert_with_expiry(key.clone(), value, expires_at);
+    } else if !context.data.try_insert(key.clone(), value, expires_at) {
+        response_writer.push_err(ResponseCode::BusyKey, "Target key name already exists.");
+        return 0;
+    }
+
+    // NOTE(vincent): propagated as SET and PEXPIREAT, the payload is not needed to replay it.
+    propagate(context, &set);
+    if let Some(at) = expires_at {
+        let millis = keyspace::to_unix_millis(at).to_string();
+        propagate(
+            context,
+            &command::encode(&[b"pexpireat", key.as_bytes(), millis.as_bytes()]),
+        );
+    }
+
+    response_writer.push_nil();
+    1
+}
+
+/// MIGRATE <ip> <port> <key> <timeout> [COPY] [REPLACE]
+///
+/// The timeout is in milliseconds, at most [`MAX_MIGRATE_TIMEOUT`]. The key is transferred without locking its shard
+/// and isn't removed if it changed in the meantime.
+fn do_migrate(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) -> u64 {
+    debug!("do_migrate, args: {:?}", args);
+
+    let ip = std::str::from_utf8(args[0])
+        .ok()
+        .and_then(|ip| ip.parse().ok());
+    let port = std::str::from_utf8(args[1])
+        .ok()
+        .and_then(|port| port.parse().ok());
+    let target = match ip.zip(port) {
+        Some((ip, port)) => SocketAddrV4::new(ip, port),
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid target address");
+            return 0;
+        }
+    };
+
+    let key = match std::str::from_utf8(args[2]) {
+        Ok(key) => key,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid key");
+            return 0;
+        }
+    };
+
+    let timeout = match parse_u64(args[3]) {
+        Some(timeout) if timeout > 0 => Duration::from_millis(timeout).min(MAX_MIGRATE_TIMEOUT),
+        _ => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid timeout");
+            return 0;
+        }
+    };
+
+    // The RESTORE would wait for the request running it
+    if is_own_address(&context.config.read().unwrap(), target) {
+        response_writer.push_err(ResponseCode::Unknown, "the target is this server");
+        return 0;
+    }
+
+    let (mut copy, mut replace) = (false, false);
+    for option in &args[4..] {
+        match *option {
+            b"copy" => copy = true,
+            b"replace" => replace = true,
+            _ => {
+                response_writer.push_err(ResponseCode::Unknown, "syntax error");
+                return 0;
+            }
+        }
+    }
+
+    let (value, expires_at, version) = match context.data.get_versioned(key) {
+        Some(entry) => entry,
+        None => {
+            response_writer.push_string("NOKEY");
+            return 0;
+        }
+    };
+
+    match migrate::transfer(target, key, &value, expires_at, replace, timeout) {
+        Ok(()) if copy => {
+            response_writer.push_nil();
+            0
+        }
+        Ok(()) => {
+            if !context.data.remove_if_version(key, version) {
+                response_writer.push_err(
+                    ResponseCode::Unknown,
+                    "the key changed during the migration, it was not removed",
+                );
+                return 0;
+            }
+
+            response_writer.push_nil();
+            propagate(context, &command::encode(&[b"del", key.as_bytes()]));
+            1
+        }
+        Err(PeerError::Refused(message)) => {
+            response_writer.push_err(
+                ResponseCode::Unknown,
+                format!("Target instance replied with error: {}", message),
+            );
+            0
+        }
+        Err(err) => {
+            response_writer.push_err(
+                ResponseCode::IOErr,
+                format!("error or timeout migrating to {}: {}", target, err),
+            );
+            0
+        }
+    }
+}
+
+/// Returns true if `addr` reaches this server: the address it listens on, its announced address or a loopback
+/// address if it listens on every interface.
+fn is_own_address(config: &ServerConfig, addr: SocketAddrV4) -> bool {
+    if addr.port() != config.port {
+        return false;
+    }
+
+    let ip = *addr.ip();
+    ip == config.bind
+        || ip == *config.announce_address().ip()
+        || (config.bind.is_unspecified() && (ip.is_loopback() || ip.is_unspecified()))
+}
+
+fn do_keys(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_keys, args: {:?}", args);
+
+    let keys = context.data.keys();
+
+    response_writer.push_arr(keys.len());
+
+    for key in keys {
+        response_writer.push_string(key);
+    }
+}
+
+fn do_bgsave(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_bgsave, args: {:?}", args);
+
+    let path = context.config.read().unwrap().snapshot_path.clone();
+
+    match context
+        .saver
+        .start(Arc::clone(&context.data), path, context.dirty.total())
+    {
+        Ok(true) => response_writer.push_string("Background saving started"),
+        Ok(false) => {
+            response_writer.push_err(ResponseCode::Unknown, "Background save already in progress")
+        }
+        Err(err) => response_writer.push_err(
+            ResponseCode::Unknown,
+            format!("unable to start background save: {}", err),
+        ),
+    }
+}
+
+/// Send a message to the subscribers of a shard channel, replying with the number of subscribers.
+fn do_spublish(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_spublish, args: {:?}", args);
+
+    let (channel, message) = (args[0], args[1]);
+
+    let frame = build_response(|writer| {
+        writer.push_arr(3);
+        writer.push_string("smessage");
+        writer.push_string(channel);
+        writer.push_string(message);
+    });
+
+    response_writer.push_int(context.pubsub.publish(channel, &frame));
+}
+
+fn do_pubsub(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_pubsub, args: {:?}", args);
+
+    match args {
+        [b"shardchannels"] | [b"shardchannels", _] => {
+            let channels: Vec<Vec<u8>> = context
+                .pubsub
+                .channels()
+                .into_iter()
+                .filter(|channel| args.len() == 1 || glob::matches(args[1], channel))
+                .collect();
+
+            let size: usize = 5 + channels.iter().map(|c| 5 + c.len()).sum::<usize>();
+            if size > protocol::MAX_MSG_LEN {
+                response_writer.push_err(ResponseCode::TooBig, "response too large");
+                return;
+            }
+
+            response_writer.push_arr(channels.len());
+            for channel in channels {
+                response_writer.push_string(channel);
+            }
+        }
+        [b"shardnumsub", channels @ ..] => {
+            let size: usize = 5 + channels.iter().map(|c| 5 + c.len() + 9).sum::<usize>();
+            if size > protocol::MAX_MSG_LEN {
+                response_writer.push_err(ResponseCode::TooBig, "response too large");
+                return;
+            }
+
+            response_writer.push_arr(channels.len() * 2);
+            for channel in channels {
+                response_writer.push_string(channel);
+                response_writer.push_int(context.pubsub.nb_subscribers(channel));
+            }
+        }
+        _ => response_writer.push_err(ResponseCode::Unknown, "invalid PUBSUB subcommand"),
+    }
+}
+
+fn do_ping(args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_ping, args: {:?}", args);
+
+    match args {
+        [] => response_writer.push_string("PONG"),
+        [message] => response_writer.push_string(message),
+        _ => response_writer.push_err(ResponseCode::Unknown, "wrong number of arguments for PING"),
+    }
+}
+
+/// Exit the server, saving a snapshot first with SAVE or if there are save rules, unless NOSAVE is given.
+/// The server keeps running if the snapshot can't be saved.
+fn do_shutdown(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_shutdown, args: {:?}", args);
+
+    let (save, path) = {
+        let config = context.config.read().unwrap();
+        let save = match args {
+            [] => !config.save_rules.is_empty(),
+            [b"save"] => true,
+            [b"nosave"] => false,
+            _ => {
+                response_writer.push_err(ResponseCode::Unknown, "syntax error");
+                return;
+            }
+        };
+
+        (save, config.snapshot_path.clone())
+    };
+
+    if save {
+        info!("saving the snapshot before shutting down");
+
+        if let Err(err) = snapshot::save(&context.data, &path) {
+            warn!(
+                "unable to save the snapshot, not shutting down, err: {}",
+                err
+            );
+            response_writer.push_err(
+                ResponseCode::Unknown,
+                format!("unable to save the snapshot: {}", err),
+            );
+            return;
+        }
+    }
+
+    // NOTE(vincent): the append only file is written without buffering, there's nothing to flush.
+    info!("shutting down");
+    for stop in context.event_loops.lock().unwrap().iter() {
+        stop.send(());
+    }
+
+    response_writer.push_nil();
+}
+
+/// Reply with the sections of `args` as `field:value` lines under a `# Section` header, or every section if there
+/// are none.
+fn do_info(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_info, args: {:?}", args);
+
+    let all = args.is_empty() || args.iter().any(|&section| section == b"all");
+    let wants = |section: &[u8]| all || args.contains(&section);
+
+    let mut sections = Vec::new();
+    if wants(b"persistence") {
+        let dirty = context.dirty.total();
+        let aof_enabled = context.aof.lock().unwrap().is_some();
+
+        sections.push(format!(
+            "# Persistence\r\nchanges_since_last_save:{}\r\nbgsave_in_progress:{}\r\n\
+             last_bgsave_status:{}\r\naof_enabled:{}\r\n",
+            context.saver.changes_since_save(dirty),
+            context.saver.is_in_progress() as u8,
+            if context.saver.last_save_failed() {
+                "err"
+            } else {
+                "ok"
+            },
+            aof_enabled as u8,
+        ));
+    }
+    if wants(b"stats") {
+        let mut stats = format!(
+            "# Stats\r\nconnected_clients:{}\r\n{}total_changes:{}\r\n",
+            context.nb_clients.load(Ordering::Relaxed),
+            context.stats.info(),
+            context.dirty.total(),
+        );
+        for (command, count) in context.dirty.by_command() {
+            stats.push_str(&format!("changes_{}:{}\r\n", command, count));
+        }
+
+        sections.push(stats);
+    }
+    if wants(b"replication") {
+        sections.push(format!("# Replication\r\n{}", context.replication.info()));
+    }
+    if wants(b"latencystats") {
+        let mut stats = "# Latencystats\r\n".to_string();
+        for summary in context.latency.summaries(&[]) {
+            stats.push_str(&format!(
+                "latency_percentiles_usec_{}:p50={},p99={},p99.9={}\r\n",
+                summary.command, summary.p50, summary.p99, summary.p999
+            ));
+        }
+
+        sections.push(stats);
+    }
+    if wants(b"commandstats") {
+        let mut stats = "# Commandstats\r\n".to_string();
+        for summary in context.latency.summaries(&[]) {
+            stats.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},failed_calls={}\r\n",
+                summary.command,
+                summary.calls,
+                summary.total,
+                summary.total as f64 / summary.calls as f64,
+                summary.failed_calls
+            ));
+        }
+
+        sections.push(stats);
+    }
+
+    let info = sections.join("\r\n");
+    if info.len() > MAX_RESPONSE_STRING_LEN {
+        response_writer.push_err(ResponseCode::TooBig, "response too large");
+    } else {
+        response_writer.push_string(info);
+    }
+}
+
+fn do_memory(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_memory, args: {:?}", args);
+
+    match args {
+        // NOTE(vincent): the size of a key is exact, SAMPLES is accepted for compatibility but ignored.
+        [b"usage", key] | [b"usage", key, b"samples", _] => {
+            let usage = std::str::from_utf8(key)
+                .ok()
+                .and_then(|key| context.data.memory_usage(key));
+
+            match usage {
+                Some(usage) => response_writer.push_int(usage),
+                None => response_writer.push_nil(),
+            }
+        }
+        [b"stats"] => {
+            let used_memory = context.data.used_memory();
+            let nb_keys = context.data.len();
+
+            let stats = [
+                ("dataset.bytes", used_memory),
+                ("dataset.peak.bytes", context.data.peak_memory()),
+                ("overhead.hashtable.main", context.data.overhead()),
+                ("keys.count", nb_keys),
+                (
+                    "keys.bytes-per-key",
+                    used_memory.checked_div(nb_keys).unwrap_or(0),
+                ),
+                ("maxmemory", context.config.read().unwrap().max_memory),
+            ];
+
+            response_writer.push_arr(stats.len() * 2);
+            for (name, value) in stats {
+                response_writer.push_string(name);
+                response_writer.push_int(value);
+            }
+        }
+        _ => response_writer.push_err(ResponseCode::Unknown, "invalid MEMORY subcommand"),
+    }
+}
+
+fn do_latency(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_latency, args: {:?}", args);
+
+    match args {
+        [b"history", command] => {
+            let history = context.latency.history(command);
+
+            response_writer.push_arr(history.len());
+            for (time, latency) in history {
+                response_writer.push_arr(2);
+                response_writer.push_int(time as usize);
+                response_writer.push_int(latency as usize);
+            }
+        }
+        [b"histogram", commands @ ..] => {
+            let summaries = context.latency.summaries(commands);
+
+            // Array headers, integers and strings with their data type and length
+            let size: usize = 5 + summaries
+                .iter()
+                .map(|summary| 5 + (5 + summary.command.len()) + 5 * 9)
+                .sum::<usize>();
+            if size > protocol::MAX_MSG_LEN {
+                response_writer.push_err(ResponseCode::TooBig, "response too large");
+                return;
+            }
+
+            response_writer.push_arr(summaries.len());
+            for summary in summaries {
+                response_writer.push_arr(6);
+                response_writer.push_string(summary.command);
+                response_writer.push_int(summary.calls as usize);
+                response_writer.push_int(summary.p50 as usize);
+                response_writer.push_int(summary.p99 as usize);
+                response_writer.push_int(summary.p999 as usize);
+                response_writer.push_int(summary.max as usize);
+            }
+        }
+        [b"reset", commands @ ..] => response_writer.push_int(context.latency.reset(commands)),
+        _ => response_writer.push_err(ResponseCode::Unknown, "invalid LATENCY subcommand"),
+    }
+}
+
+fn do_config_get(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_config_get, args: {:?}", args);
+
+    let pattern = match std::str::from_utf8(args[0]) {
+        Ok(pattern) => pattern,
+        Err(_) => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid pattern");
+            return;
+        }
+    };
+
+    let parameters = context.config.read().unwrap().get_parameters(pattern);
+
+    response_writer.push_arr(parameters.len() * 2);
+    for (name, value) in parameters {
+        response_writer.push_string(name);
+        response_writer.push_string(value);
+    }
+}
+
+fn do_config_set(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_config_set, args: {:?}", args);
+
+    let (name, value) = match (std::str::from_utf8(args[0]), std::str::from_utf8(args[1])) {
+        (Ok(name), Ok(value)) => (name, value),
+        _ => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid parameter");
+            return;
+        }
+    };
+
+    let mut config = context.config.write().unwrap();
+    match config.set_parameter(name, value) {
+        Ok(()) => {
+            log::set_level(config.log_level);
+            response_writer.push_nil()
+        }
+        Err(err) => response_writer.push_err(ResponseCode::Unknown, err.to_string()),
+    }
+}
+
+fn do_cluster(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_cluster, args: {:?}", args);
+
+    if let [b"keyslot", key] = args {
+        response_writer.push_int(cluster::key_slot(key) as usize);
+        return;
+    }
+
+    let cluster = match &context.cluster {
+        Some(cluster) => cluster,
+        None => {
+            response_writer.push_err(
+                ResponseCode::Unknown,
+                "This instance has cluster support disabled",
+            );
+            return;
+        }
+    };
+
+    let (slot, action) = match args {
+        [b"info"] => {
+            response_writer.push_string(cluster.read().unwrap().info());
+            return;
+        }
+        [b"myid"] => {
+            let myself = cluster.read().unwrap().address(Owner::Myself);
+            response_writer.push_string(cluster::node_id(myself));
+            return;
+        }
+        [b"nodes"] => {
+            let nodes = cluster.read().unwrap().describe_nodes();
+            if nodes.len() > MAX_RESPONSE_STRING_LEN {
+                response_writer.push_err(ResponseCode::TooBig, "response too large");
+            } else {
+                response_writer.push_string(nodes);
+            }
+            return;
+        }
+        [b"slots"] => {
+            do_cluster_slots(&cluster.read().unwrap(), response_writer);
+            return;
+        }
+        [b"setslot", slot, action @ ..] => match cluster::parse_slot(slot) {
+            Some(slot) => (slot, action),
+            None => {
+                response_writer.push_err(ResponseCode::Unknown, "invalid slot");
+                return;
+            }
+        },
+        _ => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid CLUSTER subcommand");
+            return;
+        }
+    };
+
+    let node = |value: &[u8]| std::str::from_utf8(value).ok()?.parse().ok();
+
+    let mut cluster = cluster.write().unwrap();
+    let done = match action {
+        [b"migrating", target] => {
+            node(target).is_some_and(|target| cluster.set_migrating(slot, target))
+        }
+        [b"importing", source] => {
+            node(source).is_some_and(|source| cluster.set_importing(slot, source))
+        }
+        [b"stable"] => {
+            cluster.set_stable(slot);
+            true
+        }
+        [b"node", b"myself"] => {
+            cluster.set_owner(slot, Owner::Myself);
+            true
+        }
+        [b"node", owner] => match node(owner) {
+            Some(owner) => {
+                cluster.set_owner(slot, Owner::Node(owner));
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    };
+
+    if done {
+        response_writer.push_nil();
+    } else {
+        response_writer.push_err(ResponseCode::Unknown, "invalid CLUSTER SETSLOT");
+    }
+}
+
+/// Reply with the slot ranges, each one as `[start, end, [ip, port, id]]`.
+fn do_cluster_slots(cluster: &Cluster, response_writer: &mut protocol::Writer) {
+    let ranges: Vec<_> = cluster
+        .slot_ranges()
+        .into_iter()
+        .map(|range| {
+            let addr = cluster.address(range.owner);
+            (
+                range,
+                addr.ip().to_string(),
+                addr.port(),
+                cluster::node_id(addr),
+            )
+        })
+        .collect();
+
+    // Array headers, integers and strings with their data type and length
+    let size: usize = 5 + ranges
+        .iter()
+        .map(|(_, ip, _, id)| 5 + 9 + 9 + 5 + (5 + ip.len()) + 9 + (5 + id.len()))
+        .sum::<usize>();
+    if size > protocol::MAX_MSG_LEN {
+        response_writer.push_err(ResponseCode::TooBig, "response too large");
+        return;
+    }
+
+    response_writer.push_arr(ranges.len());
+    for (range, ip, port, id) in ranges {
+        response_writer.push_arr(3);
+        response_writer.push_int(range.start as usize);
+        response_writer.push_int(range.end as usize);
+
+        response_writer.push_arr(3);
+        response_writer.push_string(ip);
+        response_writer.push_int(port as usize);
+        response_writer.push_string(id);
+    }
+}
+
+/// Executed by the event loop of the connection, `client` is the client sending the request and `tracking_redirect`
+/// its tracking state.
+fn do_client(
+    context: &Context,
+    client: &Client,
+    tracking_redirect: &mut Option<u64>,
+    args: &[&[u8]],
+    response_writer: &mut protocol::Writer,
+) {
+    debug!("do_client, args: {:?}", args);
+
+    let addr = |value: &[u8]| std::str::from_utf8(value).ok()?.parse().ok();
+
+    match args {
+        [b"id"] => response_writer.push_int(client.id as usize),
+        [b"info"] => response_writer.push_string(context.clients.describe(client)),
+        [b"getname"] => match client.name() {
+            name if name.is_empty() => response_writer.push_nil(),
+            name => response_writer.push_string(name),
+        },
+        [b"setname", name] => {
+            // The names are listed separated by spaces
+            if name
+                .iter()
+                .any(|&b| b.is_ascii_whitespace() || !b.is_ascii_graphic())
+            {
+                response_writer.push_err(
+                    ResponseCode::Unknown,
+                    "Client names cannot contain spaces, newlines or special characters.",
+                );
+                return;
+            }
+
+            client.set_name(&String::from_utf8_lossy(name));
+            response_writer.push_nil();
+        }
+        [b"tracking", b"on", b"redirect", id] => match parse_u64(id) {
+            Some(id) if context.clients.contains(id) => {
+                *tracking_redirect = Some(id);
+                response_writer.push_nil();
+            }
+            Some(_) => response_writer.push_err(
+                ResponseCode::Unknown,
+                "The client ID you want redirect to does not exist",
+            ),
+            None => response_writer.push_err(ResponseCode::Unknown, "invalid client id"),
+        },
+        [b"tracking", b"on"] => response_writer.push_err(
+            ResponseCode::Unknown,
+            "tracking needs REDIRECT to a client subscribed to __redis__:invalidate",
+        ),
+        [b"tracking", b"off"] => {
+            if tracking_redirect.take().is_some() {
+                context.tracking.forget(client.id);
+            }
+            response_writer.push_nil();
+        }
+        [b"list"] => {
+            let list = context.clients.list();
+            if list.len() > MAX_RESPONSE_STRING_LEN {
+                response_writer.push_err(ResponseCode::TooBig, "response too large");
+            } else {
+                response_writer.push_string(list);
+            }
+        }
+        // The old form kills a single client by address
+        [b"kill", target] => match addr(target) {
+            Some(target) if context.clients.kill(&KillFilter::Addr(target)) > 0 => {
+                response_writer.push_nil()
+            }
+            Some(_) => response_writer.push_err(ResponseCode::Unknown, "No such client"),
+            None => response_writer.push_err(ResponseCode::Unknown, "invalid address"),
+        },
+        [b"kill", filter, value] => {
+            let filter = match *filter {
+                b"id" => std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|id| id.parse().ok())
+                    .map(KillFilter::Id),
+                b"addr" => addr(value).map(KillFilter::Addr),
+                _ => None,
+            };
+
+            match filter {
+                Some(filter) => response_writer.push_int(context.clients.kill(&filter)),
+                None => response_writer.push_err(ResponseCode::Unknown, "invalid filter"),
+            }
+        }
+        _ => response_writer.push_err(ResponseCode::Unknown, "invalid CLIENT subcommand"),
+    }
+}
+
+fn do_replica_of(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_replica_of, args: {:?}", args);
+
+    let value = format!(
+        "{} {}",
+        String::from_utf8_lossy(args[0]),
+        String::from_utf8_lossy(args[1])
+    );
+
+    let primary = match config::parse_replica_of(&value) {
+        Some(primary) => primary,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid primary address");
+            return;
+        }
+    };
+
+    change_primary(context, primary);
+
+    response_writer.push_nil();
+}
+
+/// Replicate from `primary`, or stop replicating if `None`.
+fn change_primary(context: &Context, primary: Option<SocketAddrV4>) {
+    context.config.write().unwrap().replica_of = primary;
+    // Only a primary expires the keys, its replicas wait for the DEL
+    context.data.set_expire_keys(primary.is_none());
+    context.replication.set_primary(primary);
+}
+
+fn do_role(context: &Context, _args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_role");
+
+    let epoch = context.failover.epoch() as usize;
+
+    match context.config.read().unwrap().replica_of {
+        Some(primary) => {
+            response_writer.push_arr(4);
+            response_writer.push_string("slave");
+            response_writer.push_string(primary.ip().to_string());
+            response_writer.push_int(primary.port() as usize);
+            response_writer.push_int(epoch);
+        }
+        None => {
+            response_writer.push_arr(2);
+            response_writer.push_string("master");
+            response_writer.push_int(epoch);
+        }
+    }
+}
+
+fn do_failover_vote(context: &Context, args: &[&[u8]], response_writer: &mut protocol::Writer) {
+    debug!("do_failover_vote, args: {:?}", args);
+
+    let epoch = match parse_u64(args[0]) {
+        Some(epoch) => epoch,
+        None => {
+            response_writer.push_err(ResponseCode::Unknown, "invalid epoch");
+            return;
+        }
+    };
+
+    let (primary, down_after) = {
+        let config = context.config.read().unwrap();
+        (config.replica_of, config.failover_down_after)
+    };
+
+    let granted = context.failover.vote(epoch, primary, down_after);
+    info!(
+        "vote for {} in epoch {} granted: {}",
+        String::from_utf8_lossy(args[1]),
+        epoch,
+        granted
+    );
+
+    response_writer.push_int(granted as usize);
+}
+
+enum ConnectionAction {
+    DoNothing,
+    Delete,
+}
+
+fn do_read_request(
+    context: &Context,
+    connection: &mut Connection,
+    dispatcher: &Dispatcher,
+) -> ConnectionAction {
+    loop {
+        let result = match try_fill_buffer(context, connection, dispatcher) {
+            Err(err) => {
+                match err {
+                    RequestError::EndOfStream => {
+                        debug!(
+                            "end of stream for connection from {}, fd={}",
+                            connection.addr, connection.fd
+                        );
+                    }
+                    err => {
+                        warn!(
+                            "try_fill_buffer call failed for {}, err: {}",
+                            connection.addr, err
+                        );
+                    }
+                }
+                return ConnectionAction::Delete;
+            }
+            Ok(v) => v,
+        };
+        if !result {
+            break;
+        }
+    }
+
+    ConnectionAction::DoNothing
+}
+
+fn do_send_responses(context: &Context, connection: &mut Connection) -> ConnectionAction {
+    loop {
+        let res = match try_flush_buffer(context, connection) {
+            Err(err) => {
+                warn!("do_send_responses: got error {}", err);
+
+                return ConnectionAction::Delete;
+            }
+            Ok(v) => v,
+        };
+
+        if !res {
+            break;
+        }
+    }
+
+    ConnectionAction::DoNothing
+}
+
+fn try_flush_buffer(context: &Context, connection: &mut Connection) -> io::Result<bool> {
+    let written = {
+        let write_buf = connection.write_buf.slices(MAX_IOVECS);
+
+        match crate::writev(connection.fd, &write_buf) {
+            Ok(n) => n,
+            Err(err) => {
+                if err.raw_os_error().unwrap() != libc::EAGAIN {
+                    return Err(err);
+                }
+                return Ok(false);
+            }
+        }
+    };
+
+    connection.write_buf.consume(written);
+    context
+        .stats
+        .net_output_bytes
+        .fetch_add(written as u64, Ordering::Relaxed);
+
+    if connection.write_buf.is_empty() {
+        // Response was fully sent, change state back
+
+        connection.state = State::ReadRequest;
+        connection.response_started = None;
+
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Apply the configured socket options to an accepted connection.
+fn configure_connection(config: &ServerConfig, fd: i32) -> io::Result<()> {
+    if let Some(idle) = config.tcp_keepalive {
+        crate::set_keepalive(
+            fd,
+            idle.as_secs() as u32,
+            config.tcp_keepalive_interval.as_secs() as u32,
+            config.tcp_keepalive_count,
+        )?;
+    }
+
+    crate::set_nodelay(fd, config.tcp_nodelay)
+}
+
+/// Accept a connection on the listener `fd`, returning its fd if it was not rejected. Connections to the admin
+/// listener are not limited by `max_clients`.
+fn accept_new_connection(
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    fd: i32,
+    admin: bool,
+) -> io::Result<Option<i32>> {
+    // Accept new connection
+
+    let mut client_addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut client_addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    // Closed on the early returns, until the connection owns it
+    let socket = unsafe {
+        OwnedSocket::from_raw_fd(crate::accept(fd, &mut client_addr, &mut client_addr_len)?)
+    };
+    let conn_fd = socket.as_raw_fd();
+
+    let addr = crate::socket_addr(&client_addr);
+
+    debug!("accepted connection from {}, fd={}", addr, conn_fd);
+
+    let config = context.config.read().unwrap();
+
+    // Reject the connection if we already have too many
+    if !admin && context.nb_clients.load(Ordering::Relaxed) >= config.max_clients {
+        warn!(
+            "rejecting connection from {}, fd={}, max clients reached",
+            addr, conn_fd
+        );
+
+        reject_connection(conn_fd, "max number of clients reached");
+
+        return Ok(None);
+    }
+
+    socket.set_nonblocking(true)?;
+
+    if let Err(err) = configure_connection(&config, conn_fd) {
+        warn!(
+            "unable to configure connection fd={}, err: {}",
+            conn_fd, err
+        );
+    }
+
+    // Create the connection state, closed with ConnectionAction::Delete from now on
+
+    let conn_fd = socket.into_raw_fd();
+    let client = context.clients.register(conn_fd, addr);
+
+    let connection = Connection {
+        id: client.id,
+        fd: conn_fd,
+        addr,
+        client,
+        state: State::ReadRequest,
+        in_flight: 0,
+        last_activity: Instant::now(),
+        request_started: None,
+        response_started: None,
+        next_check: None,
+        authenticated: false,
+        asking: false,
+        admin,
+        transaction: None,
+        tracking_redirect: None,
+        watched: Vec::new(),
+        channels: Vec::new(),
+        read_buf: ConnectionBuffer::new(config.client_buffer_limit),
+        write_buf: WriteQueue::new(config.client_buffer_limit),
+    };
+    connections.insert(conn_fd, connection);
+
+    context
+        .stats
+        .total_connections
+        .fetch_add(1, Ordering::Relaxed);
+    if !admin {
+        context.nb_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Ok(Some(conn_fd))
+}
+
+/// Tell the client why its connection is about to be closed.
+///
+/// This is best effort: the socket is still blocking but the reply is tiny, it fits in the socket buffer.
+fn reject_connection(fd: i32, message: &str) {
+    let mut buf = vec![0; protocol::BUF_LEN];
+
+    let written = {
+        let mut writer = protocol::Writer::new(&mut buf);
+        writer.push_err(ResponseCode::Unknown, message);
+        writer.finish();
+        writer.written()
+    };
+
+    if let Err(err) = crate::write(fd, &buf[0..written]) {
+        warn!(
+            "unable to write to rejected connection fd={}, err: {}",
+            fd, err
+        );
+    }
+}
+
+/// Apply `action` to the connection, either closing it or updating what the poller waits for depending on its state.
+fn update_connection<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    fd: i32,
+    action: ConnectionAction,
+) -> io::Result<()> {
+    match action {
+        ConnectionAction::DoNothing => match connections.get(&fd) {
+            Some(conn) if matches!(conn.state, State::Replica(_)) => {
+                let conn = connections.remove(&fd).unwrap();
+                let psync = match conn.state {
+                    State::Replica(psync) => psync,
+                    _ => unreachable!(),
+                };
+                poller.deregister(fd)?;
+
+                context.nb_clients.fetch_sub(1, Ordering::Relaxed);
+                context.clients.unregister(&conn.client);
+
+                debug!(
+                    "connection from {} is a replica at offset {}, fd={}",
+                    conn.addr, psync.offset, fd
+                );
+                // The replication owns the socket now
+                let socket = unsafe { OwnedSocket::from_raw_fd(fd) };
+                if let Err(err) =
+                    context
+                        .replication
+                        .add_replica(socket, Arc::clone(&context.data), psync)
+                {
+                    warn!("unable to add replica fd={}, err: {}", fd, err);
+                }
+
+                Ok(())
+            }
+            Some(conn) => poller.modify(fd, conn.state.interest()),
+            None => Ok(()),
+        },
+        ConnectionAction::Delete => {
+            if let Some(conn) = connections.remove(&fd) {
+                context.clients.unregister(&conn.client);
+                for channel in &conn.channels {
+                    context.pubsub.unsubscribe(channel, conn.id);
+                }
+                if conn.tracking_redirect.is_some() {
+                    context.tracking.forget(conn.id);
+                }
+                debug!("closing connection from {}, fd={}", conn.addr, fd);
+
+                if !conn.admin {
+                    context.nb_clients.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+            poller.deregister(fd)?;
+
+            crate::close(fd)
+        }
+    }
+}
+
+/// Process an active connection.
+fn process_connection<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    dispatcher: &Dispatcher,
+    fd: i32,
+) -> io::Result<()> {
+    let conn = match connections.get_mut(&fd) {
+        Some(conn) => conn,
+        None => {
+            debug!("no connection for fd={}", fd);
+            return Ok(());
+        }
+    };
+
+    conn.last_activity = Instant::now();
+
+    let action = match conn.state {
+        State::ReadRequest => do_read_request(context, conn, dispatcher),
+        State::Processing | State::Replica(_) => ConnectionAction::DoNothing,
+        State::SendResponse => match do_send_responses(context, conn) {
+            // Process the requests left unprocessed because of backpressure, if any
+            ConnectionAction::DoNothing
+                if matches!(conn.state, State::ReadRequest) && !conn.read_buf.is_empty() =>
+            {
+                match process_requests(context, conn, dispatcher) {
+                    Ok(()) => ConnectionAction::DoNothing,
+                    Err(err) => {
+                        warn!("process_requests call failed, err: {}", err);
+                        ConnectionAction::Delete
+                    }
+                }
+            }
+            action => action,
+        },
+    };
+
+    update_connection(poller, context, connections, fd, action)
+}
+
+/// Process the requests completed by the worker pool since the last time we were woken up.
+fn process_completions<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    timers: &mut TimerWheel<(i32, u64, Instant)>,
+    timeouts: &Timeouts,
+    dispatcher: &Dispatcher,
+) -> io::Result<()> {
+    for completion in dispatcher.completions.drain() {
+        let conn = match connections.get_mut(&completion.fd) {
+            // The fd may have been reused by a new connection after the original one was closed
+            Some(conn) if conn.id == completion.conn_id => conn,
+            _ => {
+                debug!(
+                    "dropping response for closed connection {}",
+                    completion.conn_id
+                );
+                continue;
+            }
+        };
+
+        conn.last_activity = Instant::now();
+
+        let action = match complete_request(context, conn, dispatcher, completion.response) {
+            Ok(()) => ConnectionAction::DoNothing,
+            Err(err) => {
+                warn!("complete_request call failed, err: {}", err);
+                ConnectionAction::Delete
+            }
+        };
+
+        update_connection(poller, context, connections, completion.fd, action)?;
+
+        if let Some(conn) = connections.get_mut(&completion.fd) {
+            schedule_timer(conn, timers, timeouts);
+        }
+    }
+
+    Ok(())
+}
+
+/// Queue the messages published to the channels the connections subscribed to, and start sending them.
+fn process_messages<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    timers: &mut TimerWheel<(i32, u64, Instant)>,
+    timeouts: &Timeouts,
+    dispatcher: &Dispatcher,
+) -> io::Result<()> {
+    for message in dispatcher.messages.drain() {
+        let conn = match connections.get_mut(&message.fd) {
+            // The fd may have been reused by a new connection after the subscriber was closed
+            Some(conn) if conn.id == message.conn_id => conn,
+            _ => continue,
+        };
+
+        let action = match push_response(context, conn, message.frame) {
+            // Otherwise the message is sent along with the responses
+            Ok(()) if matches!(conn.state, State::ReadRequest) => {
+                conn.state = State::SendResponse;
+                conn.response_started.get_or_insert_with(Instant::now);
+                do_send_responses(context, conn)
+            }
+            Ok(()) => ConnectionAction::DoNothing,
+            Err(err) => {
+                // NOTE(vincent): a subscriber which doesn't keep up is closed, like any client not reading its responses.
+                warn!(
+                    "unable to queue message for {}, closing the connection, err: {}",
+                    conn.addr, err
+                );
+                ConnectionAction::Delete
+            }
+        };
+
+        update_connection(poller, context, connections, message.fd, action)?;
+
+        if let Some(conn) = connections.get_mut(&message.fd) {
+            schedule_timer(conn, timers, timeouts);
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute the requests forwarded by the other event loops and send back the responses.
+fn process_forwarded_jobs(context: &Context, jobs: &Mailbox<Job>) {
+    for job in jobs.drain() {
+        let response = workers::catch_panic(|| execute_request(context, &job.body));
+
+        job.completions.send(Completion {
+            conn_id: job.conn_id,
+            fd: job.fd,
+            response,
+        });
+    }
+}
+
+/// Make sure the connection's timer expires no later than its earliest deadline.
+///
+/// Timers can't be cancelled, so a new one is only inserted when the deadline moves closer; the later timers are
+/// ignored when they expire.
+fn schedule_timer(
+    conn: &mut Connection,
+    timers: &mut TimerWheel<(i32, u64, Instant)>,
+    timeouts: &Timeouts,
+) {
+    let deadline = match conn.deadline(timeouts) {
+        Some((deadline, _)) => deadline,
+        None => Instant::now() + TIMERS_DISABLED_CHECK,
+    };
+
+    if conn
+        .next_check
+        .is_none_or(|next_check| deadline < next_check)
+    {
+        timers.insert(deadline, (conn.fd, conn.id, deadline));
+        conn.next_check = Some(deadline);
+    }
+}
+
+/// Close the connections which reached one of their deadlines: idle for too long, too slow to send a request or
+/// too slow to read the responses.
+///
+/// The timeouts can be changed at runtime so every connection always has a timer, even when they're disabled.
+fn close_timed_out_connections<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+    timers: &mut TimerWheel<(i32, u64, Instant)>,
+    timeouts: &Timeouts,
+) -> io::Result<()> {
+    let now = Instant::now();
+
+    for (fd, conn_id, check) in timers.expire(now) {
+        let conn = match connections.get_mut(&fd) {
+            Some(conn) if conn.id == conn_id && conn.next_check == Some(check) => conn,
+            // Already closed or superseded by an earlier timer
+            _ => continue,
+        };
+
+        conn.next_check = None;
+
+        match conn.deadline(timeouts) {
+            Some((deadline, reason)) if deadline <= now => {
+                debug!(
+                    "closing connection from {}, id={}, reason: {}",
+                    conn.addr, conn_id, reason
+                );
+
+                update_connection(poller, context, connections, fd, ConnectionAction::Delete)?;
+            }
+            _ => schedule_timer(conn, timers, timeouts),
+        }
+    }
+
+    Ok(())
+}
+
+/// Close the connections killed with CLIENT KILL.
+fn close_killed_connections<P: Poller>(
+    poller: &mut P,
+    context: &Context,
+    connections: &mut HashMap<i32, Connection>,
+) -> io::Result<()> {
+    let killed: Vec<(i32, SocketAddrV4)> = connections
+        .values()
+        .filter(|conn| conn.client.is_killed())
+        .map(|conn| (conn.fd, conn.addr))
+        .collect();
+
+    for (fd, addr) in killed {
+        debug!(
+            "closing connection from {}, fd={}, reason: killed",
+            addr, fd
+        );
+
+        update_connection(poller, context, connections, fd, ConnectionAction::Delete)?;
+    }
+
+    Ok(())
+}
+
+fn run_event_loop<P: Poller>(
+    poller: &mut P,
+    fd: i32,
+    admin_fd: Option<i32>,
+    context: &Context,
+    dispatcher: &Dispatcher,
+) -> anyhow::Result<()> {
+    let mut connections: HashMap<i32, Connection> = HashMap::new();
+
+    // Connections are only checked for timeouts when their timer expires, see `close_timed_out_connections`
+    let mut timers = TimerWheel::new(TIMERS_SLOTS, TIMERS_RESOLUTION, Instant::now());
+
+    poller.register(fd, Interest::Read)?;
+    if let Some(admin_fd) = admin_fd {
+        poller.register(admin_fd, Interest::Read)?;
+    }
+    poller.register(dispatcher.completions.fd(), Interest::Read)?;
+    poller.register(dispatcher.messages.fd(), Interest::Read)?;
+    poller.register(dispatcher.stop.fd(), Interest::Read)?;
+    context
+        .event_loops
+        .lock()
+        .unwrap()
+        .push(dispatcher.stop.sender());
+    if let Dispatch::Sharded { jobs, .. } = &dispatcher.dispatch {
+        poller.register(jobs.fd(), Interest::Read)?;
+    }
+
+    let mut events: Vec<Event> = Vec::new();
+
+    loop {
+        // Wait for active fds
+
+        let timeout = timers.time_to_next_tick(Instant::now()).min(POLL_TIMEOUT);
+
+        events.clear();
+        poller.wait(&mut events, timeout)?;
+
+        let timeouts = Timeouts::from_config(&context.config.read().unwrap());
+
+        // Process active connections
+
+        for event in &events {
+            if !(event.readable || event.writable || event.error) {
+                continue;
+            }
+
+            // Try to accept new connections if a listening fd is active
+            if event.fd == fd || Some(event.fd) == admin_fd {
+                let admin = event.fd != fd;
+                let conn_fd =
+                    match accept_new_connection(context, &mut connections, event.fd, admin)? {
+                        Some(conn_fd) => conn_fd,
+                        None => continue,
+                    };
+                poller.register(conn_fd, Interest::Read)?;
+
+                if let Some(conn) = connections.get_mut(&conn_fd) {
+                    schedule_timer(conn, &mut timers, &timeouts);
+                }
+
+                continue;
+            }
+
+            // Requests executed elsewhere are done
+            if event.fd == dispatcher.completions.fd() {
+                process_completions(
+                    poller,
+                    context,
+                    &mut connections,
+                    &mut timers,
+                    &timeouts,
+                    dispatcher,
+                )?;
+                continue;
+            }
+
+            // The server is shut down, the connections are closed but not the listeners
+            if event.fd == dispatcher.stop.fd() {
+                info!("stopping the event loop");
+
+                let fds: Vec<i32> = connections.keys().copied().collect();
+                for conn_fd in fds {
+                    update_connection(
+                        poller,
+                        context,
+                        &mut connections,
+                        conn_fd,
+                        ConnectionAction::Delete,
+                    )?;
+                }
+
+                return Ok(());
+            }
+
+            // Messages were published to channels our connections subscribed to
+            if event.fd == dispatcher.messages.fd() {
+                process_messages(
+                    poller,
+                    context,
+                    &mut connections,
+                    &mut timers,
+                    &timeouts,
+                    dispatcher,
+                )?;
+                continue;
+            }
+
+            // Other event loops forwarded requests for keys we own
+            if let Dispatch::Sharded { jobs, .. } = &dispatcher.dispatch {
+                if event.fd == jobs.fd() {
+                    process_forwarded_jobs(context, jobs);
+                    continue;
+                }
+            }
+
+            // The connection may have been closed while processing a previous event
+            if !connections.contains_key(&event.fd) {
+                continue;
+            }
+
+            process_connection(poller, context, &mut connections, dispatcher, event.fd)?;
+
+            if let Some(conn) = connections.get_mut(&event.fd) {
+                schedule_timer(conn, &mut timers, &timeouts);
+            }
+        }
+
+        close_timed_out_connections(poller, context, &mut connections, &mut timers, &timeouts)?;
+
+        if context.clients.has_killed() {
+            close_killed_connections(poller, context, &mut connections)?;
+        }
+
+        save_if_needed(context);
+    }
+}
+
+/// Start a background save if one of the save rules is met.
+fn save_if_needed(context: &Context) {
+    let path = {
+        let config = context.config.read().unwrap();
+        if !context
+            .saver
+            .should_save(&config.save_rules, context.dirty.total(), Instant::now())
+        {
+            return;
+        }
+
+        config.snapshot_path.clone()
+    };
+
+    match context
+        .saver
+        .start(Arc::clone(&context.data), path, context.dirty.total())
+    {
+        Ok(true) => info!("save rule met, background saving started"),
+        Ok(false) => {}
+        Err(err) => warn!("unable to start background save, err: {}", err),
+    }
+}
+
+/// Propagate a DEL for every key which expired, so the replicas and the append only file don't have to expire them
+/// on their own.
+fn propagate_expirations(context: &Arc<Context>) {
+    let weak = Arc::downgrade(context);
+
+    context.data.set_expire_hook(Box::new(move |key| {
+        if let Some(context) = weak.upgrade() {
+            context.dirty.record(b"expired", 1);
+            context.stats.expired_keys.fetch_add(1, Ordering::Relaxed);
+            propagate(&context, &command::encode(&[b"del", key.as_bytes()]));
+        }
+    }));
+}
+
+/// Send an invalidation for every tracked key modified, to the clients subscribed to
+/// [`tracking::INVALIDATE_CHANNEL`] the readers redirected them to.
+fn invalidate_tracked_keys(context: &Arc<Context>) {
+    let weak = Arc::downgrade(context);
+
+    context.data.set_write_hook(Box::new(move |key| {
+        let context = match weak.upgrade() {
+            Some(context) => context,
+            None => return,
+        };
+
+        let redirects = context.tracking.invalidate(key);
+        if redirects.is_empty() {
+            return;
+        }
+
+        let frame = build_response(|writer| {
+            writer.push_arr(3);
+            writer.push_string("smessage");
+            writer.push_string(tracking::INVALIDATE_CHANNEL);
+            writer.push_string(key);
+        });
+        for redirect in redirects {
+            context
+                .pubsub
+                .send_to(tracking::INVALIDATE_CHANNEL, redirect, &frame);
+        }
+    }));
+}
+
+/// Follow the primary, if any, on a dedicated thread. The primary can be changed at any time with REPLICAOF.
+fn start_replication(context: &Arc<Context>) -> io::Result<thread::JoinHandle<()>> {
+    let context = Arc::clone(context);
+
+    thread::Builder::new()
+        .name("replication".to_string())
+        .spawn(move || {
+            let password = || context.config.read().unwrap().masterauth.clone();
+
+            context
+                .replication
+                .run_replica(&context.data, password, |body| {
+                    let mut buf = vec![0; protocol::BUF_LEN];
+                    if let Err(err) = do_request(&context, body, &mut buf) {
+                        warn!(
+                            "unable to apply replicated request {:?}, err: {}",
+                            body, err
+                        );
+                    }
+                });
+        })
+}
+
+/// Monitor the failover peers, if any, on a dedicated thread.
+fn start_failover(context: &Arc<Context>) -> io::Result<Option<thread::JoinHandle<()>>> {
+    let peers = context.config.read().unwrap().failover_peers.clone();
+    if peers.is_empty() {
+        return Ok(None);
+    }
+
+    let context = Arc::clone(context);
+
+    let thread = thread::Builder::new()
+        .name("failover".to_string())
+        .spawn(move || context.failover.run(context.as_ref(), &peers))?;
+
+    Ok(Some(thread))
+}
+
+/// Create a socket listening on the address configured.
+///
+/// With `reuse_port` multiple sockets can listen on the same port, the kernel balancing the connections between them.
+fn create_listener(addr: SocketAddrV4, backlog: i32, reuse_port: bool) -> io::Result<OwnedSocket> {
+    // Create socket
+
+    let addr = crate::SockAddr::from(addr);
+    let socket = OwnedSocket::tcp(&addr)?;
+    let fd = socket.as_raw_fd();
+
+    debug!("created socket fd={}", fd);
+
+    crate::set_socket_opt(fd, SO_REUSEADDR, 1)?;
+    if reuse_port {
+        crate::set_socket_opt(fd, SO_REUSEPORT, 1)?;
+    }
+    crate::set_socket_nonblocking(fd)?;
+
+    // Bind
+
+    debug!("binding socket");
+
+    crate::bind(fd, &addr)?;
+
+    // Listen
+
+    crate::listen(fd, backlog)?;
+
+    // With the port picked by the kernel if it was 0
+    info!("listening on {}", crate::local_addr(fd)?);
+
+    Ok(socket)
+}
+
+/// Create the listener of the admin commands, on localhost only.
+fn create_admin_listener(config: &ServerConfig, port: u16) -> io::Result<OwnedSocket> {
+    create_listener(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        config.backlog,
+        false,
+    )
+}
+
+/// Run one event loop per thread, each owning the keyspace shard with the same index.
+fn run_thread_per_core(config: &ServerConfig, context: Arc<Context>) -> anyhow::Result<()> {
+    let mailboxes = (0..config.threads)
+        .map(|_| Mailbox::new())
+        .collect::<io::Result<Vec<Mailbox<Job>>>>()?;
+    let peers: Vec<MailboxSender<Job>> = mailboxes.iter().map(|jobs| jobs.sender()).collect();
+
+    let (results_sender, results) = mpsc::channel();
+
+    for (index, jobs) in mailboxes.into_iter().enumerate() {
+        let config = config.clone();
+        let context = Arc::clone(&context);
+        let peers = peers.clone();
+        let results_sender = results_sender.clone();
+
+        thread::Builder::new()
+            .name(format!("shard-{}", index))
+            .spawn(move || {
+                let run = || -> anyhow::Result<()> {
+                    let listener = create_listener(
+                        SocketAddrV4::new(config.bind, config.port),
+                        config.backlog,
+                        true,
+                    )?;
+                    // Only the first event loop serves the admin listener
+                    let admin_listener = match config.admin_port {
+                        Some(port) if index == 0 => Some(create_admin_listener(&config, port)?),
+                        _ => None,
+                    };
+
+                    let dispatcher = Dispatcher {
+                        dispatch: Dispatch::Sharded { index, peers, jobs },
+                        completions: Mailbox::new()?,
+                        messages: Mailbox::new()?,
+                        stop: Mailbox::new()?,
+                    };
+
+                    let mut poller = DefaultPoller::new()?;
+
+                    run_event_loop(
+                        &mut poller,
+                        listener.as_raw_fd(),
+                        admin_listener.as_ref().map(AsRawFd::as_raw_fd),
+                        &context,
+                        &dispatcher,
+                    )
+                };
+
+                let _ = results_sender.send(run());
+            })?;
+    }
+
+    // Event loops only return on errors or after a SHUTDOWN, stop everything as soon as one does
+    match results.recv() {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("every event loop thread panicked")),
+    }
+}
+
+/// Load the snapshot saved by a previous run, if any.
+fn load_snapshot(context: &Context, config: &ServerConfig) -> anyhow::Result<()> {
+    let path = &config.snapshot_path;
+    let start = Instant::now();
+
+    let loaded = snapshot::load(&context.data, path)
+        .with_context(|| format!("unable to load the snapshot {}", path.display()))?;
+
+    if let Some(nb_entries) = loaded {
+        info!(
+            "loaded {} keys from {} in {:?}",
+            nb_entries,
+            path.display(),
+            start.elapsed()
+        );
+    }
+
+    Ok(())
+}
+
+/// Replay the append only file, then open it to log the new writes.
+fn load_append_only_file(context: &Context, config: &ServerConfig) -> anyhow::Result<()> {
+    let path = &config.aof_path;
+    let start = Instant::now();
+
+    let replayed = aof::replay(path, config.aof_load_truncated, |body| {
+        let mut buf = vec![0; protocol::BUF_LEN];
+        if let Err(err) = do_request(context, body, &mut buf) {
+            warn!("unable to replay request {:?}, err: {}", body, err);
+        }
+    })
+    .with_context(|| format!("unable to replay the append only file {}", path.display()))?;
+
+    if let Some(nb_requests) = replayed {
+        info!(
+            "replayed {} requests from {} in {:?}",
+            nb_requests,
+            path.display(),
+            start.elapsed()
+        );
+    }
+
+    let aof = AppendOnlyFile::open(path)
+        .with_context(|| format!("unable to open the append only file {}", path.display()))?;
+    *context.aof.lock().unwrap() = Some(aof);
+
+    Ok(())
+}
+
+/// Load the modules given with `--loadmodule`, before the data since the append only file can contain their commands.
+fn load_modules(context: &Context, config: &ServerConfig) -> anyhow::Result<()> {
+    for path in &config.load_modules {
+        modules::load(path, &context.commands)
+            .with_context(|| format!("unable to load the module {}", path.display()))?;
+
+        info!("loaded module {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Load the data saved by a previous run: the append only file if enabled, otherwise the snapshot.
+fn load_data(context: &Context, config: &ServerConfig) -> anyhow::Result<()> {
+    if config.appendonly {
+        load_append_only_file(context, config)
+    } else {
+        load_snapshot(context, config)
+    }
+}
+
+/// Write the keyspace to the newline-delimited JSON file at `path`.
+fn export_json(context: &Context, path: &Path) -> anyhow::Result<()> {
+    let start = Instant::now();
+
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let nb_entries = json::export(&context.data, &mut writer)?;
+        writer.flush()?;
+
+        Ok::<_, io::Error>(nb_entries)
+    })();
+    let nb_entries = result.with_context(|| format!("unable to export to {}", path.display()))?;
+
+    info!(
+        "exported {} keys to {} in {:?}",
+        nb_entries,
+        path.display(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Read the newline-delimited JSON file at `path` in the keyspace, then save it the same way as the other writes.
+fn import_json(context: &Context, config: &ServerConfig, path: &Path) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let now = SystemTime::now();
+
+    let file = File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+
+    let mut nb_imported = 0;
+    let nb_entries = json::import(BufReader::new(file), |(key, value, expires_at)| {
+        if expires_at.is_some_and(|at| at <= now) {
+            return;
+        }
+        nb_imported += 1;
+
+        propagate(
+            context,
+            &command::encode(&[b"set", key.as_bytes(), value.as_bytes()]),
+        );
+        if let Some(at) = expires_at {
+            let millis = keyspace::to_unix_millis(at).to_string();
+            propagate(
+                context,
+                &command::encode(&[b"pexpireat", key.as_bytes(), millis.as_bytes()]),
+            );
+        }
+
+        context.data.insert_with_expiry(key, value, expires_at);
+    })
+    .with_context(|| format!("unable to import {}", path.display()))?;
+
+    // The append only file is written as the entries are imported
+    if !config.appendonly {
+        snapshot::save(&context.data, &config.snapshot_path).with_context(|| {
+            format!(
+                "unable to save the snapshot {}",
+                config.snapshot_path.display()
+            )
+        })?;
+    }
+
+    info!(
+        "imported {} keys from {} in {:?}, skipped {} expired keys",
+        nb_imported,
+        path.display(),
+        start.elapsed(),
+        nb_entries - nb_imported
+    );
+
+    Ok(())
+}
+
+/// Run the server in the mode of `config`, only returning once an export or an import is done or if serving fails.
+pub fn run(config: ServerConfig) -> anyhow::Result<()> {
+    match &config.mode {
+        Mode::Serve => {}
+        Mode::ExportJson(path) => {
+            let context = Context::new(config.clone(), NB_SHARDS)?;
+            load_modules(&context, &config)?;
+            load_data(&context, &config)?;
+
+            return export_json(&context, path);
+        }
+        Mode::ImportJson(path) => {
+            let context = Context::new(config.clone(), NB_SHARDS)?;
+            load_modules(&context, &config)?;
+            load_data(&context, &config)?;
+
+            return import_json(&context, &config, path);
+        }
+    }
+
+    if config.threads > 1 {
+        info!("starting {} event loops", config.threads);
+
+        let context = Arc::new(Context::new(config.clone(), config.threads)?);
+        load_modules(&context, &config)?;
+        load_data(&context, &config)?;
+        propagate_expirations(&context);
+        invalidate_tracked_keys(&context);
+        start_replication(&context)?;
+        start_failover(&context)?;
+
+        return run_thread_per_core(&config, context);
+    }
+
+    Server::builder().config(config).persist().spawn()?.wait()
+}
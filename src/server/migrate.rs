@@ -0,0 +1,120 @@
+//! Moving keys between servers: DUMP serializes a value, RESTORE creates a key from it and MIGRATE does both, the
+//! RESTORE being sent to the target server.
+//!
+//! A serialized value is made of a version byte, the value and a CRC-64 of both in big-endian.
+
+use crate::command;
+use crate::server::crc64;
+use crate::server::keyspace;
+use crate::server::peer::{Peer, PeerError};
+use onlyerror::Error;
+use std::net::SocketAddrV4;
+use std::time::{Duration, SystemTime};
+
+const VERSION: u8 = 1;
+const CRC_LEN: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("payload too short")]
+    TooShort,
+    #[error("unsupported payload version {0}")]
+    UnsupportedVersion(u8),
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("value is not valid UTF-8")]
+    InvalidValue,
+}
+
+/// Serialize `value` for RESTORE.
+pub fn dump(value: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + value.len() + CRC_LEN);
+    payload.push(VERSION);
+    payload.extend_from_slice(value.as_bytes());
+
+    let crc = crc64::checksum(&payload);
+    payload.extend_from_slice(&crc.to_be_bytes());
+
+    payload
+}
+
+/// Deserialize a value serialized by [`dump`].
+pub fn restore(payload: &[u8]) -> Result<String, PayloadError> {
+    if payload.len() < 1 + CRC_LEN {
+        return Err(PayloadError::TooShort);
+    }
+
+    let (data, crc) = payload.split_at(payload.len() - CRC_LEN);
+    if crc64::checksum(data).to_be_bytes() != crc {
+        return Err(PayloadError::ChecksumMismatch);
+    }
+
+    if data[0] != VERSION {
+        return Err(PayloadError::UnsupportedVersion(data[0]));
+    }
+
+    String::from_utf8(data[1..].to_vec()).map_err(|_| PayloadError::InvalidValue)
+}
+
+/// Create the key on `target` with RESTORE, waiting at most `timeout` for every network operation.
+///
+/// ASKING is sent first so that the target accepts the key if it's importing its slot, in cluster mode.
+pub fn transfer(
+    target: SocketAddrV4,
+    key: &str,
+    value: &str,
+    expires_at: Option<SystemTime>,
+    replace: bool,
+    timeout: Duration,
+) -> Result<(), PeerError> {
+    let payload = dump(value);
+    let ttl = expires_at
+        .map(keyspace::to_unix_millis)
+        .unwrap_or(0)
+        .to_string();
+
+    let mut restore: Vec<&[u8]> = vec![
+        b"restore",
+        key.as_bytes(),
+        ttl.as_bytes(),
+        &payload,
+        b"absttl",
+    ];
+    if replace {
+        restore.push(b"replace");
+    }
+
+    let peer = Peer::connect(target, timeout)?;
+    peer.send(&[command::encode(&[b"asking"]), command::encode(&restore)])?;
+
+    // One response for ASKING, one for RESTORE
+    peer.read_response()?;
+    peer.read_response()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, restore, PayloadError};
+
+    #[test]
+    fn payload() {
+        let payload = dump("foobar");
+        assert_eq!(1 + 6 + 8, payload.len());
+        assert_eq!("foobar", restore(&payload).unwrap());
+        assert_eq!("", restore(&dump("")).unwrap());
+
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 0xff;
+        assert!(matches!(
+            restore(&corrupted),
+            Err(PayloadError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            restore(&payload[..8]),
+            Err(PayloadError::TooShort)
+        ));
+    }
+}
@@ -0,0 +1,409 @@
+//! Loading of the modules given with `--loadmodule`, see [`crate::module`] for their API.
+//!
+//! The commands of a module are registered like the builtin ones, see [`Commands`]. Their replies are buffered and
+//! checked before being written, so that a module can't send a malformed or too large response.
+//!
+//! NOTE(vincent): a module is never unloaded, its commands stay registered until the server exits.
+
+use crate::module::{self, Api, Call, CommandFn, InitFn, Module, Slice};
+use crate::server::commands::{Commands, RegisterError};
+use crate::{protocol, ResponseCode};
+use onlyerror::Error;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Error, Debug)]
+pub enum ModuleError {
+    #[error("invalid path {0}")]
+    InvalidPath(String),
+    #[error("unable to open module: {0}")]
+    Open(String),
+    #[error("module doesn't export {0}")]
+    MissingInit(String),
+    #[error("module initialization failed with {0}")]
+    Init(i32),
+    #[error("unable to register command")]
+    Register(#[from] RegisterError),
+}
+
+/// What the commands of a module act on, implemented by the server context.
+pub trait Host {
+    fn get(&self, key: &str) -> Option<String>;
+    /// Returns false if the value can't be stored because the used memory is over `maxmemory`.
+    fn set(&self, key: String, value: String) -> bool;
+    fn del(&self, key: &str) -> bool;
+}
+
+/// Load the shared object at `path` and register its commands.
+pub fn load<H: Host + 'static>(path: &Path, commands: &Commands<H>) -> Result<(), ModuleError> {
+    let path_c = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| ModuleError::InvalidPath(path.display().to_string()))?;
+
+    // NOTE(vincent): the handle is never closed, the registered commands point into the module
+    let handle = unsafe { libc::dlopen(path_c.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(ModuleError::Open(last_dl_error()));
+    }
+
+    let symbol = unsafe { libc::dlsym(handle, module::INIT_SYMBOL.as_ptr().cast()) };
+    if symbol.is_null() {
+        let name = &module::INIT_SYMBOL[..module::INIT_SYMBOL.len() - 1];
+        return Err(ModuleError::MissingInit(
+            String::from_utf8_lossy(name).into_owned(),
+        ));
+    }
+
+    let init: InitFn = unsafe { std::mem::transmute::<*mut libc::c_void, InitFn>(symbol) };
+
+    init_module(init, commands)
+}
+
+fn last_dl_error() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        return "unknown error".to_string();
+    }
+
+    unsafe { CStr::from_ptr(err) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The module being initialized, behind the [`Module`] pointer.
+struct Loading<'a, H> {
+    commands: &'a Commands<H>,
+    /// The first registration error, returned instead of the result of the initialization.
+    error: Option<RegisterError>,
+}
+
+fn init_module<H: Host + 'static>(init: InitFn, commands: &Commands<H>) -> Result<(), ModuleError> {
+    let mut loading = Loading {
+        commands,
+        error: None,
+    };
+    let api = api::<H>();
+
+    let result = unsafe { init(&mut loading as *mut Loading<H> as *mut Module, &api) };
+
+    match (loading.error, result) {
+        (Some(err), _) => Err(err.into()),
+        (None, 0) => Ok(()),
+        (None, code) => Err(ModuleError::Init(code)),
+    }
+}
+
+fn api<H: Host + 'static>() -> Api {
+    Api {
+        version: module::API_VERSION,
+        register_command: register_command::<H>,
+        get: get::<H>,
+        set: set::<H>,
+        del: del::<H>,
+        reply_nil: reply_nil::<H>,
+        reply_int: reply_int::<H>,
+        reply_string: reply_string::<H>,
+        reply_error: reply_error::<H>,
+        reply_array: reply_array::<H>,
+    }
+}
+
+unsafe extern "C" fn register_command<H: Host + 'static>(
+    module: *mut Module,
+    name: Slice,
+    arity: i32,
+    handler: CommandFn,
+) -> i32 {
+    let loading = &mut *(module as *mut Loading<H>);
+
+    let name = match std::str::from_utf8(name.as_bytes()) {
+        Ok(name) => name,
+        Err(_) => return -1,
+    };
+
+    let result = loading.commands.register(
+        name,
+        arity,
+        Arc::new(move |host, args, writer| call(handler, host, args, writer)),
+    );
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            loading.error.get_or_insert(err);
+            -1
+        }
+    }
+}
+
+enum Reply {
+    Nil,
+    Int(u64),
+    Str(Vec<u8>),
+    Err(Vec<u8>),
+    Arr(usize),
+}
+
+/// The command being executed, behind the [`Call`] pointer.
+struct Executing<'a, H> {
+    host: &'a H,
+    /// The values returned by `get`, kept until the command returns.
+    values: Vec<String>,
+    replies: Vec<Reply>,
+    size: usize,
+    /// Number of replies still expected: one at first, plus the length of each array.
+    pending: usize,
+    invalid: bool,
+}
+
+impl<H> Executing<'_, H> {
+    fn reply(&mut self, reply: Reply) {
+        if self.pending == 0 {
+            self.invalid = true;
+            return;
+        }
+        self.pending -= 1;
+
+        self.size += match &reply {
+            Reply::Nil => 1,
+            Reply::Int(_) => 9,
+            Reply::Str(value) => 5 + value.len(),
+            Reply::Err(message) => 9 + message.len(),
+            Reply::Arr(len) => {
+                self.pending += len;
+                5
+            }
+        };
+
+        // Don't buffer more than what could be written anyway
+        if self.size <= protocol::MAX_MSG_LEN {
+            self.replies.push(reply);
+        }
+    }
+}
+
+fn call<H: Host + 'static>(
+    handler: CommandFn,
+    host: &H,
+    args: &[&[u8]],
+    writer: &mut protocol::Writer,
+) -> u64 {
+    let args: Vec<Slice> = args.iter().map(|arg| Slice::new(arg)).collect();
+    let api = api::<H>();
+
+    let mut executing = Executing {
+        host,
+        values: Vec::new(),
+        replies: Vec::new(),
+        size: 0,
+        pending: 1,
+        invalid: false,
+    };
+
+    let changed = unsafe {
+        handler(
+            &mut executing as *mut Executing<H> as *mut Call,
+            &api,
+            args.as_ptr(),
+            args.len(),
+        )
+    };
+
+    if executing.invalid || executing.pending > 0 {
+        writer.push_err(ResponseCode::Unknown, "invalid reply from module command");
+    } else if executing.size > protocol::MAX_MSG_LEN {
+        writer.push_err(ResponseCode::TooBig, "response too large");
+    } else {
+        for reply in executing.replies {
+            match reply {
+                Reply::Nil => writer.push_nil(),
+                Reply::Int(value) => writer.push_int(value as usize),
+                Reply::Str(value) => writer.push_string(value),
+                Reply::Err(message) => writer.push_err(ResponseCode::Unknown, message),
+                Reply::Arr(len) => writer.push_arr(len),
+            }
+        }
+    }
+
+    changed
+}
+
+unsafe fn executing<'a, H>(call: *mut Call) -> &'a mut Executing<'a, H> {
+    &mut *(call as *mut Executing<H>)
+}
+
+unsafe extern "C" fn get<H: Host>(call: *mut Call, key: Slice, value: *mut Slice) -> i32 {
+    let executing = executing::<H>(call);
+
+    let key = match std::str::from_utf8(key.as_bytes()) {
+        Ok(key) => key,
+        Err(_) => return -1,
+    };
+
+    match executing.host.get(key) {
+        Some(found) => {
+            *value = Slice::new(found.as_bytes());
+            executing.values.push(found);
+            1
+        }
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn set<H: Host>(call: *mut Call, key: Slice, value: Slice) -> i32 {
+    let executing = executing::<H>(call);
+
+    let key = match String::from_utf8(key.as_bytes().to_vec()) {
+        Ok(key) => key,
+        Err(_) => return -1,
+    };
+    let value = match String::from_utf8(value.as_bytes().to_vec()) {
+        Ok(value) => value,
+        Err(_) => return -1,
+    };
+
+    if executing.host.set(key, value) {
+        0
+    } else {
+        -1
+    }
+}
+
+unsafe extern "C" fn del<H: Host>(call: *mut Call, key: Slice) -> i32 {
+    let executing = executing::<H>(call);
+
+    match std::str::from_utf8(key.as_bytes()) {
+        Ok(key) => executing.host.del(key) as i32,
+        Err(_) => 0,
+    }
+}
+
+unsafe extern "C" fn reply_nil<H: Host>(call: *mut Call) {
+    executing::<H>(call).reply(Reply::Nil)
+}
+
+unsafe extern "C" fn reply_int<H: Host>(call: *mut Call, value: u64) {
+    executing::<H>(call).reply(Reply::Int(value))
+}
+
+unsafe extern "C" fn reply_string<H: Host>(call: *mut Call, value: Slice) {
+    executing::<H>(call).reply(Reply::Str(value.as_bytes().to_vec()))
+}
+
+unsafe extern "C" fn reply_error<H: Host>(call: *mut Call, message: Slice) {
+    executing::<H>(call).reply(Reply::Err(message.as_bytes().to_vec()))
+}
+
+unsafe extern "C" fn reply_array<H: Host>(call: *mut Call, len: usize) {
+    executing::<H>(call).reply(Reply::Arr(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{init_module, Host, ModuleError};
+    use crate::module::{Api, Call, Module, Slice};
+    use crate::protocol::{self, BUF_LEN};
+    use crate::server::commands::{Commands, RegisterError};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    struct Keys(Mutex<HashMap<String, String>>);
+
+    impl Host for Keys {
+        fn get(&self, key: &str) -> Option<String> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+
+        fn set(&self, key: String, value: String) -> bool {
+            self.0.lock().unwrap().insert(key, value);
+            true
+        }
+
+        fn del(&self, key: &str) -> bool {
+            self.0.lock().unwrap().remove(key).is_some()
+        }
+    }
+
+    /// Appends its second argument to the key of its first one and replies with both values.
+    unsafe extern "C" fn append(
+        call: *mut Call,
+        api: *const Api,
+        args: *const Slice,
+        _: usize,
+    ) -> u64 {
+        let api = &*api;
+        let args = std::slice::from_raw_parts(args, 2);
+
+        let mut old = Slice::new(b"");
+        (api.get)(call, args[0], &mut old);
+        let new = [old.as_bytes(), args[1].as_bytes()].concat();
+        (api.set)(call, args[0], Slice::new(&new));
+
+        (api.reply_array)(call, 2);
+        (api.reply_string)(call, old);
+        (api.reply_string)(call, Slice::new(&new));
+        1
+    }
+
+    unsafe extern "C" fn twice(call: *mut Call, api: *const Api, _: *const Slice, _: usize) -> u64 {
+        ((*api).reply_nil)(call);
+        ((*api).reply_nil)(call);
+        0
+    }
+
+    unsafe extern "C" fn init(module: *mut Module, api: *const Api) -> i32 {
+        let api = &*api;
+        if (api.register_command)(module, Slice::new(b"append"), 3, append) != 0 {
+            return 1;
+        }
+        (api.register_command)(module, Slice::new(b"twice"), 1, twice)
+    }
+
+    unsafe extern "C" fn init_twice(module: *mut Module, api: *const Api) -> i32 {
+        ((*api).register_command)(module, Slice::new(b"twice"), 1, twice);
+        ((*api).register_command)(module, Slice::new(b"twice"), 1, twice);
+        0
+    }
+
+    fn call(commands: &Commands<Keys>, keys: &Keys, request: &[&[u8]]) -> (Option<u64>, Vec<u8>) {
+        let mut buf = [0; BUF_LEN];
+        let (changed, written) = {
+            let mut writer = protocol::Writer::new(&mut buf);
+            let changed = commands.call(keys, request[0], &request[1..], &mut writer);
+            writer.finish();
+            (changed, writer.written())
+        };
+
+        (changed, buf[4..written].to_vec())
+    }
+
+    #[test]
+    fn commands() {
+        let commands = Commands::new();
+        init_module(init, &commands).unwrap();
+        assert!(matches!(
+            init_module(init_twice, &Commands::<Keys>::new()),
+            Err(ModuleError::Register(RegisterError::AlreadyRegistered(_)))
+        ));
+
+        let keys = Keys(Mutex::new(HashMap::new()));
+        keys.set("foo".to_string(), "ab".to_string());
+
+        let (changed, body) = call(&commands, &keys, &[b"append", b"foo", b"cd"]);
+        assert_eq!(Some(1), changed);
+        assert_eq!(Some("abcd".to_string()), keys.get("foo"));
+        assert_eq!(
+            b"\x04\x00\x00\x00\x02\x02\x00\x00\x00\x02ab\x02\x00\x00\x00\x04abcd",
+            &body[..]
+        );
+
+        // Arity is checked like for the builtin commands
+        assert_eq!(None, call(&commands, &keys, &[b"append", b"foo"]).0);
+
+        // A command replying twice gets an error instead
+        let (changed, body) = call(&commands, &keys, &[b"twice"]);
+        assert_eq!(Some(0), changed);
+        assert_eq!(1, body[0]);
+    }
+}
@@ -0,0 +1,287 @@
+//! Export of a span per command to an OpenTelemetry collector, with OTLP over HTTP and the JSON encoding.
+//!
+//! The spans are queued and sent in batches by a background thread, so a slow or unreachable collector never
+//! slows down the commands: when the queue is full the spans are dropped.
+//!
+//! NOTE(vincent): the protocol has no way to carry a trace context from the clients, so every span is the root
+//! of its own trace.
+
+use crate::server::json;
+use crate::{warn, OwnedSocket};
+use onlyerror::Error;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write as _};
+use std::net::SocketAddrV4;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Spans waiting to be sent, the ones recorded once it's full are dropped.
+const QUEUE_LEN: usize = 4096;
+/// Maximum number of spans sent in one request.
+const BATCH_LEN: usize = 512;
+/// How long a span waits at most before being sent.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+const SERVICE_NAME: &str = "my-own-redis";
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("collector replied with {0:?}")]
+    Refused(String),
+}
+
+/// The execution of a command.
+#[derive(Debug)]
+pub struct Span {
+    pub command: String,
+    pub nb_keys: usize,
+    /// Size of the request and of the response.
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub start: SystemTime,
+    pub duration: Duration,
+}
+
+/// A span with its identifiers, ready to be sent.
+struct Identified {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    span: Span,
+}
+
+pub struct Exporter {
+    spans: Option<mpsc::SyncSender<Identified>>,
+    thread: Option<thread::JoinHandle<()>>,
+    random_state: RandomState,
+    random_counter: AtomicU64,
+}
+
+impl Exporter {
+    /// Start the thread sending the spans to the collector at `endpoint`.
+    pub fn new(endpoint: SocketAddrV4) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+
+        let thread = thread::Builder::new()
+            .name("otlp-exporter".to_string())
+            .spawn(move || export_loop(endpoint, receiver))?;
+
+        Ok(Self {
+            spans: Some(sender),
+            thread: Some(thread),
+            random_state: RandomState::new(),
+            random_counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue `span` to be sent, it's dropped if the queue is full.
+    pub fn record(&self, span: Span) {
+        let mut trace_id = [0; 16];
+        trace_id[..8].copy_from_slice(&self.random().to_be_bytes());
+        trace_id[8..].copy_from_slice(&self.random().to_be_bytes());
+
+        // NOTE(vincent): safe because the sender is only taken when dropping
+        let spans = self.spans.as_ref().unwrap();
+        let _ = spans.try_send(Identified {
+            trace_id,
+            span_id: self.random().to_be_bytes(),
+            span,
+        });
+    }
+
+    fn random(&self) -> u64 {
+        let mut s = self.random_state.build_hasher();
+        s.write_u64(self.random_counter.fetch_add(1, Ordering::Relaxed));
+
+        s.finish()
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        // Closing the channel makes the thread send what's left and exit
+        drop(self.spans.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn export_loop(endpoint: SocketAddrV4, receiver: mpsc::Receiver<Identified>) {
+    let mut batch = Vec::with_capacity(BATCH_LEN);
+
+    loop {
+        // Wait for the first span of the batch, then for the others until the batch is full or old enough
+        let first = match receiver.recv() {
+            Ok(span) => span,
+            Err(mpsc::RecvError) => return,
+        };
+        batch.push(first);
+
+        let deadline = Instant::now() + EXPORT_INTERVAL;
+        let mut closed = false;
+        while batch.len() < BATCH_LEN {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(span) => batch.push(span),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        if let Err(err) = export(endpoint, &batch) {
+            warn!(
+                "unable to export {} spans to {}, err: {}",
+                batch.len(),
+                endpoint,
+                err
+            );
+        }
+        batch.clear();
+
+        if closed {
+            return;
+        }
+    }
+}
+
+/// Send `spans` to the collector at `endpoint` in one request.
+fn export(endpoint: SocketAddrV4, spans: &[Identified]) -> Result<(), ExportError> {
+    let body = encode(spans)?;
+
+    let mut request = Vec::with_capacity(body.len() + 128);
+    write!(
+        request,
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint,
+        body.len()
+    )?;
+    request.extend_from_slice(&body);
+
+    let socket = OwnedSocket::tcp(&endpoint.into())?;
+    send_request(socket.as_raw_fd(), endpoint, &request)
+}
+
+fn send_request(fd: i32, endpoint: SocketAddrV4, request: &[u8]) -> Result<(), ExportError> {
+    crate::set_socket_timeout(fd, EXPORT_TIMEOUT)?;
+    crate::connect(fd, &endpoint.into())?;
+    crate::write_full(fd, request)?;
+
+    // Only the status line matters
+    let mut buf = [0; 256];
+    let response = crate::read(fd, &mut buf)?;
+    let status_line = String::from_utf8_lossy(response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(ExportError::Refused(status_line)),
+    }
+}
+
+fn to_unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encode `spans` as an OTLP `ExportTraceServiceRequest`.
+fn encode(spans: &[Identified]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    write!(
+        buf,
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":\"{}\"}}}}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"{}\"}},\"spans\":[",
+        SERVICE_NAME, SERVICE_NAME
+    )?;
+
+    for (i, identified) in spans.iter().enumerate() {
+        let span = &identified.span;
+        let start = to_unix_nanos(span.start);
+
+        if i > 0 {
+            buf.push(b',');
+        }
+
+        // Kind 2 is a server span, the 64 bit integers are strings in the JSON encoding
+        write!(
+            buf,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{}\",\"name\":",
+            to_hex(&identified.trace_id),
+            to_hex(&identified.span_id)
+        )?;
+        json::write_string(&mut buf, &span.command)?;
+        write!(
+            buf,
+            ",\"kind\":2,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+            start,
+            start + span.duration.as_nanos()
+        )?;
+        buf.extend_from_slice(b"{\"key\":\"db.operation.name\",\"value\":{\"stringValue\":");
+        json::write_string(&mut buf, &span.command)?;
+        write!(
+            buf,
+            "}}}},{{\"key\":\"db.keys\",\"value\":{{\"intValue\":\"{}\"}}}},{{\"key\":\"db.request.size\",\"value\":{{\"intValue\":\"{}\"}}}},{{\"key\":\"db.response.size\",\"value\":{{\"intValue\":\"{}\"}}}}]}}",
+            span.nb_keys, span.bytes_in, span.bytes_out
+        )?;
+    }
+
+    buf.extend_from_slice(b"]}]}]}");
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Identified, Span};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn encode_spans() {
+        let spans = [Identified {
+            trace_id: [0xab; 16],
+            span_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            span: Span {
+                command: "get".to_string(),
+                nb_keys: 1,
+                bytes_in: 20,
+                bytes_out: 12,
+                start: UNIX_EPOCH + Duration::from_secs(1),
+                duration: Duration::from_micros(15),
+            },
+        }];
+
+        let body = String::from_utf8(encode(&spans).unwrap()).unwrap();
+        assert!(body.starts_with(
+            "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\",\"value\":{\"stringValue\":\"my-own-redis\"}}]}"
+        ));
+        assert!(body.contains(
+            "\"spans\":[{\"traceId\":\"abababababababababababababababab\",\"spanId\":\"0102030405060708\",\"name\":\"get\",\"kind\":2,\"startTimeUnixNano\":\"1000000000\",\"endTimeUnixNano\":\"1000015000\""
+        ));
+        assert!(body.contains("{\"key\":\"db.keys\",\"value\":{\"intValue\":\"1\"}}"));
+        assert!(
+            body.contains("{\"key\":\"db.response.size\",\"value\":{\"intValue\":\"12\"}}]}]}]}]}")
+        );
+
+        assert_eq!(
+            "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\",\"value\":{\"stringValue\":\"my-own-redis\"}}]},\"scopeSpans\":[{\"scope\":{\"name\":\"my-own-redis\"},\"spans\":[]}]}]}",
+            String::from_utf8(encode(&[]).unwrap()).unwrap()
+        );
+    }
+}
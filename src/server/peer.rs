@@ -0,0 +1,88 @@
+//! Blocking connections to other servers, to send them requests like a client.
+
+use crate::protocol::{self, DataType};
+use crate::OwnedSocket;
+use onlyerror::Error;
+use std::io;
+use std::net::SocketAddrV4;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("invalid response")]
+    Protocol(#[from] protocol::Error),
+    #[error("peer replied with {0:?}")]
+    Refused(String),
+    #[error("request too large")]
+    TooLarge,
+}
+
+pub struct Peer {
+    socket: OwnedSocket,
+}
+
+impl Peer {
+    /// Connect to `addr`. Every network operation on the connection fails after `timeout`.
+    pub fn connect(addr: SocketAddrV4, timeout: Duration) -> Result<Self, PeerError> {
+        let addr = addr.into();
+        let peer = Self {
+            socket: OwnedSocket::tcp(&addr)?,
+        };
+        let fd = peer.socket.as_raw_fd();
+
+        crate::set_socket_timeout(fd, timeout)?;
+        crate::connect(fd, &addr)?;
+
+        Ok(peer)
+    }
+
+    /// Send the requests, as encoded by [`crate::command::encode`], all at once.
+    pub fn send(&self, requests: &[Vec<u8>]) -> Result<(), PeerError> {
+        let mut frames = Vec::new();
+        for request in requests {
+            if request.len() > protocol::MAX_MSG_LEN {
+                return Err(PeerError::TooLarge);
+            }
+
+            frames.extend_from_slice(&(request.len() as u32).to_be_bytes());
+            frames.extend_from_slice(request);
+        }
+
+        self.socket.write_full(&frames)?;
+
+        Ok(())
+    }
+
+    /// Read the next response, returning its body. Error responses are returned as [`PeerError::Refused`].
+    pub fn read_response(&self) -> Result<Vec<u8>, PeerError> {
+        let mut length = [0; 4];
+        crate::read_full(self.socket.as_raw_fd(), &mut length)?;
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > protocol::MAX_MSG_LEN {
+            return Err(protocol::Error::MessageTooLong(length).into());
+        }
+
+        let mut body = vec![0; length];
+        crate::read_full(self.socket.as_raw_fd(), &mut body)?;
+
+        let mut reader = protocol::Reader::new(&body);
+        if reader.read_data_type()? == DataType::Err {
+            let (_, message) = reader.read_err()?;
+            return Err(PeerError::Refused(
+                String::from_utf8_lossy(message).into_owned(),
+            ));
+        }
+
+        Ok(body)
+    }
+
+    /// Send a single request and wait for its response.
+    pub fn request(&self, args: &[&[u8]]) -> Result<Vec<u8>, PeerError> {
+        self.send(&[crate::command::encode(args)])?;
+        self.read_response()
+    }
+}
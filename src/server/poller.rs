@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+mod kqueue;
+
+/// The poller used by the server, selected at compile time.
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub type DefaultPoller = PollPoller;
+
+/// The poller used by the server, selected at compile time.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub type DefaultPoller = kqueue::KqueuePoller;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interest {
+    /// Keep the fd registered without waiting for anything, errors may still be reported.
+    None,
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+pub struct Event {
+    pub fd: i32,
+    pub readable: bool,
+    pub writable: bool,
+    pub error: bool,
+}
+
+/// A readiness notification mechanism.
+///
+/// The event loop only talks to this trait, which means a new backend (epoll, io_uring, etc) only has to implement it.
+pub trait Poller {
+    /// Start watching `fd` for `interest`.
+    fn register(&mut self, fd: i32, interest: Interest) -> io::Result<()>;
+
+    /// Change what we're watching `fd` for.
+    fn modify(&mut self, fd: i32, interest: Interest) -> io::Result<()>;
+
+    /// Stop watching `fd`. Must be called before closing the file descriptor.
+    fn deregister(&mut self, fd: i32) -> io::Result<()>;
+
+    /// Wait up to `timeout` for events, appending them to `events`.
+    fn wait(&mut self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()>;
+}
+
+/// A [`Poller`] implemented with `poll(2)`.
+#[derive(Default)]
+pub struct PollPoller {
+    fds: Vec<libc::pollfd>,
+    positions: HashMap<i32, usize>,
+}
+
+impl PollPoller {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn events_for(interest: Interest) -> libc::c_short {
+        (match interest {
+            Interest::None => 0,
+            Interest::Read => libc::POLLIN,
+            Interest::Write => libc::POLLOUT,
+        }) | libc::POLLERR
+    }
+}
+
+impl Poller for PollPoller {
+    fn register(&mut self, fd: i32, interest: Interest) -> io::Result<()> {
+        if self.positions.contains_key(&fd) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        self.positions.insert(fd, self.fds.len());
+        self.fds.push(libc::pollfd {
+            fd,
+            events: Self::events_for(interest),
+            revents: 0,
+        });
+
+        Ok(())
+    }
+
+    fn modify(&mut self, fd: i32, interest: Interest) -> io::Result<()> {
+        match self.positions.get(&fd) {
+            Some(&pos) => {
+                self.fds[pos].events = Self::events_for(interest);
+                Ok(())
+            }
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn deregister(&mut self, fd: i32) -> io::Result<()> {
+        let pos = match self.positions.remove(&fd) {
+            Some(pos) => pos,
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        self.fds.swap_remove(pos);
+        if let Some(moved) = self.fds.get(pos) {
+            self.positions.insert(moved.fd, pos);
+        }
+
+        Ok(())
+    }
+
+    fn wait(&mut self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()> {
+        let rv = unsafe {
+            libc::poll(
+                self.fds.as_mut_ptr(),
+                self.fds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if rv < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for pfd in &self.fds {
+            if pfd.revents <= 0 {
+                continue;
+            }
+
+            events.push(Event {
+                fd: pfd.fd,
+                readable: (pfd.revents & (libc::POLLIN | libc::POLLHUP)) != 0,
+                writable: (pfd.revents & libc::POLLOUT) != 0,
+                error: (pfd.revents & (libc::POLLERR | libc::POLLNVAL)) != 0,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultPoller, Event, Interest, PollPoller, Poller};
+    use std::time::Duration;
+
+    fn make_pipe() -> (i32, i32) {
+        let mut fds = [0; 2];
+        let rv = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        assert_eq!(0, rv);
+
+        (fds[0], fds[1])
+    }
+
+    fn check_poller<P: Poller>(mut poller: P) {
+        let (read_fd, write_fd) = make_pipe();
+
+        poller.register(read_fd, Interest::Read).unwrap();
+        poller.register(write_fd, Interest::Write).unwrap();
+
+        // Only the write end is ready
+        let mut events: Vec<Event> = Vec::new();
+        poller
+            .wait(&mut events, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(write_fd, events[0].fd);
+        assert!(events[0].writable);
+
+        // Now the read end is ready too
+        crate::write_full(write_fd, b"foobar").unwrap();
+        poller.modify(write_fd, Interest::Read).unwrap();
+
+        events.clear();
+        poller
+            .wait(&mut events, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(read_fd, events[0].fd);
+        assert!(events[0].readable);
+
+        // Nothing is watched anymore
+        poller.deregister(read_fd).unwrap();
+        poller.deregister(write_fd).unwrap();
+
+        events.clear();
+        poller.wait(&mut events, Duration::from_millis(10)).unwrap();
+        assert!(events.is_empty());
+
+        crate::close(read_fd).unwrap();
+        crate::close(write_fd).unwrap();
+    }
+
+    #[test]
+    fn poll_poller() {
+        check_poller(PollPoller::new().unwrap());
+    }
+
+    #[test]
+    fn default_poller() {
+        check_poller(DefaultPoller::new().unwrap());
+    }
+}
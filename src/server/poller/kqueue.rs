@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use super::{Event, Interest, Poller};
+
+const MAX_EVENTS: usize = 1024;
+
+fn filter_for(interest: Interest) -> Option<i16> {
+    match interest {
+        Interest::None => None,
+        Interest::Read => Some(libc::EVFILT_READ as i16),
+        Interest::Write => Some(libc::EVFILT_WRITE as i16),
+    }
+}
+
+/// A [`Poller`] implemented with kqueue, used on macOS and the BSDs.
+///
+/// Interest changes are buffered and only submitted to the kernel on the next call to [`Poller::wait`],
+/// which means switching a connection between reading and writing doesn't cost an extra syscall.
+pub struct KqueuePoller {
+    fd: i32,
+    interests: HashMap<i32, Interest>,
+    changes: Vec<libc::kevent>,
+    events: Vec<libc::kevent>,
+}
+
+impl KqueuePoller {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::kqueue() };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            interests: HashMap::new(),
+            changes: Vec::new(),
+            events: Vec::with_capacity(MAX_EVENTS),
+        })
+    }
+
+    fn push_change(&mut self, fd: i32, filter: i16, flags: u16) {
+        let mut change: libc::kevent = unsafe { mem::zeroed() };
+        change.ident = fd as libc::uintptr_t;
+        change.filter = filter as _;
+        change.flags = flags as _;
+
+        self.changes.push(change);
+    }
+}
+
+impl Poller for KqueuePoller {
+    fn register(&mut self, fd: i32, interest: Interest) -> io::Result<()> {
+        if self.interests.contains_key(&fd) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        self.interests.insert(fd, interest);
+        if let Some(filter) = filter_for(interest) {
+            self.push_change(fd, filter, libc::EV_ADD as u16);
+        }
+
+        Ok(())
+    }
+
+    fn modify(&mut self, fd: i32, interest: Interest) -> io::Result<()> {
+        let previous = match self.interests.insert(fd, interest) {
+            Some(previous) => previous,
+            None => {
+                self.interests.remove(&fd);
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+        };
+
+        if previous != interest {
+            if let Some(filter) = filter_for(previous) {
+                self.push_change(fd, filter, libc::EV_DELETE as u16);
+            }
+            if let Some(filter) = filter_for(interest) {
+                self.push_change(fd, filter, libc::EV_ADD as u16);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&mut self, fd: i32) -> io::Result<()> {
+        // The kernel drops the registration by itself when the fd is closed, we only need to make sure
+        // we don't submit pending changes for it.
+
+        if self.interests.remove(&fd).is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        self.changes
+            .retain(|change| change.ident != fd as libc::uintptr_t);
+
+        Ok(())
+    }
+
+    fn wait(&mut self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()> {
+        let ts = libc::timespec {
+            tv_sec: timeout.as_secs() as _,
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+
+        self.events.clear();
+
+        let n = unsafe {
+            libc::kevent(
+                self.fd,
+                self.changes.as_ptr(),
+                self.changes.len() as _,
+                self.events.as_mut_ptr(),
+                self.events.capacity() as _,
+                &ts,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.changes.clear();
+
+        // NOTE(vincent): safe because the kernel initialized the first `n` events
+        unsafe { self.events.set_len(n as usize) };
+
+        events.extend(self.events.iter().map(|ev| Event {
+            fd: ev.ident as i32,
+            readable: ev.filter as i16 == libc::EVFILT_READ as i16,
+            writable: ev.filter as i16 == libc::EVFILT_WRITE as i16,
+            error: (ev.flags as u16 & libc::EV_ERROR as u16) != 0,
+        }));
+
+        Ok(())
+    }
+}
+
+impl Drop for KqueuePoller {
+    fn drop(&mut self) {
+        let _ = crate::close(self.fd);
+    }
+}
@@ -0,0 +1,180 @@
+//! Shard channels for SSUBSCRIBE and SPUBLISH.
+//!
+//! A message published to a channel is only delivered to the subscribers connected to this node: in cluster mode the
+//! channel is routed by its hash slot like a key, so its publishers and subscribers all end up on the node owning it.
+//!
+//! The connections belong to their event loop, so a message is sent to the mailbox of the event loop of every
+//! subscriber which then queues it on the connection.
+
+use crate::server::workers::MailboxSender;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A message to queue on a connection, already serialized.
+pub struct Message {
+    pub conn_id: u64,
+    pub fd: i32,
+    pub frame: Vec<u8>,
+}
+
+struct Subscriber {
+    conn_id: u64,
+    fd: i32,
+    messages: MailboxSender<Message>,
+}
+
+pub struct PubSub {
+    channels: Mutex<HashMap<Vec<u8>, Vec<Subscriber>>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Subscribe the connection `conn_id` to `channel`, its messages are sent to `messages`.
+    /// Returns false if it was already subscribed.
+    pub fn subscribe(
+        &self,
+        channel: &[u8],
+        conn_id: u64,
+        fd: i32,
+        messages: &MailboxSender<Message>,
+    ) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let subscribers = channels.entry(channel.to_vec()).or_default();
+
+        if subscribers.iter().any(|s| s.conn_id == conn_id) {
+            return false;
+        }
+
+        subscribers.push(Subscriber {
+            conn_id,
+            fd,
+            messages: messages.clone(),
+        });
+
+        true
+    }
+
+    /// Unsubscribe the connection `conn_id` from `channel`, returns false if it wasn't subscribed.
+    pub fn unsubscribe(&self, channel: &[u8], conn_id: u64) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+
+        let subscribers = match channels.get_mut(channel) {
+            Some(subscribers) => subscribers,
+            None => return false,
+        };
+
+        let len = subscribers.len();
+        subscribers.retain(|s| s.conn_id != conn_id);
+        let removed = subscribers.len() < len;
+
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+
+        removed
+    }
+
+    /// Send `frame` to every subscriber of `channel`, returning how many there are.
+    pub fn publish(&self, channel: &[u8], frame: &[u8]) -> usize {
+        let channels = self.channels.lock().unwrap();
+
+        let subscribers = match channels.get(channel) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+
+        for subscriber in subscribers {
+            subscriber.messages.send(Message {
+                conn_id: subscriber.conn_id,
+                fd: subscriber.fd,
+                frame: frame.to_vec(),
+            });
+        }
+
+        subscribers.len()
+    }
+
+    /// Send `frame` to the connection `conn_id` only if it's subscribed to `channel`, returning true if it is.
+    pub fn send_to(&self, channel: &[u8], conn_id: u64, frame: &[u8]) -> bool {
+        let channels = self.channels.lock().unwrap();
+
+        let subscriber = channels
+            .get(channel)
+            .and_then(|subscribers| subscribers.iter().find(|s| s.conn_id == conn_id));
+
+        match subscriber {
+            Some(subscriber) => {
+                subscriber.messages.send(Message {
+                    conn_id,
+                    fd: subscriber.fd,
+                    frame: frame.to_vec(),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the channels with at least one subscriber, sorted.
+    pub fn channels(&self) -> Vec<Vec<u8>> {
+        let mut channels: Vec<Vec<u8>> = self.channels.lock().unwrap().keys().cloned().collect();
+        channels.sort();
+
+        channels
+    }
+
+    pub fn nb_subscribers(&self, channel: &[u8]) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map(|subscribers| subscribers.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, PubSub};
+    use crate::server::workers::Mailbox;
+
+    #[test]
+    fn publish() {
+        let pubsub = PubSub::new();
+        let mailbox = Mailbox::<Message>::new().unwrap();
+
+        assert!(pubsub.subscribe(b"news", 1, 10, &mailbox.sender()));
+        assert!(!pubsub.subscribe(b"news", 1, 10, &mailbox.sender()));
+        assert!(pubsub.subscribe(b"news", 2, 11, &mailbox.sender()));
+        assert!(pubsub.subscribe(b"sport", 2, 11, &mailbox.sender()));
+
+        assert_eq!(2, pubsub.publish(b"news", b"hello"));
+        assert_eq!(0, pubsub.publish(b"weather", b"hello"));
+
+        let messages = mailbox.drain();
+        assert_eq!(2, messages.len());
+        assert_eq!((1, 10), (messages[0].conn_id, messages[0].fd));
+        assert_eq!((2, 11), (messages[1].conn_id, messages[1].fd));
+        assert_eq!(b"hello", messages[1].frame.as_slice());
+
+        assert!(pubsub.send_to(b"sport", 2, b"goal"));
+        assert!(!pubsub.send_to(b"sport", 1, b"goal"));
+        let messages = mailbox.drain();
+        assert_eq!(1, messages.len());
+        assert_eq!((2, 11), (messages[0].conn_id, messages[0].fd));
+
+        assert_eq!(vec![b"news".to_vec(), b"sport".to_vec()], pubsub.channels());
+
+        assert!(pubsub.unsubscribe(b"news", 1));
+        assert!(!pubsub.unsubscribe(b"news", 1));
+        assert_eq!(1, pubsub.nb_subscribers(b"news"));
+
+        assert!(pubsub.unsubscribe(b"sport", 2));
+        assert_eq!(vec![b"news".to_vec()], pubsub.channels());
+    }
+}
@@ -0,0 +1,889 @@
+//! Replication: a replica receives a snapshot of its primary's keyspace, then every write the primary executes.
+//!
+//! A replica connects to its primary like a client, authenticates with AUTH if the primary requires a password, and
+//! sends a [`Psync`] frame with the replication ID and the offset it reached, if any. The primary replies with
+//! [`PsyncReply::FullResync`] followed by a snapshot, or with [`PsyncReply::Continue`] if the writes the replica
+//! missed are still in its backlog. From then on the connection is a stream of every request which changed the
+//! primary's keyspace, framed like the requests of the clients (see [`protocol::parse_message`]).
+//!
+//! The offsets count the bytes of the stream since the primary started, each primary identifying its stream with a
+//! random replication ID.
+
+use crate::protocol::{DataType, Psync, PsyncReply, CAPA_CONTINUE};
+use crate::server::crc64;
+use crate::server::keyspace::Keyspace;
+use crate::server::snapshot::{self, LoadError};
+use crate::{command, info, protocol, warn, OwnedSocket};
+use onlyerror::Error;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddrV4;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Maximum number of writes queued for a replica, it's disconnected if it can't keep up.
+const REPLICA_BACKLOG: usize = 100_000;
+/// Bytes of the most recent writes kept for the replicas continuing after a disconnection.
+const STREAM_BACKLOG_LEN: usize = 1024 * 1024;
+/// How long a replica waits before connecting again to its primary after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("invalid snapshot")]
+    Snapshot(#[from] LoadError),
+    #[error("invalid request")]
+    Protocol(#[from] protocol::Error),
+    #[error("primary refused with {0:?}")]
+    Refused(String),
+    #[error("unexpected reply of type {0}")]
+    UnexpectedReply(DataType),
+    #[error("primary continued at offset {got} instead of {want}")]
+    UnexpectedOffset { want: u64, got: u64 },
+}
+
+/// How far a replica connected to us is, in bytes of the stream since it connected.
+#[derive(Default)]
+struct ReplicaProgress {
+    /// Set once the snapshot, or the backlog, is sent.
+    online: AtomicBool,
+    /// Writes queued for the replica.
+    queued: AtomicU64,
+    /// Writes sent to the replica.
+    sent: AtomicU64,
+}
+
+/// A replica connected to us.
+struct ReplicaLink {
+    id: u64,
+    addr: SocketAddrV4,
+    /// Offset of the stream when the replica connected.
+    start_offset: u64,
+    writes: mpsc::SyncSender<Arc<[u8]>>,
+    progress: Arc<ReplicaProgress>,
+}
+
+/// How a replica starts.
+enum Start {
+    /// With a snapshot taken at `offset`.
+    Full { offset: u64 },
+    /// With the writes of the backlog it missed since `offset`.
+    Continue { offset: u64, missed: Vec<u8> },
+}
+
+/// The stream of writes sent to the replicas.
+struct Stream {
+    replicas: Vec<ReplicaLink>,
+    /// Bytes of writes fed since we started, whether replicas were connected or not.
+    offset: u64,
+    /// The last bytes of the stream, ending at `offset`.
+    backlog: VecDeque<u8>,
+}
+
+impl Stream {
+    /// Returns the writes a replica missed since `offset`, if they're all still in the backlog.
+    fn missed_since(&self, offset: u64) -> Option<Vec<u8>> {
+        let missed = self.offset.checked_sub(offset)? as usize;
+        if missed > self.backlog.len() {
+            return None;
+        }
+
+        Some(
+            self.backlog
+                .range(self.backlog.len() - missed..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LinkState {
+    Down,
+    /// Connected, waiting for the snapshot.
+    Syncing,
+    Up,
+}
+
+/// The primary we replicate from.
+struct PrimaryLink {
+    addr: Option<SocketAddrV4>,
+    /// Incremented every time the primary changes, to abandon the synchronization with the previous one.
+    generation: u64,
+    /// The connection to the primary while synchronizing, shut down to interrupt it.
+    fd: Option<i32>,
+
+    state: LinkState,
+    /// The stream of the primary we followed and how far we got in it, to continue from there after a disconnection.
+    replication_id: Option<String>,
+    offset: u64,
+    /// When we last received something from the primary.
+    last_io: Option<Instant>,
+    /// When the last full synchronization completed.
+    last_sync: Option<SystemTime>,
+    /// Set by [`Replication::stop`] to make [`Replication::run_replica`] return.
+    stopped: bool,
+}
+
+pub struct Replication {
+    /// Identifies our stream, random.
+    id: String,
+    stream: Mutex<Stream>,
+    next_replica_id: AtomicU64,
+
+    primary: Mutex<PrimaryLink>,
+    primary_changed: Condvar,
+}
+
+/// Returns the [`Psync`] frame sent by a replica, if the request body is one.
+pub fn parse_psync(body: &[u8]) -> Option<Psync> {
+    protocol::Reader::new(body).read_psync().ok()
+}
+
+/// Returns a new replication ID, 40 hexadecimal characters.
+fn new_replication_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let seed = format!(
+        "{:?}-{}-{}",
+        SystemTime::now(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let mut id: String = (0..3)
+        .map(|i| {
+            format!(
+                "{:016x}",
+                crc64::checksum(format!("{}-{}", seed, i).as_bytes())
+            )
+        })
+        .collect();
+    id.truncate(40);
+
+    id
+}
+
+impl Replication {
+    pub fn new(primary: Option<SocketAddrV4>) -> Self {
+        Self {
+            id: new_replication_id(),
+            stream: Mutex::new(Stream {
+                replicas: Vec::new(),
+                offset: 0,
+                backlog: VecDeque::new(),
+            }),
+            next_replica_id: AtomicU64::new(0),
+            primary: Mutex::new(PrimaryLink {
+                addr: primary,
+                generation: 0,
+                fd: None,
+                state: LinkState::Down,
+                replication_id: None,
+                offset: 0,
+                last_io: None,
+                last_sync: None,
+                stopped: false,
+            }),
+            primary_changed: Condvar::new(),
+        }
+    }
+
+    /// Start streaming to the replica which sent `psync` on `socket`: a snapshot of `keyspace` first or the writes it
+    /// missed, then every write passed to [`Replication::feed`].
+    ///
+    /// The socket is closed once the replica is disconnected, or right away if this fails.
+    pub fn add_replica(
+        &self,
+        socket: OwnedSocket,
+        keyspace: Arc<Keyspace>,
+        psync: Psync,
+    ) -> io::Result<()> {
+        socket.set_nonblocking(false)?;
+        let fd = socket.as_raw_fd();
+        let addr = crate::peer_addr(fd)?;
+
+        let id = self.next_replica_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::sync_channel(REPLICA_BACKLOG);
+        let progress = Arc::new(ReplicaProgress::default());
+
+        // Registered before the snapshot is taken so no write is missed. The writes already in the snapshot are
+        // applied again by the replica which ends up with the same data.
+        let start = {
+            let mut stream = self.stream.lock().unwrap();
+
+            let missed = (psync.capabilities & CAPA_CONTINUE != 0
+                && psync.replication_id == self.id)
+                .then(|| stream.missed_since(psync.offset))
+                .flatten();
+            let start = match missed {
+                Some(missed) => {
+                    progress
+                        .queued
+                        .store(missed.len() as u64, Ordering::Relaxed);
+                    Start::Continue {
+                        offset: psync.offset,
+                        missed,
+                    }
+                }
+                None => Start::Full {
+                    offset: stream.offset,
+                },
+            };
+
+            let start_offset = match start {
+                Start::Full { offset } | Start::Continue { offset, .. } => offset,
+            };
+            stream.replicas.push(ReplicaLink {
+                id,
+                addr,
+                start_offset,
+                writes: sender,
+                progress: Arc::clone(&progress),
+            });
+
+            start
+        };
+
+        let replication_id = self.id.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("replica-{}", id))
+            .spawn(move || {
+                match stream_to_replica(fd, &keyspace, &replication_id, start, receiver, &progress)
+                {
+                    Ok(()) => warn!("replica {} disconnected, it can't keep up", id),
+                    Err(err) => warn!("replica {} disconnected, err: {}", id, err),
+                }
+
+                drop(socket);
+            });
+
+        if let Err(err) = spawned {
+            self.stream
+                .lock()
+                .unwrap()
+                .replicas
+                .retain(|replica| replica.id != id);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Send a request which changed the keyspace to every replica.
+    pub fn feed(&self, body: &[u8]) {
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+
+        let mut stream = self.stream.lock().unwrap();
+
+        stream.offset += frame.len() as u64;
+        stream.backlog.extend(&frame);
+        let excess = stream.backlog.len().saturating_sub(STREAM_BACKLOG_LEN);
+        stream.backlog.drain(..excess);
+
+        if stream.replicas.is_empty() {
+            return;
+        }
+
+        let frame: Arc<[u8]> = Arc::from(frame);
+
+        stream.replicas.retain(
+            |replica| match replica.writes.try_send(Arc::clone(&frame)) {
+                Ok(()) => {
+                    replica
+                        .progress
+                        .queued
+                        .fetch_add(frame.len() as u64, Ordering::Relaxed);
+                    true
+                }
+                // NOTE(vincent): dropping the sender makes the replica's thread disconnect it once it sent the writes
+                // queued; it will continue from the backlog or synchronize again from scratch.
+                Err(TrySendError::Full(_)) => false,
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        );
+    }
+
+    /// Replicate from `primary` from now on, or stop replicating if `None`.
+    pub fn set_primary(&self, primary: Option<SocketAddrV4>) {
+        let mut link = self.primary.lock().unwrap();
+
+        link.addr = primary;
+        link.generation += 1;
+        link.state = LinkState::Down;
+        link.last_io = None;
+        link.last_sync = None;
+        // Once promoted our keyspace diverges from the stream we followed, it can't be continued anymore
+        if primary.is_none() {
+            link.replication_id = None;
+            link.offset = 0;
+        }
+
+        // Interrupt the synchronization with the previous primary, if any
+        if let Some(fd) = link.fd.take() {
+            let _ = crate::shutdown(fd);
+        }
+
+        self.primary_changed.notify_all();
+    }
+
+    /// Make [`Replication::run_replica`] return, interrupting the synchronization in progress if any.
+    pub fn stop(&self) {
+        let mut link = self.primary.lock().unwrap();
+
+        link.stopped = true;
+        link.generation += 1;
+        if let Some(fd) = link.fd.take() {
+            let _ = crate::shutdown(fd);
+        }
+
+        self.primary_changed.notify_all();
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.primary.lock().unwrap().generation == generation
+    }
+
+    /// Update the state of the link to the primary, unless it changed since `generation`.
+    fn update_link<F: FnOnce(&mut PrimaryLink)>(&self, generation: u64, f: F) {
+        let mut link = self.primary.lock().unwrap();
+        if link.generation == generation {
+            f(&mut link);
+        }
+    }
+
+    /// Describe the replication for the INFO command, one `field:value` line per field.
+    ///
+    /// The lag of a replica is the bytes of writes queued for it but not sent yet.
+    pub fn info(&self) -> String {
+        let mut info = String::new();
+
+        // A replica reports the stream it follows
+        let replication_id = {
+            let link = self.primary.lock().unwrap();
+
+            match link.addr {
+                None => info.push_str("role:master\r\n"),
+                Some(addr) => {
+                    let state = if link.state == LinkState::Up {
+                        "up"
+                    } else {
+                        "down"
+                    };
+                    let last_io = link
+                        .last_io
+                        .map(|instant| instant.elapsed().as_secs() as i64)
+                        .unwrap_or(-1);
+                    let last_sync = link
+                        .last_sync
+                        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or(0);
+
+                    let _ = write!(
+                        info,
+                        "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n\
+                         master_last_io_seconds_ago:{}\r\nmaster_sync_in_progress:{}\r\n\
+                         master_last_sync_time:{}\r\nslave_repl_offset:{}\r\n",
+                        addr.ip(),
+                        addr.port(),
+                        state,
+                        last_io,
+                        (link.state == LinkState::Syncing) as u8,
+                        last_sync,
+                        link.offset,
+                    );
+                }
+            }
+
+            link.replication_id
+                .clone()
+                .unwrap_or_else(|| self.id.clone())
+        };
+
+        let stream = self.stream.lock().unwrap();
+
+        let _ = write!(info, "connected_slaves:{}\r\n", stream.replicas.len());
+        for (i, replica) in stream.replicas.iter().enumerate() {
+            let online = replica.progress.online.load(Ordering::Relaxed);
+            let queued = replica.progress.queued.load(Ordering::Relaxed);
+            let sent = replica.progress.sent.load(Ordering::Relaxed);
+
+            let _ = write!(
+                info,
+                "slave{}:id={},ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                replica.id,
+                replica.addr.ip(),
+                replica.addr.port(),
+                if online { "online" } else { "sync" },
+                replica.start_offset + sent,
+                queued.saturating_sub(sent),
+            );
+        }
+
+        let _ = write!(
+            info,
+            "master_replid:{}\r\nmaster_repl_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+            replication_id,
+            stream.offset,
+            stream.backlog.len(),
+        );
+
+        info
+    }
+
+    /// Follow the primary set with [`Replication::set_primary`], calling `apply` with every write it sends.
+    /// `password` is called before every synchronization, to authenticate with the primary.
+    ///
+    /// This only returns once [`Replication::stop`] is called, it's meant to run on its own thread.
+    pub fn run_replica<P, F>(&self, keyspace: &Keyspace, password: P, mut apply: F)
+    where
+        P: Fn() -> Option<String>,
+        F: FnMut(&[u8]),
+    {
+        loop {
+            let (addr, generation) = {
+                let mut link = self.primary.lock().unwrap();
+                while link.addr.is_none() && !link.stopped {
+                    link = self.primary_changed.wait(link).unwrap();
+                }
+                if link.stopped {
+                    return;
+                }
+
+                (link.addr.unwrap(), link.generation)
+            };
+
+            info!("replicating from {}", addr);
+
+            let password = password();
+
+            if let Err(err) = self.sync(addr, generation, password, keyspace, &mut apply) {
+                if self.is_current(generation) {
+                    warn!("replication from {} failed, err: {}", addr, err);
+                }
+            }
+
+            // Wait a bit before trying again, unless the primary changed in the meantime
+            let link = self.primary.lock().unwrap();
+            if link.generation == generation {
+                let _ = self
+                    .primary_changed
+                    .wait_timeout(link, RECONNECT_DELAY)
+                    .unwrap();
+            }
+        }
+    }
+
+    fn sync<F: FnMut(&[u8])>(
+        &self,
+        addr: SocketAddrV4,
+        generation: u64,
+        password: Option<String>,
+        keyspace: &Keyspace,
+        apply: &mut F,
+    ) -> Result<(), SyncError> {
+        let socket = OwnedSocket::tcp(&addr.into())?;
+        let fd = socket.as_raw_fd();
+
+        {
+            let mut link = self.primary.lock().unwrap();
+            if link.generation != generation {
+                return Ok(());
+            }
+            link.fd = Some(fd);
+        }
+
+        let result = self.sync_with(fd, addr, generation, password, keyspace, apply);
+
+        self.update_link(generation, |link| link.state = LinkState::Down);
+
+        // NOTE(vincent): only closed once it can't be shut down by `set_primary` anymore, otherwise the fd could be
+        // reused in between.
+        {
+            let mut link = self.primary.lock().unwrap();
+            if link.fd == Some(fd) {
+                link.fd = None;
+            }
+        }
+        drop(socket);
+
+        result
+    }
+
+    fn sync_with<F: FnMut(&[u8])>(
+        &self,
+        fd: i32,
+        addr: SocketAddrV4,
+        generation: u64,
+        password: Option<String>,
+        keyspace: &Keyspace,
+        apply: &mut F,
+    ) -> Result<(), SyncError> {
+        crate::connect(fd, &addr.into())?;
+
+        // The primary may have changed while connecting
+        if !self.is_current(generation) {
+            return Ok(());
+        }
+        self.update_link(generation, |link| link.state = LinkState::Syncing);
+
+        if let Some(password) = password {
+            send_request(fd, &[b"auth", password.as_bytes()])?;
+            read_reply(fd)?;
+        }
+
+        let psync = {
+            let link = self.primary.lock().unwrap();
+            Psync {
+                capabilities: CAPA_CONTINUE,
+                replication_id: link.replication_id.clone().unwrap_or_default(),
+                offset: link.offset,
+            }
+        };
+        send_psync(fd, &psync)?;
+
+        match parse_psync_reply(&read_message(fd)?)? {
+            PsyncReply::FullResync {
+                replication_id,
+                offset,
+                snapshot_len,
+            } => {
+                let mut data = vec![0; snapshot_len as usize];
+                crate::read_full(fd, &mut data)?;
+
+                // NOTE(vincent): the append only file of the replica is not rewritten, it only contains the writes
+                // received after the synchronization.
+                keyspace.clear();
+                let nb_entries = snapshot::read(keyspace, &data[..])?;
+
+                info!(
+                    "synchronized {} keys from {} at offset {}",
+                    nb_entries, addr, offset
+                );
+
+                self.update_link(generation, |link| {
+                    link.replication_id = Some(replication_id);
+                    link.offset = offset;
+                    link.last_sync = Some(SystemTime::now());
+                });
+            }
+            PsyncReply::Continue { offset, .. } => {
+                if offset != psync.offset {
+                    return Err(SyncError::UnexpectedOffset {
+                        want: psync.offset,
+                        got: offset,
+                    });
+                }
+
+                info!("continuing replication from {} at offset {}", addr, offset);
+            }
+        }
+
+        self.update_link(generation, |link| {
+            link.state = LinkState::Up;
+            link.last_io = Some(Instant::now());
+        });
+
+        // Then every write
+
+        loop {
+            let body = read_message(fd)?;
+            apply(&body);
+
+            self.update_link(generation, |link| {
+                link.offset += (4 + body.len()) as u64;
+                link.last_io = Some(Instant::now());
+            });
+        }
+    }
+}
+
+fn send_request(fd: i32, args: &[&[u8]]) -> io::Result<()> {
+    let request = command::encode(args);
+
+    let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&request);
+
+    crate::write_full(fd, &frame)
+}
+
+/// Read a message framed like the requests and responses, returning its body.
+fn read_message(fd: i32) -> Result<Vec<u8>, SyncError> {
+    let mut length = [0; 4];
+    crate::read_full(fd, &mut length)?;
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > protocol::MAX_MSG_LEN {
+        return Err(protocol::Error::MessageTooLong(length).into());
+    }
+
+    let mut body = vec![0; length];
+    crate::read_full(fd, &mut body)?;
+
+    Ok(body)
+}
+
+fn send_psync(fd: i32, psync: &Psync) -> io::Result<()> {
+    let mut buf = vec![0; protocol::BUF_LEN];
+    let written = {
+        let mut writer = protocol::Writer::new(&mut buf);
+        writer.push_psync(psync);
+        writer.finish();
+        writer.written()
+    };
+
+    crate::write_full(fd, &buf[..written])
+}
+
+/// Parse the primary's reply to PSYNC, which can be an error response instead.
+fn parse_psync_reply(body: &[u8]) -> Result<PsyncReply, SyncError> {
+    match protocol::Reader::new(body).read_psync_reply() {
+        Ok(reply) => Ok(reply),
+        Err(protocol::Error::UnexpectedFrame(_)) => match parse_reply(body)? {
+            Some(_) => Err(SyncError::UnexpectedReply(DataType::Int)),
+            None => Err(SyncError::UnexpectedReply(DataType::Nil)),
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Read the primary's reply to one of our requests, which is either nil or an Int.
+fn read_reply(fd: i32) -> Result<Option<u64>, SyncError> {
+    parse_reply(&read_message(fd)?)
+}
+
+fn parse_reply(body: &[u8]) -> Result<Option<u64>, SyncError> {
+    let mut reader = protocol::Reader::new(body);
+
+    match reader.read_data_type()? {
+        DataType::Nil => Ok(None),
+        DataType::Int => Ok(Some(reader.read_int()?)),
+        DataType::Err => {
+            let (_, message) = reader.read_err()?;
+            Err(SyncError::Refused(
+                String::from_utf8_lossy(message).into_owned(),
+            ))
+        }
+        data_type => Err(SyncError::UnexpectedReply(data_type)),
+    }
+}
+
+/// Send a snapshot of the keyspace or the writes missed to the replica on `fd`, then every write received until the
+/// sender is dropped.
+fn stream_to_replica(
+    fd: i32,
+    keyspace: &Keyspace,
+    replication_id: &str,
+    start: Start,
+    writes: mpsc::Receiver<Arc<[u8]>>,
+    progress: &ReplicaProgress,
+) -> io::Result<()> {
+    let mut reply = vec![0; protocol::BUF_LEN];
+    let mut write_reply = |reply_frame: &PsyncReply| {
+        let written = {
+            let mut writer = protocol::Writer::new(&mut reply);
+            writer.push_psync_reply(reply_frame);
+            writer.finish();
+            writer.written()
+        };
+        crate::write_full(fd, &reply[..written])
+    };
+
+    match start {
+        Start::Full { offset } => {
+            let mut data = Vec::new();
+            let nb_entries = snapshot::write(keyspace, &mut data)?;
+
+            write_reply(&PsyncReply::FullResync {
+                replication_id: replication_id.to_string(),
+                offset,
+                snapshot_len: data.len() as u64,
+            })?;
+            crate::write_full(fd, &data)?;
+
+            info!("sent {} keys to replica fd={}", nb_entries, fd);
+        }
+        Start::Continue { offset, missed } => {
+            write_reply(&PsyncReply::Continue {
+                replication_id: replication_id.to_string(),
+                offset,
+            })?;
+            crate::write_full(fd, &missed)?;
+            progress
+                .sent
+                .fetch_add(missed.len() as u64, Ordering::Relaxed);
+
+            info!(
+                "sent {} bytes of backlog to replica fd={}",
+                missed.len(),
+                fd
+            );
+        }
+    }
+
+    progress.online.store(true, Ordering::Relaxed);
+
+    for frame in writes {
+        crate::write_full(fd, &frame)?;
+        progress
+            .sent
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_psync, parse_reply, read_message, Replication, Stream, SyncError};
+    use crate::protocol::{Psync, Writer, BUF_LEN, CAPA_CONTINUE};
+    use crate::server::keyspace::Keyspace;
+    use crate::{command, OwnedSocket, ResponseCode};
+    use std::collections::VecDeque;
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn psync_request() {
+        let mut buf = vec![0; BUF_LEN];
+        let written = {
+            let mut writer = Writer::new(&mut buf);
+            writer.push_psync(&Psync {
+                capabilities: CAPA_CONTINUE,
+                replication_id: "abcd".to_string(),
+                offset: 20,
+            });
+            writer.finish();
+            writer.written()
+        };
+
+        let psync = parse_psync(&buf[4..written]).unwrap();
+        assert_eq!("abcd", psync.replication_id);
+        assert_eq!(20, psync.offset);
+
+        assert!(parse_psync(&command::encode(&[b"sync"])).is_none());
+    }
+
+    #[test]
+    fn missed_since() {
+        let stream = Stream {
+            replicas: Vec::new(),
+            offset: 100,
+            backlog: VecDeque::from(vec![1, 2, 3, 4]),
+        };
+
+        assert_eq!(Some(vec![]), stream.missed_since(100));
+        assert_eq!(Some(vec![3, 4]), stream.missed_since(98));
+        assert_eq!(Some(vec![1, 2, 3, 4]), stream.missed_since(96));
+        // Not in the backlog anymore
+        assert_eq!(None, stream.missed_since(95));
+        // Ahead of the stream, the replica followed another primary
+        assert_eq!(None, stream.missed_since(101));
+    }
+
+    #[test]
+    fn reply() {
+        let mut buf = vec![0; BUF_LEN];
+        let mut encode = |f: &dyn Fn(&mut Writer)| {
+            let mut writer = Writer::new(&mut buf);
+            f(&mut writer);
+            writer.finish();
+            let written = writer.written();
+            buf[4..written].to_vec()
+        };
+
+        assert_eq!(None, parse_reply(&encode(&|w| w.push_nil())).unwrap());
+        assert_eq!(Some(20), parse_reply(&encode(&|w| w.push_int(20))).unwrap());
+        assert!(matches!(
+            parse_reply(&encode(&|w| w.push_err(ResponseCode::NoAuth, "no"))),
+            Err(SyncError::Refused(message)) if message == "no"
+        ));
+        assert!(matches!(
+            parse_reply(&encode(&|w| w.push_string("foo"))),
+            Err(SyncError::UnexpectedReply(_))
+        ));
+    }
+
+    #[test]
+    fn replicate() {
+        let primary_keyspace = Arc::new(Keyspace::new(4, 1, None));
+        primary_keyspace.insert("foo".to_string(), "bar".to_string());
+
+        let primary = Arc::new(Replication::new(None));
+
+        // Stand-in for the event loop, which hands the connections sending PSYNC to the replication
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        {
+            let primary = Arc::clone(&primary);
+            let keyspace = Arc::clone(&primary_keyspace);
+
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let fd = stream.into_raw_fd();
+
+                let psync = parse_psync(&read_message(fd).unwrap()).unwrap();
+                assert_eq!("", psync.replication_id);
+
+                let socket = unsafe { OwnedSocket::from_raw_fd(fd) };
+                primary.add_replica(socket, keyspace, psync).unwrap();
+            });
+        }
+
+        let replica_keyspace = Arc::new(Keyspace::new(4, 1, None));
+        replica_keyspace.insert("stale".to_string(), "value".to_string());
+
+        let replica = Arc::new(Replication::new(Some(SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            port,
+        ))));
+        assert!(replica.info().contains("master_link_status:down\r\n"));
+
+        let (applied, writes) = mpsc::channel();
+        {
+            let keyspace = Arc::clone(&replica_keyspace);
+            let replica = Arc::clone(&replica);
+
+            thread::spawn(move || {
+                replica.run_replica(
+                    &keyspace,
+                    || None,
+                    |body| applied.send(body.to_vec()).unwrap(),
+                );
+            });
+        }
+
+        // Wait for the full synchronization
+        while !replica.info().contains("master_link_status:up\r\n") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(vec!["foo".to_string()], replica_keyspace.keys());
+
+        let body = command::encode(&[b"set", b"bar", b"baz"]);
+        primary.feed(&body);
+
+        assert_eq!(body, writes.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        let info = primary.info();
+        assert!(info.starts_with("role:master\r\nconnected_slaves:1\r\nslave0:id=0,ip=127.0.0.1,"));
+        assert!(info.contains(&format!("master_repl_offset:{}\r\n", 4 + body.len())));
+
+        // The replica follows the stream of the primary
+        let replica_info = replica.info();
+        assert!(replica_info.contains(&format!("master_replid:{}\r\n", primary.id)));
+    }
+}
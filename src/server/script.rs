@@ -0,0 +1,1564 @@
+//! A small subset of Lua to run the scripts of EVAL and EVALSHA.
+//!
+//! A script is compiled once, then run with its keys in `KEYS` and its other arguments in `ARGV`. It runs commands
+//! with `redis.call(name, arg, ...)`, which returns the reply converted to a Lua value or raises an error if the
+//! command failed.
+//!
+//! Supported:
+//! * `local`, assignments, `if`/`elseif`/`else`, `while`, numeric `for`, `do`, `break` and `return`
+//! * nil, booleans, integers, strings and tables used as arrays
+//! * the arithmetic, comparison, logical, concatenation and length operators
+//! * `redis.call`, `tonumber`, `tostring`, `type` and `error`
+//!
+//! NOTE(vincent): numbers are 64 bit integers, `/` divides like `//`. There are no functions defined by the scripts
+//! and no global variables. A table stored in itself is never freed.
+//!
+//! The scripts are deterministic: nothing gives them the time or a random number, and they only see the keyspace
+//! through `redis.call`. A script runs until it returns, so it's stopped after [`MAX_STEPS`] steps.
+
+use crate::protocol::{self, DataType};
+use onlyerror::Error;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Statements and loop iterations a script can run before it's stopped.
+const MAX_STEPS: u64 = 10_000_000;
+/// Nesting of blocks and expressions accepted by the parser, and of tables in a reply.
+const MAX_DEPTH: usize = 200;
+/// Longest string a concatenation can build, a value can't be larger than a message anyway.
+const MAX_STRING_LEN: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("syntax error at line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("line {line}: {message}")]
+    Runtime { line: usize, message: String },
+    #[error("script stopped after {0} steps")]
+    TooLong(u64),
+}
+
+//
+// Lexer
+//
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Int(i64),
+    Str(Vec<u8>),
+    Symbol(&'static str),
+    Eof,
+}
+
+/// Longest first so that `..` isn't read as two `.`.
+const SYMBOLS: &[&str] = &[
+    "==", "~=", "<=", ">=", "//", "..", "(", ")", "[", "]", "{", "}", ",", ";", "=", "<", ">", "+",
+    "-", "*", "/", "%", "#", ".",
+];
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "if", "local", "nil", "not",
+    "or", "return", "then", "true", "while",
+];
+
+fn syntax_error<T>(line: usize, message: impl Into<String>) -> Result<T, ScriptError> {
+    Err(ScriptError::Syntax {
+        line,
+        message: message.into(),
+    })
+}
+
+/// Returns the tokens of `source` with their line.
+fn tokenize(source: &[u8]) -> Result<Vec<(Token, usize)>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut pos = 0;
+
+    while pos < source.len() {
+        let c = source[pos];
+
+        if c == b'\n' {
+            line += 1;
+            pos += 1;
+        } else if c.is_ascii_whitespace() {
+            pos += 1;
+        } else if source[pos..].starts_with(b"--") {
+            // A comment, either until the end of the line or a long one
+            pos += 2;
+            if source[pos..].starts_with(b"[[") {
+                let end = match find(&source[pos..], b"]]") {
+                    Some(end) => pos + end + 2,
+                    None => return syntax_error(line, "unfinished long comment"),
+                };
+                line += source[pos..end].iter().filter(|&&c| c == b'\n').count();
+                pos = end;
+            } else {
+                while pos < source.len() && source[pos] != b'\n' {
+                    pos += 1;
+                }
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = pos;
+            while pos < source.len() && (source[pos].is_ascii_alphanumeric() || source[pos] == b'_')
+            {
+                pos += 1;
+            }
+            // NOTE(vincent): safe because it's only ASCII
+            let name = std::str::from_utf8(&source[start..pos]).unwrap();
+            tokens.push((Token::Name(name.to_string()), line));
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < source.len() && (source[pos].is_ascii_alphanumeric() || source[pos] == b'.')
+            {
+                pos += 1;
+            }
+            let literal = std::str::from_utf8(&source[start..pos]).unwrap();
+
+            let value = match literal.strip_prefix("0x").or(literal.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => literal.parse(),
+            };
+            match value {
+                Ok(value) => tokens.push((Token::Int(value), line)),
+                Err(_) => {
+                    return syntax_error(
+                        line,
+                        format!("malformed number {}, only integers are supported", literal),
+                    )
+                }
+            }
+        } else if c == b'"' || c == b'\'' {
+            let (value, end) = read_string(source, pos, line)?;
+            tokens.push((Token::Str(value), line));
+            pos = end;
+        } else {
+            let symbol = match SYMBOLS
+                .iter()
+                .find(|s| source[pos..].starts_with(s.as_bytes()))
+            {
+                Some(symbol) => symbol,
+                None => return syntax_error(line, format!("unexpected symbol {:?}", c as char)),
+            };
+            tokens.push((Token::Symbol(symbol), line));
+            pos += symbol.len();
+        }
+    }
+
+    tokens.push((Token::Eof, line));
+
+    Ok(tokens)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Read the string literal starting with its quote at `start`, returning it and the position after its end.
+fn read_string(source: &[u8], start: usize, line: usize) -> Result<(Vec<u8>, usize), ScriptError> {
+    let quote = source[start];
+    let mut value = Vec::new();
+    let mut pos = start + 1;
+
+    loop {
+        let c = match source.get(pos) {
+            Some(b'\n') | None => return syntax_error(line, "unfinished string"),
+            Some(c) => *c,
+        };
+        pos += 1;
+
+        if c == quote {
+            return Ok((value, pos));
+        }
+        if c != b'\\' {
+            value.push(c);
+            continue;
+        }
+
+        let escaped = match source.get(pos) {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'0') => b'\0',
+            Some(c @ (b'\\' | b'"' | b'\'')) => *c,
+            _ => return syntax_error(line, "invalid escape sequence"),
+        };
+        value.push(escaped);
+        pos += 1;
+    }
+}
+
+//
+// Parser
+//
+
+#[derive(Clone, Copy, Debug)]
+enum UnaryOp {
+    Neg,
+    Not,
+    Len,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn from_symbol(token: &Token) -> Option<Self> {
+        let op = match token {
+            Token::Symbol("+") => Self::Add,
+            Token::Symbol("-") => Self::Sub,
+            Token::Symbol("*") => Self::Mul,
+            Token::Symbol("/" | "//") => Self::Div,
+            Token::Symbol("%") => Self::Mod,
+            Token::Symbol("..") => Self::Concat,
+            Token::Symbol("==") => Self::Eq,
+            Token::Symbol("~=") => Self::Ne,
+            Token::Symbol("<") => Self::Lt,
+            Token::Symbol("<=") => Self::Le,
+            Token::Symbol(">") => Self::Gt,
+            Token::Symbol(">=") => Self::Ge,
+            Token::Name(name) if name == "and" => Self::And,
+            Token::Name(name) if name == "or" => Self::Or,
+            _ => return None,
+        };
+
+        Some(op)
+    }
+
+    /// Returns the left and right priorities, like Lua: the concatenation is right associative.
+    fn priority(self) -> (u8, u8) {
+        match self {
+            Self::Or => (1, 1),
+            Self::And => (2, 2),
+            Self::Eq | Self::Ne | Self::Lt | Self::Le | Self::Gt | Self::Ge => (3, 3),
+            Self::Concat => (9, 8),
+            Self::Add | Self::Sub => (10, 10),
+            Self::Mul | Self::Div | Self::Mod => (11, 11),
+        }
+    }
+}
+
+const UNARY_PRIORITY: u8 = 12;
+
+#[derive(Debug)]
+enum Expr {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Str(Vec<u8>),
+    Name(String),
+    Index(Box<Expr>, Box<Expr>),
+    Field(Box<Expr>, String),
+    Call(Box<Expr>, Vec<Expr>),
+    Table(Vec<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Local(String, Option<Expr>),
+    Assign(Expr, Expr),
+    Call(Expr),
+    If(Vec<(Expr, Block)>, Option<Block>),
+    While(Expr, Block),
+    For {
+        var: String,
+        start: Expr,
+        end: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    Do(Block),
+    Break,
+    Return(Option<Expr>),
+}
+
+/// Statements with their line.
+type Block = Vec<(usize, Stmt)>;
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+    loops: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Token::Symbol(s) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Name(name) if name == keyword)
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.is_symbol(symbol);
+        if found {
+            self.pos += 1;
+        }
+
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ScriptError> {
+        if !self.accept_symbol(symbol) {
+            return syntax_error(
+                self.line(),
+                format!("'{}' expected near {}", symbol, self.describe()),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ScriptError> {
+        if !self.is_keyword(keyword) {
+            return syntax_error(
+                self.line(),
+                format!("'{}' expected near {}", keyword, self.describe()),
+            );
+        }
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn expect_name(&mut self) -> Result<String, ScriptError> {
+        match self.peek() {
+            Token::Name(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => syntax_error(
+                self.line(),
+                format!("name expected near {}", self.describe()),
+            ),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Token::Name(name) => format!("'{}'", name),
+            Token::Int(value) => format!("'{}'", value),
+            Token::Str(value) => format!("'{}'", String::from_utf8_lossy(value)),
+            Token::Symbol(symbol) => format!("'{}'", symbol),
+            Token::Eof => "<eof>".to_string(),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), ScriptError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return syntax_error(self.line(), "too many nested levels");
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the current token ends a block.
+    fn is_block_end(&self) -> bool {
+        matches!(self.peek(), Token::Eof)
+            || ["end", "else", "elseif"].iter().any(|k| self.is_keyword(k))
+    }
+
+    fn block(&mut self) -> Result<Block, ScriptError> {
+        self.enter()?;
+
+        let mut block = Vec::new();
+        while !self.is_block_end() {
+            if self.accept_symbol(";") {
+                continue;
+            }
+
+            let line = self.line();
+            let stmt = self.statement()?;
+            let is_return = matches!(stmt, Stmt::Return(_));
+            block.push((line, stmt));
+
+            // Like Lua, return must be the last statement of its block
+            if is_return {
+                self.accept_symbol(";");
+                if !self.is_block_end() {
+                    return syntax_error(
+                        self.line(),
+                        format!("<eof> expected near {}", self.describe()),
+                    );
+                }
+            }
+        }
+
+        self.depth -= 1;
+
+        Ok(block)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ScriptError> {
+        let keyword = match self.peek() {
+            Token::Name(name) => name.clone(),
+            _ => String::new(),
+        };
+
+        match keyword.as_str() {
+            "local" => {
+                self.pos += 1;
+                let name = self.expect_name()?;
+                let value = if self.accept_symbol("=") {
+                    Some(self.expr(0)?)
+                } else {
+                    None
+                };
+
+                Ok(Stmt::Local(name, value))
+            }
+            "if" => {
+                self.pos += 1;
+                let mut branches = Vec::new();
+                let mut otherwise = None;
+
+                loop {
+                    let condition = self.expr(0)?;
+                    self.expect_keyword("then")?;
+                    branches.push((condition, self.block()?));
+
+                    if self.is_keyword("elseif") {
+                        self.pos += 1;
+                    } else if self.is_keyword("else") {
+                        self.pos += 1;
+                        otherwise = Some(self.block()?);
+                        break;
+                    } else {
+                        break;
+                    }
+                }
+                self.expect_keyword("end")?;
+
+                Ok(Stmt::If(branches, otherwise))
+            }
+            "while" => {
+                self.pos += 1;
+                let condition = self.expr(0)?;
+                self.expect_keyword("do")?;
+                let body = self.loop_body()?;
+
+                Ok(Stmt::While(condition, body))
+            }
+            "for" => {
+                self.pos += 1;
+                let var = self.expect_name()?;
+                self.expect_symbol("=")?;
+                let start = self.expr(0)?;
+                self.expect_symbol(",")?;
+                let end = self.expr(0)?;
+                let step = if self.accept_symbol(",") {
+                    Some(self.expr(0)?)
+                } else {
+                    None
+                };
+                self.expect_keyword("do")?;
+                let body = self.loop_body()?;
+
+                Ok(Stmt::For {
+                    var,
+                    start,
+                    end,
+                    step,
+                    body,
+                })
+            }
+            "do" => {
+                self.pos += 1;
+                let body = self.block()?;
+                self.expect_keyword("end")?;
+
+                Ok(Stmt::Do(body))
+            }
+            "break" => {
+                if self.loops == 0 {
+                    return syntax_error(self.line(), "break outside a loop");
+                }
+                self.pos += 1;
+
+                Ok(Stmt::Break)
+            }
+            "return" => {
+                self.pos += 1;
+                let value = if self.is_block_end() || self.is_symbol(";") {
+                    None
+                } else {
+                    Some(self.expr(0)?)
+                };
+
+                Ok(Stmt::Return(value))
+            }
+            _ => {
+                let target = self.suffixed_expr()?;
+
+                if self.accept_symbol("=") {
+                    if !matches!(target, Expr::Name(_) | Expr::Index(..)) {
+                        return syntax_error(self.line(), "cannot assign to this expression");
+                    }
+                    return Ok(Stmt::Assign(target, self.expr(0)?));
+                }
+
+                match target {
+                    Expr::Call(..) => Ok(Stmt::Call(target)),
+                    _ => syntax_error(
+                        self.line(),
+                        format!("syntax error near {}", self.describe()),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Parse the body of a loop up to its `end`.
+    fn loop_body(&mut self) -> Result<Block, ScriptError> {
+        self.loops += 1;
+        let body = self.block()?;
+        self.loops -= 1;
+        self.expect_keyword("end")?;
+
+        Ok(body)
+    }
+
+    /// Parse an expression whose binary operators have a left priority greater than `limit`.
+    fn expr(&mut self, limit: u8) -> Result<Expr, ScriptError> {
+        self.enter()?;
+
+        let unary = match self.peek() {
+            Token::Symbol("-") => Some(UnaryOp::Neg),
+            Token::Symbol("#") => Some(UnaryOp::Len),
+            Token::Name(name) if name == "not" => Some(UnaryOp::Not),
+            _ => None,
+        };
+
+        let mut left = match unary {
+            Some(op) => {
+                self.pos += 1;
+                Expr::Unary(op, Box::new(self.expr(UNARY_PRIORITY)?))
+            }
+            None => self.simple_expr()?,
+        };
+
+        while let Some(op) = BinaryOp::from_symbol(self.peek()) {
+            let (left_priority, right_priority) = op.priority();
+            if left_priority <= limit {
+                break;
+            }
+            self.pos += 1;
+
+            let right = self.expr(right_priority)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        self.depth -= 1;
+
+        Ok(left)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, ScriptError> {
+        let expr = match self.peek() {
+            Token::Int(value) => Expr::Int(*value),
+            Token::Str(value) => Expr::Str(value.clone()),
+            Token::Name(name) if name == "nil" => Expr::Nil,
+            Token::Name(name) if name == "true" => Expr::Bool(true),
+            Token::Name(name) if name == "false" => Expr::Bool(false),
+            Token::Symbol("{") => {
+                self.pos += 1;
+
+                let mut items = Vec::new();
+                while !self.is_symbol("}") {
+                    items.push(self.expr(0)?);
+                    if !self.accept_symbol(",") && !self.accept_symbol(";") {
+                        break;
+                    }
+                }
+                self.expect_symbol("}")?;
+
+                return Ok(Expr::Table(items));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.pos += 1;
+
+        Ok(expr)
+    }
+
+    /// Parse a name or a parenthesized expression followed by indexes, fields and calls.
+    fn suffixed_expr(&mut self) -> Result<Expr, ScriptError> {
+        let mut expr = if self.accept_symbol("(") {
+            let expr = self.expr(0)?;
+            self.expect_symbol(")")?;
+            expr
+        } else {
+            Expr::Name(self.expect_name()?)
+        };
+
+        loop {
+            if self.accept_symbol("[") {
+                let index = self.expr(0)?;
+                self.expect_symbol("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.accept_symbol(".") {
+                expr = Expr::Field(Box::new(expr), self.expect_name()?);
+            } else if self.accept_symbol("(") {
+                let mut args = Vec::new();
+                if !self.is_symbol(")") {
+                    loop {
+                        args.push(self.expr(0)?);
+                        if !self.accept_symbol(",") {
+                            break;
+                        }
+                    }
+                }
+                self.expect_symbol(")")?;
+                expr = Expr::Call(Box::new(expr), args);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+}
+
+//
+// Interpreter
+//
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+    Call,
+    ToNumber,
+    ToString,
+    Type,
+    Error,
+}
+
+/// A Lua value.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Str(Vec<u8>),
+    Table(Rc<RefCell<Vec<Value>>>),
+    /// The `redis` library.
+    Library,
+    Function(Builtin),
+}
+
+impl Value {
+    fn table(items: Vec<Value>) -> Self {
+        Self::Table(Rc::new(RefCell::new(items)))
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Self::Nil | Self::Bool(false))
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Bool(_) => "boolean",
+            Self::Int(_) => "number",
+            Self::Str(_) => "string",
+            Self::Table(_) | Self::Library => "table",
+            Self::Function(_) => "function",
+        }
+    }
+
+    /// Returns the value as a number, converting the strings like Lua does in arithmetic.
+    fn to_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            Self::Str(value) => std::str::from_utf8(value).ok()?.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a string, converting the numbers like Lua does in concatenations.
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Int(value) => Some(value.to_string().into_bytes()),
+            Self::Str(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn to_display(&self) -> Vec<u8> {
+        match self {
+            Self::Nil => b"nil".to_vec(),
+            Self::Bool(value) => value.to_string().into_bytes(),
+            Self::Int(_) | Self::Str(_) => self.to_bytes().unwrap_or_default(),
+            Self::Table(_) | Self::Library => b"table".to_vec(),
+            Self::Function(_) => b"function".to_vec(),
+        }
+    }
+
+    fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) | (Self::Library, Self::Library) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Int(a), Self::Int(b)) => a == b,
+            (Self::Str(a), Self::Str(b)) => a == b,
+            (Self::Table(a), Self::Table(b)) => Rc::ptr_eq(a, b),
+            (Self::Function(a), Self::Function(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Returns the size of the value once converted to a reply, or `None` if it's nested too deeply.
+    pub fn reply_size(&self) -> Option<usize> {
+        self.reply_size_(0)
+    }
+
+    fn reply_size_(&self, depth: usize) -> Option<usize> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let size = match self {
+            Self::Int(value) if *value < 0 => 5 + value.to_string().len(),
+            Self::Int(_) | Self::Bool(true) => 9,
+            Self::Str(value) => 5 + value.len(),
+            Self::Table(items) => {
+                let mut size = 5;
+                for item in items
+                    .borrow()
+                    .iter()
+                    .take_while(|v| !matches!(v, Self::Nil))
+                {
+                    size += item.reply_size_(depth + 1)?;
+                }
+                size
+            }
+            _ => 1,
+        };
+
+        Some(size)
+    }
+
+    /// Write the value as a reply, converted like Redis does:
+    /// * a number is an Int, a string is a Str, a table is an Arr of its items up to the first nil
+    /// * true is the Int 1, false and nil are Nil
+    ///
+    /// A negative number is written as a Str since the protocol only has unsigned integers.
+    ///
+    /// [`Value::reply_size`] must have returned a size which fits in the message.
+    pub fn push_reply(&self, writer: &mut protocol::Writer) {
+        match self {
+            Self::Int(value) if *value < 0 => writer.push_string(value.to_string()),
+            Self::Int(value) => writer.push_int(*value as usize),
+            Self::Bool(true) => writer.push_int(1),
+            Self::Str(value) => writer.push_string(value),
+            Self::Table(items) => {
+                let items = items.borrow();
+                let len = items.iter().take_while(|v| !matches!(v, Self::Nil)).count();
+
+                writer.push_arr(len);
+                for item in &items[..len] {
+                    item.push_reply(writer);
+                }
+            }
+            _ => writer.push_nil(),
+        }
+    }
+
+    /// Convert the reply `body` of a command, returning the message of the error if it failed.
+    ///
+    /// Like Redis, Nil is converted to false.
+    fn from_reply(body: &[u8]) -> Result<Self, String> {
+        let mut reader = protocol::Reader::new(body);
+
+        Self::read_reply(&mut reader).map_err(|err| match err {
+            ReplyError::Failed(message) => message,
+            ReplyError::Protocol(err) => format!("invalid reply, err: {}", err),
+        })
+    }
+
+    fn read_reply(reader: &mut protocol::Reader) -> Result<Self, ReplyError> {
+        let value = match reader.read_data_type()? {
+            DataType::Nil => Self::Bool(false),
+            DataType::Int => Self::Int(reader.read_int()? as i64),
+            DataType::Str => Self::Str(reader.read_string()?.to_vec()),
+            DataType::Err => {
+                let (_, message) = reader.read_err()?;
+                return Err(ReplyError::Failed(
+                    String::from_utf8_lossy(message).into_owned(),
+                ));
+            }
+            DataType::Arr => {
+                let len = reader.read_arr_length()?;
+
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(Self::read_reply(reader)?);
+                }
+                Self::table(items)
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+#[derive(Error, Debug)]
+enum ReplyError {
+    #[error("protocol error")]
+    Protocol(#[from] protocol::Error),
+    #[error("command failed")]
+    Failed(String),
+}
+
+/// How a block finished.
+enum Flow {
+    Normal,
+    Break,
+    Return(Value),
+}
+
+struct Interpreter<F> {
+    locals: Vec<(String, Value)>,
+    keys: Value,
+    argv: Value,
+    call: F,
+    steps: u64,
+    /// Line of the statement running, for the errors.
+    line: usize,
+}
+
+impl<F: FnMut(&[Vec<u8>]) -> Vec<u8>> Interpreter<F> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ScriptError> {
+        Err(ScriptError::Runtime {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn step(&mut self) -> Result<(), ScriptError> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(ScriptError::TooLong(MAX_STEPS));
+        }
+
+        Ok(())
+    }
+
+    /// Run `block`, forgetting its locals once it's done.
+    fn exec_block(&mut self, block: &Block) -> Result<Flow, ScriptError> {
+        let nb_locals = self.locals.len();
+
+        let mut flow = Flow::Normal;
+        for (line, stmt) in block {
+            self.line = *line;
+            self.step()?;
+
+            flow = self.exec(stmt)?;
+            if !matches!(flow, Flow::Normal) {
+                break;
+            }
+        }
+
+        self.locals.truncate(nb_locals);
+
+        Ok(flow)
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow, ScriptError> {
+        match stmt {
+            Stmt::Local(name, value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Nil,
+                };
+                self.locals.push((name.clone(), value));
+            }
+            Stmt::Assign(target, value) => {
+                let value = self.eval(value)?;
+                self.assign(target, value)?;
+            }
+            Stmt::Call(call) => {
+                self.eval(call)?;
+            }
+            Stmt::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval(condition)?.is_truthy() {
+                        return self.exec_block(body);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.exec_block(body);
+                }
+            }
+            Stmt::While(condition, body) => {
+                while self.eval(condition)?.is_truthy() {
+                    self.step()?;
+
+                    match self.exec_block(body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow @ Flow::Return(_) => return Ok(flow),
+                    }
+                }
+            }
+            Stmt::For {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let start = self.eval_int(start, "'for' initial value")?;
+                let end = self.eval_int(end, "'for' limit")?;
+                let step = match step {
+                    Some(step) => self.eval_int(step, "'for' step")?,
+                    None => 1,
+                };
+                if step == 0 {
+                    return self.error("'for' step is zero");
+                }
+
+                let mut i = start;
+                while (step > 0 && i <= end) || (step < 0 && i >= end) {
+                    self.step()?;
+
+                    self.locals.push((var.clone(), Value::Int(i)));
+                    let flow = self.exec_block(body)?;
+                    self.locals.pop();
+
+                    match flow {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow @ Flow::Return(_) => return Ok(flow),
+                    }
+
+                    i = match i.checked_add(step) {
+                        Some(i) => i,
+                        None => break,
+                    };
+                }
+            }
+            Stmt::Do(body) => return self.exec_block(body),
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+        }
+
+        Ok(Flow::Normal)
+    }
+
+    fn eval_int(&mut self, expr: &Expr, what: &str) -> Result<i64, ScriptError> {
+        match self.eval(expr)?.to_int() {
+            Some(value) => Ok(value),
+            None => self.error(format!("{} must be a number", what)),
+        }
+    }
+
+    fn assign(&mut self, target: &Expr, value: Value) -> Result<(), ScriptError> {
+        match target {
+            Expr::Name(name) => match self.locals.iter_mut().rev().find(|(n, _)| n == name) {
+                Some((_, local)) => {
+                    *local = value;
+                    Ok(())
+                }
+                None => self.error(format!(
+                    "Script attempted to access nonexistent global variable '{}'",
+                    name
+                )),
+            },
+            Expr::Index(table, index) => {
+                let table = match self.eval(table)? {
+                    Value::Table(table) => table,
+                    other => {
+                        return self
+                            .error(format!("attempt to index a {} value", other.type_name()))
+                    }
+                };
+                let index = self.eval(index)?;
+
+                let mut items = table.borrow_mut();
+                let len = items.len() as i64;
+
+                match index {
+                    Value::Int(i) if i >= 1 && i <= len => items[i as usize - 1] = value,
+                    Value::Int(i) if i == len + 1 => items.push(value),
+                    _ => {
+                        return self.error(format!(
+                            "tables are arrays, invalid index {}",
+                            String::from_utf8_lossy(&index.to_display())
+                        ))
+                    }
+                }
+
+                // The length is up to the last item which isn't nil
+                while matches!(items.last(), Some(Value::Nil)) {
+                    items.pop();
+                }
+
+                Ok(())
+            }
+            // NOTE(vincent): the parser only accepts names and indexes
+            _ => unreachable!(),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, ScriptError> {
+        let value = match expr {
+            Expr::Nil => Value::Nil,
+            Expr::Bool(value) => Value::Bool(*value),
+            Expr::Int(value) => Value::Int(*value),
+            Expr::Str(value) => Value::Str(value.clone()),
+            Expr::Name(name) => match self.locals.iter().rev().find(|(n, _)| n == name) {
+                Some((_, value)) => value.clone(),
+                None => match name.as_str() {
+                    "KEYS" => self.keys.clone(),
+                    "ARGV" => self.argv.clone(),
+                    "redis" => Value::Library,
+                    "tonumber" => Value::Function(Builtin::ToNumber),
+                    "tostring" => Value::Function(Builtin::ToString),
+                    "type" => Value::Function(Builtin::Type),
+                    "error" => Value::Function(Builtin::Error),
+                    _ => {
+                        return self.error(format!(
+                            "Script attempted to access nonexistent global variable '{}'",
+                            name
+                        ))
+                    }
+                },
+            },
+            Expr::Index(table, index) => {
+                let table = self.eval(table)?;
+                let index = self.eval(index)?;
+
+                match (table, index) {
+                    (Value::Table(items), Value::Int(i)) if i >= 1 => items
+                        .borrow()
+                        .get(i as usize - 1)
+                        .cloned()
+                        .unwrap_or(Value::Nil),
+                    (Value::Table(_), _) => Value::Nil,
+                    (other, _) => {
+                        return self
+                            .error(format!("attempt to index a {} value", other.type_name()))
+                    }
+                }
+            }
+            Expr::Field(table, name) => match (self.eval(table)?, name.as_str()) {
+                (Value::Library, "call") => Value::Function(Builtin::Call),
+                (Value::Library | Value::Table(_), _) => Value::Nil,
+                (other, _) => {
+                    return self.error(format!("attempt to index a {} value", other.type_name()))
+                }
+            },
+            Expr::Call(function, args) => {
+                let function = match self.eval(function)? {
+                    Value::Function(function) => function,
+                    other => {
+                        return self.error(format!("attempt to call a {} value", other.type_name()))
+                    }
+                };
+
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval(arg)?);
+                }
+
+                self.call(function, values)?
+            }
+            Expr::Table(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(self.eval(item)?);
+                }
+                while matches!(values.last(), Some(Value::Nil)) {
+                    values.pop();
+                }
+
+                Value::table(values)
+            }
+            Expr::Unary(op, operand) => {
+                let operand = self.eval(operand)?;
+
+                match (op, &operand) {
+                    (UnaryOp::Not, _) => Value::Bool(!operand.is_truthy()),
+                    (UnaryOp::Len, Value::Str(value)) => Value::Int(value.len() as i64),
+                    (UnaryOp::Len, Value::Table(items)) => Value::Int(items.borrow().len() as i64),
+                    (UnaryOp::Len, _) => {
+                        return self.error(format!(
+                            "attempt to get length of a {} value",
+                            operand.type_name()
+                        ))
+                    }
+                    (UnaryOp::Neg, _) => match operand.to_int().and_then(i64::checked_neg) {
+                        Some(value) => Value::Int(value),
+                        None => return self.arithmetic_error(&operand, &operand),
+                    },
+                }
+            }
+            Expr::Binary(BinaryOp::And, left, right) => {
+                let left = self.eval(left)?;
+                if !left.is_truthy() {
+                    return Ok(left);
+                }
+                self.eval(right)?
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    return Ok(left);
+                }
+                self.eval(right)?
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.binary(*op, &left, &right)?
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn arithmetic_error<T>(&self, left: &Value, right: &Value) -> Result<T, ScriptError> {
+        let culprit = if left.to_int().is_none() { left } else { right };
+        match culprit.to_int() {
+            None => self.error(format!(
+                "attempt to perform arithmetic on a {} value",
+                culprit.type_name()
+            )),
+            Some(_) => self.error("integer overflow"),
+        }
+    }
+
+    fn binary(&self, op: BinaryOp, left: &Value, right: &Value) -> Result<Value, ScriptError> {
+        let value = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
+                let (a, b) = match (left.to_int(), right.to_int()) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return self.arithmetic_error(left, right),
+                };
+
+                if matches!(op, BinaryOp::Div | BinaryOp::Mod) && b == 0 {
+                    return self.error("attempt to divide by zero");
+                }
+
+                let result = match op {
+                    BinaryOp::Add => a.checked_add(b),
+                    BinaryOp::Sub => a.checked_sub(b),
+                    BinaryOp::Mul => a.checked_mul(b),
+                    BinaryOp::Div => floor_div(a, b),
+                    _ => floor_div(a, b)
+                        .and_then(|q| q.checked_mul(b))
+                        .and_then(|m| a.checked_sub(m)),
+                };
+
+                match result {
+                    Some(value) => Value::Int(value),
+                    None => return self.error("integer overflow"),
+                }
+            }
+            BinaryOp::Concat => match (left.to_bytes(), right.to_bytes()) {
+                (Some(a), Some(b)) if a.len() + b.len() > MAX_STRING_LEN => {
+                    return self.error("string length overflow")
+                }
+                (Some(mut a), Some(b)) => {
+                    a.extend_from_slice(&b);
+                    Value::Str(a)
+                }
+                _ => {
+                    let culprit = if left.to_bytes().is_none() {
+                        left
+                    } else {
+                        right
+                    };
+                    return self.error(format!(
+                        "attempt to concatenate a {} value",
+                        culprit.type_name()
+                    ));
+                }
+            },
+            BinaryOp::Eq => Value::Bool(left.equals(right)),
+            BinaryOp::Ne => Value::Bool(!left.equals(right)),
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                let ordering = match (left, right) {
+                    (Value::Int(a), Value::Int(b)) => a.cmp(b),
+                    (Value::Str(a), Value::Str(b)) => a.cmp(b),
+                    _ => {
+                        return self.error(format!(
+                            "attempt to compare {} with {}",
+                            left.type_name(),
+                            right.type_name()
+                        ))
+                    }
+                };
+
+                Value::Bool(match op {
+                    BinaryOp::Lt => ordering.is_lt(),
+                    BinaryOp::Le => ordering.is_le(),
+                    BinaryOp::Gt => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                })
+            }
+            // NOTE(vincent): evaluated lazily by eval
+            BinaryOp::And | BinaryOp::Or => unreachable!(),
+        };
+
+        Ok(value)
+    }
+
+    fn call(&mut self, function: Builtin, args: Vec<Value>) -> Result<Value, ScriptError> {
+        let first = args.first().cloned().unwrap_or(Value::Nil);
+
+        let value =
+            match function {
+                Builtin::Call => {
+                    if args.is_empty() {
+                        return self.error("Please specify at least one argument for redis.call()");
+                    }
+
+                    let mut command = Vec::with_capacity(args.len());
+                    for arg in &args {
+                        match arg.to_bytes() {
+                            Some(arg) => command.push(arg),
+                            None => return self.error(
+                                "Lua redis.call() command arguments must be strings or integers",
+                            ),
+                        }
+                    }
+
+                    let reply = (self.call)(&command);
+                    match Value::from_reply(&reply) {
+                        Ok(value) => value,
+                        Err(message) => return self.error(message),
+                    }
+                }
+                Builtin::ToNumber => first.to_int().map(Value::Int).unwrap_or(Value::Nil),
+                Builtin::ToString => Value::Str(first.to_display()),
+                Builtin::Type => Value::Str(first.type_name().as_bytes().to_vec()),
+                Builtin::Error => {
+                    return self.error(String::from_utf8_lossy(&first.to_display()).into_owned())
+                }
+            };
+
+        Ok(value)
+    }
+}
+
+/// Divide rounding towards minus infinity, like Lua.
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let q = a.checked_div(b)?;
+    if a % b != 0 && ((a < 0) != (b < 0)) {
+        Some(q - 1)
+    } else {
+        Some(q)
+    }
+}
+
+/// A compiled script.
+#[derive(Debug)]
+pub struct Script {
+    block: Block,
+}
+
+impl Script {
+    pub fn compile(source: &[u8]) -> Result<Self, ScriptError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            depth: 0,
+            loops: 0,
+        };
+
+        let block = parser.block()?;
+        if *parser.peek() != Token::Eof {
+            return syntax_error(
+                parser.line(),
+                format!("<eof> expected near {}", parser.describe()),
+            );
+        }
+
+        Ok(Self { block })
+    }
+
+    /// Run the script with `keys` and `args`, calling `call` with the arguments of every command run by
+    /// `redis.call`. `call` returns the reply body of the command.
+    pub fn run<F: FnMut(&[Vec<u8>]) -> Vec<u8>>(
+        &self,
+        keys: &[&[u8]],
+        args: &[&[u8]],
+        call: F,
+    ) -> Result<Value, ScriptError> {
+        let strings = |values: &[&[u8]]| {
+            Value::table(values.iter().map(|v| Value::Str(v.to_vec())).collect())
+        };
+
+        let mut interpreter = Interpreter {
+            locals: Vec::new(),
+            keys: strings(keys),
+            argv: strings(args),
+            call,
+            steps: 0,
+            line: 0,
+        };
+
+        match interpreter.exec_block(&self.block)? {
+            Flow::Return(value) => Ok(value),
+            // NOTE(vincent): the parser rejects a break outside a loop
+            Flow::Normal | Flow::Break => Ok(Value::Nil),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Script, ScriptError, Value};
+    use crate::protocol::{self, BUF_LEN};
+
+    fn build_reply<F: FnOnce(&mut protocol::Writer)>(f: F) -> Vec<u8> {
+        let mut buf = vec![0; BUF_LEN];
+        let mut writer = protocol::Writer::new(&mut buf);
+        f(&mut writer);
+        writer.finish();
+        let written = writer.written();
+
+        let (_, body) = protocol::parse_message(&buf[..written]).unwrap();
+        body.to_vec()
+    }
+
+    /// Run `source` without commands and returns its result as a reply body.
+    fn eval(source: &str, keys: &[&[u8]], args: &[&[u8]]) -> Result<Vec<u8>, ScriptError> {
+        let script = Script::compile(source.as_bytes())?;
+        let value = script.run(keys, args, |_| unreachable!())?;
+
+        Ok(build_reply(|writer| value.push_reply(writer)))
+    }
+
+    fn int(value: usize) -> Vec<u8> {
+        build_reply(|writer| writer.push_int(value))
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        build_reply(|writer| writer.push_string(value))
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(int(7), eval("return 1 + 2 * 3", &[], &[]).unwrap());
+        assert_eq!(int(9), eval("return (1 + 2) * 3", &[], &[]).unwrap());
+        assert_eq!(string("-4"), eval("return -7 // 2", &[], &[]).unwrap());
+        assert_eq!(int(1), eval("return -7 % 2", &[], &[]).unwrap());
+        assert_eq!(
+            "line 1: integer overflow",
+            eval("return 9223372036854775807 % -2", &[], &[])
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(int(12), eval("return '10' + 2", &[], &[]).unwrap());
+        assert_eq!(
+            string("a1b"),
+            eval("return 'a' .. 1 .. \"b\"", &[], &[]).unwrap()
+        );
+        assert_eq!(int(3), eval("return #'abc'", &[], &[]).unwrap());
+        assert_eq!(
+            int(1),
+            eval("return 1 < 2 and 'b' > 'a'", &[], &[]).unwrap()
+        );
+        assert_eq!(
+            string("x"),
+            eval("return nil or false or 'x'", &[], &[]).unwrap()
+        );
+        assert_eq!(int(1), eval("return not nil", &[], &[]).unwrap());
+        assert_eq!(
+            build_reply(|writer| writer.push_nil()),
+            eval("return 1 == '1'", &[], &[]).unwrap()
+        );
+        assert_eq!(
+            string("number"),
+            eval("return type(tonumber('42'))", &[], &[]).unwrap()
+        );
+        assert_eq!(
+            string("nil"),
+            eval("return tostring(nil)", &[], &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn statements() {
+        let source = "
+            -- sum of the arguments, skipping the odd ones
+            local sum = 0
+            for i = 1, #ARGV do
+                local n = tonumber(ARGV[i])
+                if n % 2 == 1 then
+                    -- skip
+                elseif n > 100 then
+                    break
+                else
+                    sum = sum + n
+                end
+            end
+
+            local t = {KEYS[1]}
+            local i = 0
+            while true do
+                i = i + 1
+                if i > 3 then break end
+                t[#t + 1] = i
+            end
+
+            return {sum, t}
+        ";
+        let reply = eval(source, &[b"key"], &[b"2", b"3", b"4", b"200", b"6"]).unwrap();
+
+        let expected = build_reply(|writer| {
+            writer.push_arr(2);
+            writer.push_int(6);
+            writer.push_arr(4);
+            writer.push_string("key");
+            writer.push_int(1);
+            writer.push_int(2);
+            writer.push_int(3);
+        });
+        assert_eq!(expected, reply);
+
+        // A table stops at its first nil
+        let expected = build_reply(|writer| {
+            writer.push_arr(1);
+            writer.push_int(1);
+        });
+        assert_eq!(expected, eval("return {1, nil, 3}", &[], &[]).unwrap());
+    }
+
+    #[test]
+    fn call() {
+        let script = Script::compile(
+            b"local v = redis.call('get', KEYS[1])
+            if v == false then
+                v = 0
+            end
+            redis.call('set', KEYS[1], v + ARGV[1])
+            return redis.call('get', KEYS[1])",
+        )
+        .unwrap();
+
+        let mut calls = Vec::new();
+        let value = script
+            .run(&[b"counter"], &[b"5"], |args| {
+                calls.push(args.to_vec());
+                match args[0].as_slice() {
+                    b"get" if calls.len() == 1 => build_reply(|writer| writer.push_nil()),
+                    b"get" => build_reply(|writer| writer.push_string("5")),
+                    _ => build_reply(|writer| writer.push_nil()),
+                }
+            })
+            .unwrap();
+
+        assert_eq!(string("5"), build_reply(|writer| value.push_reply(writer)));
+        assert_eq!(
+            vec![
+                vec![b"get".to_vec(), b"counter".to_vec()],
+                vec![b"set".to_vec(), b"counter".to_vec(), b"5".to_vec()],
+                vec![b"get".to_vec(), b"counter".to_vec()],
+            ],
+            calls
+        );
+
+        // A failed command raises an error
+        let err = script
+            .run(&[b"counter"], &[b"5"], |_| {
+                build_reply(|writer| writer.push_err(100u32, "invalid key"))
+            })
+            .unwrap_err();
+        assert_eq!("line 1: invalid key", err.to_string());
+    }
+
+    #[test]
+    fn errors() {
+        let syntax = |source: &str| match Script::compile(source.as_bytes()) {
+            Err(ScriptError::Syntax { line, .. }) => line,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(1, syntax("return 1.5"));
+        assert_eq!(2, syntax("local a = 1\nreturn a +"));
+        assert_eq!(1, syntax("break"));
+        assert_eq!(1, syntax("return 1 return 2"));
+        assert_eq!(1, syntax("local s = 'unfinished"));
+        assert_eq!(1, syntax(&"(".repeat(1000)));
+
+        let runtime = |source: &str| eval(source, &[], &[]).unwrap_err().to_string();
+        assert_eq!(
+            "line 1: Script attempted to access nonexistent global variable 'x'",
+            runtime("x = 1")
+        );
+        assert_eq!(
+            "line 2: attempt to divide by zero",
+            runtime("local a = 0\nreturn 1 // a")
+        );
+        assert_eq!(
+            "line 1: attempt to perform arithmetic on a string value",
+            runtime("return 'a' + 1")
+        );
+        assert_eq!(
+            "line 1: attempt to compare number with string",
+            runtime("return 1 < 'a'")
+        );
+        assert_eq!("line 1: boom", runtime("error('boom')"));
+        assert_eq!(
+            "line 1: string length overflow",
+            runtime("local s = 'x' for i = 1, 64 do s = s .. s end")
+        );
+        assert_eq!(
+            "script stopped after 10000000 steps",
+            runtime("while true do end")
+        );
+    }
+
+    #[test]
+    fn reply_size() {
+        assert_eq!(Some(9), Value::Int(1).reply_size());
+        assert_eq!(
+            Some(5 + (5 + 2) + 9 + 1),
+            Value::table(vec![
+                Value::Str(b"ab".to_vec()),
+                Value::Int(1),
+                Value::Bool(false)
+            ])
+            .reply_size()
+        );
+
+        // A table containing itself can't be converted
+        let script = Script::compile(b"local t = {1}\nt[2] = t\nreturn t").unwrap();
+        let value = script.run(&[], &[], |_| unreachable!()).unwrap();
+        assert_eq!(None, value.reply_size());
+    }
+}
@@ -0,0 +1,625 @@
+//! Snapshots of the keyspace.
+//!
+//! A snapshot starts with a header: `MAGIC` and the format version as a big-endian u16.
+//!
+//! Then comes a sequence of records, each starting with its type:
+//! * an entry: `ENTRY`, the key and the value, each prefixed by its length as a big-endian u32
+//! * an entry with an expiration: `EXPIRING_ENTRY`, the expiration time in milliseconds since the Unix epoch as a
+//!   big-endian u64, then the key and the value like an entry
+//! * the end of the snapshot: `END`, always the last record
+//!
+//! Finally the CRC-64 of everything before it, as a big-endian u64.
+//!
+//! Snapshots saved before the header was added (version 0) have no header and no checksum, they are still loaded
+//! and are converted on the next save.
+
+use crate::server::crc64::Crc64;
+use crate::server::keyspace::{self, Keyspace};
+use crate::{info, warn};
+use onlyerror::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::server::config::SaveRule;
+
+const MAGIC: [u8; 8] = *b"MORSNAP\0";
+const VERSION: u16 = 1;
+
+const ENTRY: u8 = 0x01;
+const EXPIRING_ENTRY: u8 = 0x02;
+const END: u8 = 0xff;
+
+/// How long to wait after a failed save before the save rules can trigger a new one.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("snapshot truncated at offset {0}")]
+    Truncated(u64),
+    #[error("invalid record type {record_type:#04x} at offset {offset}")]
+    InvalidRecordType { record_type: u8, offset: u64 },
+    #[error("invalid UTF-8 string at offset {0}")]
+    InvalidString(u64),
+    #[error("unexpected data after the end of the snapshot at offset {0}")]
+    TrailingData(u64),
+    #[error("not a snapshot, invalid magic")]
+    InvalidMagic,
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u16),
+    #[error("checksum mismatch, expected {expected:#018x} but got {actual:#018x}")]
+    ChecksumMismatch { expected: u64, actual: u64 },
+}
+
+/// Reads a snapshot, keeping track of the offset for the error messages and of the checksum.
+struct SnapshotReader<R> {
+    reader: R,
+    offset: u64,
+    crc: Crc64,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), LoadError> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => {
+                self.offset += buf.len() as u64;
+                self.crc.update(buf);
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(LoadError::Truncated(self.offset))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, LoadError> {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, LoadError> {
+        let mut buf = [0; 2];
+        self.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, LoadError> {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn read_string(&mut self) -> Result<String, LoadError> {
+        let offset = self.offset;
+
+        let mut length = [0; 4];
+        self.read_exact(&mut length)?;
+
+        let mut data = vec![0; u32::from_be_bytes(length) as usize];
+        self.read_exact(&mut data)?;
+
+        String::from_utf8(data).map_err(|_| LoadError::InvalidString(offset))
+    }
+
+    /// Read the header, returning the version of the snapshot.
+    fn read_header(&mut self) -> Result<u16, LoadError> {
+        let mut magic = [0; MAGIC.len()];
+        self.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(LoadError::InvalidMagic);
+        }
+
+        match self.read_u16()? {
+            version @ 1..=VERSION => Ok(version),
+            version => Err(LoadError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn is_at_end(&mut self) -> Result<bool, LoadError> {
+        let mut buf = [0; 1];
+        Ok(self.reader.read(&mut buf)? == 0)
+    }
+}
+
+/// Computes the checksum of everything written through it.
+struct ChecksumWriter<W> {
+    writer: W,
+    crc: Crc64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+/// Write every entry of the keyspace to `writer`, returning the number of entries written.
+///
+/// The keyspace is copied one shard at a time so it stays available, the snapshot is not a point in time view
+/// across shards.
+pub fn write<W: Write>(keyspace: &Keyspace, writer: &mut W) -> io::Result<usize> {
+    let mut writer = ChecksumWriter {
+        writer,
+        crc: Crc64::new(),
+    };
+    let mut nb_entries = 0;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+
+    for index in 0..keyspace.nb_shards() {
+        for (key, value, expires_at) in keyspace.shard_entries(index) {
+            match expires_at {
+                Some(at) => {
+                    writer.write_all(&[EXPIRING_ENTRY])?;
+                    writer.write_all(&keyspace::to_unix_millis(at).to_be_bytes())?;
+                }
+                None => writer.write_all(&[ENTRY])?,
+            }
+            write_bytes(&mut writer, key.as_bytes())?;
+            write_bytes(&mut writer, value.as_bytes())?;
+
+            nb_entries += 1;
+        }
+    }
+
+    writer.write_all(&[END])?;
+
+    let checksum = writer.crc.finish();
+    writer.writer.write_all(&checksum.to_be_bytes())?;
+
+    Ok(nb_entries)
+}
+
+/// Save a snapshot of the keyspace to `path`.
+///
+/// The snapshot is written to a temporary file first, `path` is only replaced once it's complete.
+pub fn save(keyspace: &Keyspace, path: &Path) -> io::Result<usize> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp-{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let nb_entries = write(keyspace, &mut writer)?;
+
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)?;
+
+        Ok(nb_entries)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Insert every entry of the snapshot read from `reader` in the keyspace, returning the number of entries inserted.
+///
+/// Entries which expired since the snapshot was saved are skipped.
+pub fn read<R: BufRead>(keyspace: &Keyspace, mut reader: R) -> Result<usize, LoadError> {
+    // Snapshots saved before the header was added start directly with a record
+    let legacy = reader.fill_buf()?.first().is_some_and(|b| *b != MAGIC[0]);
+
+    let mut reader = SnapshotReader {
+        reader,
+        offset: 0,
+        crc: Crc64::new(),
+    };
+    let mut nb_entries = 0;
+    let now = SystemTime::now();
+
+    let version = if legacy { 0 } else { reader.read_header()? };
+    if version == 0 {
+        info!("loading a snapshot without a version, it will be converted on the next save");
+    }
+
+    loop {
+        let offset = reader.offset;
+
+        match reader.read_u8()? {
+            ENTRY => {
+                let key = reader.read_string()?;
+                let value = reader.read_string()?;
+
+                keyspace.insert(key, value);
+                nb_entries += 1;
+            }
+            EXPIRING_ENTRY => {
+                let expires_at = keyspace::from_unix_millis(reader.read_u64()?);
+                let key = reader.read_string()?;
+                let value = reader.read_string()?;
+
+                if expires_at > now {
+                    keyspace.insert_with_expiry(key, value, Some(expires_at));
+                    nb_entries += 1;
+                }
+            }
+            END => break,
+            record_type => {
+                return Err(LoadError::InvalidRecordType {
+                    record_type,
+                    offset,
+                })
+            }
+        }
+    }
+
+    if version > 0 {
+        let actual = reader.crc.finish();
+        let expected = reader.read_u64()?;
+
+        if actual != expected {
+            return Err(LoadError::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    if !reader.is_at_end()? {
+        return Err(LoadError::TrailingData(reader.offset));
+    }
+
+    Ok(nb_entries)
+}
+
+/// Load the snapshot at `path` in the keyspace, if it exists.
+/// Returns the number of entries loaded.
+pub fn load(keyspace: &Keyspace, path: &Path) -> Result<Option<usize>, LoadError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    read(keyspace, BufReader::new(file)).map(Some)
+}
+
+struct SaverState {
+    in_progress: AtomicBool,
+    /// Number of keys changed when the last successful save started, see [`crate::server::dirty::Dirty`].
+    saved_dirty: AtomicU64,
+    last_save: Mutex<Instant>,
+    last_failure: Mutex<Option<Instant>>,
+}
+
+/// Saves snapshots on a background thread, one at a time.
+pub struct BackgroundSaver {
+    state: Arc<SaverState>,
+}
+
+impl BackgroundSaver {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(SaverState {
+                in_progress: AtomicBool::new(false),
+                saved_dirty: AtomicU64::new(0),
+                last_save: Mutex::new(Instant::now()),
+                last_failure: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Returns the number of changes not saved yet, `dirty` being the number of keys changed since we started.
+    pub fn changes_since_save(&self, dirty: u64) -> u64 {
+        dirty.saturating_sub(self.state.saved_dirty.load(Ordering::Relaxed))
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        self.state.in_progress.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the last save failed.
+    pub fn last_save_failed(&self) -> bool {
+        self.state.last_failure.lock().unwrap().is_some()
+    }
+
+    /// Returns true if one of the `rules` is met and a save should be started.
+    /// `dirty` is the number of keys changed since we started.
+    pub fn should_save(&self, rules: &[SaveRule], dirty: u64, now: Instant) -> bool {
+        if let Some(last_failure) = *self.state.last_failure.lock().unwrap() {
+            if now.saturating_duration_since(last_failure) < RETRY_DELAY {
+                return false;
+            }
+        }
+
+        let dirty = self.changes_since_save(dirty);
+        let elapsed = now.saturating_duration_since(*self.state.last_save.lock().unwrap());
+
+        rules
+            .iter()
+            .any(|rule| dirty >= rule.changes && elapsed >= rule.after)
+    }
+
+    /// Start saving a snapshot of the keyspace to `path`, `dirty` being the number of keys changed since we started.
+    /// Returns `false` if a save is already in progress.
+    pub fn start(&self, keyspace: Arc<Keyspace>, path: PathBuf, dirty: u64) -> io::Result<bool> {
+        if self.state.in_progress.swap(true, Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        let state = Arc::clone(&self.state);
+
+        let spawned = thread::Builder::new()
+            .name("bgsave".to_string())
+            .spawn(move || {
+                let start = Instant::now();
+
+                match save(&keyspace, &path) {
+                    Ok(nb_entries) => {
+                        info!(
+                            "saved {} keys to {} in {:?}",
+                            nb_entries,
+                            path.display(),
+                            start.elapsed()
+                        );
+
+                        // The changes made while saving may not be in the snapshot, they're still counted
+                        state.saved_dirty.fetch_max(dirty, Ordering::Relaxed);
+                        *state.last_save.lock().unwrap() = Instant::now();
+                        *state.last_failure.lock().unwrap() = None;
+                    }
+                    Err(err) => {
+                        warn!("unable to save to {}, err: {}", path.display(), err);
+
+                        *state.last_failure.lock().unwrap() = Some(Instant::now());
+                    }
+                }
+
+                state.in_progress.store(false, Ordering::SeqCst);
+            });
+
+        if let Err(err) = spawned {
+            self.state.in_progress.store(false, Ordering::SeqCst);
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, read, write, BackgroundSaver, LoadError, END, ENTRY, EXPIRING_ENTRY, MAGIC};
+    use crate::server::config::SaveRule;
+    use crate::server::crc64;
+    use crate::server::keyspace::{self, Keyspace};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::{env, fs, thread};
+
+    const HEADER_LEN: usize = MAGIC.len() + 2;
+
+    #[test]
+    fn write_entries() {
+        let keyspace = Keyspace::new(4, 1, None);
+        keyspace.insert("foo".to_string(), "bar".to_string());
+
+        let mut buf = Vec::new();
+        assert_eq!(1, write(&keyspace, &mut buf).unwrap());
+
+        let mut expected = MAGIC.to_vec();
+        expected.extend_from_slice(&[0, 1]);
+        expected.push(ENTRY);
+        expected.extend_from_slice(&[0, 0, 0, 3]);
+        expected.extend_from_slice(b"foo");
+        expected.extend_from_slice(&[0, 0, 0, 3]);
+        expected.extend_from_slice(b"bar");
+        expected.push(END);
+        expected.extend_from_slice(&crc64::checksum(&expected).to_be_bytes());
+
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn expiring_entries() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let expires_at = keyspace::from_unix_millis(4_000_000_000_000);
+        keyspace.insert_with_expiry("foo".to_string(), "bar".to_string(), Some(expires_at));
+
+        let mut buf = Vec::new();
+        assert_eq!(1, write(&keyspace, &mut buf).unwrap());
+        assert_eq!(EXPIRING_ENTRY, buf[HEADER_LEN]);
+        assert_eq!(
+            4_000_000_000_000u64.to_be_bytes(),
+            buf[HEADER_LEN + 1..HEADER_LEN + 9]
+        );
+
+        let loaded = Keyspace::new(4, 1, None);
+        assert_eq!(1, read(&loaded, &buf[..]).unwrap());
+        let ttl = loaded.ttl("foo").unwrap().unwrap();
+        assert_eq!(
+            expires_at
+                .duration_since(SystemTime::now())
+                .unwrap()
+                .as_secs(),
+            ttl.as_secs()
+        );
+
+        // Expired since the snapshot was saved
+        let mut expired = vec![EXPIRING_ENTRY];
+        expired.extend_from_slice(&1_000u64.to_be_bytes());
+        expired.extend_from_slice(&[0, 0, 0, 3]);
+        expired.extend_from_slice(b"foo");
+        expired.extend_from_slice(&[0, 0, 0, 3]);
+        expired.extend_from_slice(b"bar");
+        expired.push(END);
+
+        let loaded = Keyspace::new(4, 1, None);
+        assert_eq!(0, read(&loaded, &expired[..]).unwrap());
+        assert!(loaded.keys().is_empty());
+    }
+
+    #[test]
+    fn read_entries() {
+        let keyspace = Keyspace::new(4, 1, None);
+        for i in 0..100 {
+            keyspace.insert(format!("foo{}", i), format!("bar{}", i));
+        }
+
+        let mut buf = Vec::new();
+        write(&keyspace, &mut buf).unwrap();
+
+        let loaded = Keyspace::new(4, 1, None);
+        assert_eq!(100, read(&loaded, &buf[..]).unwrap());
+        assert_eq!(Some("bar42".to_string()), loaded.get("foo42"));
+
+        // Truncated in the header, in the middle of an entry and in the checksum
+        assert!(matches!(
+            read(&loaded, &buf[0..4]),
+            Err(LoadError::Truncated(0))
+        ));
+        assert!(matches!(
+            read(&loaded, &buf[0..HEADER_LEN + 4]),
+            Err(LoadError::Truncated(_))
+        ));
+        assert!(matches!(
+            read(&loaded, &buf[0..buf.len() - 1]),
+            Err(LoadError::Truncated(_))
+        ));
+
+        let mut corrupt = buf.clone();
+        corrupt[HEADER_LEN] = 0x42;
+        assert!(matches!(
+            read(&loaded, &corrupt[..]),
+            Err(LoadError::InvalidRecordType {
+                record_type: 0x42,
+                offset: 10
+            })
+        ));
+
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert!(matches!(
+            read(&loaded, &trailing[..]),
+            Err(LoadError::TrailingData(_))
+        ));
+    }
+
+    #[test]
+    fn versions() {
+        let keyspace = Keyspace::new(4, 1, None);
+        keyspace.insert("foo".to_string(), "bar".to_string());
+
+        let mut buf = Vec::new();
+        write(&keyspace, &mut buf).unwrap();
+
+        let loaded = Keyspace::new(4, 1, None);
+
+        // A single bit flipped in the value
+        let mut corrupt = buf.clone();
+        corrupt[buf.len() - 10] ^= 0x01;
+        assert!(matches!(
+            read(&loaded, &corrupt[..]),
+            Err(LoadError::ChecksumMismatch { .. })
+        ));
+
+        let mut newer = buf.clone();
+        newer[MAGIC.len() + 1] = 2;
+        assert!(matches!(
+            read(&loaded, &newer[..]),
+            Err(LoadError::UnsupportedVersion(2))
+        ));
+
+        let mut invalid = buf.clone();
+        invalid[1] = b'X';
+        assert!(matches!(
+            read(&loaded, &invalid[..]),
+            Err(LoadError::InvalidMagic)
+        ));
+
+        // Saved before the header and the checksum were added
+        let legacy = &buf[HEADER_LEN..buf.len() - 8];
+        assert_eq!(1, read(&loaded, legacy).unwrap());
+        assert_eq!(Some("bar".to_string()), loaded.get("foo"));
+    }
+
+    #[test]
+    fn load_missing() {
+        let keyspace = Keyspace::new(4, 1, None);
+        let path = env::temp_dir().join("snapshot-test-missing");
+
+        assert!(load(&keyspace, &path).unwrap().is_none());
+    }
+
+    #[test]
+    fn save_rules() {
+        let saver = BackgroundSaver::new();
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+
+        let rules = [
+            SaveRule {
+                after: secs(60),
+                changes: 1,
+            },
+            SaveRule {
+                after: secs(10),
+                changes: 3,
+            },
+        ];
+
+        assert!(!saver.should_save(&rules, 0, now + secs(100)));
+
+        assert!(!saver.should_save(&rules, 1, now + secs(30)));
+        assert!(saver.should_save(&rules, 1, now + secs(60)));
+
+        assert!(!saver.should_save(&rules, 3, now + secs(5)));
+        assert!(saver.should_save(&rules, 3, now + secs(10)));
+
+        assert!(!saver.should_save(&[], 3, now + secs(100)));
+    }
+
+    #[test]
+    fn background_save() {
+        let keyspace = Arc::new(Keyspace::new(4, 1, None));
+        for i in 0..100 {
+            keyspace.insert(format!("foo{}", i), format!("bar{}", i));
+        }
+
+        let path = env::temp_dir().join(format!("snapshot-test-{}", std::process::id()));
+
+        let saver = BackgroundSaver::new();
+        assert_eq!(100, saver.changes_since_save(100));
+        assert!(saver
+            .start(Arc::clone(&keyspace), path.clone(), 100)
+            .unwrap());
+
+        // The snapshot is renamed to its final path once complete
+        while !path.exists() {
+            thread::yield_now();
+        }
+
+        let loaded = Keyspace::new(4, 1, None);
+        assert_eq!(100, load(&loaded, &path).unwrap().unwrap());
+
+        while saver.is_in_progress() {
+            thread::yield_now();
+        }
+        assert_eq!(20, saver.changes_since_save(120));
+        assert!(!saver.last_save_failed());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Counters of what the server did since it started, reported by INFO and by
+//! [`Server::metrics`](crate::server::embedded::Server::metrics).
+
+use crate::server::latency::Summary;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+pub struct Stats {
+    /// Connections accepted, the rejected ones are not counted.
+    pub total_connections: AtomicU64,
+    /// Requests received from the clients.
+    pub total_commands: AtomicU64,
+    /// Reads of a key which existed.
+    pub keyspace_hits: AtomicU64,
+    /// Reads of a key which didn't exist.
+    pub keyspace_misses: AtomicU64,
+    pub expired_keys: AtomicU64,
+    pub evicted_keys: AtomicU64,
+    /// Bytes read from and written to the clients.
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a read of a key, a hit if it existed.
+    pub fn record_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.keyspace_hits
+        } else {
+            &self.keyspace_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counters formatted for INFO.
+    pub fn info(&self) -> String {
+        let counters = [
+            ("total_connections_received", &self.total_connections),
+            ("total_commands_processed", &self.total_commands),
+            ("keyspace_hits", &self.keyspace_hits),
+            ("keyspace_misses", &self.keyspace_misses),
+            ("expired_keys", &self.expired_keys),
+            ("evicted_keys", &self.evicted_keys),
+            ("total_net_input_bytes", &self.net_input_bytes),
+            ("total_net_output_bytes", &self.net_output_bytes),
+        ];
+
+        let mut info = String::new();
+        for (name, counter) in counters {
+            let _ = write!(info, "{}:{}\r\n", name, counter.load(Ordering::Relaxed));
+        }
+
+        info
+    }
+}
+
+/// The metrics of a server at one point in time, for the applications embedding it to export them.
+///
+/// The counters only grow since the server started, a rate like the operations per second is the difference between
+/// two snapshots.
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub total_commands: u64,
+    pub total_connections: u64,
+    pub connected_clients: usize,
+    pub keys: usize,
+    /// Size of the keys and values in bytes.
+    pub used_memory: usize,
+    pub peak_memory: usize,
+    pub keyspace_hits: u64,
+    pub keyspace_misses: u64,
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    pub net_input_bytes: u64,
+    pub net_output_bytes: u64,
+    /// Calls and latency of every command executed, sorted by command.
+    pub commands: Vec<Summary>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the share of the reads of a key which found it, `None` before the first read.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.keyspace_hits + self.keyspace_misses;
+        if lookups == 0 {
+            return None;
+        }
+
+        Some(self.keyspace_hits as f64 / lookups as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn info() {
+        let stats = Stats::new();
+        stats.record_lookup(true);
+        stats.record_lookup(false);
+        stats.record_lookup(false);
+        stats.net_input_bytes.fetch_add(100, Ordering::Relaxed);
+
+        let info = stats.info();
+        assert!(info.starts_with("total_connections_received:0\r\n"));
+        assert!(info.contains("\r\nkeyspace_hits:1\r\nkeyspace_misses:2\r\n"));
+        assert!(info.contains("\r\ntotal_net_input_bytes:100\r\n"));
+    }
+}
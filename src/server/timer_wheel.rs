@@ -0,0 +1,127 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// A hashed timer wheel.
+///
+/// Timers are stored in the slot of the tick they expire at, modulo the number of slots; timers expiring after
+/// a full turn of the wheel simply stay in their slot until their deadline is reached.
+///
+/// Timers can't be cancelled: the owner is expected to check if the timer is still relevant when it expires,
+/// and insert it again if it's not. This keeps "touching" a timer free, which is what we want for things like
+/// idle timeouts where the activity is frequent but expirations are rare.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<(Instant, T)>>,
+    resolution: Duration,
+    start: Instant,
+    /// The next tick to process.
+    tick: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(nb_slots: usize, resolution: Duration, now: Instant) -> Self {
+        assert!(nb_slots > 0);
+        assert!(!resolution.is_zero());
+
+        let mut slots = Vec::with_capacity(nb_slots);
+        for _ in 0..nb_slots {
+            slots.push(Vec::new());
+        }
+
+        Self {
+            slots,
+            resolution,
+            start: now,
+            tick: 0,
+        }
+    }
+
+    fn tick_of(&self, instant: Instant) -> u64 {
+        let elapsed = instant.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.resolution.as_nanos()) as u64
+    }
+
+    pub fn insert(&mut self, deadline: Instant, value: T) {
+        // Never put a timer in a slot already processed, it would have to wait for a full turn
+        let tick = self.tick_of(deadline).max(self.tick);
+        let pos = (tick % self.slots.len() as u64) as usize;
+
+        self.slots[pos].push((deadline, value));
+    }
+
+    /// Returns how long to wait until the next tick.
+    pub fn time_to_next_tick(&self, now: Instant) -> Duration {
+        let next = self.start + self.resolution * (self.tick_of(now) + 1) as u32;
+        next.saturating_duration_since(now)
+    }
+
+    /// Remove and return every timer expired at `now`.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut result = Vec::new();
+
+        let now_tick = self.tick_of(now);
+
+        // No need to go around the wheel more than once
+        let nb_ticks = (now_tick + 1)
+            .saturating_sub(self.tick)
+            .min(self.slots.len() as u64);
+
+        for i in 0..nb_ticks {
+            let pos = ((self.tick + i) % self.slots.len() as u64) as usize;
+
+            let slot = mem::take(&mut self.slots[pos]);
+            for (deadline, value) in slot {
+                if deadline <= now {
+                    result.push(value);
+                } else {
+                    self.slots[pos].push((deadline, value));
+                }
+            }
+        }
+
+        // The current tick is not over yet, timers may still be added to it
+        self.tick = self.tick.max(now_tick);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimerWheel;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timer_wheel() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        let mut wheel = TimerWheel::new(4, ms(10), now);
+
+        wheel.insert(now + ms(5), "a");
+        wheel.insert(now + ms(25), "b");
+        // More than a full turn of the wheel
+        wheel.insert(now + ms(95), "c");
+
+        assert!(wheel.expire(now).is_empty());
+        assert_eq!(vec!["a"], wheel.expire(now + ms(10)));
+        assert!(wheel.expire(now + ms(20)).is_empty());
+        assert_eq!(vec!["b"], wheel.expire(now + ms(30)));
+        assert!(wheel.expire(now + ms(60)).is_empty());
+        assert_eq!(vec!["c"], wheel.expire(now + ms(100)));
+
+        // Timers in the past expire on the next call
+        wheel.insert(now, "d");
+        assert_eq!(vec!["d"], wheel.expire(now + ms(100)));
+    }
+
+    #[test]
+    fn time_to_next_tick() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        let wheel = TimerWheel::<()>::new(4, ms(10), now);
+
+        assert_eq!(ms(10), wheel.time_to_next_tick(now));
+        assert_eq!(ms(7), wheel.time_to_next_tick(now + ms(3)));
+    }
+}
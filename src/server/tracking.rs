@@ -0,0 +1,111 @@
+//! Keys read by the clients with tracking enabled, for CLIENT TRACKING.
+//!
+//! The protocol can't tell a message from a response, so like Redis without RESP3 the invalidations are redirected
+//! to another connection subscribed to [`INVALIDATE_CHANNEL`] with SSUBSCRIBE.
+//!
+//! When a key is modified, every client which read it since it was last modified is sent an invalidation and the key
+//! is forgotten until a client reads it again.
+//!
+//! NOTE(vincent): only the keys read by a single command are tracked, not the ones read in a transaction or a script.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+/// A client reading a tracked key and the client its invalidations are redirected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Reader {
+    client_id: u64,
+    redirect: u64,
+}
+
+pub struct Tracking {
+    keys: Mutex<HashMap<String, Vec<Reader>>>,
+    /// Number of tracked keys, so that modifying a key doesn't take the lock when nothing is tracked.
+    nb_keys: AtomicUsize,
+}
+
+impl Tracking {
+    pub fn new() -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+            nb_keys: AtomicUsize::new(0),
+        }
+    }
+
+    /// Track `key` read by `client_id`, whose invalidations are sent to `redirect`.
+    pub fn track(&self, key: &str, client_id: u64, redirect: u64) {
+        let mut keys = self.keys.lock().unwrap();
+
+        let readers = keys.entry(key.to_string()).or_default();
+        let reader = Reader {
+            client_id,
+            redirect,
+        };
+        if !readers.contains(&reader) {
+            readers.push(reader);
+        }
+
+        self.nb_keys.store(keys.len(), Ordering::Relaxed);
+    }
+
+    /// Forget `key` once it's modified, returning the clients to send the invalidation to.
+    pub fn invalidate(&self, key: &str) -> Vec<u64> {
+        if self.nb_keys.load(Ordering::Relaxed) == 0 {
+            return Vec::new();
+        }
+
+        let mut keys = self.keys.lock().unwrap();
+        let readers = match keys.remove(key) {
+            Some(readers) => readers,
+            None => return Vec::new(),
+        };
+        self.nb_keys.store(keys.len(), Ordering::Relaxed);
+
+        let mut redirects: Vec<u64> = readers.iter().map(|r| r.redirect).collect();
+        redirects.sort_unstable();
+        redirects.dedup();
+
+        redirects
+    }
+
+    /// Forget every key read by `client_id`, once it disabled tracking or disconnected.
+    pub fn forget(&self, client_id: u64) {
+        let mut keys = self.keys.lock().unwrap();
+
+        keys.retain(|_, readers| {
+            readers.retain(|r| r.client_id != client_id);
+            !readers.is_empty()
+        });
+
+        self.nb_keys.store(keys.len(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tracking;
+
+    #[test]
+    fn invalidate() {
+        let tracking = Tracking::new();
+        assert!(tracking.invalidate("foo").is_empty());
+
+        tracking.track("foo", 1, 10);
+        tracking.track("foo", 1, 10);
+        tracking.track("foo", 2, 10);
+        tracking.track("foo", 3, 11);
+        tracking.track("bar", 3, 11);
+
+        // One invalidation per redirect, then the key is forgotten
+        assert_eq!(vec![10, 11], tracking.invalidate("foo"));
+        assert!(tracking.invalidate("foo").is_empty());
+
+        tracking.track("foo", 1, 10);
+        tracking.forget(3);
+        assert!(tracking.invalidate("bar").is_empty());
+        assert_eq!(vec![10], tracking.invalidate("foo"));
+    }
+}
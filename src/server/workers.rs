@@ -0,0 +1,264 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// A request handed to the worker pool or to the event loop owning its key.
+pub struct Job {
+    pub conn_id: u64,
+    pub fd: i32,
+    pub body: Vec<u8>,
+    pub completions: MailboxSender<Completion>,
+}
+
+/// The response to a [`Job`], sent back to the event loop which submitted it.
+pub struct Completion {
+    pub conn_id: u64,
+    pub fd: i32,
+    /// The message of the panic if executing the request panicked.
+    pub response: Result<Vec<u8>, String>,
+}
+
+/// Call `f`, returning the message of the panic instead if it panicked.
+///
+/// The state `f` touched may be left inconsistent, it's up to the caller to drop what depends on it.
+pub fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        }
+    })
+}
+
+/// Sends messages to a [`Mailbox`] and wakes up the event loop polling it.
+pub struct MailboxSender<T> {
+    sender: mpsc::Sender<T>,
+    wake_fd: i32,
+}
+
+impl<T> Clone for MailboxSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            wake_fd: self.wake_fd,
+        }
+    }
+}
+
+impl<T> MailboxSender<T> {
+    pub fn send(&self, message: T) {
+        if self.sender.send(message).is_err() {
+            // The event loop is gone, nobody cares about this message anymore
+            return;
+        }
+
+        // NOTE(vincent): if the pipe is full the event loop has a pending wake up already, so EAGAIN is fine.
+        let _ = crate::write(self.wake_fd, &[1]);
+    }
+}
+
+/// A channel which can be waited on by an event loop.
+///
+/// The read end of a pipe is written to every time a message is sent: register [`Mailbox::fd`] in the
+/// poller to be woken up when there's something to process.
+pub struct Mailbox<T> {
+    receiver: mpsc::Receiver<T>,
+    sender: MailboxSender<T>,
+    read_fd: i32,
+}
+
+impl<T> Mailbox<T> {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let rv = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if rv < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+        crate::set_socket_nonblocking(read_fd)?;
+        crate::set_socket_nonblocking(write_fd)?;
+
+        let (sender, receiver) = mpsc::channel();
+
+        Ok(Self {
+            receiver,
+            sender: MailboxSender {
+                sender,
+                wake_fd: write_fd,
+            },
+            read_fd,
+        })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.read_fd
+    }
+
+    pub fn sender(&self) -> MailboxSender<T> {
+        self.sender.clone()
+    }
+
+    /// Consume the pending wake ups and return every message received so far.
+    pub fn drain(&self) -> Vec<T> {
+        let mut buf = [0; 128];
+        while let Ok(data) = crate::read(self.read_fd, &mut buf) {
+            if data.is_empty() {
+                break;
+            }
+        }
+
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        let _ = crate::close(self.read_fd);
+        let _ = crate::close(self.sender.wake_fd);
+    }
+}
+
+type Handler = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+/// A fixed size pool of threads executing requests off the event loop.
+pub struct WorkerPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start `size` workers, each calling `handler` with the request body and sending back the response it returns.
+    pub fn new<F>(size: usize, handler: F) -> io::Result<Self>
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut threads = Vec::with_capacity(size);
+        for i in 0..size {
+            let handler = Arc::clone(&handler);
+            let receiver = Arc::clone(&receiver);
+
+            let thread = thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || loop {
+                    let job = {
+                        let receiver = receiver.lock().unwrap();
+                        match receiver.recv() {
+                            Ok(job) => job,
+                            Err(_) => return,
+                        }
+                    };
+
+                    // A panic only fails the request, the worker keeps going
+                    let response = catch_panic(|| handler(&job.body));
+
+                    job.completions.send(Completion {
+                        conn_id: job.conn_id,
+                        fd: job.fd,
+                        response,
+                    });
+                })?;
+
+            threads.push(thread);
+        }
+
+        Ok(Self {
+            jobs: Some(sender),
+            threads,
+        })
+    }
+
+    pub fn submit(&self, job: Job) {
+        // NOTE(vincent): safe because the sender is only taken when dropping the pool
+        let jobs = self.jobs.as_ref().unwrap();
+
+        // NOTE(vincent): workers only exit when the pool is dropped
+        jobs.send(job).unwrap();
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the channel makes every worker exit its loop
+        drop(self.jobs.take());
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Completion, Job, Mailbox, WorkerPool};
+
+    #[test]
+    fn worker_pool() {
+        let pool = WorkerPool::new(2, |body| body.to_ascii_uppercase()).unwrap();
+        let mailbox = Mailbox::<Completion>::new().unwrap();
+
+        for i in 0..10 {
+            pool.submit(Job {
+                conn_id: i,
+                fd: i as i32,
+                body: format!("foobar{}", i).into_bytes(),
+                completions: mailbox.sender(),
+            });
+        }
+
+        let mut completions = Vec::new();
+        while completions.len() < 10 {
+            completions.extend(mailbox.drain());
+        }
+
+        completions.sort_by_key(|completion| completion.conn_id);
+        for (i, completion) in completions.iter().enumerate() {
+            assert_eq!(i as u64, completion.conn_id);
+            assert_eq!(
+                format!("FOOBAR{}", i).as_bytes(),
+                completion.response.as_ref().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn worker_panic() {
+        let pool = WorkerPool::new(1, |body| {
+            assert_ne!(body, b"crash", "crashed on purpose");
+            body.to_vec()
+        })
+        .unwrap();
+        let mailbox = Mailbox::<Completion>::new().unwrap();
+
+        for (i, body) in [&b"crash"[..], b"foobar"].into_iter().enumerate() {
+            pool.submit(Job {
+                conn_id: i as u64,
+                fd: i as i32,
+                body: body.to_vec(),
+                completions: mailbox.sender(),
+            });
+        }
+
+        let mut completions = Vec::new();
+        while completions.len() < 2 {
+            completions.extend(mailbox.drain());
+        }
+
+        let error = completions[0].response.as_ref().unwrap_err();
+        assert!(error.contains("crashed on purpose"), "{}", error);
+        // The same worker executed the next request
+        assert_eq!(
+            b"foobar",
+            completions[1].response.as_ref().unwrap().as_slice()
+        );
+    }
+}
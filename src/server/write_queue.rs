@@ -0,0 +1,125 @@
+use crate::protocol::BUF_LEN;
+use crate::server::connection_buffer::BufferError;
+use std::collections::VecDeque;
+
+/// The responses waiting to be sent on a connection, one segment per response.
+///
+/// Keeping the responses apart avoids copying them, they are sent together with a vectored write.
+pub struct WriteQueue {
+    segments: VecDeque<Vec<u8>>,
+    /// Number of bytes of the first segment already sent.
+    offset: usize,
+    /// Number of bytes not sent yet, across every segment.
+    len: usize,
+    limit: usize,
+}
+
+impl WriteQueue {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            segments: VecDeque::new(),
+            offset: 0,
+            len: 0,
+            limit,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the next response may not fit: no more responses should be produced until the queue is flushed.
+    pub fn is_full(&self) -> bool {
+        self.len + BUF_LEN > self.limit
+    }
+
+    pub fn push(&mut self, segment: Vec<u8>) -> Result<(), BufferError> {
+        if self.len + segment.len() > self.limit {
+            return Err(BufferError::LimitReached(self.limit));
+        }
+
+        self.len += segment.len();
+        self.segments.push_back(segment);
+
+        Ok(())
+    }
+
+    /// Returns the data not sent yet, in at most `max` slices.
+    pub fn slices(&self, max: usize) -> Vec<&[u8]> {
+        self.segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                if i == 0 {
+                    &segment[self.offset..]
+                } else {
+                    &segment[..]
+                }
+            })
+            .take(max)
+            .collect()
+    }
+
+    /// Mark `n` bytes as sent, dropping the segments fully sent.
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len);
+        self.len -= n;
+
+        let mut n = self.offset + n;
+        while let Some(segment) = self.segments.front() {
+            if n < segment.len() {
+                break;
+            }
+
+            n -= segment.len();
+            self.segments.pop_front();
+        }
+
+        self.offset = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteQueue;
+    use crate::protocol::BUF_LEN;
+
+    #[test]
+    fn write_queue() {
+        let mut queue = WriteQueue::new(10);
+
+        queue.push(b"foo".to_vec()).unwrap();
+        queue.push(b"bar".to_vec()).unwrap();
+        queue.push(b"baz".to_vec()).unwrap();
+        assert!(queue.push(b"quux".to_vec()).is_err());
+        assert_eq!(9, queue.len());
+
+        assert_eq!(vec![&b"foo"[..], b"bar"], queue.slices(2));
+
+        queue.consume(4);
+        assert_eq!(vec![&b"ar"[..], b"baz"], queue.slices(8));
+
+        queue.consume(2);
+        assert_eq!(vec![&b"baz"[..]], queue.slices(8));
+
+        queue.consume(3);
+        assert!(queue.is_empty());
+        assert!(queue.slices(8).is_empty());
+    }
+
+    #[test]
+    fn is_full() {
+        let mut queue = WriteQueue::new(BUF_LEN + 2);
+        assert!(!queue.is_full());
+
+        queue.push(b"foo".to_vec()).unwrap();
+        assert!(queue.is_full());
+
+        queue.consume(3);
+        assert!(!queue.is_full());
+    }
+}
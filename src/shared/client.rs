@@ -0,0 +1,568 @@
+//! A synchronous client, for the programs talking to the server without going through the `client` binary.
+//!
+//! ```no_run
+//! use shared::client::Client;
+//!
+//! let mut client = Client::connect("127.0.0.1:1234")?;
+//! client.set(b"name", b"vincent")?;
+//! assert_eq!(Some(b"vincent".to_vec()), client.get(b"name")?);
+//! # Ok::<(), shared::client::Error>(())
+//! ```
+//!
+//! Each call sends its command and waits for the reply, [`Client::pipeline`] sends several commands before reading
+//! their replies. Nothing is retried: after an I/O error the client should be dropped and a new one connected.
+//!
+//! With the `std-net` feature the client connects with [`std::net::TcpStream`] and
+//! [`std::os::unix::net::UnixStream`] instead of the raw sockets of this crate, which the server still uses. Without
+//! the `std` feature the client then doesn't depend on libc.
+
+use crate::protocol::{self, BUF_LEN, MAX_MSG_LEN};
+use crate::url::{ConnectionUrl, UrlError};
+use crate::{command, debug, ErrorKind};
+use onlyerror::Error;
+use std::io;
+
+pub use crate::reply::Value;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+    #[error("protocol error")]
+    Protocol(#[from] protocol::Error),
+    #[error("connection closed by the server")]
+    EndOfStream,
+    #[error("invalid address {0:?}, want host:port or the path of a unix socket")]
+    InvalidAddress(String),
+    #[error("error reply {code}: {message}")]
+    Reply { code: u32, message: String },
+    #[error("unexpected reply {0:?}")]
+    UnexpectedReply(Value),
+    #[error("invalid URL")]
+    Url(#[from] UrlError),
+    #[error("{0} isn't supported by the server")]
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// Returns what went wrong, to handle the errors without matching every variant:
+    ///
+    /// ```no_run
+    /// use shared::client::Client;
+    /// use shared::ErrorKind;
+    ///
+    /// let mut client = Client::connect("127.0.0.1:1234")?;
+    /// match client.get(b"a") {
+    ///     Ok(value) => println!("{:?}", value),
+    ///     Err(err) if err.kind() == ErrorKind::Closed => println!("disconnected"),
+    ///     Err(err) => return Err(err),
+    /// }
+    /// # Ok::<(), shared::client::Error>(())
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::IO(err) => ErrorKind::of_io(err),
+            Self::Protocol(err) => err.kind(),
+            Self::EndOfStream => ErrorKind::Closed,
+            Self::InvalidAddress(_) | Self::Url(_) => ErrorKind::InvalidInput,
+            Self::Reply { .. } => ErrorKind::Reply,
+            Self::UnexpectedReply(_) => ErrorKind::Protocol,
+            Self::Unsupported(_) => ErrorKind::Unsupported,
+        }
+    }
+}
+
+/// Turns an error reply into an [`Error::Reply`].
+fn into_result(value: Value) -> Result<Value, Error> {
+    match value {
+        Value::Error { code, message } => Err(Error::Reply { code, message }),
+        value => Ok(value),
+    }
+}
+
+/// Encode the commands one message each, failing if one doesn't fit in a message.
+pub fn encode_commands(commands: &[Vec<&[u8]>]) -> Result<Vec<u8>, protocol::Error> {
+    let mut buf = Vec::with_capacity(protocol::buffer_size_needed(commands));
+
+    for command in commands {
+        let body = command::encode(command);
+        if body.len() > MAX_MSG_LEN {
+            return Err(protocol::Error::MessageTooLong(body.len()));
+        }
+
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+    }
+
+    Ok(buf)
+}
+
+/// Split `host:port`, without the brackets around an IPv6 host.
+fn split_addr(addr: &str) -> Result<(&str, u16), Error> {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) => (host, port),
+        None => return Err(Error::InvalidAddress(addr.to_string())),
+    };
+    let port = port
+        .parse()
+        .map_err(|_| Error::InvalidAddress(addr.to_string()))?;
+
+    let host = match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(host) => host,
+        None => host,
+    };
+
+    Ok((host, port))
+}
+
+#[cfg(not(feature = "std-net"))]
+mod stream {
+    use super::{split_addr, Error};
+    use crate::{debug, OwnedSocket};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    /// The connection to the server, on a raw socket.
+    pub struct Stream(OwnedSocket);
+
+    impl Stream {
+        /// Connect to `addr`, either `host:port` or the path of a unix socket if it contains a `/`.
+        pub fn connect(addr: &str) -> Result<Self, Error> {
+            if addr.contains('/') {
+                let socket = OwnedSocket::unix()?;
+                crate::connect_unix(socket.as_raw_fd(), Path::new(addr))?;
+                return Ok(Self(socket));
+            }
+
+            let (host, port) = split_addr(addr)?;
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address found for {}", host),
+            );
+
+            // The addresses of the host are tried in order
+            for addr in crate::resolve(host, port)? {
+                let socket = OwnedSocket::tcp(&addr)?;
+                match crate::connect(socket.as_raw_fd(), &addr) {
+                    Ok(()) => return Ok(Self(socket)),
+                    Err(err) => {
+                        debug!("unable to connect to {}: {}", addr, err);
+                        last_err = err;
+                    }
+                }
+            }
+
+            Err(last_err.into())
+        }
+
+        pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf).map(|read_buf| read_buf.len())
+        }
+
+        pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.0.write_full(buf)
+        }
+    }
+}
+
+#[cfg(feature = "std-net")]
+mod stream {
+    use super::{split_addr, Error};
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+
+    /// The connection to the server, on a stream of the standard library.
+    pub enum Stream {
+        Tcp(TcpStream),
+        Unix(UnixStream),
+    }
+
+    impl Stream {
+        /// Connect to `addr`, either `host:port` or the path of a unix socket if it contains a `/`.
+        pub fn connect(addr: &str) -> Result<Self, Error> {
+            if addr.contains('/') {
+                return Ok(Self::Unix(UnixStream::connect(addr)?));
+            }
+
+            let (host, port) = split_addr(addr)?;
+            Ok(Self::Tcp(TcpStream::connect((host, port))?))
+        }
+
+        pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                Self::Tcp(stream) => stream.read(buf),
+                Self::Unix(stream) => stream.read(buf),
+            }
+        }
+
+        pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            match self {
+                Self::Tcp(stream) => stream.write_all(buf),
+                Self::Unix(stream) => stream.write_all(buf),
+            }
+        }
+    }
+}
+
+pub struct Client {
+    stream: stream::Stream,
+    /// What was read past the last message.
+    pending: Vec<u8>,
+}
+
+impl Client {
+    /// Connect to `addr`, either `host:port` or the path of a unix socket if it contains a `/`.
+    pub fn connect(addr: &str) -> Result<Self, Error> {
+        let stream = stream::Stream::connect(addr)?;
+
+        debug!("connected to {}", addr);
+
+        Ok(Self {
+            stream,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Connect to the server of a connection URL, see [`crate::url`], and authenticate if it has a password.
+    ///
+    /// The server has no TLS and a single database, the URLs asking for either are rejected.
+    pub fn connect_url(url: &str) -> Result<Self, Error> {
+        let url: ConnectionUrl = url.parse()?;
+        if url.tls {
+            return Err(Error::Unsupported("TLS"));
+        }
+        if url.db != 0 {
+            return Err(Error::Unsupported("a database other than 0"));
+        }
+
+        let mut client = Self::connect(&url.endpoint.to_string())?;
+
+        if let Some(password) = &url.password {
+            let mut args: Vec<&[u8]> = Vec::with_capacity(2);
+            if let Some(user) = &url.user {
+                args.push(user.as_bytes());
+            }
+            args.push(password.as_bytes());
+
+            into_result(client.execute("auth", &args)?)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Execute the command `cmd`, returning its reply even if it's an error.
+    pub fn execute(&mut self, cmd: &str, args: &[&[u8]]) -> Result<Value, Error> {
+        let mut command = Vec::with_capacity(args.len() + 1);
+        command.push(cmd.as_bytes());
+        command.extend_from_slice(args);
+
+        let mut replies = self.pipeline(&[command])?;
+        Ok(replies.remove(0))
+    }
+
+    /// Send all the commands before reading their replies, returned in the same order.
+    pub fn pipeline(&mut self, commands: &[Vec<&[u8]>]) -> Result<Vec<Value>, Error> {
+        let buf = encode_commands(commands)?;
+        self.stream.write_all(&buf)?;
+
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            let body = self.read_message()?;
+            replies.push(Value::decode(&body)?);
+        }
+
+        Ok(replies)
+    }
+
+    /// Returns the value of `key`, `None` if it doesn't exist.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match into_result(self.execute("get", &[key])?)? {
+            Value::Nil => Ok(None),
+            Value::Bytes(value) => Ok(Some(value)),
+            value => Err(Error::UnexpectedReply(value)),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        into_result(self.execute("set", &[key, value])?)?;
+        Ok(())
+    }
+
+    /// Delete the keys, returning how many existed.
+    ///
+    /// NOTE(vincent): the server only deletes the first key for now, the keys can belong to different shards.
+    pub fn del(&mut self, keys: &[&[u8]]) -> Result<u64, Error> {
+        match into_result(self.execute("del", keys)?)? {
+            Value::Int(n) => Ok(n),
+            value => Err(Error::UnexpectedReply(value)),
+        }
+    }
+
+    /// Read the body of the next message, keeping what's read past it for the next call.
+    fn read_message(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            match protocol::parse_message(&self.pending) {
+                Ok((read, body)) => {
+                    let body = body.to_vec();
+                    self.pending.drain(..read);
+                    return Ok(body);
+                }
+                Err(protocol::Error::InputTooShort(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(Error::EndOfStream);
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_commands, split_addr, Client, Error, Value};
+    use crate::mock::{MockServer, Response};
+    use crate::protocol::{self, Writer, BUF_LEN};
+    use crate::{command, ErrorKind, ResponseCode};
+
+    fn message(push: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut buf = [0; BUF_LEN];
+        let mut writer = Writer::new(&mut buf);
+        push(&mut writer);
+        writer.finish();
+
+        let written = writer.written();
+        buf[..written].to_vec()
+    }
+
+    #[test]
+    fn commands() {
+        let server = MockServer::builder()
+            .expect(&[b"set", b"a", b"1"], Response::Reply(Value::Nil))
+            .expect(
+                &[b"get", b"a"],
+                Response::Reply(Value::Bytes(b"1".to_vec())),
+            )
+            .expect(&[b"get", b"b"], Response::Reply(Value::Nil))
+            .expect(&[b"del", b"a", b"b"], Response::Reply(Value::Int(1)))
+            .expect(
+                &[b"get", b"c"],
+                Response::Reply(Value::Error {
+                    code: ResponseCode::Unknown.into(),
+                    message: "boom".to_string(),
+                }),
+            )
+            .expect(&[b"ping"], Response::Reply(Value::Bytes(b"PONG".to_vec())))
+            .start()
+            .unwrap();
+        let mut client = Client::connect(&server.addr()).unwrap();
+
+        client.set(b"a", b"1").unwrap();
+        assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+        assert_eq!(None, client.get(b"b").unwrap());
+        assert_eq!(1, client.del(&[b"a", b"b"]).unwrap());
+        assert!(matches!(
+            client.get(b"c"),
+            Err(Error::Reply { code: 100, message }) if message == "boom"
+        ));
+        assert_eq!(
+            Value::Bytes(b"PONG".to_vec()),
+            client.execute("ping", &[]).unwrap()
+        );
+
+        // Writing to the closed socket fails first most of the time
+        server.verify();
+        assert!(matches!(
+            client.get(b"a"),
+            Err(Error::IO(_) | Error::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn error_kinds() {
+        let too_long = ((protocol::MAX_MSG_LEN + 1) as u32).to_be_bytes().to_vec();
+        let server = MockServer::builder()
+            .expect(
+                &[b"get", b"a"],
+                Response::Reply(Value::Error {
+                    code: ResponseCode::Unknown.into(),
+                    message: "boom".to_string(),
+                }),
+            )
+            .expect(
+                &[b"get", b"b"],
+                Response::Raw(b"\x00\x00\x00\x01\xff".to_vec()),
+            )
+            .expect(&[b"get", b"c"], Response::Close)
+            .expect(&[b"get", b"d"], Response::Raw(too_long))
+            .start()
+            .unwrap();
+
+        let mut client = Client::connect(&server.addr()).unwrap();
+        assert_eq!(ErrorKind::Reply, client.get(b"a").unwrap_err().kind());
+        assert_eq!(ErrorKind::Protocol, client.get(b"b").unwrap_err().kind());
+        assert_eq!(ErrorKind::Closed, client.get(b"c").unwrap_err().kind());
+
+        let mut client = Client::connect(&server.addr()).unwrap();
+        assert_eq!(ErrorKind::TooLong, client.get(b"d").unwrap_err().kind());
+        server.verify();
+
+        for result in [
+            Client::connect("localhost"),
+            Client::connect_url("http://localhost"),
+        ] {
+            assert_eq!(
+                Some(ErrorKind::InvalidInput),
+                result.err().map(|err| err.kind())
+            );
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        // Both replies in a single write
+        let mut replies = message(|w| w.push_int(2));
+        replies.extend(message(|w| {
+            w.push_arr(2);
+            w.push_string("a");
+            w.push_nil();
+        }));
+
+        let server = MockServer::builder()
+            .expect(&[b"del", b"a"], Response::Raw(Vec::new()))
+            .expect(&[b"keys"], Response::Raw(replies))
+            .start()
+            .unwrap();
+        let mut client = Client::connect(&server.addr()).unwrap();
+
+        assert_eq!(
+            vec![
+                Value::Int(2),
+                Value::Array(vec![Value::Bytes(b"a".to_vec()), Value::Nil])
+            ],
+            client
+                .pipeline(&[vec![b"del", b"a"], vec![b"keys"]])
+                .unwrap()
+        );
+        server.verify();
+    }
+
+    /// A xorshift generator, good enough to build random replies and commands.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn bytes(&mut self, max_len: u64) -> Vec<u8> {
+            let len = self.below(max_len + 1);
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        /// Small enough that the biggest tree still fits in a message.
+        fn value(&mut self, depth: u32) -> Value {
+            match self.below(if depth > 0 { 5 } else { 4 }) {
+                0 => Value::Nil,
+                1 => Value::Error {
+                    code: self.next() as u32,
+                    message: self
+                        .bytes(16)
+                        .into_iter()
+                        .map(|b| char::from(b'a' + b % 26))
+                        .collect(),
+                },
+                2 => Value::Bytes(self.bytes(16)),
+                3 => Value::Int(self.next()),
+                _ => Value::Array((0..self.below(5)).map(|_| self.value(depth - 1)).collect()),
+            }
+        }
+    }
+
+    fn push_value(writer: &mut Writer, value: &Value) {
+        match value {
+            Value::Nil => writer.push_nil(),
+            Value::Error { code, message } => writer.push_err(*code, message),
+            Value::Bytes(value) => writer.push_string(value),
+            Value::Int(value) => writer.push_int(*value as usize),
+            Value::Array(items) => {
+                writer.push_arr(items.len());
+                for item in items {
+                    push_value(writer, item);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_values() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+
+        for _ in 0..10_000 {
+            let value = rng.value(3);
+
+            let message = message(|w| push_value(w, &value));
+            let (read, body) = protocol::parse_message(&message).unwrap();
+            assert_eq!(message.len(), read);
+            assert_eq!(value, Value::decode(body).unwrap());
+            assert_eq!(body, value.encode());
+        }
+    }
+
+    #[test]
+    fn roundtrip_commands() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+
+        for _ in 0..10_000 {
+            let args: Vec<Vec<Vec<u8>>> = (0..rng.below(8))
+                .map(|_| (0..rng.below(6)).map(|_| rng.bytes(64)).collect())
+                .collect();
+            let commands: Vec<Vec<&[u8]>> = args
+                .iter()
+                .map(|args| args.iter().map(Vec::as_slice).collect())
+                .collect();
+
+            let buf = encode_commands(&commands).unwrap();
+            assert_eq!(buf.len(), protocol::buffer_size_needed(&commands));
+
+            let mut remaining = buf.as_slice();
+            for command in &commands {
+                let (read, body) = protocol::parse_message(remaining).unwrap();
+                assert_eq!(*command, command::parse(body).unwrap());
+                remaining = &remaining[read..];
+            }
+            assert!(remaining.is_empty());
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(("localhost", 1234), split_addr("localhost:1234").unwrap());
+        assert_eq!(("::1", 1234), split_addr("[::1]:1234").unwrap());
+    }
+
+    #[test]
+    fn invalid_address() {
+        for addr in ["localhost", "localhost:port", "localhost:99999"] {
+            assert!(matches!(
+                Client::connect(addr),
+                Err(Error::InvalidAddress(_))
+            ));
+        }
+    }
+}
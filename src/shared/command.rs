@@ -1,18 +1,12 @@
-use onlyerror::Error;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 use crate::protocol;
 
-#[derive(Error, Debug)]
-pub enum ParseCommandError {
-    #[error("protocol error")]
-    Protocol(#[from] protocol::Error),
-}
-
 pub type ParsedCommand<'a> = Vec<&'a [u8]>;
 
-pub fn parse<'a>(body: &'a [u8]) -> Result<ParsedCommand<'a>, ParseCommandError> {
-    println!("==> body: {:?}", body);
-
+pub fn parse<'a>(body: &'a [u8]) -> Result<ParsedCommand<'a>, protocol::Error> {
     let mut reader = protocol::Reader::new(body);
 
     // 1. Parse the number of arguments.
@@ -24,7 +18,8 @@ pub fn parse<'a>(body: &'a [u8]) -> Result<ParsedCommand<'a>, ParseCommandError>
 
     // 2. Parse each argument
 
-    let mut args: Vec<&'a [u8]> = Vec::with_capacity(n_args as usize);
+    // NOTE(vincent): the number comes from the network, each argument takes at least a byte of the body
+    let mut args: Vec<&'a [u8]> = Vec::with_capacity((n_args as usize).min(body.len()));
     while n_args > 0 {
         let arg = {
             reader.read_data_type_expecting(protocol::DataType::Str)?;
@@ -39,9 +34,256 @@ pub fn parse<'a>(body: &'a [u8]) -> Result<ParsedCommand<'a>, ParseCommandError>
     Ok(args)
 }
 
+/// Encode the arguments of a command as a request body, the reverse of [`parse`].
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let size = 1 + 8 + args.iter().map(|arg| 1 + 4 + arg.len()).sum::<usize>();
+    let mut body = Vec::with_capacity(size);
+
+    body.push(protocol::DataType::Int as u8);
+    body.extend_from_slice(&(args.len() as u64).to_be_bytes());
+
+    for arg in args {
+        body.push(protocol::DataType::Str as u8);
+        body.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+        body.extend_from_slice(arg);
+    }
+
+    body
+}
+
 pub fn is_valid<T: AsRef<[u8]>>(value: T) -> bool {
     let cmd = value.as_ref();
-    KNOWN_COMMANDS.contains(&cmd)
+    COMMANDS
+        .iter()
+        .any(|command| command.name.as_bytes() == cmd)
+}
+
+/// Returns the command named `name` in [`COMMANDS`].
+pub fn find<T: AsRef<[u8]>>(name: T) -> Option<&'static CommandInfo> {
+    let name = name.as_ref();
+    COMMANDS
+        .iter()
+        .find(|command| command.name.as_bytes() == name)
+}
+
+/// A command of the server, as the clients know it.
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// The number of arguments counting the name like in Redis: exact if positive, the minimum if negative.
+    pub arity: i32,
+    /// What the command does, in one line.
+    pub summary: &'static str,
+    /// Empty if the command has no subcommands.
+    pub subcommands: &'static [&'static str],
+    /// True if the command never changes anything, so it can be sent again safely.
+    pub read_only: bool,
+}
+
+impl CommandInfo {
+    /// Returns true if the command can be called with `nb_args` arguments, not counting its name.
+    pub fn accepts(&self, nb_args: usize) -> bool {
+        let nb_args = nb_args as i64 + 1;
+        let arity = self.arity as i64;
+
+        if arity > 0 {
+            nb_args == arity
+        } else {
+            nb_args >= -arity
+        }
+    }
+
+    /// Describes the number of arguments the command takes, like `1 arg` or `2+ args`.
+    pub fn describe_args(&self) -> String {
+        // The arity counts the name of the command
+        match self.arity {
+            1 => "no args".to_string(),
+            -1 => "any args".to_string(),
+            2 => "1 arg".to_string(),
+            arity if arity > 0 => format!("{} args", arity - 1),
+            arity => format!("{}+ args", -arity - 1),
+        }
+    }
 }
 
-const KNOWN_COMMANDS: &[&[u8]] = &[b"get", b"set", b"del"];
+const fn info(
+    name: &'static str,
+    arity: i32,
+    summary: &'static str,
+    subcommands: &'static [&'static str],
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        arity,
+        summary,
+        subcommands,
+        read_only: false,
+    }
+}
+
+const fn read_only(
+    name: &'static str,
+    arity: i32,
+    summary: &'static str,
+    subcommands: &'static [&'static str],
+) -> CommandInfo {
+    CommandInfo {
+        read_only: true,
+        ..info(name, arity, summary, subcommands)
+    }
+}
+
+/// The commands of the server, sorted by name. The commands of the modules and those only sent by the other nodes
+/// aren't there.
+pub const COMMANDS: &[CommandInfo] = &[
+    info(
+        "asking",
+        1,
+        "Let the next command access a slot being imported",
+        &[],
+    ),
+    info("auth", -2, "Authenticate the connection", &[]),
+    info("bgsave", -1, "Save a snapshot in the background", &[]),
+    info(
+        "client",
+        -2,
+        "Manage the client connections",
+        &[
+            "getname", "id", "info", "kill", "list", "setname", "tracking",
+        ],
+    ),
+    info(
+        "cluster",
+        -2,
+        "Inspect and configure the cluster",
+        &["info", "keyslot", "myid", "nodes", "setslot", "slots"],
+    ),
+    info(
+        "config",
+        -3,
+        "Get or set configuration parameters",
+        &["get", "set"],
+    ),
+    info("del", -2, "Delete keys", &[]),
+    info("discard", 1, "Discard the commands queued since MULTI", &[]),
+    read_only("dump", -2, "Serialize the value of a key", &[]),
+    info("eval", -3, "Run a script", &[]),
+    info("evalsha", -3, "Run a script loaded with SCRIPT LOAD", &[]),
+    info("exec", 1, "Execute the commands queued since MULTI", &[]),
+    info(
+        "expire",
+        -3,
+        "Set the time to live of a key in seconds",
+        &[],
+    ),
+    read_only("get", 2, "Get the value of a key", &[]),
+    read_only(
+        "info",
+        -1,
+        "Get information and statistics about the server",
+        &[],
+    ),
+    read_only("keys", -1, "List the keys", &[]),
+    info(
+        "latency",
+        -2,
+        "Inspect the latency of the commands",
+        &["histogram", "history", "reset"],
+    ),
+    read_only(
+        "memory",
+        -2,
+        "Inspect the memory usage",
+        &["stats", "usage"],
+    ),
+    info("migrate", -5, "Move a key to another node", &[]),
+    info("multi", 1, "Start a transaction", &[]),
+    info(
+        "pexpireat",
+        -3,
+        "Set the expiration time of a key as a unix timestamp in milliseconds",
+        &[],
+    ),
+    read_only("ping", -1, "Check that the server is alive", &[]),
+    read_only(
+        "pubsub",
+        -2,
+        "Inspect the shard channels",
+        &["shardchannels", "shardnumsub"],
+    ),
+    info(
+        "replicaof",
+        -3,
+        "Replicate another server, or stop with NO ONE",
+        &[],
+    ),
+    info("restore", -4, "Create a key from a serialized value", &[]),
+    read_only("role", -1, "Get the replication role of the server", &[]),
+    info(
+        "script",
+        -2,
+        "Manage the script cache",
+        &["exists", "flush", "load"],
+    ),
+    info("set", -3, "Set the value of a key", &[]),
+    info(
+        "setex",
+        -4,
+        "Set the value and the time to live of a key",
+        &[],
+    ),
+    info("shutdown", -1, "Stop the server", &[]),
+    info("spublish", 3, "Publish a message to a shard channel", &[]),
+    info("ssubscribe", -2, "Subscribe to shard channels", &[]),
+    info("sunsubscribe", -1, "Unsubscribe from shard channels", &[]),
+    read_only("ttl", -2, "Get the time to live of a key in seconds", &[]),
+    info("unwatch", 1, "Forget the keys watched with WATCH", &[]),
+    info(
+        "watch",
+        -2,
+        "Abort the next transaction if a key is modified",
+        &[],
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, find, is_valid, parse, COMMANDS};
+
+    #[test]
+    fn encode_parse() {
+        let args: &[&[u8]] = &[b"set", b"foo", b"", b"bar"];
+
+        let body = encode(args);
+        assert_eq!(args, parse(&body).unwrap().as_slice());
+
+        // Truncated in the middle of an argument, or claiming more arguments than there are
+        assert!(parse(&body[..body.len() - 1]).is_err());
+        assert!(parse(b"\x03\xff\xff\xff\xff\xff\xff\xff\xff").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert!(COMMANDS.windows(2).all(|pair| pair[0].name < pair[1].name));
+        assert!(COMMANDS.iter().all(|command| command.arity != 0));
+
+        assert!(is_valid("get"));
+        assert!(is_valid(b"cluster"));
+        assert!(!is_valid("GET"));
+        assert!(!is_valid("nope"));
+
+        assert!(find("get").unwrap().read_only);
+        assert!(!find("set").unwrap().read_only);
+        assert!(find("nope").is_none());
+
+        let get = find("get").unwrap();
+        assert!(get.accepts(1));
+        assert!(!get.accepts(0) && !get.accepts(2));
+        assert_eq!("1 arg", get.describe_args());
+        let set = find("set").unwrap();
+        assert!(set.accepts(2) && set.accepts(5));
+        assert!(!set.accepts(1));
+        assert_eq!("2+ args", set.describe_args());
+        assert!(find("ping").unwrap().accepts(0));
+        assert!(!find("exec").unwrap().accepts(1));
+    }
+}
@@ -0,0 +1,39 @@
+//! The kinds of errors of the library, to handle the errors of the different modules the same way.
+//!
+//! Each error type keeps its own variants and their sources, and has a `kind` method returning an [`ErrorKind`].
+
+/// What went wrong, whatever the error type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Reading or writing a socket failed.
+    Io,
+    /// Reading or writing a socket timed out.
+    Timeout,
+    /// The other end closed the connection.
+    Closed,
+    /// The bytes received aren't a valid message, or not the one expected.
+    Protocol,
+    /// A message is longer than [`MAX_MSG_LEN`](crate::protocol::MAX_MSG_LEN).
+    TooLong,
+    /// The server replied with an error.
+    Reply,
+    /// An address, a URL or an argument isn't valid.
+    InvalidInput,
+    /// The server doesn't support what was asked.
+    Unsupported,
+}
+
+impl ErrorKind {
+    /// Returns the kind of an I/O error: a timeout, a closed connection or another I/O error.
+    #[cfg(any(feature = "std", feature = "std-net"))]
+    pub fn of_io(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        match err.kind() {
+            TimedOut | WouldBlock => Self::Timeout,
+            UnexpectedEof | ConnectionReset | ConnectionAborted | BrokenPipe => Self::Closed,
+            _ => Self::Io,
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! A C ABI over [`Client`], declared in `include/mor.h`.
+//!
+//! The library is built for C with `cargo rustc --release --lib --features ffi --crate-type cdylib` (or
+//! `staticlib`). The client is an opaque pointer and each reply is a tree allocated here, freed with
+//! [`mor_free_reply`]:
+//!
+//! ```c
+//! mor_client *client = mor_connect("127.0.0.1:1234");
+//!
+//! const uint8_t *argv[] = {(const uint8_t *)"get", (const uint8_t *)"name"};
+//! size_t argv_len[] = {3, 4};
+//! mor_reply *reply = mor_command(client, 2, argv, argv_len);
+//! if (reply != NULL && reply->type == MOR_REPLY_STR) {
+//!     printf("%.*s\n", (int)reply->len, reply->str);
+//! }
+//!
+//! mor_free_reply(reply);
+//! mor_close(client);
+//! ```
+
+use crate::client::{Client, Value};
+use crate::debug;
+use std::ffi::{c_char, CStr};
+use std::{ptr, slice};
+
+/// The type of a [`Reply`], the data type of the protocol.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyType {
+    Nil = 0,
+    Err = 1,
+    Str = 2,
+    Int = 3,
+    Arr = 4,
+}
+
+/// A reply of the server, `mor_reply` in C.
+///
+/// * `code`, `str` and `len` are set for an error, `str` and `len` for a string
+/// * `integer` is set for an integer
+/// * `elements` and `n_elements` are set for an array
+#[repr(C)]
+pub struct Reply {
+    pub kind: ReplyType,
+    pub code: u32,
+    pub integer: u64,
+    pub str: *mut u8,
+    pub len: usize,
+    pub elements: *mut Reply,
+    pub n_elements: usize,
+}
+
+impl Reply {
+    fn new(kind: ReplyType) -> Self {
+        Self {
+            kind,
+            code: 0,
+            integer: 0,
+            str: ptr::null_mut(),
+            len: 0,
+            elements: ptr::null_mut(),
+            n_elements: 0,
+        }
+    }
+
+    fn with_bytes(kind: ReplyType, bytes: Vec<u8>) -> Self {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+
+        let mut reply = Self::new(kind);
+        reply.str = bytes as *mut u8;
+        reply.len = bytes.len();
+        reply
+    }
+
+    // NOTE(vincent): no struct update syntax in here, the base would be dropped and free what was just copied out of it
+    fn from_value(value: Value) -> Self {
+        match value {
+            Value::Nil => Self::new(ReplyType::Nil),
+            Value::Error { code, message } => {
+                let mut reply = Self::with_bytes(ReplyType::Err, message.into_bytes());
+                reply.code = code;
+                reply
+            }
+            Value::Bytes(bytes) => Self::with_bytes(ReplyType::Str, bytes),
+            Value::Int(integer) => {
+                let mut reply = Self::new(ReplyType::Int);
+                reply.integer = integer;
+                reply
+            }
+            Value::Array(items) => {
+                let elements: Box<[Reply]> = items.into_iter().map(Self::from_value).collect();
+                let elements = Box::into_raw(elements);
+
+                let mut reply = Self::new(ReplyType::Arr);
+                reply.elements = elements as *mut Reply;
+                reply.n_elements = elements.len();
+                reply
+            }
+        }
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        // SAFETY: both were leaked from boxed slices of these lengths by `from_value` and are only freed here
+        unsafe {
+            if !self.str.is_null() {
+                drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                    self.str, self.len,
+                )));
+            }
+            if !self.elements.is_null() {
+                drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                    self.elements,
+                    self.n_elements,
+                )));
+            }
+        }
+    }
+}
+
+/// Connect to `addr`, either `host:port` or the path of a unix socket.
+/// Returns NULL if `addr` isn't valid UTF-8 or the connection fails.
+///
+/// # Safety
+///
+/// `addr` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mor_connect(addr: *const c_char) -> *mut Client {
+    if addr.is_null() {
+        return ptr::null_mut();
+    }
+
+    let addr = match CStr::from_ptr(addr).to_str() {
+        Ok(addr) => addr,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    match Client::connect(addr) {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(err) => {
+            debug!("unable to connect to {}: {}", addr, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Close the connection and free the client. Does nothing if `client` is NULL.
+///
+/// # Safety
+///
+/// `client` must come from [`mor_connect`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mor_close(client: *mut Client) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Execute the command made of the `argc` arguments in `argv`, the length of each one in `argv_len`.
+///
+/// Returns the reply, error replies included, or NULL if there are no arguments or the command couldn't be sent
+/// or its reply read. After NULL the client should be closed.
+///
+/// # Safety
+///
+/// `client` must come from [`mor_connect`], `argv` and `argv_len` must both point to `argc` elements and each
+/// argument to `argv_len[i]` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mor_command(
+    client: *mut Client,
+    argc: usize,
+    argv: *const *const u8,
+    argv_len: *const usize,
+) -> *mut Reply {
+    if client.is_null() || argc == 0 || argv.is_null() || argv_len.is_null() {
+        return ptr::null_mut();
+    }
+    let client = &mut *client;
+
+    let command = command_args(argc, argv, argv_len);
+
+    match client.pipeline(&[command]) {
+        Ok(mut replies) => Box::into_raw(Box::new(Reply::from_value(replies.remove(0)))),
+        Err(err) => {
+            debug!("unable to execute the command: {}", err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Send the `n` commands before reading their replies, command `i` made of the `argc[i]` arguments in `argv[i]`,
+/// the length of each one in `argv_len[i]` like for [`mor_command`].
+///
+/// Returns an array reply holding the `n` replies in order, or NULL if a command has no arguments or the commands
+/// couldn't be sent or their replies read. After NULL the client should be closed.
+///
+/// # Safety
+///
+/// `client` must come from [`mor_connect`], `argc`, `argv` and `argv_len` must point to `n` elements and each
+/// command must be valid for [`mor_command`].
+#[no_mangle]
+pub unsafe extern "C" fn mor_pipeline(
+    client: *mut Client,
+    n: usize,
+    argc: *const usize,
+    argv: *const *const *const u8,
+    argv_len: *const *const usize,
+) -> *mut Reply {
+    if client.is_null() || n == 0 || argc.is_null() || argv.is_null() || argv_len.is_null() {
+        return ptr::null_mut();
+    }
+    let client = &mut *client;
+
+    let mut commands = Vec::with_capacity(n);
+    for i in 0..n {
+        let (argc, argv, argv_len) = (*argc.add(i), *argv.add(i), *argv_len.add(i));
+        if argc == 0 || argv.is_null() || argv_len.is_null() {
+            return ptr::null_mut();
+        }
+
+        commands.push(command_args(argc, argv, argv_len));
+    }
+
+    match client.pipeline(&commands) {
+        Ok(replies) => Box::into_raw(Box::new(Reply::from_value(Value::Array(replies)))),
+        Err(err) => {
+            debug!("unable to execute the pipeline: {}", err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The arguments of a command, see [`mor_command`].
+unsafe fn command_args<'a>(
+    argc: usize,
+    argv: *const *const u8,
+    argv_len: *const usize,
+) -> Vec<&'a [u8]> {
+    slice::from_raw_parts(argv, argc)
+        .iter()
+        .zip(slice::from_raw_parts(argv_len, argc))
+        .map(|(&arg, &len)| {
+            // NOTE(vincent): the pointer of an empty argument can be NULL, which from_raw_parts doesn't allow
+            if len == 0 {
+                &[][..]
+            } else {
+                slice::from_raw_parts(arg, len)
+            }
+        })
+        .collect()
+}
+
+/// Free a reply and its elements. Does nothing if `reply` is NULL.
+///
+/// # Safety
+///
+/// `reply` must come from [`mor_command`] or [`mor_pipeline`] and not be used afterwards, nor its elements.
+#[no_mangle]
+pub unsafe extern "C" fn mor_free_reply(reply: *mut Reply) {
+    if !reply.is_null() {
+        drop(Box::from_raw(reply));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mor_close, mor_command, mor_connect, mor_free_reply, mor_pipeline, ReplyType};
+    use crate::protocol::{self, Writer, BUF_LEN};
+    use crate::{command, ResponseCode};
+    use std::ffi::CString;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::{fs, ptr, slice, thread};
+
+    #[test]
+    fn commands() {
+        let path =
+            std::env::temp_dir().join(format!("my-own-redis-ffi-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Replies with an array of the arguments and their number, or an error if there's only one
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut pending = Vec::new();
+            for _ in 0..4 {
+                let (read, args) = loop {
+                    if let Ok((read, body)) = protocol::parse_message(&pending) {
+                        let args: Vec<Vec<u8>> = command::parse(body)
+                            .unwrap()
+                            .into_iter()
+                            .map(<[u8]>::to_vec)
+                            .collect();
+                        break (read, args);
+                    }
+
+                    let mut buf = [0; BUF_LEN];
+                    let n = stream.read(&mut buf).unwrap();
+                    assert!(n > 0, "client closed the connection");
+                    pending.extend_from_slice(&buf[..n]);
+                };
+                pending.drain(..read);
+
+                let mut buf = [0; BUF_LEN];
+                let mut writer = Writer::new(&mut buf);
+                if args.len() > 1 {
+                    writer.push_arr(args.len() + 1);
+                    for arg in &args {
+                        writer.push_string(arg);
+                    }
+                    writer.push_int(args.len());
+                } else {
+                    writer.push_err(ResponseCode::Unknown, "boom");
+                }
+                writer.finish();
+                let written = writer.written();
+                stream.write_all(&buf[..written]).unwrap();
+            }
+        });
+
+        unsafe {
+            assert!(mor_connect(ptr::null()).is_null());
+
+            let addr = CString::new(path.to_str().unwrap()).unwrap();
+            let client = mor_connect(addr.as_ptr());
+            assert!(!client.is_null());
+
+            let argv = [b"echo".as_ptr(), b"".as_ptr(), b"foo".as_ptr()];
+            let argv_len = [4, 0, 3];
+            assert!(mor_command(client, 0, argv.as_ptr(), argv_len.as_ptr()).is_null());
+
+            let reply = mor_command(client, 3, argv.as_ptr(), argv_len.as_ptr());
+            assert_eq!(ReplyType::Arr, (*reply).kind);
+            let elements = slice::from_raw_parts((*reply).elements, (*reply).n_elements);
+            assert_eq!(4, elements.len());
+            assert_eq!(
+                b"echo",
+                slice::from_raw_parts(elements[0].str, elements[0].len)
+            );
+            assert_eq!(0, elements[1].len);
+            assert_eq!(
+                b"foo",
+                slice::from_raw_parts(elements[2].str, elements[2].len)
+            );
+            assert_eq!(ReplyType::Int, elements[3].kind);
+            assert_eq!(3, elements[3].integer);
+            mor_free_reply(reply);
+
+            let reply = mor_command(client, 1, argv.as_ptr(), argv_len.as_ptr());
+            assert_eq!(ReplyType::Err, (*reply).kind);
+            assert_eq!(100, (*reply).code);
+            assert_eq!(b"boom", slice::from_raw_parts((*reply).str, (*reply).len));
+            mor_free_reply(reply);
+
+            let argc = [3, 1];
+            let argvs = [argv.as_ptr(), argv.as_ptr()];
+            let argv_lens = [argv_len.as_ptr(), argv_len.as_ptr()];
+            let reply = mor_pipeline(client, 2, argc.as_ptr(), argvs.as_ptr(), argv_lens.as_ptr());
+            assert_eq!(ReplyType::Arr, (*reply).kind);
+            let replies = slice::from_raw_parts((*reply).elements, (*reply).n_elements);
+            assert_eq!(2, replies.len());
+            assert_eq!(ReplyType::Arr, replies[0].kind);
+            assert_eq!(4, replies[0].n_elements);
+            assert_eq!(ReplyType::Err, replies[1].kind);
+            mor_free_reply(reply);
+
+            mor_close(client);
+        }
+
+        server.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+}
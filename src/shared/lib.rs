@@ -1,205 +1,61 @@
+//! The protocol, the commands and the replies only need `alloc`, the sockets, the client, the logging and the modules
+//! need the `std` feature, enabled by default. The server needs the `server` feature, also enabled by default. The
+//! `wasm` directory wraps the codec for JavaScript.
+//!
+//! The `std-net` feature without `std` builds the client on [`std::net`] instead, along with the URLs, the logging and
+//! the modules, without depending on libc. The raw sockets, the syslog target of the logging and the server aren't
+//! available then.
+
 #![allow(clippy::comparison_chain)]
+#![cfg_attr(not(any(feature = "std", feature = "std-net")), no_std)]
+
+extern crate alloc;
 
-use libc::{setsockopt, socket, AF_INET, F_GETFL, F_SETFL, O_NONBLOCK, SOCK_STREAM, SOL_SOCKET};
-use onlyerror::Error;
-use std::fmt;
-use std::io;
-use std::mem;
+use core::fmt;
 
+#[cfg(any(feature = "std", feature = "std-net"))]
+pub mod client;
 pub mod command;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(any(feature = "std", feature = "std-net"))]
+pub mod log;
+#[cfg(all(any(feature = "std", feature = "std-net"), any(test, feature = "mock")))]
+pub mod mock;
+#[cfg(any(feature = "std", feature = "std-net"))]
+pub mod module;
+#[cfg(feature = "std")]
+mod net;
 pub mod protocol;
-
-pub fn make_addr(addr: [u8; 4], port: u16) -> libc::sockaddr_in {
-    let s_addr = u32::from_be_bytes(addr);
-
-    libc::sockaddr_in {
-        sin_family: AF_INET as libc::sa_family_t,
-        sin_port: port.to_be(),
-        sin_addr: libc::in_addr {
-            s_addr: s_addr.to_be(),
-        },
-        sin_zero: [0; 8],
-        #[cfg(target_os = "macos")]
-        sin_len: 0,
-    }
-}
-
-pub fn create_socket() -> io::Result<i32> {
-    let fd = unsafe { socket(AF_INET, SOCK_STREAM, 0) };
-    if fd < 0 {
-        Err(std::io::Error::last_os_error())
-    } else {
-        Ok(fd)
-    }
-}
-
-pub fn set_socket_nonblocking(fd: i32) -> io::Result<()> {
-    let mut flags = unsafe { libc::fcntl(fd, F_GETFL, 0) };
-    if flags < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    flags |= O_NONBLOCK;
-
-    let res = unsafe { libc::fcntl(fd, F_SETFL, flags) };
-    if res < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(())
-}
-
-pub fn set_socket_opt(fd: i32, opt: libc::c_int, val: i32) -> io::Result<()> {
-    let n = unsafe {
-        setsockopt(
-            fd,
-            SOL_SOCKET,
-            opt,
-            &val as *const _ as *const libc::c_void,
-            mem::size_of_val(&val) as libc::socklen_t,
-        )
-    };
-    if n < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(())
-}
-
-pub fn bind(fd: i32, addr: &libc::sockaddr_in) -> io::Result<()> {
-    let rv = unsafe {
-        libc::bind(
-            fd,
-            addr as *const _ as *const libc::sockaddr,
-            mem::size_of_val(addr) as libc::socklen_t,
-        )
-    };
-    if rv < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(())
-}
-
-pub fn listen(fd: i32, backlog: libc::c_int) -> io::Result<()> {
-    let rv = unsafe { libc::listen(fd, backlog) };
-    if rv < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(())
-}
-
-pub fn accept(
-    fd: i32,
-    addr: &mut libc::sockaddr_in,
-    addr_len: &mut libc::socklen_t,
-) -> io::Result<i32> {
-    let conn_fd = unsafe { libc::accept(fd, addr as *mut _ as *mut libc::sockaddr, addr_len) };
-    if conn_fd < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(conn_fd)
-}
-
-pub fn close(fd: i32) -> io::Result<()> {
-    let n = unsafe { libc::close(fd) };
-    if n < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-    Ok(())
-}
-
-pub fn connect(fd: i32, addr: &libc::sockaddr_in) -> io::Result<()> {
-    let n = unsafe {
-        libc::connect(
-            fd,
-            addr as *const _ as *const libc::sockaddr,
-            mem::size_of_val(addr) as libc::socklen_t,
-        )
-    };
-    if n < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-    Ok(())
-}
-
-pub fn read(fd: i32, buf: &mut [u8]) -> io::Result<&[u8]> {
-    let n = unsafe { libc::read(fd, buf as *mut _ as *mut libc::c_void, buf.len() - 1) };
-    if n < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    let data = &buf[0..n as usize];
-
-    Ok(data)
-}
-
-#[derive(Error, Debug)]
-pub enum ReadFullError {
-    #[error("i/o error")]
-    IO(#[from] io::Error),
-    #[error("end of stream")]
-    EndOfStream,
-}
-
-pub fn read_full(fd: i32, buf: &mut [u8]) -> Result<(), ReadFullError> {
-    let mut remaining = buf.len();
-    let mut write_buf = buf;
-
-    while remaining > 0 {
-        let n = unsafe { libc::read(fd, write_buf as *mut _ as *mut libc::c_void, remaining) };
-        if n == 0 {
-            return Err(ReadFullError::EndOfStream);
-        } else if n < 0 {
-            return Err(ReadFullError::IO(std::io::Error::last_os_error()));
-        }
-
-        let n = n as usize;
-        assert!(n <= remaining);
-
-        remaining -= n as usize;
-        write_buf = &mut write_buf[n as usize..];
-    }
-
-    Ok(())
-}
-
-pub fn write(fd: i32, buf: &[u8]) -> io::Result<usize> {
-    let n = unsafe { libc::write(fd, buf as *const _ as *const libc::c_void, buf.len()) };
-    if n < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-
-    Ok(n as usize)
-}
-
-pub fn write_full(fd: i32, buf: &[u8]) -> io::Result<()> {
-    let mut remaining = buf.len();
-    let mut buf = buf;
-
-    while remaining > 0 {
-        let n = unsafe { libc::write(fd, buf as *const _ as *const libc::c_void, buf.len()) };
-        if n < 0 {
-            return Err(std::io::Error::last_os_error());
-        }
-
-        let n = n as usize;
-        assert!(n <= remaining);
-
-        remaining -= n as usize;
-        buf = &buf[n as usize..];
-    }
-
-    Ok(())
-}
+pub mod reply;
+#[cfg(feature = "server")]
+#[path = "../server/lib.rs"]
+pub mod server;
+pub mod slot;
+#[cfg(any(feature = "std", feature = "std-net"))]
+pub mod url;
+
+pub use error::ErrorKind;
+#[cfg(feature = "std")]
+pub use net::*;
 
 #[derive(Copy, Clone)]
 #[repr(u32)]
 pub enum ResponseCode {
     Unknown = 100,
     TooBig = 101,
+    OutOfMemory = 102,
+    ReadOnly = 103,
+    NoAuth = 104,
+    WrongPass = 105,
+    Moved = 106,
+    Ask = 107,
+    ClusterDown = 108,
+    BusyKey = 109,
+    IOErr = 110,
+    CrossSlot = 111,
+    NoScript = 112,
 }
 
 impl From<ResponseCode> for u32 {
@@ -208,11 +64,47 @@ impl From<ResponseCode> for u32 {
     }
 }
 
+impl TryFrom<u32> for ResponseCode {
+    type Error = protocol::Error;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        let code = match code {
+            100 => Self::Unknown,
+            101 => Self::TooBig,
+            102 => Self::OutOfMemory,
+            103 => Self::ReadOnly,
+            104 => Self::NoAuth,
+            105 => Self::WrongPass,
+            106 => Self::Moved,
+            107 => Self::Ask,
+            108 => Self::ClusterDown,
+            109 => Self::BusyKey,
+            110 => Self::IOErr,
+            111 => Self::CrossSlot,
+            112 => Self::NoScript,
+            _ => return Err(protocol::Error::InvalidResponseCode(code)),
+        };
+
+        Ok(code)
+    }
+}
+
 impl fmt::Display for ResponseCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Unknown => write!(f, "UNKNOWN"),
             Self::TooBig => write!(f, "TOOBIG"),
+            Self::OutOfMemory => write!(f, "OOM"),
+            Self::ReadOnly => write!(f, "READONLY"),
+            Self::NoAuth => write!(f, "NOAUTH"),
+            Self::WrongPass => write!(f, "WRONGPASS"),
+            Self::Moved => write!(f, "MOVED"),
+            Self::Ask => write!(f, "ASK"),
+            Self::ClusterDown => write!(f, "CLUSTERDOWN"),
+            Self::BusyKey => write!(f, "BUSYKEY"),
+            Self::IOErr => write!(f, "IOERR"),
+            Self::CrossSlot => write!(f, "CROSSSLOT"),
+            Self::NoScript => write!(f, "NOSCRIPT"),
         }
     }
 }
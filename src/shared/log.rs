@@ -0,0 +1,382 @@
+//! Logging with levels, to stderr, a file or syslog.
+//!
+//! Events are logged with the [`error!`](crate::error), [`warn!`](crate::warn), [`info!`](crate::info),
+//! [`debug!`](crate::debug) and [`trace!`](crate::trace) macros. The arguments of an event above the current level
+//! are not even formatted, so the hot paths can log every request at the debug or trace level for free.
+//!
+//! The `max-level-off`, `max-level-error`, `max-level-warn`, `max-level-info` and `max-level-debug` features compile
+//! the events more verbose than [`STATIC_MAX_LEVEL`] out entirely, for release builds not even checking the level.
+
+#[cfg(feature = "std")]
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn syslog_priority(&self) -> libc::c_int {
+        match self {
+            Self::Error => libc::LOG_ERR,
+            Self::Warn => libc::LOG_WARNING,
+            Self::Info => libc::LOG_INFO,
+            Self::Debug | Self::Trace => libc::LOG_DEBUG,
+        }
+    }
+}
+
+/// Where the events are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Stderr,
+    /// Appended to the file.
+    File(PathBuf),
+    Syslog,
+}
+
+impl Target {
+    /// Parses `stderr`, `syslog` or the path of a file.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "" => None,
+            "stderr" => Some(Self::Stderr),
+            "syslog" => Some(Self::Syslog),
+            path => Some(Self::File(PathBuf::from(path))),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Stderr => "stderr".to_string(),
+            Self::File(path) => path.display().to_string(),
+            Self::Syslog => "syslog".to_string(),
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+    #[cfg(feature = "std")]
+    Syslog,
+}
+
+/// The most verbose level compiled in, `None` if nothing is. The most restrictive `max-level-*` feature wins.
+pub const STATIC_MAX_LEVEL: Option<Level> = if cfg!(feature = "max-level-off") {
+    None
+} else if cfg!(feature = "max-level-error") {
+    Some(Level::Error)
+} else if cfg!(feature = "max-level-warn") {
+    Some(Level::Warn)
+} else if cfg!(feature = "max-level-info") {
+    Some(Level::Info)
+} else if cfg!(feature = "max-level-debug") {
+    Some(Level::Debug)
+} else {
+    Some(Level::Trace)
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static SINK: Mutex<Sink> = Mutex::new(Sink::Stderr);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns true if the events of `level` are logged.
+///
+/// Inlined so that the check against [`STATIC_MAX_LEVEL`] is a constant in the callers and the event compiled out.
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    Some(level) <= STATIC_MAX_LEVEL && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write the events to `target` from now on.
+///
+/// Syslog needs libc, without the `std` feature it's unsupported.
+pub fn set_target(target: &Target) -> io::Result<()> {
+    let sink = match target {
+        Target::Stderr => Sink::Stderr,
+        Target::File(path) => Sink::File(OpenOptions::new().create(true).append(true).open(path)?),
+        #[cfg(feature = "std")]
+        Target::Syslog => {
+            unsafe { libc::openlog(c"my-own-redis".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+            Sink::Syslog
+        }
+        #[cfg(not(feature = "std"))]
+        Target::Syslog => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "syslog needs the std feature",
+            ))
+        }
+    };
+
+    *SINK.lock().unwrap() = sink;
+
+    Ok(())
+}
+
+/// Log an event, used by the macros which check the level first.
+#[doc(hidden)]
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    let mut sink = SINK.lock().unwrap();
+
+    // NOTE(vincent): there's nowhere to report a failure to log, the event is dropped.
+    match &mut *sink {
+        Sink::Stderr => {
+            let _ = writeln!(
+                io::stderr().lock(),
+                "{}",
+                format_event(SystemTime::now(), level, module, args)
+            );
+        }
+        Sink::File(file) => {
+            let _ = writeln!(
+                file,
+                "{}",
+                format_event(SystemTime::now(), level, module, args)
+            );
+        }
+        #[cfg(feature = "std")]
+        Sink::Syslog => {
+            // syslog adds the timestamp itself
+            let message = format!("{}: {}", module, args).replace('\0', "\\0");
+            let message = CString::new(message).unwrap();
+            unsafe { libc::syslog(level.syslog_priority(), c"%s".as_ptr(), message.as_ptr()) };
+        }
+    }
+}
+
+fn format_event(time: SystemTime, level: Level, module: &str, args: fmt::Arguments) -> String {
+    format!(
+        "{} {:<5} {}: {}",
+        format_timestamp(time),
+        level.name().to_uppercase(),
+        module,
+        args
+    )
+}
+
+/// Formats `time` in UTC like `2023-11-14T22:13:20.123Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+
+    // Civil date from the number of days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+/// Formats `data` like `hexdump -C`: one line per 16 bytes with their offset, their value in hex and as ASCII.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut lines = Vec::new();
+
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let mut line = format!("{:08x} ", i * 16);
+        for j in 0..16 {
+            if j == 8 {
+                line.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => line.push_str(&format!(" {:02x}", b)),
+                None => line.push_str("   "),
+            }
+        }
+
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        line.push_str(&format!("  |{}|", ascii));
+
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+/// Log an event at `level` if it's enabled.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        enabled, format_event, format_timestamp, hexdump, Level, Target, STATIC_MAX_LEVEL,
+    };
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn level() {
+        assert_eq!(Some(Level::Warn), Level::parse("warn"));
+        assert_eq!(None, Level::parse("WARN"));
+        assert!(Level::Error < Level::Trace);
+
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            assert_eq!(Some(level), Level::parse(level.name()));
+        }
+    }
+
+    #[test]
+    fn static_max_level() {
+        // Nothing is less verbose than off
+        assert!(None < Some(Level::Error));
+
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            if Some(level) > STATIC_MAX_LEVEL {
+                assert!(!enabled(level), "{:?}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(Some(Target::Stderr), Target::parse("stderr"));
+        assert_eq!(Some(Target::Syslog), Target::parse("syslog"));
+        assert_eq!(
+            Some(Target::File(PathBuf::from("/tmp/server.log"))),
+            Target::parse("/tmp/server.log")
+        );
+        assert_eq!(None, Target::parse(""));
+    }
+
+    #[test]
+    fn timestamp() {
+        assert_eq!("1970-01-01T00:00:00.000Z", format_timestamp(UNIX_EPOCH));
+        assert_eq!(
+            "2023-11-14T22:13:20.123Z",
+            format_timestamp(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123))
+        );
+        assert_eq!(
+            "2024-02-29T12:00:00.000Z",
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1_709_208_000))
+        );
+    }
+
+    #[test]
+    fn dump() {
+        assert_eq!("", hexdump(b""));
+        assert_eq!(
+            "00000000  00 00 00 0f 03 00 00 00  00 00 00 00 01 02 00 00  |................|\n\
+             00000010  00 03 67 65 74                                    |..get|",
+            hexdump(b"\x00\x00\x00\x0f\x03\x00\x00\x00\x00\x00\x00\x00\x01\x02\x00\x00\x00\x03get")
+        );
+    }
+
+    #[test]
+    fn event() {
+        assert_eq!(
+            "1970-01-01T00:00:01.000Z WARN  server::aof: truncated 2 bytes",
+            format_event(
+                UNIX_EPOCH + Duration::from_secs(1),
+                Level::Warn,
+                "server::aof",
+                format_args!("truncated {} bytes", 2)
+            )
+        );
+    }
+}
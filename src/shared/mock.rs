@@ -0,0 +1,274 @@
+//! A fake server for the tests of the programs using [`Client`](crate::client::Client), enabled by the `mock`
+//! feature. It expects the exact commands of a script, in order, and sends the scripted response to each one:
+//!
+//! ```
+//! use shared::client::{Client, Error, Value};
+//! use shared::mock::{MockServer, Response};
+//!
+//! let server = MockServer::builder()
+//!     .expect(&[b"get", b"a"], Response::Reply(Value::Bytes(b"1".to_vec())))
+//!     .expect(&[b"get", b"b"], Response::Close)
+//!     .start()
+//!     .unwrap();
+//!
+//! let mut client = Client::connect(&server.addr()).unwrap();
+//! assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+//! assert!(matches!(client.get(b"b"), Err(Error::EndOfStream)));
+//!
+//! server.verify();
+//! ```
+//!
+//! The connections are accepted one after the other, a client reconnecting after a [`Response::Close`] continues
+//! the script. Once the script is done the connection is closed.
+
+use crate::client::Value;
+use crate::protocol::{self, BUF_LEN};
+use crate::{command, debug};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the server checks if it has to stop while it waits.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the server does after receiving an expected command.
+#[derive(Clone, Debug)]
+pub enum Response {
+    Reply(Value),
+    /// Send the bytes as they are, to send malformed messages or several replies at once.
+    Raw(Vec<u8>),
+    /// Close the connection without replying.
+    Close,
+}
+
+/// The script of a [`MockServer`], see the [module documentation](self).
+#[derive(Default)]
+pub struct Builder {
+    script: Vec<(Vec<u8>, Response)>,
+}
+
+impl Builder {
+    /// Expect the command made of `args` next and respond with `response`.
+    pub fn expect(mut self, args: &[&[u8]], response: Response) -> Self {
+        self.script.push((command::encode(args), response));
+        self
+    }
+
+    /// Listen on a port of the loopback interface picked by the kernel and run the script on a new thread.
+    pub fn start(self) -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("mock-server".to_string())
+                .spawn(move || run(listener, self.script, &stop))?
+        };
+
+        Ok(MockServer {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running fake server, stopped when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<(), String>>>,
+}
+
+impl MockServer {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// The address to connect to, as taken by [`Client::connect`](crate::client::Client::connect).
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Stop the server and panic if it received something else than the script or not all of it.
+    pub fn verify(mut self) {
+        if let Err(err) = self.stop() {
+            panic!("mock server: {}", err);
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        self.stop.store(true, Ordering::Relaxed);
+
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(_) => Err("the server panicked".to_string()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+fn run(
+    listener: TcpListener,
+    script: Vec<(Vec<u8>, Response)>,
+    stop: &AtomicBool,
+) -> Result<(), String> {
+    let mut connection = None;
+    let mut pending = Vec::new();
+
+    for (expected, response) in script {
+        let body = loop {
+            if let Ok((read, body)) = protocol::parse_message(&pending) {
+                let body = body.to_vec();
+                pending.drain(..read);
+                break body;
+            }
+
+            if stop.load(Ordering::Relaxed) {
+                return Err(format!("never received {}", describe(&expected)));
+            }
+
+            let stream: &mut TcpStream = match &mut connection {
+                Some(stream) => stream,
+                None => match listener.accept() {
+                    Ok((stream, addr)) => {
+                        debug!("mock server: accepted {}", addr);
+                        stream
+                            .set_nonblocking(false)
+                            .and_then(|_| stream.set_read_timeout(Some(POLL_INTERVAL)))
+                            .map_err(|err| err.to_string())?;
+                        connection.insert(stream)
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                    Err(err) => return Err(err.to_string()),
+                },
+            };
+
+            let mut buf = [0; BUF_LEN];
+            match stream.read(&mut buf) {
+                // The client is gone, wait for the next one
+                Ok(0) => {
+                    connection = None;
+                    pending.clear();
+                }
+                Ok(n) => pending.extend_from_slice(&buf[..n]),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) => return Err(err.to_string()),
+            }
+        };
+
+        if body != expected {
+            return Err(format!(
+                "expected {}, received {}",
+                describe(&expected),
+                describe(&body)
+            ));
+        }
+
+        // The loop above only returns with a connection
+        let stream = connection.as_mut().unwrap();
+        let written = match response {
+            Response::Reply(value) => {
+                let body = value.encode();
+                let mut message = (body.len() as u32).to_be_bytes().to_vec();
+                message.extend_from_slice(&body);
+                stream.write_all(&message)
+            }
+            Response::Raw(bytes) => stream.write_all(&bytes),
+            Response::Close => {
+                connection = None;
+                pending.clear();
+                Ok(())
+            }
+        };
+        written.map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Formats the command in a request body like `"get" "a"`, or the bytes if it's not a valid command.
+fn describe(body: &[u8]) -> String {
+    match command::parse(body) {
+        Ok(args) => args
+            .iter()
+            .map(|arg| format!("{:?}", String::from_utf8_lossy(arg)))
+            .collect::<Vec<_>>()
+            .join(" "),
+        Err(_) => format!("{:?}", body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockServer, Response};
+    use crate::client::{Client, Error, Value};
+
+    #[test]
+    fn script() {
+        let server = MockServer::builder()
+            .expect(&[b"set", b"a", b"1"], Response::Reply(Value::Nil))
+            .expect(&[b"get", b"a"], Response::Close)
+            .expect(
+                &[b"get", b"a"],
+                Response::Raw(b"\x00\x00\x00\x01\xff".to_vec()),
+            )
+            .start()
+            .unwrap();
+
+        let mut client = Client::connect(&server.addr()).unwrap();
+        client.set(b"a", b"1").unwrap();
+        assert!(matches!(client.get(b"a"), Err(Error::EndOfStream)));
+
+        let mut client = Client::connect(&server.addr()).unwrap();
+        assert!(matches!(client.get(b"a"), Err(Error::Protocol(_))));
+
+        server.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = r#"expected "get" "a", received "get" "b""#)]
+    fn unexpected_command() {
+        let server = MockServer::builder()
+            .expect(&[b"get", b"a"], Response::Reply(Value::Nil))
+            .start()
+            .unwrap();
+
+        let mut client = Client::connect(&server.addr()).unwrap();
+        assert!(client.get(b"b").is_err());
+
+        server.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = r#"never received "ping""#)]
+    fn missing_command() {
+        let server = MockServer::builder()
+            .expect(&[b"ping"], Response::Reply(Value::Nil))
+            .start()
+            .unwrap();
+
+        server.verify();
+    }
+}
@@ -0,0 +1,97 @@
+//! The stable API of the modules loaded by the server with `--loadmodule`.
+//!
+//! A module is a shared object exporting a function named like [`INIT_SYMBOL`], of type [`InitFn`]. It's called once
+//! at startup, before the data is loaded, and registers the commands of the module with [`Api::register_command`].
+//!
+//! A command is then called with the [`Api`], an opaque [`Call`] and its arguments, without its name. It reads and
+//! modifies the keyspace with the functions of the [`Api`], replies with the `reply_*` functions and returns the
+//! number of keys it changed, so that its request is propagated and counted for the save rules.
+//!
+//! Everything is passed as C types so that a module can be written in any language, and [`API_VERSION`] is bumped
+//! whenever a function is added to or changed in [`Api`].
+//!
+//! NOTE(vincent): the commands are called concurrently by the workers, they must be thread safe.
+
+use std::marker::PhantomData;
+
+pub const API_VERSION: u32 = 1;
+
+/// The name of the function initializing a module, NUL terminated for `dlsym`.
+pub const INIT_SYMBOL: &[u8] = b"my_own_redis_module_init\0";
+
+/// Bytes owned by the caller, valid for the duration of the call only.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Slice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl Slice {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` bytes valid for `'a`.
+    pub unsafe fn as_bytes<'a>(&self) -> &'a [u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// The module being initialized.
+#[repr(C)]
+pub struct Module {
+    _private: [u8; 0],
+    _marker: PhantomData<*mut u8>,
+}
+
+/// The command being executed.
+#[repr(C)]
+pub struct Call {
+    _private: [u8; 0],
+    _marker: PhantomData<*mut u8>,
+}
+
+/// Initializes a module, returning 0 on success.
+pub type InitFn = unsafe extern "C" fn(module: *mut Module, api: *const Api) -> i32;
+
+/// Executes a command, returning the number of keys it changed.
+pub type CommandFn = unsafe extern "C" fn(
+    call: *mut Call,
+    api: *const Api,
+    args: *const Slice,
+    nb_args: usize,
+) -> u64;
+
+/// The functions a module can call. Those returning an `i32` return a negative value on error.
+#[repr(C)]
+pub struct Api {
+    pub version: u32,
+    /// Register `name` with its `arity`, counting the name like in Redis. Only valid during the initialization.
+    pub register_command: unsafe extern "C" fn(
+        module: *mut Module,
+        name: Slice,
+        arity: i32,
+        handler: CommandFn,
+    ) -> i32,
+    /// Returns 1 and sets `value` if `key` exists, 0 otherwise. `value` is valid until the command returns.
+    pub get: unsafe extern "C" fn(call: *mut Call, key: Slice, value: *mut Slice) -> i32,
+    /// Returns 0, or -1 if the key or value isn't valid UTF-8 or if the used memory is over `maxmemory`.
+    pub set: unsafe extern "C" fn(call: *mut Call, key: Slice, value: Slice) -> i32,
+    /// Returns 1 if `key` was removed, 0 if it didn't exist.
+    pub del: unsafe extern "C" fn(call: *mut Call, key: Slice) -> i32,
+    pub reply_nil: unsafe extern "C" fn(call: *mut Call),
+    pub reply_int: unsafe extern "C" fn(call: *mut Call, value: u64),
+    pub reply_string: unsafe extern "C" fn(call: *mut Call, value: Slice),
+    pub reply_error: unsafe extern "C" fn(call: *mut Call, message: Slice),
+    /// Starts an array of `len` elements, which are the next replies.
+    pub reply_array: unsafe extern "C" fn(call: *mut Call, len: usize),
+}
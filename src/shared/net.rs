@@ -0,0 +1,636 @@
+//! The sockets, on top of the system calls.
+
+use libc::{
+    setsockopt, socket, AF_INET, AF_INET6, F_GETFL, F_SETFL, O_NONBLOCK, SOCK_STREAM, SOL_SOCKET,
+};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+/// A socket address in the form the system calls take, IPv4 or IPv6.
+#[derive(Clone, Copy)]
+pub struct SockAddr {
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
+}
+
+impl SockAddr {
+    /// Copy the address of `len` bytes at `addr`, as returned by the system.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to a valid `sockaddr_in` or `sockaddr_in6` of `len` bytes.
+    unsafe fn from_raw(addr: *const libc::sockaddr, len: libc::socklen_t) -> Self {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = len.min(mem::size_of_val(&storage) as libc::socklen_t);
+        std::ptr::copy_nonoverlapping(
+            addr as *const u8,
+            &mut storage as *mut _ as *mut u8,
+            len as usize,
+        );
+
+        Self { storage, len }
+    }
+
+    /// The address family to create the socket with, `AF_INET` or `AF_INET6`.
+    pub fn family(&self) -> libc::c_int {
+        self.storage.ss_family as libc::c_int
+    }
+
+    pub fn as_socket_addr(&self) -> SocketAddr {
+        if self.family() == AF_INET6 {
+            let addr = unsafe { &*(&self.storage as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            ))
+        } else {
+            let addr = unsafe { &*(&self.storage as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::V4(socket_addr(addr))
+        }
+    }
+
+    fn as_ptr(&self) -> *const libc::sockaddr {
+        &self.storage as *const _ as *const libc::sockaddr
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                raw.sin_family = AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                raw.sin6_family = AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr.s6_addr = addr.ip().octets();
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        Self {
+            storage,
+            len: len as libc::socklen_t,
+        }
+    }
+}
+
+impl From<SocketAddrV4> for SockAddr {
+    fn from(addr: SocketAddrV4) -> Self {
+        SocketAddr::V4(addr).into()
+    }
+}
+
+impl PartialEq for SockAddr {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_socket_addr() == other.as_socket_addr()
+    }
+}
+
+impl Eq for SockAddr {}
+
+impl fmt::Display for SockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_socket_addr().fmt(f)
+    }
+}
+
+impl fmt::Debug for SockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_socket_addr().fmt(f)
+    }
+}
+
+/// Create a TCP socket for the addresses of `family`, see [`SockAddr::family`].
+pub fn create_socket(family: libc::c_int) -> io::Result<i32> {
+    let fd = unsafe { socket(family, SOCK_STREAM, 0) };
+    if fd < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+pub fn set_socket_nonblocking(fd: i32) -> io::Result<()> {
+    let mut flags = unsafe { libc::fcntl(fd, F_GETFL, 0) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    flags |= O_NONBLOCK;
+
+    let res = unsafe { libc::fcntl(fd, F_SETFL, flags) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn set_socket_blocking(fd: i32) -> io::Result<()> {
+    let mut flags = unsafe { libc::fcntl(fd, F_GETFL, 0) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    flags &= !O_NONBLOCK;
+
+    let res = unsafe { libc::fcntl(fd, F_SETFL, flags) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn set_socket_opt(fd: i32, opt: libc::c_int, val: i32) -> io::Result<()> {
+    let n = unsafe {
+        setsockopt(
+            fd,
+            SOL_SOCKET,
+            opt,
+            &val as *const _ as *const libc::c_void,
+            mem::size_of_val(&val) as libc::socklen_t,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Make the blocking calls on the socket, `connect(2)` included, fail with `EAGAIN` after `timeout`.
+/// A zero timeout means no timeout.
+pub fn set_socket_timeout(fd: i32, timeout: Duration) -> io::Result<()> {
+    let val = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+
+    for opt in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+        let n = unsafe {
+            setsockopt(
+                fd,
+                SOL_SOCKET,
+                opt,
+                &val as *const _ as *const libc::c_void,
+                mem::size_of_val(&val) as libc::socklen_t,
+            )
+        };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set_tcp_opt(fd: i32, opt: libc::c_int, val: i32) -> io::Result<()> {
+    let n = unsafe {
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            opt,
+            &val as *const _ as *const libc::c_void,
+            mem::size_of_val(&val) as libc::socklen_t,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "openbsd")))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+/// Enable TCP keepalive on the socket: after `idle` seconds without traffic a probe is sent every `interval` seconds,
+/// and the connection is dropped after `count` unanswered probes.
+///
+/// OpenBSD doesn't support tuning keepalive per socket, only the system-wide settings are used there.
+pub fn set_keepalive(fd: i32, idle: u32, interval: u32, count: u32) -> io::Result<()> {
+    set_socket_opt(fd, libc::SO_KEEPALIVE, 1)?;
+
+    #[cfg(not(target_os = "openbsd"))]
+    {
+        set_tcp_opt(fd, TCP_KEEPIDLE, idle as i32)?;
+        set_tcp_opt(fd, libc::TCP_KEEPINTVL, interval as i32)?;
+        set_tcp_opt(fd, libc::TCP_KEEPCNT, count as i32)?;
+    }
+    #[cfg(target_os = "openbsd")]
+    let _ = (idle, interval, count);
+
+    Ok(())
+}
+
+pub fn set_nodelay(fd: i32, enabled: bool) -> io::Result<()> {
+    set_tcp_opt(fd, libc::TCP_NODELAY, enabled as i32)
+}
+
+pub fn bind(fd: i32, addr: &SockAddr) -> io::Result<()> {
+    let rv = unsafe { libc::bind(fd, addr.as_ptr(), addr.len) };
+    if rv < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn listen(fd: i32, backlog: libc::c_int) -> io::Result<()> {
+    let rv = unsafe { libc::listen(fd, backlog) };
+    if rv < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn accept(
+    fd: i32,
+    addr: &mut libc::sockaddr_in,
+    addr_len: &mut libc::socklen_t,
+) -> io::Result<i32> {
+    let conn_fd = unsafe { libc::accept(fd, addr as *mut _ as *mut libc::sockaddr, addr_len) };
+    if conn_fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(conn_fd)
+}
+
+pub fn close(fd: i32) -> io::Result<()> {
+    let n = unsafe { libc::close(fd) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A socket closed when dropped.
+///
+/// The free functions taking a raw fd still work with [`AsRawFd::as_raw_fd`], the socket keeps owning it.
+#[derive(Debug)]
+pub struct OwnedSocket(RawFd);
+
+impl OwnedSocket {
+    /// Create a TCP socket which can connect to or bind `addr`, see [`create_socket`].
+    pub fn tcp(addr: &SockAddr) -> io::Result<Self> {
+        create_socket(addr.family()).map(Self)
+    }
+
+    /// Create a unix socket, see [`create_unix_socket`].
+    pub fn unix() -> io::Result<Self> {
+        create_unix_socket().map(Self)
+    }
+
+    /// Read like [`read`], an empty slice means the end of the stream.
+    pub fn read<'a>(&self, buf: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        read(self.0, buf)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        write(self.0, buf)
+    }
+
+    pub fn write_full(&self, buf: &[u8]) -> io::Result<()> {
+        write_full(self.0, buf)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        if nonblocking {
+            set_socket_nonblocking(self.0)
+        } else {
+            set_socket_blocking(self.0)
+        }
+    }
+}
+
+impl AsRawFd for OwnedSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl IntoRawFd for OwnedSocket {
+    /// Give up the ownership of the fd, which the caller must close.
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
+}
+
+impl FromRawFd for OwnedSocket {
+    /// Take the ownership of `fd`, which must be an open socket that nothing else closes.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self(fd)
+    }
+}
+
+impl Drop for OwnedSocket {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
+
+/// Shut down both directions of the connection, waking up any thread blocked reading or writing it.
+pub fn shutdown(fd: i32) -> io::Result<()> {
+    let n = unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn connect(fd: i32, addr: &SockAddr) -> io::Result<()> {
+    let n = unsafe { libc::connect(fd, addr.as_ptr(), addr.len) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Connect like [`connect`] but fail with [`io::ErrorKind::TimedOut`] if it takes longer than `timeout`.
+///
+/// The socket is made non-blocking for the duration of the connect and blocking again afterwards.
+pub fn connect_timeout(fd: i32, addr: &SockAddr, timeout: Duration) -> io::Result<()> {
+    set_socket_nonblocking(fd)?;
+
+    match connect(fd, addr) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+
+            let timeout_ms = timeout.as_millis().clamp(1, libc::c_int::MAX as u128);
+            let n = unsafe { libc::poll(&mut pollfd, 1, timeout_ms as libc::c_int) };
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+            }
+
+            // Writable means the connect is done, successfully or not
+            let mut error: libc::c_int = 0;
+            let mut len = mem::size_of_val(&error) as libc::socklen_t;
+            let n = unsafe {
+                libc::getsockopt(
+                    fd,
+                    SOL_SOCKET,
+                    libc::SO_ERROR,
+                    &mut error as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if error != 0 {
+                return Err(io::Error::from_raw_os_error(error));
+            }
+        }
+        Err(err) => return Err(err),
+    }
+
+    set_socket_blocking(fd)
+}
+
+pub fn create_unix_socket() -> io::Result<i32> {
+    let fd = unsafe { socket(libc::AF_UNIX, SOCK_STREAM, 0) };
+    if fd < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+/// Connect to the unix socket at `path`, which must fit in `sun_path` with its NUL terminator.
+pub fn connect_unix(fd: i32, path: &Path) -> io::Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid unix socket path",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    let n = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of_val(&addr) as libc::socklen_t,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the address of the other end of the connection, see `getpeername(2)`.
+pub fn peer_addr(fd: i32) -> io::Result<SocketAddrV4> {
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of_val(&addr) as libc::socklen_t;
+
+    let n = unsafe {
+        libc::getpeername(
+            fd,
+            &mut addr as *mut _ as *mut libc::sockaddr,
+            &mut addr_len,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(socket_addr(&addr))
+}
+
+/// Returns the address the socket is bound to, see `getsockname(2)`. Useful to know the port picked by the kernel
+/// when binding to port 0.
+pub fn local_addr(fd: i32) -> io::Result<SocketAddrV4> {
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of_val(&addr) as libc::socklen_t;
+
+    let n = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut _ as *mut libc::sockaddr,
+            &mut addr_len,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(socket_addr(&addr))
+}
+
+/// Converts an IPv4 address filled by the kernel, in network byte order, to a [`SocketAddrV4`].
+pub fn socket_addr(addr: &libc::sockaddr_in) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    )
+}
+
+/// Resolve the host name to its IPv4 and IPv6 addresses with `getaddrinfo(3)`, in the order they should be tried.
+///
+/// IP addresses are returned as is, without a lookup. The sockets connecting to them must be created with the family
+/// of each address, see [`OwnedSocket::tcp`].
+pub fn resolve(host: &str, port: u16) -> io::Result<Vec<SockAddr>> {
+    let c_host = std::ffi::CString::new(host)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "host contains a nul byte"))?;
+
+    let mut hints: libc::addrinfo = unsafe { mem::zeroed() };
+    hints.ai_family = libc::AF_UNSPEC;
+    hints.ai_socktype = SOCK_STREAM;
+
+    let mut res: *mut libc::addrinfo = std::ptr::null_mut();
+    let n = unsafe { libc::getaddrinfo(c_host.as_ptr(), std::ptr::null(), &hints, &mut res) };
+    if n == libc::EAI_SYSTEM {
+        return Err(std::io::Error::last_os_error());
+    }
+    if n != 0 {
+        let msg = unsafe { std::ffi::CStr::from_ptr(libc::gai_strerror(n)) };
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unable to resolve {}: {}", host, msg.to_string_lossy()),
+        ));
+    }
+
+    let mut addrs = Vec::new();
+    let mut current = res;
+    while !current.is_null() {
+        let info = unsafe { &*current };
+
+        if (info.ai_family == AF_INET || info.ai_family == AF_INET6) && !info.ai_addr.is_null() {
+            let addr = unsafe { SockAddr::from_raw(info.ai_addr, info.ai_addrlen) };
+            let mut addr = addr.as_socket_addr();
+            addr.set_port(port);
+            let addr = SockAddr::from(addr);
+
+            // NOTE(vincent): the same address can be returned once per protocol
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        current = info.ai_next;
+    }
+    unsafe { libc::freeaddrinfo(res) };
+
+    Ok(addrs)
+}
+
+pub fn read(fd: i32, buf: &mut [u8]) -> io::Result<&[u8]> {
+    let n = unsafe { libc::read(fd, buf as *mut _ as *mut libc::c_void, buf.len() - 1) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let data = &buf[0..n as usize];
+
+    Ok(data)
+}
+
+/// Read exactly `buf.len()` bytes, failing with [`io::ErrorKind::UnexpectedEof`] if the stream ends before.
+pub fn read_full(fd: i32, buf: &mut [u8]) -> io::Result<()> {
+    let mut remaining = buf.len();
+    let mut write_buf = buf;
+
+    while remaining > 0 {
+        let n = unsafe { libc::read(fd, write_buf as *mut _ as *mut libc::c_void, remaining) };
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        } else if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let n = n as usize;
+        assert!(n <= remaining);
+
+        remaining -= n as usize;
+        write_buf = &mut write_buf[n as usize..];
+    }
+
+    Ok(())
+}
+
+pub fn write(fd: i32, buf: &[u8]) -> io::Result<usize> {
+    let n = unsafe { libc::write(fd, buf as *const _ as *const libc::c_void, buf.len()) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+/// Write the buffers in order with a single system call, see `writev(2)`.
+pub fn writev(fd: i32, bufs: &[&[u8]]) -> io::Result<usize> {
+    let iovecs: Vec<libc::iovec> = bufs
+        .iter()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+
+    let n = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+pub fn write_full(fd: i32, buf: &[u8]) -> io::Result<()> {
+    let mut remaining = buf.len();
+    let mut buf = buf;
+
+    while remaining > 0 {
+        let n = unsafe { libc::write(fd, buf as *const _ as *const libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let n = n as usize;
+        assert!(n <= remaining);
+
+        remaining -= n as usize;
+        buf = &buf[n as usize..];
+    }
+
+    Ok(())
+}
@@ -1,5 +1,8 @@
+use crate::ErrorKind;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{fmt, mem};
 use onlyerror::Error;
-use std::{fmt, mem};
 
 const HEADER_LEN: usize = 4;
 pub const MAX_MSG_LEN: usize = 4096;
@@ -21,9 +24,20 @@ pub enum Error {
     InvalidResponseCode(u32),
     #[error("incoherent data type, want {want} but got {got}")]
     IncoherentDataType { got: DataType, want: DataType },
+    #[error("unexpected frame {0}")]
+    UnexpectedFrame(u8),
 }
 
-type Result<T> = std::result::Result<T, Error>;
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::MessageTooLong(_) => ErrorKind::TooLong,
+            _ => ErrorKind::Protocol,
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
 
 pub fn parse_message(buf: &[u8]) -> Result<(usize, &[u8])> {
     const N: usize = mem::size_of::<u32>();
@@ -43,6 +57,10 @@ pub fn parse_message(buf: &[u8]) -> Result<(usize, &[u8])> {
         return Err(Error::MessageTooLong(length));
     }
 
+    if buf.len() < N + length {
+        return Err(Error::InputTooShort(buf.len()));
+    }
+
     // 2. Compute the results
 
     let read = N + length;
@@ -122,11 +140,6 @@ impl<'a> Reader<'a> {
     }
 
     pub fn read_data_type(&mut self) -> Result<DataType> {
-        eprintln!(
-            "\x1b[34m==> start/read_data_type/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
-
         if self.pos >= self.buf.len() {
             return Err(Error::InputTooShort(self.buf.len()));
         }
@@ -142,11 +155,6 @@ impl<'a> Reader<'a> {
 
         self.pos += 1;
 
-        eprintln!(
-            "\x1b[34m==> end/read_data_type/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
-
         Ok(result)
     }
 
@@ -156,11 +164,6 @@ impl<'a> Reader<'a> {
     }
 
     pub fn read_string(&mut self) -> Result<&'a [u8]> {
-        eprintln!(
-            "\x1b[34m==> start/read_string/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
-
         // let data_type = self.read_data_type()?;
         // if data_type != DataType::Str {
         //     return Err(Error::IncoherentDataType {
@@ -171,36 +174,30 @@ impl<'a> Reader<'a> {
         //
         let length: u32 = self.read_int_()?;
 
-        let result = &self.buf[self.pos..self.pos + length as usize];
-        self.pos += result.len();
+        let buf = &self.buf[self.pos..];
+        if buf.len() < length as usize {
+            return Err(Error::InputTooShort(self.buf.len()));
+        }
 
-        eprintln!(
-            "\x1b[34m==> end/read_string/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
+        let result = &buf[..length as usize];
+        self.pos += result.len();
 
         Ok(result)
     }
 
-    pub fn read_err(&mut self) -> Result<(u32, &[u8])> {
-        eprintln!(
-            "\x1b[34m==> start/read_err/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
-
+    pub fn read_err(&mut self) -> Result<(u32, &'a [u8])> {
         const N: usize = mem::size_of::<u32>();
 
         let response_code = self.read_int_::<u32, N>()?;
         let length: u32 = self.read_int_()?;
 
         let buf = &self.buf[self.pos..];
-        let result = &buf[0..length as usize];
-        self.pos += result.len();
+        if buf.len() < length as usize {
+            return Err(Error::InputTooShort(self.buf.len()));
+        }
 
-        eprintln!(
-            "\x1b[34m==> end/read_err/body: {:?}\x1b[0m",
-            self.clone_remaining()
-        );
+        let result = &buf[..length as usize];
+        self.pos += result.len();
 
         Ok((response_code, result))
     }
@@ -210,6 +207,58 @@ impl<'a> Reader<'a> {
         let n: u32 = self.read_int_()?;
         Ok(n as usize)
     }
+
+    /// Read a [`Psync`] frame, see [`Writer::push_psync`].
+    pub fn read_psync(&mut self) -> Result<Psync> {
+        match self.read_frame_kind()? {
+            PSYNC => Ok(Psync {
+                capabilities: self.read_int_()?,
+                replication_id: self.read_replication_id()?,
+                offset: self.read_int()?,
+            }),
+            kind => Err(Error::UnexpectedFrame(kind)),
+        }
+    }
+
+    /// Read a [`PsyncReply`] frame, see [`Writer::push_psync_reply`].
+    pub fn read_psync_reply(&mut self) -> Result<PsyncReply> {
+        match self.read_frame_kind()? {
+            FULL_RESYNC => Ok(PsyncReply::FullResync {
+                replication_id: self.read_replication_id()?,
+                offset: self.read_int()?,
+                snapshot_len: self.read_int()?,
+            }),
+            CONTINUE => Ok(PsyncReply::Continue {
+                replication_id: self.read_replication_id()?,
+                offset: self.read_int()?,
+            }),
+            kind => Err(Error::UnexpectedFrame(kind)),
+        }
+    }
+
+    fn read_frame_kind(&mut self) -> Result<u8> {
+        match self.buf.get(self.pos) {
+            Some(&kind) => {
+                self.pos += 1;
+                Ok(kind)
+            }
+            None => Err(Error::InputTooShort(self.buf.len())),
+        }
+    }
+
+    fn read_replication_id(&mut self) -> Result<String> {
+        let length: u32 = self.read_int_()?;
+
+        let buf = &self.buf[self.pos..];
+        if buf.len() < length as usize {
+            return Err(Error::InputTooShort(self.buf.len()));
+        }
+
+        let id = String::from_utf8_lossy(&buf[..length as usize]).into_owned();
+        self.pos += length as usize;
+
+        Ok(id)
+    }
 }
 
 /// Wraps a buffer and provides methods to serialize data to the buffer.
@@ -271,6 +320,43 @@ impl fmt::Display for DataType {
     }
 }
 
+// Replication handshake
+//
+// A replica starts by sending a PSYNC frame instead of a request, the primary replies with a FULLRESYNC or a CONTINUE
+// frame instead of a response; then the stream of writes follows. The frames are framed like the requests and the
+// responses but their first byte, the frame kind, is never a valid data type so they can't be mistaken for either.
+
+const PSYNC: u8 = 0x10;
+const FULL_RESYNC: u8 = 0x11;
+const CONTINUE: u8 = 0x12;
+
+/// The replica can continue from its offset instead of synchronizing from scratch.
+pub const CAPA_CONTINUE: u32 = 1 << 0;
+
+/// Sent by a replica to start replicating.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Psync {
+    /// The `CAPA_*` flags the replica supports.
+    pub capabilities: u32,
+    /// The replication ID of the stream the replica already followed, empty if none.
+    pub replication_id: String,
+    /// How far the replica got in that stream, in bytes.
+    pub offset: u64,
+}
+
+/// Sent by the primary in reply to [`Psync`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PsyncReply {
+    /// A snapshot of `snapshot_len` bytes follows, taken at `offset` in the stream identified by `replication_id`.
+    FullResync {
+        replication_id: String,
+        offset: u64,
+        snapshot_len: u64,
+    },
+    /// The stream continues right after `offset`, the offset of the replica.
+    Continue { replication_id: String, offset: u64 },
+}
+
 impl<'a> Writer<'a> {
     /// Creates a new `Writer` wrapping the provided slice.
     ///
@@ -453,6 +539,125 @@ impl<'a> Writer<'a> {
         self.pos += DATA_TYPE_LEN + N;
     }
 
+    /// Write the body of a message serialized by another writer, to nest a whole response in an array.
+    ///
+    /// # Examples
+    /// ```
+    /// # use shared::protocol::{self, BUF_LEN, Writer};
+    /// let mut nested_buf: [u8; BUF_LEN] = [0; BUF_LEN];
+    /// let mut nested = Writer::new(&mut nested_buf);
+    /// nested.push_int(8);
+    /// nested.finish();
+    /// let (_, body) = protocol::parse_message(&nested_buf).unwrap();
+    ///
+    /// let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+    /// let mut writer = Writer::new(&mut buf);
+    /// let written = {
+    ///     writer.push_arr(1);
+    ///     writer.push_message(body);
+    ///     writer.finish();
+    ///     writer.written()
+    /// };
+    ///
+    /// assert_eq!(
+    ///     &[
+    ///         0x00, 0x00, 0x00, 0x0e, // message length in bytes
+    ///         0x04, 0x00, 0x00, 0x00, 0x01, // array of 1 element
+    ///         0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, // the nested Int
+    ///     ],
+    ///     &buf[0..written],
+    /// );
+    /// ```
+    pub fn push_message(&mut self, body: &[u8]) {
+        self.push_raw(body);
+    }
+
+    /// Write a [`Psync`] frame to the buffer, it must be the only thing in the message.
+    /// The frame is made of:
+    /// * a u8 representing the frame kind (the value <b>0x10</b>)
+    /// * a u32 representing the capabilities
+    /// * the replication ID, as a u32 length followed by the data
+    /// * a u64 representing the offset
+    ///
+    /// # Examples
+    /// ```
+    /// # use shared::protocol::{Psync, BUF_LEN, CAPA_CONTINUE, Writer};
+    /// # let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+    ///
+    /// let mut writer = Writer::new(&mut buf);
+    /// let written = {
+    ///     writer.push_psync(&Psync {
+    ///         capabilities: CAPA_CONTINUE,
+    ///         replication_id: "ab".to_string(),
+    ///         offset: 20,
+    ///     });
+    ///     writer.finish();
+    ///     writer.written()
+    /// };
+    ///
+    /// assert_eq!(
+    ///     &[
+    ///         0x00, 0x00, 0x00, 0x13, // message length in bytes
+    ///         0x10, // frame kind PSYNC
+    ///         0x00, 0x00, 0x00, 0x01, // capabilities
+    ///         0x00, 0x00, 0x00, 0x02, b'a', b'b', // replication ID
+    ///         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, // offset
+    ///     ],
+    ///     &buf[0..written],
+    /// );
+    /// ```
+    pub fn push_psync(&mut self, psync: &Psync) {
+        self.push_frame_kind(PSYNC);
+        self.push_raw(&psync.capabilities.to_be_bytes());
+        self.push_replication_id(&psync.replication_id);
+        self.push_raw(&psync.offset.to_be_bytes());
+    }
+
+    /// Write a [`PsyncReply`] frame to the buffer, it must be the only thing in the message.
+    /// The frame is made of a u8 representing the frame kind (<b>0x11</b> for FULLRESYNC, <b>0x12</b> for CONTINUE),
+    /// the replication ID as a u32 length followed by the data, a u64 representing the offset and for FULLRESYNC only
+    /// a u64 representing the length of the snapshot.
+    pub fn push_psync_reply(&mut self, reply: &PsyncReply) {
+        match reply {
+            PsyncReply::FullResync {
+                replication_id,
+                offset,
+                snapshot_len,
+            } => {
+                self.push_frame_kind(FULL_RESYNC);
+                self.push_replication_id(replication_id);
+                self.push_raw(&offset.to_be_bytes());
+                self.push_raw(&snapshot_len.to_be_bytes());
+            }
+            PsyncReply::Continue {
+                replication_id,
+                offset,
+            } => {
+                self.push_frame_kind(CONTINUE);
+                self.push_replication_id(replication_id);
+                self.push_raw(&offset.to_be_bytes());
+            }
+        }
+    }
+
+    fn push_frame_kind(&mut self, kind: u8) {
+        self.push_raw(&[kind]);
+    }
+
+    fn push_replication_id(&mut self, id: &str) {
+        self.push_raw(&(id.len() as u32).to_be_bytes());
+        self.push_raw(id.as_bytes());
+    }
+
+    fn push_raw(&mut self, bytes: &[u8]) {
+        let buf = &mut self.buf[self.pos..];
+
+        assert!(buf.len() >= bytes.len());
+
+        buf[..bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
     /// Return the number of bytes written into the buffer
     /// Note that there's always 4 bytes written for the message length, even if you don't push anything.
     ///
@@ -470,28 +675,51 @@ impl<'a> Writer<'a> {
     pub fn written(&self) -> usize {
         self.pos
     }
+
+    /// Returns true if the response written so far is an error.
+    ///
+    /// # Examples
+    /// ```
+    /// # use shared::protocol::{BUF_LEN, Writer};
+    /// # let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+    ///
+    /// let mut writer = Writer::new(&mut buf);
+    /// assert!(!writer.is_err());
+    ///
+    /// writer.push_err(1u32, "no such key");
+    /// assert!(writer.is_err());
+    /// ```
+    pub fn is_err(&self) -> bool {
+        self.pos > HEADER_LEN && self.buf[HEADER_LEN] == DataType::Err as u8
+    }
 }
 
+/// Returns the number of bytes needed to send the commands, one message each.
 pub fn buffer_size_needed(commands: &[Vec<&[u8]>]) -> usize {
-    // Layout:
+    // Layout of each message:
     //
-    // * 4 bytes for the number of strings
-    // per string
+    // * 4 bytes for the message length
+    // * the number of arguments as an Int: data type and 8 bytes
+    // per argument, a Str:
+    // * data type
     // * 4 bytes for the length
     // * data bytes
 
-    let size_for_all_strings = commands
-        .iter()
-        .fold(0, |acc, value| -> usize { acc + STRING_LEN + value.len() });
+    commands.iter().fold(0, |acc, args| -> usize {
+        let size_for_all_strings = args.iter().fold(0, |acc, arg| -> usize {
+            acc + DATA_TYPE_LEN + STRING_LEN + arg.len()
+        });
 
-    4 + size_for_all_strings
+        acc + HEADER_LEN + DATA_TYPE_LEN + INTEGER_LEN + size_for_all_strings
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{protocol::BUF_LEN, ResponseCode};
+    use alloc::string::ToString;
 
-    use super::{parse_message, Writer};
+    use super::{parse_message, Psync, PsyncReply, Reader, Writer, CAPA_CONTINUE};
 
     #[test]
     fn reader() {
@@ -500,6 +728,12 @@ mod tests {
         let (read, request) = parse_message(data).unwrap();
         assert_eq!(10, read);
         assert_eq!(b"foobar", request);
+
+        // Lengths past the end of the input
+        assert!(Reader::new(b"\x00\x00\x00\x04foo").read_string().is_err());
+        assert!(Reader::new(b"\x00\x00\x00\x64\xff\xff\xff\xff")
+            .read_err()
+            .is_err());
     }
 
     #[test]
@@ -551,4 +785,50 @@ mod tests {
         let written = &buf[0..written];
         assert_eq!(b"\x00\x00\x00\x08\x02\x00\x00\x00\x03foo", written);
     }
+
+    #[test]
+    fn psync_frames() {
+        let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
+
+        let psync = Psync {
+            capabilities: CAPA_CONTINUE,
+            replication_id: "foo".to_string(),
+            offset: 1234,
+        };
+        let written = {
+            let mut writer = Writer::new(&mut buf);
+            writer.push_psync(&psync);
+            writer.finish();
+            writer.written()
+        };
+        assert_eq!(psync, Reader::new(&buf[4..written]).read_psync().unwrap());
+        assert!(Reader::new(&buf[4..written]).read_psync_reply().is_err());
+
+        for reply in [
+            PsyncReply::FullResync {
+                replication_id: "foo".to_string(),
+                offset: 1234,
+                snapshot_len: 100,
+            },
+            PsyncReply::Continue {
+                replication_id: "foo".to_string(),
+                offset: 1234,
+            },
+        ] {
+            let written = {
+                let mut writer = Writer::new(&mut buf);
+                writer.push_psync_reply(&reply);
+                writer.finish();
+                writer.written()
+            };
+            assert_eq!(
+                reply,
+                Reader::new(&buf[4..written]).read_psync_reply().unwrap()
+            );
+        }
+
+        // A request is not a PSYNC frame, and a truncated frame is invalid
+        assert!(Reader::new(b"\x03\x00").read_psync().is_err());
+        assert!(Reader::new(&buf[4..10]).read_psync_reply().is_err());
+    }
 }
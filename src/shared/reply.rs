@@ -0,0 +1,134 @@
+//! The replies of the server, only needing `alloc` like the protocol to decode them anywhere.
+
+use crate::protocol;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A reply of the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Nil,
+    Error { code: u32, message: String },
+    Bytes(Vec<u8>),
+    Int(u64),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Decode the reply in the body of a message.
+    pub fn decode(body: &[u8]) -> Result<Self, protocol::Error> {
+        Self::read(&mut protocol::Reader::new(body))
+    }
+
+    /// Encode the reply as the body of a message, the reverse of [`Value::decode`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        self.write(&mut body);
+        body
+    }
+
+    fn write(&self, body: &mut Vec<u8>) {
+        match self {
+            Self::Nil => body.push(protocol::DataType::Nil as u8),
+            Self::Error { code, message } => {
+                body.push(protocol::DataType::Err as u8);
+                body.extend_from_slice(&code.to_be_bytes());
+                body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+                body.extend_from_slice(message.as_bytes());
+            }
+            Self::Bytes(value) => {
+                body.push(protocol::DataType::Str as u8);
+                body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                body.extend_from_slice(value);
+            }
+            Self::Int(value) => {
+                body.push(protocol::DataType::Int as u8);
+                body.extend_from_slice(&value.to_be_bytes());
+            }
+            Self::Array(items) => {
+                body.push(protocol::DataType::Arr as u8);
+                body.extend_from_slice(&(items.len() as u32).to_be_bytes());
+                for item in items {
+                    item.write(body);
+                }
+            }
+        }
+    }
+
+    fn read(reader: &mut protocol::Reader) -> Result<Self, protocol::Error> {
+        let value = match reader.read_data_type()? {
+            protocol::DataType::Nil => Self::Nil,
+            protocol::DataType::Err => {
+                let (code, message) = reader.read_err()?;
+                Self::Error {
+                    code,
+                    message: String::from_utf8_lossy(message).into_owned(),
+                }
+            }
+            protocol::DataType::Str => Self::Bytes(reader.read_string()?.to_vec()),
+            protocol::DataType::Int => Self::Int(reader.read_int()?),
+            protocol::DataType::Arr => {
+                let n = reader.read_arr_length()?;
+
+                let mut items = Vec::new();
+                for _ in 0..n {
+                    items.push(Self::read(reader)?);
+                }
+                Self::Array(items)
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Returns the string, `None` if it's not a string or not valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the integer, `None` if it's not an integer or doesn't fit in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => i64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::Error { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+    use alloc::string::String;
+    use alloc::vec;
+
+    #[test]
+    fn conversions() {
+        let value = Value::Bytes(b"abc".to_vec());
+        assert_eq!(Some("abc"), value.as_str());
+        assert_eq!(Some(&b"abc"[..]), value.as_bytes());
+        assert_eq!(None, value.as_i64());
+        assert_eq!(None, Value::Bytes(vec![0xff]).as_str());
+
+        assert_eq!(Some(3), Value::Int(3).as_i64());
+        assert_eq!(None, Value::Int(u64::MAX).as_i64());
+        assert_eq!(None, Value::Nil.as_str());
+
+        assert!(Value::Error {
+            code: 100,
+            message: String::new()
+        }
+        .is_error());
+        assert!(!Value::Nil.is_error());
+    }
+}
@@ -0,0 +1,61 @@
+//! The hash slots partitioning the keyspace in cluster mode, computed like in Redis so that the clients can route
+//! the requests themselves.
+
+pub const NB_SLOTS: usize = 16384;
+
+/// CRC-16/XMODEM, the checksum used to compute the slot of a key.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Returns the slot of `key`.
+///
+/// Only the part between the first `{` and the next `}` is hashed if it's not empty, so that related keys can be put
+/// in the same slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|&c| c == b'{') {
+        Some(start) => match key[start + 1..].iter().position(|&c| c == b'}') {
+            Some(length) if length > 0 => &key[start + 1..start + 1 + length],
+            _ => key,
+        },
+        None => key,
+    };
+
+    crc16(hashed) % NB_SLOTS as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, key_slot};
+
+    #[test]
+    fn slots() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(5061, key_slot(b"bar"));
+
+        // Hash tags
+        assert_eq!(key_slot(b"user1000"), key_slot(b"{user1000}.following"));
+        assert_eq!(
+            key_slot(b"{user1000}.following"),
+            key_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_slot(b"bar"), key_slot(b"foo{bar}{zap}"));
+        assert_ne!(key_slot(b"bar"), key_slot(b"foo{}{bar}"));
+        assert_ne!(key_slot(b"bar"), key_slot(b"{bar"));
+    }
+}
@@ -0,0 +1,323 @@
+//! Connection URLs, in the form used by the Redis clients:
+//!
+//! * `redis://[[user]:password@]host[:port][/db]`, `rediss://` for TLS
+//! * `unix://[[user]:password@]/path/to/socket[?db=db]`
+//!
+//! The user and the password can be percent-encoded, the port is 1234 if missing and the database 0.
+
+use crate::ErrorKind;
+use onlyerror::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const DEFAULT_PORT: u16 = 1234;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UrlError {
+    #[error("unknown scheme {0:?}, want redis, rediss or unix")]
+    UnknownScheme(String),
+    #[error("missing host")]
+    MissingHost,
+    #[error("missing path of the unix socket")]
+    MissingPath,
+    #[error("invalid port {0:?}")]
+    InvalidPort(String),
+    #[error("invalid database {0:?}")]
+    InvalidDatabase(String),
+    #[error("unknown parameter {0:?}")]
+    UnknownParameter(String),
+    #[error("invalid percent-encoding in {0:?}")]
+    InvalidEncoding(String),
+}
+
+impl UrlError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidInput
+    }
+}
+
+/// Where a URL points to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    /// Formats the endpoint as taken by [`Client::connect`](crate::client::Client::connect).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // An IPv6 address
+            Self::Tcp { host, port } if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Self::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A parsed connection URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionUrl {
+    pub endpoint: Endpoint,
+    /// The user authenticated with AUTH, the default user if `None`.
+    pub user: Option<String>,
+    /// The password sent with AUTH after connecting, no AUTH if `None`.
+    pub password: Option<String>,
+    /// The index of the database.
+    pub db: u32,
+    /// Whether the connection uses TLS, the `rediss` scheme.
+    pub tls: bool,
+}
+
+impl FromStr for ConnectionUrl {
+    type Err = UrlError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some(parts) => parts,
+            None => return Err(UrlError::UnknownScheme(url.to_string())),
+        };
+
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+
+        // The user info ends at the first '@', one in the password must be encoded
+        let (user, password, rest) = match rest.split_once('@') {
+            Some((user_info, rest)) => {
+                let (user, password) = match user_info.split_once(':') {
+                    Some((user, password)) => (user, Some(password)),
+                    None => (user_info, None),
+                };
+
+                let user = match decode(user)? {
+                    user if user.is_empty() => None,
+                    user => Some(user),
+                };
+                let password = match password {
+                    Some(password) => Some(decode(password)?),
+                    None => None,
+                };
+
+                (user, password, rest)
+            }
+            None => (None, None, rest),
+        };
+
+        let mut db = None;
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            match param.split_once('=') {
+                Some(("db", value)) => db = Some(parse_db(value)?),
+                _ => return Err(UrlError::UnknownParameter(param.to_string())),
+            }
+        }
+
+        let (endpoint, tls) = match scheme {
+            "redis" | "rediss" => {
+                let (authority, path) = match rest.find('/') {
+                    Some(pos) => rest.split_at(pos),
+                    None => (rest, ""),
+                };
+
+                match path.trim_start_matches('/') {
+                    "" => {}
+                    path => db = Some(parse_db(path)?),
+                }
+
+                (parse_authority(authority)?, scheme == "rediss")
+            }
+            "unix" => {
+                let path = decode(rest)?;
+                if path.is_empty() {
+                    return Err(UrlError::MissingPath);
+                }
+
+                (Endpoint::Unix(PathBuf::from(path)), false)
+            }
+            _ => return Err(UrlError::UnknownScheme(scheme.to_string())),
+        };
+
+        Ok(Self {
+            endpoint,
+            user,
+            password,
+            db: db.unwrap_or(0),
+            tls,
+        })
+    }
+}
+
+/// Parse `host[:port]`, with the host between brackets for an IPv6 address.
+fn parse_authority(authority: &str) -> Result<Endpoint, UrlError> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(UrlError::InvalidPort(port.to_string())),
+            },
+            None => return Err(UrlError::MissingHost),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(UrlError::MissingHost);
+    }
+
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| UrlError::InvalidPort(port.to_string()))?,
+        None => DEFAULT_PORT,
+    };
+
+    Ok(Endpoint::Tcp {
+        host: host.to_string(),
+        port,
+    })
+}
+
+fn parse_db(value: &str) -> Result<u32, UrlError> {
+    value
+        .parse()
+        .map_err(|_| UrlError::InvalidDatabase(value.to_string()))
+}
+
+/// Decode the `%XX` sequences of `value`.
+fn decode(value: &str) -> Result<String, UrlError> {
+    let invalid = || UrlError::InvalidEncoding(value.to_string());
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'%' {
+            bytes.push(b);
+            rest = tail;
+            continue;
+        }
+
+        let hex = match tail.get(..2) {
+            Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => hex,
+            _ => return Err(invalid()),
+        };
+        // Only ASCII digits so both unwraps can't fail
+        bytes.push(u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap());
+        rest = &tail[2..];
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionUrl, Endpoint, UrlError};
+    use std::path::PathBuf;
+
+    fn parse(url: &str) -> Result<ConnectionUrl, UrlError> {
+        url.parse()
+    }
+
+    fn tcp(host: &str, port: u16) -> Endpoint {
+        Endpoint::Tcp {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_urls() {
+        assert_eq!(
+            ConnectionUrl {
+                endpoint: tcp("localhost", 1234),
+                user: None,
+                password: None,
+                db: 0,
+                tls: false,
+            },
+            parse("redis://localhost").unwrap()
+        );
+        assert_eq!(
+            ConnectionUrl {
+                endpoint: tcp("10.0.0.1", 6379),
+                user: Some("vincent".to_string()),
+                password: Some("p@ss:word".to_string()),
+                db: 2,
+                tls: true,
+            },
+            parse("rediss://vincent:p%40ss:word@10.0.0.1:6379/2").unwrap()
+        );
+
+        let url = parse("redis://:secret@[::1]:6379/").unwrap();
+        assert_eq!(tcp("::1", 6379), url.endpoint);
+        assert_eq!("[::1]:6379", url.endpoint.to_string());
+        assert_eq!(None, url.user);
+        assert_eq!(Some("secret".to_string()), url.password);
+        assert_eq!(0, url.db);
+        assert_eq!(tcp("::1", 1234), parse("redis://[::1]").unwrap().endpoint);
+        assert_eq!(3, parse("redis://localhost?db=3").unwrap().db);
+
+        let url = parse("unix://default:secret@/tmp/redis.sock?db=1").unwrap();
+        assert_eq!(
+            Endpoint::Unix(PathBuf::from("/tmp/redis.sock")),
+            url.endpoint
+        );
+        assert_eq!("/tmp/redis.sock", url.endpoint.to_string());
+        assert_eq!(Some("default".to_string()), url.user);
+        assert_eq!(1, url.db);
+        assert!(!url.tls);
+        assert_eq!(
+            Endpoint::Unix(PathBuf::from("/tmp/my redis.sock")),
+            parse("unix:///tmp/my%20redis.sock").unwrap().endpoint
+        );
+    }
+
+    #[test]
+    fn invalid_urls() {
+        for (url, err) in [
+            (
+                "localhost:1234",
+                UrlError::UnknownScheme("localhost:1234".to_string()),
+            ),
+            (
+                "http://localhost",
+                UrlError::UnknownScheme("http".to_string()),
+            ),
+            ("redis://", UrlError::MissingHost),
+            ("redis://:1234", UrlError::MissingHost),
+            ("redis://[::1", UrlError::MissingHost),
+            (
+                "redis://localhost:port",
+                UrlError::InvalidPort("port".to_string()),
+            ),
+            (
+                "redis://localhost:99999",
+                UrlError::InvalidPort("99999".to_string()),
+            ),
+            (
+                "redis://localhost/zero",
+                UrlError::InvalidDatabase("zero".to_string()),
+            ),
+            (
+                "redis://localhost?timeout=1",
+                UrlError::UnknownParameter("timeout=1".to_string()),
+            ),
+            ("unix://", UrlError::MissingPath),
+            (
+                "redis://:%4@localhost",
+                UrlError::InvalidEncoding("%4".to_string()),
+            ),
+            (
+                "redis://:%+f@localhost",
+                UrlError::InvalidEncoding("%+f".to_string()),
+            ),
+        ] {
+            assert_eq!(Err(err), parse(url), "{}", url);
+        }
+    }
+}
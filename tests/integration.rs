@@ -0,0 +1,310 @@
+//! End-to-end tests: the server runs on a thread of the test process on an ephemeral port, driven by the client of
+//! the library and by the client binary.
+
+use shared::client::{Client, Value};
+use shared::server::{Flow, MetricsSnapshot, Server};
+use shared::ResponseCode;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// An embedded server without persistence, stopped when dropped.
+struct TestServer {
+    _server: Server,
+    addr: String,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+        let addr = server.addr().to_string();
+
+        Self {
+            _server: server,
+            addr,
+        }
+    }
+
+    fn client(&self) -> Client {
+        Client::connect(&self.addr).unwrap()
+    }
+
+    fn port(&self) -> &str {
+        self.addr.rsplit_once(':').unwrap().1
+    }
+
+    /// Run the client binary with `args`, returning its exit code and what it printed.
+    fn run_client(&self, args: &[&str]) -> (i32, String) {
+        let output = Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["-h", "127.0.0.1", "-p", self.port()])
+            .args(args)
+            .output()
+            .unwrap();
+
+        (
+            output.status.code().unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    }
+}
+
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(body);
+    frame
+}
+
+#[test]
+fn commands() {
+    let server = TestServer::start();
+    let mut client = server.client();
+
+    assert_eq!(None, client.get(b"a").unwrap());
+    client.set(b"a", b"1").unwrap();
+    client.set(b"b", b"2").unwrap();
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+    assert_eq!(1, client.del(&[b"a"]).unwrap());
+    assert_eq!(0, client.del(&[b"a"]).unwrap());
+    assert_eq!(None, client.get(b"a").unwrap());
+    assert_eq!(Some(b"2".to_vec()), client.get(b"b").unwrap());
+
+    assert_eq!(
+        Value::Bytes(b"PONG".to_vec()),
+        client.execute("ping", &[]).unwrap()
+    );
+    assert!(client.execute("nope", &[]).unwrap().is_error());
+}
+
+#[test]
+fn invalid_expire_times() {
+    let server = TestServer::start();
+    let mut client = server.client();
+
+    client.set(b"a", b"1").unwrap();
+    for (cmd, args) in [
+        ("expire", &[b"a".as_slice(), b"18446744073709551615"][..]),
+        ("setex", &[b"b", b"18446744073709551615", b"v"]),
+        ("setex", &[b"b", b"0", b"v"]),
+    ] {
+        match client.execute(cmd, args).unwrap() {
+            Value::Error { message, .. } => assert_eq!("invalid expire time", message),
+            reply => panic!("unexpected reply {:?} to {}", reply, cmd),
+        }
+    }
+
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+    assert_eq!(None, client.get(b"b").unwrap());
+}
+
+#[test]
+fn embedded_metrics() {
+    let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+    let mut client = Client::connect(&server.addr().to_string()).unwrap();
+    client.set(b"a", b"1").unwrap();
+    client.get(b"a").unwrap();
+
+    let metrics: MetricsSnapshot = server.metrics();
+    assert_eq!(2, metrics.total_commands);
+    assert_eq!(1, metrics.keys);
+    assert_eq!(Some(1.0), metrics.hit_ratio());
+
+    let calls: Vec<_> = metrics
+        .commands
+        .iter()
+        .map(|summary| (summary.command.as_str(), summary.calls))
+        .collect();
+    assert_eq!(vec![("get", 1), ("set", 1)], calls);
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn embedded_hooks() {
+    let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let after_seen = Arc::clone(&seen);
+    server.on_command(
+        |cmd, _, writer| {
+            if cmd == b"flushall" {
+                writer.push_err(ResponseCode::Unknown, "denied");
+                return Flow::Stop;
+            }
+            Flow::Continue
+        },
+        move |cmd, _, _| {
+            after_seen
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(cmd).to_string())
+        },
+    );
+
+    let mut client = Client::connect(&server.addr().to_string()).unwrap();
+    client.set(b"a", b"1").unwrap();
+    match client.execute("flushall", &[]).unwrap() {
+        Value::Error { message, .. } => assert_eq!("denied", message),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+
+    server.shutdown().unwrap();
+    assert_eq!(vec!["set", "flushall", "get"], *seen.lock().unwrap());
+}
+
+#[test]
+fn shutdown() {
+    let server = Server::builder().bind("127.0.0.1:0").spawn().unwrap();
+    let addr = server.addr().to_string();
+
+    let mut client = Client::connect(&addr).unwrap();
+    let _ = client.execute("shutdown", &[b"nosave"]);
+
+    // The event loop returns instead of exiting the process
+    server.wait().unwrap();
+    assert!(Client::connect(&addr).is_err());
+}
+
+#[test]
+fn migrate() {
+    let (source, target) = (TestServer::start(), TestServer::start());
+    let mut client = source.client();
+    client.set(b"a", b"1").unwrap();
+
+    let migrate = |client: &mut Client, addr: &str| {
+        let (ip, port) = addr.rsplit_once(':').unwrap();
+        client
+            .execute(
+                "migrate",
+                &[
+                    ip.as_bytes(),
+                    port.as_bytes(),
+                    b"a",
+                    b"18446744073709551615",
+                ],
+            )
+            .unwrap()
+    };
+
+    // Refused right away instead of waiting for itself
+    match migrate(&mut client, &source.addr) {
+        Value::Error { message, .. } => assert_eq!("the target is this server", message),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    assert_eq!(Value::Nil, migrate(&mut client, &target.addr));
+    assert_eq!(None, client.get(b"a").unwrap());
+    assert_eq!(Some(b"1".to_vec()), target.client().get(b"a").unwrap());
+}
+
+#[test]
+fn pipeline() {
+    let server = TestServer::start();
+    let mut client = server.client();
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+
+    let sets: Vec<Vec<&[u8]>> = keys
+        .iter()
+        .map(|key| vec![b"set".as_slice(), key.as_bytes(), key.as_bytes()])
+        .collect();
+    let replies = client.pipeline(&sets).unwrap();
+    assert_eq!(100, replies.len());
+    assert!(replies.iter().all(|reply| !reply.is_error()));
+
+    let gets: Vec<Vec<&[u8]>> = keys
+        .iter()
+        .map(|key| vec![b"get".as_slice(), key.as_bytes()])
+        .collect();
+    let replies = client.pipeline(&gets).unwrap();
+    for (key, reply) in keys.iter().zip(&replies) {
+        assert_eq!(Some(key.as_str()), reply.as_str());
+    }
+}
+
+#[test]
+fn malformed_frames() {
+    let server = TestServer::start();
+
+    // A frame whose body isn't a command gets an error reply
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream.write_all(&frame(b"\xff\xff")).unwrap();
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).unwrap();
+    let mut body = vec![0; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut body).unwrap();
+    assert!(Value::decode(&body).unwrap().is_error());
+
+    // A frame longer than the maximum closes the connection
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let mut buf = [0; 16];
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+
+    // The other clients don't notice
+    let mut client = server.client();
+    client.set(b"a", b"1").unwrap();
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+}
+
+#[test]
+fn disconnect_mid_request() {
+    let server = TestServer::start();
+
+    let mut client = server.client();
+    client.set(b"a", b"1").unwrap();
+
+    // Half a frame, then gone
+    {
+        let mut stream = TcpStream::connect(&server.addr).unwrap();
+        let frame = frame(&shared::command::encode(&[b"set", b"a", b"2"]));
+        stream.write_all(&frame[..frame.len() / 2]).unwrap();
+    }
+
+    // The partial request is never executed
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+    assert_eq!(Some(b"1".to_vec()), server.client().get(b"a").unwrap());
+}
+
+#[test]
+fn connection_url() {
+    let server = TestServer::start();
+
+    let mut client = Client::connect_url(&format!("redis://{}/0", server.addr)).unwrap();
+    client.set(b"a", b"1").unwrap();
+    assert_eq!(Some(b"1".to_vec()), client.get(b"a").unwrap());
+
+    assert!(Client::connect_url(&format!("rediss://{}", server.addr)).is_err());
+    assert!(Client::connect_url(&format!("redis://{}/1", server.addr)).is_err());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-u", &format!("redis://{}", server.addr), "get", "a"])
+        .output()
+        .unwrap();
+    assert_eq!(b"1\n", output.stdout.as_slice());
+}
+
+#[test]
+fn client_binary() {
+    let server = TestServer::start();
+
+    assert_eq!(0, server.run_client(&["set", "name", "vincent"]).0);
+    assert_eq!(
+        (0, "vincent\n".to_string()),
+        server.run_client(&["get", "name"])
+    );
+    assert_eq!(
+        (0, "\"vincent\"\n".to_string()),
+        server.run_client(&["--output", "json", "get", "name"])
+    );
+
+    // The library sees what the binary wrote
+    assert_eq!(
+        Some(b"vincent".to_vec()),
+        server.client().get(b"name").unwrap()
+    );
+
+    // An error reply
+    assert_eq!(1, server.run_client(&["nope"]).0);
+}
@@ -0,0 +1,191 @@
+//! The codec of the protocol for JavaScript, built for `wasm32-unknown-unknown` without the `std` feature:
+//!
+//! ```text
+//! cargo build --release --target wasm32-unknown-unknown
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/my_own_redis_wasm.wasm
+//! ```
+//!
+//! ```js
+//! import init, { parseMessage, decodeReply } from "./pkg/my_own_redis_wasm.js";
+//!
+//! await init();
+//! const message = parseMessage(buf);
+//! if (message !== undefined) {
+//!     buf = buf.subarray(message.read);
+//!     const reply = decodeReply(message.body);
+//! }
+//! ```
+
+use shared::protocol;
+use shared::reply::Value;
+use wasm_bindgen::prelude::*;
+
+/// A message framed by [`parse_message`].
+#[wasm_bindgen]
+pub struct Message {
+    read: usize,
+    body: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Message {
+    /// The number of bytes of the buffer making the message, to skip before parsing the next one.
+    #[wasm_bindgen(getter)]
+    pub fn read(&self) -> usize {
+        self.read
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn body(&self) -> Vec<u8> {
+        self.body.clone()
+    }
+}
+
+/// Parse the first message of `buf`, `undefined` if it doesn't hold a whole message yet.
+#[wasm_bindgen(js_name = parseMessage)]
+pub fn parse_message(buf: &[u8]) -> Result<Option<Message>, JsError> {
+    try_parse_message(buf).map_err(|err| JsError::new(&err.to_string()))
+}
+
+fn try_parse_message(buf: &[u8]) -> Result<Option<Message>, protocol::Error> {
+    match protocol::parse_message(buf) {
+        Ok((read, body)) => Ok(Some(Message {
+            read,
+            body: body.to_vec(),
+        })),
+        Err(protocol::Error::InputTooShort(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// A reply of the server, see [`shared::reply::Value`].
+#[wasm_bindgen]
+pub struct Reply(Value);
+
+#[wasm_bindgen]
+impl Reply {
+    /// One of `nil`, `error`, `bytes`, `int` or `array`.
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        let kind = match self.0 {
+            Value::Nil => "nil",
+            Value::Error { .. } => "error",
+            Value::Bytes(_) => "bytes",
+            Value::Int(_) => "int",
+            Value::Array(_) => "array",
+        };
+        kind.to_string()
+    }
+
+    /// The code of an error reply.
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> Option<u32> {
+        match self.0 {
+            Value::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The message of an error reply.
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> Option<String> {
+        match &self.0 {
+            Value::Error { message, .. } => Some(message.clone()),
+            _ => None,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        self.0.as_bytes().map(|value| value.to_vec())
+    }
+
+    /// The bytes as a string, `undefined` if they aren't valid UTF-8.
+    #[wasm_bindgen(getter)]
+    pub fn string(&self) -> Option<String> {
+        self.0.as_str().map(|value| value.to_string())
+    }
+
+    /// The integer as a `BigInt`, the server replies with unsigned 64 bits integers.
+    #[wasm_bindgen(getter)]
+    pub fn int(&self) -> Option<u64> {
+        match self.0 {
+            Value::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The items of an array reply.
+    #[wasm_bindgen(getter)]
+    pub fn items(&self) -> Option<Vec<Reply>> {
+        match &self.0 {
+            Value::Array(items) => Some(items.iter().cloned().map(Reply).collect()),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the reply in the body of a message.
+#[wasm_bindgen(js_name = decodeReply)]
+pub fn decode_reply(body: &[u8]) -> Result<Reply, JsError> {
+    Value::decode(body)
+        .map(Reply)
+        .map_err(|err| JsError::new(&err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{try_parse_message, Reply};
+    use shared::protocol::{self, Writer, BUF_LEN};
+    use shared::reply::Value;
+
+    // NOTE(vincent): JsError can only be built on wasm, the tests go through the functions behind the exports.
+
+    #[test]
+    fn parse_message() {
+        let mut buf = [0; BUF_LEN];
+        let mut writer = Writer::new(&mut buf);
+        writer.push_string("foo");
+        writer.finish();
+        let n = writer.written();
+
+        let message = try_parse_message(&buf[..n]).unwrap().unwrap();
+        assert_eq!(n, message.read());
+        assert_eq!(
+            Value::Bytes(b"foo".to_vec()),
+            Value::decode(&message.body()).unwrap()
+        );
+
+        assert!(try_parse_message(&buf[..n - 1]).unwrap().is_none());
+        assert!(matches!(
+            try_parse_message(&[0xff, 0xff, 0xff, 0xff]),
+            Err(protocol::Error::MessageTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn reply() {
+        let reply = Reply(Value::Array(vec![
+            Value::Int(3),
+            Value::Bytes(b"foo".to_vec()),
+            Value::Error {
+                code: 100,
+                message: "unknown command".to_string(),
+            },
+        ]));
+        assert_eq!("array", reply.kind());
+
+        let items = reply.items().unwrap();
+        assert_eq!(3, items.len());
+        assert_eq!(Some(3), items[0].int());
+        assert_eq!(Some("foo".to_string()), items[1].string());
+        assert_eq!(Some(b"foo".to_vec()), items[1].bytes());
+        assert_eq!(None, items[1].code());
+        assert_eq!("error", items[2].kind());
+        assert_eq!(Some(100), items[2].code());
+        assert_eq!(Some("unknown command".to_string()), items[2].message());
+
+        assert_eq!("nil", Reply(Value::Nil).kind());
+        assert_eq!(None, Reply(Value::Bytes(vec![0xff])).string());
+    }
+}